@@ -0,0 +1,97 @@
+//! S3 Batch Operations manifests: the objects a command selected, written as CSV for a Batch
+//! Operations job to act on, instead of acting on them one request at a time.
+
+use crate::error::{AppError, Result};
+use crate::output::summary;
+use crate::s3::{KEY_ENCODE_SET, S3Client};
+use crate::storage::get_store_and_path;
+use object_store::ObjectStoreExt;
+use percent_encoding::utf8_percent_encode;
+
+/// A manifest as uploaded, how a job refers to it.
+#[derive(Debug)]
+pub struct UploadedManifest {
+    pub object_arn: String,
+    pub e_tag: String,
+}
+
+/// Bucket of the S3 URL `url`, which holds objects a manifest lists or the manifest itself.
+fn bucket(url: &str) -> Result<String> {
+    let client = S3Client::from_url(url)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "S3 Batch Operations manifests need s3:// URLs, got {url}"
+        ))
+    })?;
+    Ok(client.bucket().to_string())
+}
+
+/// `S3BatchOperations_CSV_20180820` manifest of the objects at `keys` in `bucket`: a
+/// `bucket,key` line per object, with the key URL-encoded.
+fn manifest_csv(bucket: &str, keys: &[&str]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for key in keys {
+        let key = utf8_percent_encode(key, KEY_ENCODE_SET).to_string();
+        writer.write_record([bucket, key.as_str()])?;
+    }
+    Ok(writer
+        .into_inner()
+        .map_err(csv::IntoInnerError::into_error)?)
+}
+
+/// Manifest at `url` listing objects in the bucket of a source.
+#[derive(Debug)]
+pub struct BatchManifest {
+    url: String,
+    bucket: String,
+}
+
+impl BatchManifest {
+    /// Manifest at `url` for objects below `src`.
+    ///
+    /// # Errors
+    ///
+    /// Fails unless both are S3 URLs, as only S3 Batch Operations reads these manifests.
+    pub fn new(src: &str, url: &str) -> Result<Self> {
+        bucket(url)?;
+        Ok(Self {
+            url: url.to_string(),
+            bucket: bucket(src)?,
+        })
+    }
+
+    /// Uploads the manifest of the objects at `keys`, replacing the one there.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the upload fails.
+    pub async fn put(&self, keys: &[&str]) -> Result<UploadedManifest> {
+        let (store, path) = get_store_and_path(&self.url)?;
+        let put = store
+            .put(&path, manifest_csv(&self.bucket, keys)?.into())
+            .await?;
+        summary!(
+            "Listed {} objects in the S3 Batch Operations manifest {}",
+            keys.len(),
+            self.url
+        );
+        Ok(UploadedManifest {
+            object_arn: format!("arn:aws:s3:::{}/{path}", bucket(&self.url)?),
+            e_tag: put.e_tag.unwrap_or_default().trim_matches('"').to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_csv_encodes_keys() -> Result<()> {
+        let csv = manifest_csv("logs", &["app/2024-06-01.log", "app/a b,c.log"])?;
+        assert_eq!(
+            String::from_utf8_lossy(&csv),
+            "logs,app/2024-06-01.log\nlogs,app/a%20b%2Cc.log\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+//! Catalog of the archives below a destination, `catalog.jsonl` with a line per archive that
+//! runs append to, so the archive holding a key can be found without listing and reading
+//! every manifest.
+
+use crate::compressor::ArchiveIndex;
+use crate::error::{AppError, Result};
+use crate::manifest::Manifest;
+use chrono::{DateTime, Utc};
+use object_store::{
+    ObjectStore, ObjectStoreExt, PutMode, PutOptions, PutPayload, UpdateVersion, path::Path,
+};
+use serde::{Deserialize, Serialize};
+
+/// Name of the catalog below the destination prefix.
+pub const CATALOG_NAME: &str = "catalog.jsonl";
+
+/// Attempts at appending when other runs keep updating the catalog concurrently.
+const APPEND_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// Key of the archive.
+    pub archive: String,
+    /// Key of its manifest.
+    pub manifest: String,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub cutoff: Option<DateTime<Utc>>,
+    pub objects: usize,
+    /// Size of the archive.
+    pub size: u64,
+    /// Smallest and largest archived key, `None` for empty archives.
+    pub first_key: Option<String>,
+    pub last_key: Option<String>,
+    /// Hex encoded SHA-256 of the archive.
+    pub sha256: String,
+    /// Hex encoded SHA-256 of the manifest.
+    pub manifest_sha256: String,
+    /// ID of the run that wrote the archive.
+    #[serde(default)]
+    pub run_id: Option<String>,
+}
+
+impl CatalogEntry {
+    pub fn new(manifest: &Manifest, manifest_sha256: String, index: &ArchiveIndex) -> Self {
+        let keys = manifest.objects.iter().map(|entry| &entry.key);
+        Self {
+            archive: manifest.archive.clone(),
+            manifest: Manifest::location(&Path::from(manifest.archive.as_str())).to_string(),
+            created: Utc::now(),
+            cutoff: manifest.cutoff,
+            objects: manifest.objects.len(),
+            size: index.size,
+            first_key: keys.clone().min().cloned(),
+            last_key: keys.max().cloned(),
+            sha256: index.sha256.clone(),
+            manifest_sha256,
+            run_id: manifest.run_id.clone(),
+        }
+    }
+
+    /// Whether `key` falls into the key range of the archive. The manifest tells whether it
+    /// is really in there.
+    pub fn covers(&self, key: &str) -> bool {
+        self.first_key.as_deref().is_some_and(|first| first <= key)
+            && self.last_key.as_deref().is_some_and(|last| key <= last)
+    }
+}
+
+/// Where the catalog of the archives below `dst_path` is.
+pub fn location(dst_path: &Path) -> Path {
+    dst_path.clone().join(CATALOG_NAME)
+}
+
+/// Appends `entry` to the catalog at `location`. Object stores cannot append, so the catalog
+/// is rewritten, conditionally on it not having changed where the store supports it.
+pub async fn append(store: &dyn ObjectStore, location: &Path, entry: &CatalogEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    for _ in 0..APPEND_ATTEMPTS {
+        let (mut catalog, mode) = match store.get(location).await {
+            Ok(result) => {
+                let version = UpdateVersion {
+                    e_tag: result.meta.e_tag.clone(),
+                    version: result.meta.version.clone(),
+                };
+                (result.bytes().await?.to_vec(), PutMode::Update(version))
+            }
+            Err(object_store::Error::NotFound { .. }) => (Vec::new(), PutMode::Create),
+            Err(e) => return Err(e.into()),
+        };
+        catalog.extend_from_slice(&line);
+        let payload = PutPayload::from(catalog);
+
+        match store
+            .put_opts(location, payload.clone(), PutOptions::from(mode))
+            .await
+        {
+            Ok(_) => return Ok(()),
+            // Another run appended in the meantime.
+            Err(
+                object_store::Error::Precondition { .. }
+                | object_store::Error::AlreadyExists { .. },
+            ) => {}
+            Err(object_store::Error::NotImplemented { .. }) => {
+                store.put(location, payload).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(AppError::Archive(format!(
+        "{location} kept changing, could not add {}",
+        entry.archive
+    )))
+}
+
+/// The entries of the catalog at `location`, oldest first.
+pub async fn load(store: &dyn ObjectStore, location: &Path) -> Result<Vec<CatalogEntry>> {
+    let bytes = store.get(location).await?.bytes().await?;
+    bytes
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(serde_json::from_slice(line)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::ObjectMeta;
+    use object_store::memory::InMemory;
+
+    fn entry(archive: &str, keys: &[&str]) -> CatalogEntry {
+        let objects: Vec<ObjectMeta> = keys
+            .iter()
+            .map(|key| ObjectMeta {
+                location: Path::from(*key),
+                last_modified: Utc::now(),
+                size: 1,
+                e_tag: None,
+                version: None,
+            })
+            .collect();
+        let manifest = Manifest::new(
+            &Path::from(archive),
+            None,
+            &objects,
+            &ArchiveIndex::default(),
+            &[],
+        );
+        CatalogEntry::new(&manifest, String::new(), &ArchiveIndex::default())
+    }
+
+    #[tokio::test]
+    async fn test_append_keeps_earlier_entries() -> Result<()> {
+        let store = InMemory::new();
+        let location = location(&Path::from("archive"));
+        append(&store, &location, &entry("a.tar.xz", &["logs/b", "logs/a"])).await?;
+        append(&store, &location, &entry("b.tar.xz", &["logs/c"])).await?;
+
+        let entries = load(&store, &location).await?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].first_key.as_deref(), Some("logs/a"));
+        assert_eq!(entries[0].last_key.as_deref(), Some("logs/b"));
+        assert!(entries[0].covers("logs/aa"));
+        assert!(!entries[0].covers("logs/c"));
+        assert_eq!(entries[1].manifest, "b.tar.xz.manifest.json");
+        Ok(())
+    }
+}
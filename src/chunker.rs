@@ -0,0 +1,110 @@
+//! Content-defined chunking: cut points depend on the bytes around them, not on their offset,
+//! so data shifted by an insertion still splits into mostly the same chunks.
+
+use bytes::{Bytes, BytesMut};
+
+/// Random values per byte for the gear hash, generated with splitmix64.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Splits a byte stream fed in pieces into chunks of about the configured average size, at
+/// most four times as large and, except for the last one, at least a quarter of it.
+pub struct Chunker {
+    min_size: usize,
+    max_size: usize,
+    mask: u64,
+    buf: BytesMut,
+    /// Bytes of `buf` already fed into `hash`.
+    scanned: usize,
+    hash: u64,
+}
+
+impl Chunker {
+    pub fn new(avg_size: usize) -> Self {
+        let avg_size = avg_size.max(64);
+        Self {
+            min_size: avg_size / 4,
+            max_size: avg_size * 4,
+            mask: (1 << avg_size.ilog2()) - 1,
+            buf: BytesMut::new(),
+            scanned: 0,
+            hash: 0,
+        }
+    }
+
+    /// Appends `data`, returning the chunks it completes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Bytes> {
+        self.buf.extend_from_slice(data);
+        let mut chunks = Vec::new();
+        while let Some(end) = self.next_cut() {
+            chunks.push(self.buf.split_to(end).freeze());
+        }
+        chunks
+    }
+
+    /// The rest after the last cut, `None` when there is none.
+    pub fn finish(self) -> Option<Bytes> {
+        (!self.buf.is_empty()).then(|| self.buf.freeze())
+    }
+
+    fn next_cut(&mut self) -> Option<usize> {
+        // Nothing is cut below the minimum size, so hashing only starts there.
+        let start = self.scanned.max(self.min_size);
+        for i in start..self.buf.len() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[usize::from(self.buf[i])]);
+            if self.hash & self.mask == 0 || i + 1 >= self.max_size {
+                self.scanned = 0;
+                self.hash = 0;
+                return Some(i + 1);
+            }
+        }
+        self.scanned = start.max(self.buf.len());
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(data: &[u8], piece: usize) -> Vec<Bytes> {
+        let mut chunker = Chunker::new(1024);
+        let mut chunks: Vec<Bytes> = data
+            .chunks(piece)
+            .flat_map(|piece| chunker.push(piece))
+            .collect();
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn test_chunks_do_not_depend_on_offsets() {
+        let data: Vec<u8> = (0..64 * 1024_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13).to_le_bytes()[0])
+            .collect();
+        let original = chunks(&data, 1000);
+        assert_eq!(original, chunks(&data, 7));
+        assert_eq!(original.concat(), data);
+        assert!(original.iter().all(|chunk| chunk.len() <= 4096));
+
+        // Inserting at the start only changes the first chunk.
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&data);
+        let shifted = chunks(&shifted, 1000);
+        assert_eq!(shifted[1..], original[1..]);
+    }
+}
@@ -0,0 +1,486 @@
+use async_compression::Level;
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use async_compression::tokio::write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
+#[cfg(feature = "brotli")]
+use async_compression::tokio::{bufread::BrotliDecoder, write::BrotliEncoder};
+use object_store::{Attribute, Attributes};
+use serde::Deserialize;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+/// Compression formats recognised by their file extension. Brotli needs the `brotli` feature.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Codec {
+    #[must_use]
+    pub fn from_extension(extension: Option<&str>) -> Option<Self> {
+        match extension? {
+            "gz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            "xz" => Some(Self::Xz),
+            "bz2" => Some(Self::Bzip2),
+            #[cfg(feature = "brotli")]
+            "br" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bz2",
+            #[cfg(feature = "brotli")]
+            Self::Brotli => "br",
+        }
+    }
+
+    #[must_use]
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Gzip => "application/gzip",
+            Self::Zstd => "application/zstd",
+            Self::Xz => "application/x-xz",
+            Self::Bzip2 => "application/x-bzip2",
+            #[cfg(feature = "brotli")]
+            Self::Brotli => "application/x-brotli",
+        }
+    }
+
+    /// Token used in `Content-Encoding` headers.
+    #[must_use]
+    pub const fn content_encoding(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bzip2",
+            #[cfg(feature = "brotli")]
+            Self::Brotli => "br",
+        }
+    }
+
+    /// Attributes for `original` compressed with this codec, following its convention: with a
+    /// `Content-Encoding` the content type describes the decoded payload and is kept, otherwise
+    /// the content type names the codec.
+    #[must_use]
+    pub fn attributes(self, original: &Attributes) -> Attributes {
+        let mut attributes = original.clone();
+        if original.get(&Attribute::ContentEncoding).is_some() {
+            attributes.insert(Attribute::ContentEncoding, self.content_encoding().into());
+        } else {
+            attributes.insert(Attribute::ContentType, self.content_type().into());
+        }
+        attributes
+    }
+
+    /// Encoder passing content that is compressed already through as cheaply as the format
+    /// allows: raw blocks for zstd, which skip compression altogether, stored blocks for gzip
+    /// and the fastest level for the others. Shutting it down shuts `writer` down as well.
+    pub fn store_encoder<'a, W>(self, writer: W) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+    where
+        W: AsyncWrite + Unpin + Send + 'a,
+    {
+        match self {
+            Self::Zstd => Box::new(RawZstdFrame::new(writer)),
+            Self::Gzip => self.encoder(writer, Level::Precise(0)),
+            _ => self.encoder(writer, Level::Fastest),
+        }
+    }
+
+    /// Rough upper bound of the memory the encoder needs at `level`; anything but
+    /// [`Level::Fastest`] is treated like [`Level::Best`].
+    #[must_use]
+    pub const fn encoder_memory(self, level: Level) -> usize {
+        const MIB: usize = 1024 * 1024;
+        let fastest = matches!(level, Level::Fastest);
+        match self {
+            Self::Gzip => MIB / 4,
+            Self::Zstd if fastest => 4 * MIB,
+            Self::Zstd => 1024 * MIB,
+            Self::Xz if fastest => 3 * MIB,
+            Self::Xz => 674 * MIB,
+            Self::Bzip2 if fastest => 2 * MIB,
+            Self::Bzip2 => 8 * MIB,
+            #[cfg(feature = "brotli")]
+            Self::Brotli if fastest => MIB,
+            #[cfg(feature = "brotli")]
+            Self::Brotli => 80 * MIB,
+        }
+    }
+
+    pub fn decoder<R>(self, reader: R) -> Box<dyn AsyncRead + Unpin + Send>
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+    {
+        // Archives are written as a series of independently compressed frames.
+        macro_rules! concatenated {
+            ($decoder:ident) => {{
+                let mut decoder = $decoder::new(reader);
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }};
+        }
+        match self {
+            Self::Gzip => concatenated!(GzipDecoder),
+            Self::Zstd => concatenated!(ZstdDecoder),
+            Self::Xz => concatenated!(XzDecoder),
+            Self::Bzip2 => concatenated!(BzDecoder),
+            #[cfg(feature = "brotli")]
+            Self::Brotli => concatenated!(BrotliDecoder),
+        }
+    }
+
+    /// Shutting the encoder down finishes the stream and shuts `writer` down as well.
+    pub fn encoder<'a, W>(self, writer: W, level: Level) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+    where
+        W: AsyncWrite + Unpin + Send + 'a,
+    {
+        match self {
+            Self::Gzip => Box::new(GzipEncoder::with_quality(writer, level)),
+            Self::Zstd => Box::new(ZstdEncoder::with_quality(writer, level)),
+            Self::Xz => Box::new(XzEncoder::with_quality(writer, level)),
+            Self::Bzip2 => Box::new(BzEncoder::with_quality(writer, level)),
+            #[cfg(feature = "brotli")]
+            Self::Brotli => Box::new(BrotliEncoder::with_quality(writer, level)),
+        }
+    }
+}
+
+/// Compression effort, traded against speed.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Fastest,
+    Best,
+    /// The codec's default level, except for objects compressed already, e.g. images, videos
+    /// or gzip files, which `archive` stores with [`Codec::store_encoder`] instead.
+    Auto,
+}
+
+impl Compression {
+    #[must_use]
+    pub const fn level(self) -> Level {
+        match self {
+            Self::Fastest => Level::Fastest,
+            Self::Best => Level::Best,
+            Self::Auto => Level::Default,
+        }
+    }
+}
+
+/// Extensions of formats that are compressed already.
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "orc", "parquet", "png", "pptx",
+    "rar", "tgz", "webm", "webp", "woff2", "xlsx", "xz", "zip", "zst",
+];
+
+/// Content types of formats that are compressed already, besides images, audio and video.
+const PRECOMPRESSED_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/vnd.apache.parquet",
+    "application/x-7z-compressed",
+    "application/x-brotli",
+    "application/x-bzip2",
+    "application/x-gzip",
+    "application/x-rar-compressed",
+    "application/x-xz",
+    "application/zip",
+    "application/zstd",
+];
+
+/// Images and audio that are not compressed.
+const UNCOMPRESSED_MEDIA: &[&str] = &[
+    "audio/wav",
+    "audio/x-wav",
+    "image/bmp",
+    "image/svg+xml",
+    "image/tiff",
+];
+
+/// Whether the object at `key` with `attributes` is compressed already, going by its
+/// `Content-Encoding`, then its `Content-Type` and, when that is missing or generic, the
+/// extension of the key.
+#[must_use]
+pub fn is_precompressed(key: &str, attributes: &Attributes) -> bool {
+    let header = |attribute| {
+        attributes
+            .get(&attribute)
+            .map(|value| value.as_ref().to_ascii_lowercase())
+    };
+    if header(Attribute::ContentEncoding).is_some_and(|encoding| encoding != "identity") {
+        return true;
+    }
+    let content_type = header(Attribute::ContentType)
+        .and_then(|value| {
+            value
+                .split(';')
+                .next()
+                .map(|media| media.trim().to_string())
+        })
+        .filter(|media| {
+            !media.is_empty()
+                && !["application/octet-stream", "binary/octet-stream"].contains(&media.as_str())
+        });
+    content_type.map_or_else(
+        || {
+            key.rsplit_once('.').is_some_and(|(_, extension)| {
+                PRECOMPRESSED_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+            })
+        },
+        |media| {
+            PRECOMPRESSED_TYPES.contains(&media.as_str())
+                || (["image/", "audio/", "video/"]
+                    .iter()
+                    .any(|kind| media.starts_with(kind))
+                    && !UNCOMPRESSED_MEDIA.contains(&media.as_str()))
+        },
+    )
+}
+
+/// Signatures compressed formats start with, at the offset given.
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+    (0, b"\x1f\x8b"),           // gzip
+    (0, b"\x28\xb5\x2f\xfd"),   // zstd
+    (0, b"\xfd7zXZ\x00"),       // xz
+    (0, b"BZh"),                // bzip2
+    (0, b"\x04\x22\x4d\x18"),   // lz4
+    (0, b"PK\x03\x04"),         // zip, also docx, xlsx and jar
+    (0, b"7z\xbc\xaf\x27\x1c"), // 7z
+    (0, b"Rar!\x1a\x07"),       // rar
+    (0, b"\xff\xd8\xff"),       // jpeg
+    (0, b"\x89PNG"),            // png
+    (0, b"GIF8"),               // gif
+    (8, b"WEBP"),               // webp
+    (4, b"ftyp"),               // mp4, mov, heic and avif
+    (0, b"\x1a\x45\xdf\xa3"),   // mkv and webm
+    (0, b"OggS"),               // ogg and opus
+    (0, b"fLaC"),               // flac
+    (0, b"ID3"),                // mp3
+    (0, b"PAR1"),               // parquet
+    (0, b"wOF2"),               // woff2
+];
+
+/// Whether content starting with `start` is in a compressed format, by its magic bytes.
+#[must_use]
+pub fn has_compressed_magic(start: &[u8]) -> bool {
+    COMPRESSED_MAGIC.iter().any(|(offset, magic)| {
+        start
+            .get(*offset..offset + magic.len())
+            .is_some_and(|bytes| bytes == *magic)
+    })
+}
+
+/// Zstandard frames are made of blocks of at most this size.
+const ZSTD_MAX_BLOCK: usize = 128 * 1024;
+
+/// Header of a zstd frame of unknown size, without checksum or dictionary, whose window
+/// fits a whole block.
+const ZSTD_RAW_FRAME_HEADER: [u8; 6] = [0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x38];
+
+/// A zstd frame of raw blocks, content written as it is, which zstd decoders read like any
+/// other frame. Shutting it down ends the frame and shuts `inner` down as well.
+struct RawZstdFrame<W> {
+    inner: W,
+    /// Headers and blocks not written to `inner` yet, from `flushed` on.
+    pending: Vec<u8>,
+    flushed: usize,
+    started: bool,
+    finished: bool,
+}
+
+impl<W> RawZstdFrame<W> {
+    const fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            flushed: 0,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Queues a block of `data`, the frame header first.
+    fn push_block(&mut self, data: &[u8], last: bool) {
+        if !self.started {
+            self.pending.extend_from_slice(&ZSTD_RAW_FRAME_HEADER);
+            self.started = true;
+        }
+        // Little endian: the last block flag, the block type (0, raw) and the size.
+        let header = (data.len() << 3) | usize::from(last);
+        self.pending.extend_from_slice(&header.to_le_bytes()[..3]);
+        self.pending.extend_from_slice(data);
+    }
+}
+
+impl<W: AsyncWrite + Unpin> RawZstdFrame<W> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.flushed < self.pending.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.flushed..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.flushed += written;
+        }
+        self.pending.clear();
+        self.flushed = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for RawZstdFrame<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        let taken = buf.len().min(ZSTD_MAX_BLOCK);
+        if taken > 0 {
+            this.push_block(&buf[..taken], false);
+        }
+        Poll::Ready(Ok(taken))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.finished {
+            ready!(this.poll_pending(cx))?;
+            this.push_block(&[], true);
+            this.finished = true;
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_codecs_round_trip() -> Result<()> {
+        for codec in [
+            Codec::Gzip,
+            Codec::Zstd,
+            Codec::Xz,
+            Codec::Bzip2,
+            #[cfg(feature = "brotli")]
+            Codec::Brotli,
+        ] {
+            let (writer, reader) = tokio::io::duplex(64 * 1024);
+            let mut encoder = codec.encoder(writer, Level::Fastest);
+            encoder.write_all(b"hello").await?;
+            encoder.shutdown().await?;
+
+            let mut decoded = String::new();
+            codec
+                .decoder(tokio::io::BufReader::new(reader))
+                .read_to_string(&mut decoded)
+                .await?;
+            assert_eq!(decoded, "hello", "{codec:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_precompressed() {
+        let typed = |content_type: &str| {
+            let mut attributes = Attributes::new();
+            attributes.insert(Attribute::ContentType, content_type.to_string().into());
+            attributes
+        };
+        let none = Attributes::new();
+        assert!(is_precompressed("photos/a.JPG", &none));
+        assert!(is_precompressed("exports/day.parquet", &none));
+        assert!(!is_precompressed("logs/app.log", &none));
+        assert!(!is_precompressed("logs.d/app", &none));
+
+        assert!(is_precompressed("media/clip", &typed("video/mp4")));
+        assert!(!is_precompressed("media/logo", &typed("image/svg+xml")));
+        assert!(is_precompressed(
+            "x",
+            &typed("application/gzip; charset=binary")
+        ));
+        // Generic content types leave it to the extension.
+        assert!(is_precompressed("a.gz", &typed("binary/octet-stream")));
+        assert!(!is_precompressed("a.gz", &typed("text/plain")));
+
+        let mut encoded = typed("application/json");
+        encoded.insert(Attribute::ContentEncoding, "gzip".into());
+        assert!(is_precompressed("events/a.json", &encoded));
+    }
+
+    #[tokio::test]
+    async fn test_store_encoders_round_trip() -> Result<()> {
+        let content = "stored ".repeat(50_000);
+        for codec in [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::Bzip2] {
+            let (writer, reader) = tokio::io::duplex(64 * 1024);
+            let writing = tokio::spawn({
+                let content = content.clone();
+                async move {
+                    let mut encoder = codec.store_encoder(writer);
+                    encoder.write_all(content.as_bytes()).await?;
+                    encoder.shutdown().await
+                }
+            });
+
+            let mut decoded = String::new();
+            codec
+                .decoder(tokio::io::BufReader::new(reader))
+                .read_to_string(&mut decoded)
+                .await?;
+            writing.await.map_err(std::io::Error::other)??;
+            assert_eq!(decoded, content, "{codec:?}");
+        }
+
+        // Raw blocks take the content as it is.
+        let mut raw = Vec::new();
+        let mut encoder = Codec::Zstd.store_encoder(&mut raw);
+        encoder.write_all(content.as_bytes()).await?;
+        encoder.shutdown().await?;
+        drop(encoder);
+        assert!(raw.len() > content.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_compressed_magic() {
+        assert!(has_compressed_magic(b"\x1f\x8b\x08\x00"));
+        assert!(has_compressed_magic(b"\x00\x00\x00\x20ftypisom"));
+        assert!(has_compressed_magic(b"RIFF\x00\x00\x00\x00WEBPVP8 "));
+        assert!(!has_compressed_magic(b"RIFF\x00\x00\x00\x00WAVEfmt "));
+        assert!(!has_compressed_magic(b"{\"id\":1}"));
+        assert!(!has_compressed_magic(b""));
+    }
+
+    #[test]
+    fn test_from_extension() {
+        assert_eq!(Codec::from_extension(Some("zst")), Some(Codec::Zstd));
+        assert_eq!(Codec::from_extension(Some("log")), None);
+        assert_eq!(Codec::from_extension(None), None);
+    }
+}
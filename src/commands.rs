@@ -1,48 +1,61 @@
-use crate::compressor::compress;
-use crate::error::{AppError, Result};
-use crate::object_storage::delete_keys;
-use crate::storage::get_store_and_path;
-use async_compression::Level;
-use chrono::{DateTime as ChronoDateTime, Duration, Utc};
-use object_store::path::Path;
+//! One module per subcommand of the binary. The entry points print their progress and
+//! report failures to the command line; see the README for what each of them does.
+#![allow(clippy::missing_errors_doc)]
 
-pub async fn archive(
-    src: String,
-    dst: String,
-    cutoff: Option<ChronoDateTime<Utc>>,
-    buffer_size: usize,
-    level: Level,
-) -> Result<()> {
-    let (src_store, src_path) = get_store_and_path(&src)?;
-    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+mod advise;
+mod archive;
+mod cat;
+mod checksum;
+mod dedup_archive;
+mod delete_markers;
+mod estimate;
+mod inventory;
+mod list_archives;
+mod ls;
+mod mv;
+mod presign;
+mod recompress;
+mod restore;
+mod self_test;
+mod stat;
+mod sync;
+mod thaw;
+mod train_dict;
+mod transition;
+mod trash_gc;
+mod untrash;
+mod verify_archive;
 
-    println!("Archiving from {src} to {dst}");
+pub use advise::{AdviceFormat, AdviseOptions, advise};
+pub(crate) use archive::ask;
+pub use archive::{
+    ArchiveJob, DEFAULT_NAME_TEMPLATE, DeleteVerification, Disposal, EntryMode, GroupBy, GroupDate,
+    KeyRewrite, MAX_PART_SIZE, MIN_PART_SIZE, Order,
+};
+pub use cat::cat;
+pub use checksum::checksum;
+pub use dedup_archive::{DedupOptions, dedup_archive};
+pub use delete_markers::clean_delete_markers;
+pub use estimate::{EstimateOptions, estimate};
+pub use inventory::{InventoryFormat, inventory};
+pub use list_archives::list_archives;
+pub use ls::ls;
+pub use mv::mv;
+pub use presign::{PresignMethod, presign};
+pub use recompress::{RecompressOptions, recompress};
+pub use restore::restore;
+pub use self_test::self_test;
+pub use stat::stat;
+pub use sync::{MirrorOptions, sync};
+pub use thaw::{RestoreTier, ThawOptions, thaw};
+pub use train_dict::{TrainOptions, train_zstd_dict};
+pub use transition::{BatchOptions, transition};
+pub use trash_gc::trash_gc;
+pub use untrash::untrash;
+pub use verify_archive::verify_archive;
 
-    let cutoff_dt = cutoff.unwrap_or_else(|| {
-        let now = Utc::now();
-        now - Duration::seconds(1)
-    });
-    let cutoff_str = format!("{}", cutoff_dt.format("%Y%m%d_%H%M%S"));
-
-    let dst_file_path = dst_path.join(format!("archive_{cutoff_str}.tar.xz"));
-
-    let mut archived_keys: Vec<Path> = Vec::new();
-    compress(
-        src_store.as_ref(),
-        src_path,
-        dst_store,
-        dst_file_path,
-        cutoff_dt,
-        buffer_size,
-        level,
-        &mut archived_keys,
-    )
-    .await
-    .map_err(|e| AppError::Compression(Box::new(e)))?;
-
-    delete_keys(src_store.as_ref(), archived_keys)
-        .await
-        .map_err(|e| AppError::Deletion(Box::new(e)))?;
-
-    Ok(())
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Human,
+    Json,
 }
@@ -0,0 +1,333 @@
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use object_store::{ObjectMeta, path::Path};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Smallest size `STANDARD_IA` and `GLACIER_IR` bill objects for; below it lifecycle
+/// transitions cost more than they save and objects are better off in a tarball.
+const SMALL_OBJECT_SIZE: u64 = 128 * 1024;
+
+/// Storage class recommended for prefixes of large objects.
+const TRANSITION_STORAGE_CLASS: &str = "STANDARD_IA";
+
+/// S3 refuses transitions to `STANDARD_IA` earlier than this many days after creation.
+const MIN_TRANSITION_DAYS: u32 = 30;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdviceFormat {
+    /// A table of the prefixes with their advice.
+    Human,
+    /// S3 Lifecycle configuration JSON for the prefixes native rules suit.
+    Lifecycle,
+    /// `[[rules]]` of the configuration file for the prefixes archiving suits.
+    Rules,
+}
+
+pub struct AdviseOptions {
+    /// Objects older than this many days are the ones advised on.
+    pub older_than_days: u32,
+    /// Levels of the key hierarchy below the source prefixes are grouped by.
+    pub depth: usize,
+    pub format: AdviceFormat,
+    /// Where suggested archive rules put their archives, the prefix appended.
+    pub archive_dst: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Advice {
+    /// Nothing is old enough yet.
+    Keep,
+    /// Mostly large objects, a native lifecycle transition is cheapest.
+    Lifecycle,
+    /// Mostly small objects, archiving them into tarballs is cheapest.
+    Archive,
+}
+
+/// Objects below one prefix, in total and those older than the cutoff.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct PrefixStats {
+    objects: u64,
+    bytes: u64,
+    old_objects: u64,
+    old_bytes: u64,
+    /// Old objects smaller than [`SMALL_OBJECT_SIZE`].
+    old_small: u64,
+    oldest: Option<DateTime<Utc>>,
+}
+
+impl PrefixStats {
+    fn add(&mut self, meta: &ObjectMeta, cutoff: DateTime<Utc>) {
+        self.objects += 1;
+        self.bytes += meta.size;
+        if meta.last_modified < cutoff {
+            self.old_objects += 1;
+            self.old_bytes += meta.size;
+            if meta.size < SMALL_OBJECT_SIZE {
+                self.old_small += 1;
+            }
+        }
+        self.oldest = Some(
+            self.oldest
+                .map_or(meta.last_modified, |oldest| oldest.min(meta.last_modified)),
+        );
+    }
+
+    const fn advice(&self) -> Advice {
+        if self.old_objects == 0 {
+            Advice::Keep
+        } else if self.old_small * 2 > self.old_objects {
+            Advice::Archive
+        } else {
+            Advice::Lifecycle
+        }
+    }
+}
+
+/// Statistics per prefix of the objects below `src_path`, grouped by their first `depth` key
+/// segments below it.
+struct Analysis {
+    src_path: Path,
+    depth: usize,
+    cutoff: DateTime<Utc>,
+    prefixes: BTreeMap<String, PrefixStats>,
+}
+
+impl Analysis {
+    const fn new(src_path: Path, depth: usize, cutoff: DateTime<Utc>) -> Self {
+        Self {
+            src_path,
+            depth,
+            cutoff,
+            prefixes: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, meta: &ObjectMeta) {
+        let parts: Vec<String> = meta
+            .location
+            .prefix_match(&self.src_path)
+            .map(|parts| parts.map(|part| part.as_ref().to_string()).collect())
+            .unwrap_or_default();
+        // The file name itself is no prefix.
+        let depth = self.depth.min(parts.len().saturating_sub(1));
+        let relative = parts[..depth].join("/");
+        let prefix = [self.src_path.as_ref(), relative.as_str()]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("/");
+        self.prefixes
+            .entry(prefix)
+            .or_default()
+            .add(meta, self.cutoff);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LifecycleConfiguration {
+    rules: Vec<LifecycleRule>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LifecycleRule {
+    #[serde(rename = "ID")]
+    id: String,
+    filter: LifecycleFilter,
+    status: &'static str,
+    transitions: Vec<LifecycleTransition>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LifecycleFilter {
+    prefix: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LifecycleTransition {
+    days: u32,
+    storage_class: &'static str,
+}
+
+#[derive(Serialize)]
+struct RulesFile {
+    rules: Vec<SuggestedRule>,
+}
+
+/// A [`Rule`](crate::rules::Rule) archiving a prefix.
+#[derive(Serialize)]
+struct SuggestedRule {
+    prefix: String,
+    older_than_days: u32,
+    action: &'static str,
+    dst: String,
+}
+
+/// Lifecycle configuration transitioning the prefixes of mostly large objects.
+fn lifecycle(analysis: &Analysis, days: u32) -> LifecycleConfiguration {
+    let rules = analysis
+        .prefixes
+        .iter()
+        .filter(|(_, stats)| stats.advice() == Advice::Lifecycle)
+        .map(|(prefix, _)| LifecycleRule {
+            id: format!("transition-{}", prefix.replace('/', "-")),
+            filter: LifecycleFilter {
+                prefix: format!("{prefix}/"),
+            },
+            status: "Enabled",
+            transitions: vec![LifecycleTransition {
+                days: days.max(MIN_TRANSITION_DAYS),
+                storage_class: TRANSITION_STORAGE_CLASS,
+            }],
+        })
+        .collect();
+    LifecycleConfiguration { rules }
+}
+
+/// Retention rules archiving the prefixes of mostly small objects of `src` below
+/// `archive_dst`.
+fn rules(analysis: &Analysis, src: &str, archive_dst: &str, days: u32) -> RulesFile {
+    let src = src.trim_end_matches('/');
+    let archive_dst = archive_dst.trim_end_matches('/');
+    let root = analysis.src_path.as_ref();
+    let rules = analysis
+        .prefixes
+        .iter()
+        .filter(|(_, stats)| stats.advice() == Advice::Archive)
+        .map(|(prefix, _)| {
+            let relative = prefix
+                .strip_prefix(root)
+                .unwrap_or(prefix)
+                .trim_start_matches('/');
+            let join = |base: &str| match relative {
+                "" => format!("{base}/"),
+                relative => format!("{base}/{relative}/"),
+            };
+            SuggestedRule {
+                prefix: join(src),
+                older_than_days: days,
+                action: "archive",
+                dst: join(archive_dst),
+            }
+        })
+        .collect();
+    RulesFile { rules }
+}
+
+fn print_human(analysis: &Analysis) {
+    let percent = |part: u64, whole: u64| part * 100 / whole.max(1);
+
+    println!("PREFIX\tOBJECTS\tBYTES\tOLD%\tOLD_SMALL%\tOLDEST\tADVICE");
+    for (prefix, stats) in &analysis.prefixes {
+        println!(
+            "{prefix}\t{}\t{}\t{}\t{}\t{}\t{:?}",
+            stats.objects,
+            stats.bytes,
+            percent(stats.old_bytes, stats.bytes),
+            percent(stats.old_small, stats.old_objects),
+            stats
+                .oldest
+                .map_or_else(|| "-".to_string(), |oldest| oldest.to_rfc3339()),
+            stats.advice()
+        );
+    }
+}
+
+/// Analyzes the ages and sizes of the objects under `src` per prefix and advises between a
+/// native lifecycle transition and archiving into tarballs.
+pub async fn advise(src: String, filter: ObjectFilter, options: AdviseOptions) -> Result<()> {
+    let (store, src_path) = get_store_and_path(&src)?;
+    let cutoff = Utc::now() - Duration::days(i64::from(options.older_than_days));
+    let mut analysis = Analysis::new(src_path.clone(), options.depth, cutoff);
+
+    let mut listing = store.list(Some(&src_path));
+    while let Some(meta) = listing.try_next().await? {
+        if filter.matches(&meta) {
+            analysis.add(&meta);
+        }
+    }
+
+    match options.format {
+        AdviceFormat::Human => print_human(&analysis),
+        AdviceFormat::Lifecycle => println!(
+            "{}",
+            serde_json::to_string_pretty(&lifecycle(&analysis, options.older_than_days))?
+        ),
+        AdviceFormat::Rules => {
+            let archive_dst = options.archive_dst.as_deref().ok_or_else(|| {
+                AppError::Config("suggesting rules needs --archive-dst".to_string())
+            })?;
+            let rules = rules(&analysis, &src, archive_dst, options.older_than_days);
+            print!(
+                "{}",
+                toml::to_string(&rules).map_err(|e| AppError::Config(e.to_string()))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(key: &str, size: u64, age_days: i64) -> ObjectMeta {
+        ObjectMeta {
+            location: Path::from(key),
+            last_modified: Utc::now() - Duration::days(age_days),
+            size,
+            e_tag: None,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn test_analysis_advises_per_prefix() -> Result<()> {
+        let cutoff = Utc::now() - Duration::days(30);
+        let mut analysis = Analysis::new(Path::from("data"), 1, cutoff);
+        for object in [
+            meta("data/audit/2024/a.json", 200, 90),
+            meta("data/audit/2024/b.json", 300, 60),
+            meta("data/audit/2025/c.json", 1 << 20, 40),
+            meta("data/videos/a.mp4", 1 << 30, 90),
+            meta("data/videos/b.mp4", 100, 45),
+            meta("data/fresh/a.log", 10, 1),
+            meta("data/top.txt", 10, 1),
+        ] {
+            analysis.add(&object);
+        }
+
+        let advice: Vec<(&str, Advice)> = analysis
+            .prefixes
+            .iter()
+            .map(|(prefix, stats)| (prefix.as_str(), stats.advice()))
+            .collect();
+        assert_eq!(
+            advice,
+            vec![
+                ("data", Advice::Keep),
+                ("data/audit", Advice::Archive),
+                ("data/fresh", Advice::Keep),
+                ("data/videos", Advice::Lifecycle),
+            ]
+        );
+        assert_eq!(analysis.prefixes["data/audit"].old_objects, 3);
+
+        let lifecycle = serde_json::to_value(lifecycle(&analysis, 30))?;
+        assert_eq!(lifecycle["Rules"][0]["Filter"]["Prefix"], "data/videos/");
+        assert_eq!(lifecycle["Rules"][0]["Transitions"][0]["Days"], 30);
+
+        let rules = rules(&analysis, "s3://bucket/data/", "s3://archive/", 30);
+        assert_eq!(rules.rules.len(), 1);
+        assert_eq!(rules.rules[0].prefix, "s3://bucket/data/audit/");
+        assert_eq!(rules.rules[0].dst, "s3://archive/audit/");
+        Ok(())
+    }
+}
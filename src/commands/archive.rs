@@ -0,0 +1,1972 @@
+use crate::batch::BatchManifest;
+use crate::catalog::{self, CatalogEntry};
+use crate::codec::Codec;
+use crate::compressor::{
+    CompressOptions, ErrorBudget, FilterCommand, PIPE_CAPACITY, compress, compress_each,
+    individual_target,
+};
+use crate::dictionary::ZstdDictionary;
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::keys;
+use crate::manifest::Manifest;
+use crate::mark::ArchiveMark;
+use crate::object_storage::{DELETE_CONCURRENCY, FailedDelete, delete_keys_reporting};
+use crate::output::{info, summary, warning};
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugin;
+use crate::progress::{PROGRESS_PREFIX, Progress};
+use crate::run_id;
+use crate::s3::{CannedAcl, NewBucket, S3Client};
+use crate::storage::{
+    get_accelerated_store_and_path, get_owned_store_and_path, get_store_and_path, same_store,
+};
+use crate::trash::Trash;
+use crate::upload::{ObjectStateStore, StateStore, UploadCheckpoint};
+use crate::usage::{Prices, Usage};
+use async_compression::Level;
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use url::Url;
+
+mod audit;
+mod cursor;
+mod group;
+mod preflight;
+mod skips;
+mod slack;
+mod summary;
+
+use audit::{AUDIT_DIR, Action, AuditLog};
+use cursor::Drain;
+use group::Split;
+pub use group::{GroupBy, GroupDate, Order};
+use skips::SkipList;
+use summary::{RunSummary, SUMMARIES_DIR};
+
+/// What happens to source objects once they are safely archived.
+#[derive(Debug)]
+pub enum Disposal {
+    Delete,
+    /// Server-side move below the given trash prefix.
+    Trash(String),
+    /// Keep the objects but tag them with the given `key=value`.
+    Mark(String),
+    /// Keep the objects, listing them in an S3 Batch Operations manifest at the given URL for
+    /// a job to dispose of.
+    BatchManifest(String),
+}
+
+/// Trash, mark or batch manifest archived objects go to instead of being deleted.
+type Disposers = (Option<Trash>, Option<ArchiveMark>, Option<BatchManifest>);
+
+impl Disposal {
+    /// Name of the disposal in the run summary.
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::Delete => "delete",
+            Self::Trash(_) => "trash",
+            Self::Mark(_) => "mark",
+            Self::BatchManifest(_) => "manifest",
+        }
+    }
+
+    /// What disposes of the objects archived from `src`, none of them for deleting them.
+    fn disposers(&self, src: &str) -> Result<Disposers> {
+        Ok(match self {
+            Self::Delete => (None, None, None),
+            Self::Trash(url) => (Some(Trash::new(src, url)?), None, None),
+            Self::Mark(tag) => (None, Some(ArchiveMark::new(src, tag)?), None),
+            Self::BatchManifest(url) => (None, None, Some(BatchManifest::new(src, url)?)),
+        })
+    }
+
+    const fn action(&self) -> Action {
+        match self {
+            Self::Delete => Action::Delete,
+            Self::Trash(_) => Action::Trash,
+            Self::Mark(_) => Action::Mark,
+            Self::BatchManifest(_) => Action::Manifest,
+        }
+    }
+}
+
+/// How archived objects are checked for changes since they were listed, right before they
+/// are disposed of. Objects uploaded again in the meantime are kept.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteVerification {
+    None,
+    /// Compare entity tags, falling back to `head` for objects without one.
+    Etag,
+    /// Compare size and modification time.
+    Head,
+}
+
+/// What archiving an object produces.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryMode {
+    /// An entry in a tarball shared with other objects.
+    Tar,
+    /// A compressed copy of its own below the destination, keeping the key and attributes.
+    Individual,
+}
+
+/// Maps object keys starting with `from` to tar entry paths, or keys restored to, starting with
+/// `to` instead, written `from=to` on the command line.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+impl KeyRewrite {
+    /// Drops `prefix` from the keys starting with it.
+    #[must_use]
+    pub fn strip(prefix: impl Into<String>) -> Self {
+        Self {
+            from: prefix.into(),
+            to: String::new(),
+        }
+    }
+
+    /// `key` rewritten by the first matching rewrite; the key itself when none matches or
+    /// nothing would be left of it.
+    #[must_use]
+    pub fn apply<'a>(rewrites: &[Self], key: &'a str) -> Cow<'a, str> {
+        rewrites
+            .iter()
+            .find_map(|rewrite| {
+                let rest = key.strip_prefix(rewrite.from.as_str())?;
+                let name = format!("{}{rest}", rewrite.to);
+                (!name.is_empty()).then_some(Cow::Owned(name))
+            })
+            .unwrap_or(Cow::Borrowed(key))
+    }
+}
+
+impl FromStr for KeyRewrite {
+    type Err = AppError;
+
+    fn from_str(rewrite: &str) -> Result<Self> {
+        let (from, to) = rewrite.split_once('=').ok_or_else(|| {
+            AppError::Config(format!("expected a rewrite as FROM=TO, got '{rewrite}'"))
+        })?;
+        Ok(Self {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for KeyRewrite {
+    type Error = AppError;
+
+    fn try_from(rewrite: String) -> Result<Self> {
+        rewrite.parse()
+    }
+}
+
+/// Archive name used unless [`ArchiveJob::name_template`] says otherwise.
+pub const DEFAULT_NAME_TEMPLATE: &str = "archive_{cutoff}";
+
+/// HEAD requests in flight at once while verifying archived objects.
+const VERIFY_CONCURRENCY: usize = 32;
+
+/// Smallest part of a multipart upload S3 accepts, except for the last one.
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+/// Largest part of a multipart upload S3 accepts.
+pub const MAX_PART_SIZE: usize = 5 * 1024 * 1024 * 1024;
+
+/// A complete archive run: the objects under `src` (and `extra_src`) selected by `filter` are
+/// streamed into a single tarball below `dst`, compressed with `codec`, then disposed of as
+/// configured.
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)] // One per switch of `archive`.
+pub struct ArchiveJob {
+    /// Name of the job in notifications, the source when left out.
+    pub name: Option<String>,
+    pub src: String,
+    /// More sources in the same bucket as `src`, archived in the same run.
+    pub extra_src: Vec<String>,
+    /// One archive per source instead of a single one for all of them.
+    pub archive_per_src: bool,
+    /// Archives of different sources written concurrently, sharing the stores, their
+    /// connections and `max_memory`.
+    pub jobs: usize,
+    pub dst: String,
+    pub filter: ObjectFilter,
+    /// Part size of the multipart upload of the archive, between [`MIN_PART_SIZE`] and
+    /// [`MAX_PART_SIZE`].
+    pub buffer_size: usize,
+    /// Bytes buffered in each pipe between the tar, compression and upload stages.
+    pub io_buffer: usize,
+    /// Parts uploaded in parallel; peak memory is about `buffer_size` times this.
+    pub upload_concurrency: usize,
+    /// Batches of archived sources deleted in parallel.
+    pub delete_concurrency: usize,
+    pub codec: Codec,
+    pub level: Level,
+    /// Store objects that are compressed already, e.g. images or gzip files, instead of
+    /// compressing them, see `--compression auto`.
+    pub store_precompressed: bool,
+    /// Name of the archive below `dst`, without the `.tar.*` extension. Placeholders:
+    /// `{src_bucket}`, `{prefix}` (the source prefix with `-` for `/`), `{cutoff}`,
+    /// `{run_id}` (see [`ArchiveJob::run_id`]), `{period}` (with `group_by`, e.g. `2024-06`) and
+    /// `{seq}` (the first number not taken yet).
+    pub name_template: String,
+    /// Applied to object keys to get their path inside the tarball, first match wins.
+    pub rewrites: Vec<KeyRewrite>,
+    /// One archive per day, month or year instead of a single one, named via `{period}`.
+    pub group_by: Option<GroupBy>,
+    pub group_date: GroupDate,
+    /// Objects are packed into archives of about this many bytes each, named via `{seq}`.
+    pub target_archive_size: Option<u64>,
+    /// Objects are packed into archives of at most this many objects each, named via `{seq}`,
+    /// as listing and restoring tarballs of millions of entries gets slow.
+    pub max_entries_per_archive: Option<usize>,
+    pub order: Order,
+    pub entry_mode: EntryMode,
+    /// Manifest of an earlier archive; objects in it or its parents with the same entity tag
+    /// are left out, and the new manifests name it as their parent.
+    pub base_manifest: Option<String>,
+    pub disposal: Disposal,
+    pub delete_verification: DeleteVerification,
+    /// Canned ACL applied to the finished archive, S3 only.
+    pub dst_acl: Option<CannedAcl>,
+    /// Upload through the S3 Transfer Acceleration endpoint of the destination bucket.
+    pub accelerate: bool,
+    /// Create the destination bucket with these settings when it does not exist, S3 only.
+    pub create_dst_bucket: Option<NewBucket>,
+    /// Check listing, reading and, unless marking, deleting the sources and writing the
+    /// destination before archiving anything.
+    pub preflight: bool,
+    /// Upload the JSON summary printed at the end below `dst` as well.
+    pub upload_summary: bool,
+    /// Upload a JSON lines log of what happened to each object, and when, below `dst`.
+    pub audit_log: bool,
+    /// Slack incoming webhook told how the run went.
+    pub notify_slack_webhook: Option<String>,
+    /// Cursor object the listing resumes after, as saved by an earlier run.
+    pub resume_cursor: Option<String>,
+    /// Cursor object to leave for the next run once this one succeeded.
+    pub save_cursor: Option<String>,
+    /// Stop selecting objects after this many, to drain huge sources over several runs.
+    pub max_objects: Option<usize>,
+    /// Objects that may fail to be read, and are left in place, before the run aborts.
+    pub max_errors: usize,
+    /// State object with the objects that failed in earlier runs, leaving out those that
+    /// failed repeatedly.
+    pub skip_list: Option<String>,
+    /// Retry the objects the skip list leaves out.
+    pub retry_skipped: bool,
+    /// File with the keys to archive, one per line, or `-` for standard input, instead of
+    /// listing the sources.
+    pub keys_from: Option<String>,
+    /// Canonical user IDs of the accounts whose objects are selected, for buckets shared by
+    /// tenants; all objects when empty.
+    pub owner_ids: Vec<String>,
+    /// Shell command each object is piped through before it is archived, e.g. to scrub
+    /// personal data. Its output is buffered whole, in `spool_dir`, which it needs.
+    pub filter_cmd: Option<String>,
+    /// PGP keys the archives are encrypted to with `gpg`, as `.tar.*.gpg`.
+    pub gpg_recipients: Vec<String>,
+    /// URL of a zstd dictionary, e.g. trained with `train-zstd-dict`, to compress with; the
+    /// manifests refer to it, as restoring needs it too.
+    pub zstd_dict: Option<String>,
+    /// Record the SHA-256 S3 keeps for each source object in the tar headers and manifests,
+    /// at one `GetObjectAttributes` request per object; `src` must be on S3.
+    pub source_checksums: bool,
+    /// Record the tags of each source object in the tar headers, at one `GetObjectTagging`
+    /// request per object, so `restore` puts them back; `src` must be on S3.
+    pub preserve_tags: bool,
+    /// URL of a state object saving the multipart upload of the archive while it is in
+    /// progress, so a run after an interruption resumes it rather than starting over.
+    pub upload_state: Option<String>,
+    /// Upload the part being filled as it is once no part went out for this long, so an
+    /// archive compressed slowly is not held in memory, and its upload open, for hours.
+    pub flush_interval: Option<std::time::Duration>,
+    /// Upload `run_<id>.progress.json` below `dst` this often while the run goes on, see
+    /// [`Progress`].
+    pub progress_every: Option<std::time::Duration>,
+    /// ID of the run, see [`run_id`]; a new one when `None`.
+    pub run_id: Option<String>,
+    /// Ask on the terminal before deleting the archived sources.
+    pub confirm: bool,
+    /// Buffer on disk in this directory instead of memory.
+    pub spool_dir: Option<PathBuf>,
+    /// Memory budget in bytes; upload concurrency and prefetching are reduced to stay within
+    /// it, and the run is refused when even the minimum does not fit.
+    pub max_memory: Option<usize>,
+    /// Prices the requests and transfers of the run are reported with.
+    pub prices: Prices,
+}
+
+impl ArchiveJob {
+    /// Archives everything under `src` with the defaults of the command line.
+    #[must_use]
+    pub fn new(src: impl Into<String>, dst: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            src: src.into(),
+            extra_src: Vec::new(),
+            archive_per_src: false,
+            jobs: 1,
+            dst: dst.into(),
+            filter: ObjectFilter::default(),
+            buffer_size: 100 * 1024 * 1024,
+            io_buffer: PIPE_CAPACITY,
+            upload_concurrency: 8,
+            delete_concurrency: DELETE_CONCURRENCY,
+            codec: Codec::Xz,
+            level: Level::Fastest,
+            store_precompressed: false,
+            name_template: DEFAULT_NAME_TEMPLATE.to_string(),
+            rewrites: Vec::new(),
+            group_by: None,
+            group_date: GroupDate::Modified,
+            target_archive_size: None,
+            max_entries_per_archive: None,
+            order: Order::Key,
+            entry_mode: EntryMode::Tar,
+            base_manifest: None,
+            disposal: Disposal::Delete,
+            delete_verification: DeleteVerification::None,
+            dst_acl: None,
+            accelerate: false,
+            create_dst_bucket: None,
+            preflight: true,
+            upload_summary: false,
+            audit_log: false,
+            notify_slack_webhook: None,
+            resume_cursor: None,
+            save_cursor: None,
+            max_objects: None,
+            max_errors: 0,
+            skip_list: None,
+            retry_skipped: false,
+            keys_from: None,
+            owner_ids: Vec::new(),
+            filter_cmd: None,
+            gpg_recipients: Vec::new(),
+            zstd_dict: None,
+            source_checksums: false,
+            preserve_tags: false,
+            upload_state: None,
+            flush_interval: None,
+            progress_every: None,
+            run_id: None,
+            confirm: false,
+            spool_dir: None,
+            max_memory: None,
+            prices: Prices::default(),
+        }
+    }
+
+    /// Runs the job, reporting the requests it made and their estimated cost at the end,
+    /// followed by a JSON summary of the run, see [`RunSummary`].
+    ///
+    /// # Errors
+    ///
+    /// Fails when a URL cannot be resolved, the disposal or ACL is not supported for the
+    /// given stores, the destination bucket cannot be created, a preflight check fails,
+    /// reading, uploading or disposing of objects fails, or the summary cannot be uploaded.
+    /// Sources are only disposed of after the archive upload completed.
+    pub async fn run(mut self) -> Result<()> {
+        let src = if self.owner_ids.is_empty() {
+            get_store_and_path(&self.src)?
+        } else {
+            get_owned_store_and_path(&self.src, &self.owner_ids)?
+        };
+        let dst = if self.accelerate {
+            get_accelerated_store_and_path(&self.dst)?
+        } else {
+            get_store_and_path(&self.dst)?
+        };
+        let prices = self.prices.clone();
+        let upload_summary = self.upload_summary;
+        let name = self.name.clone();
+        let slack_webhook = self.notify_slack_webhook.clone();
+        let (dst_store, dst_path) = dst.clone();
+        let mut summary = self.summary().await?;
+        let _current = run_id::enter(&summary.run_id);
+        summary.audit = self.audit_log.then(AuditLog::default);
+        let progress = self.progress_every.map(|every| {
+            let progress = Arc::new(Progress::new(&summary.run_id, &self.src, &self.dst));
+            let location = Progress::location(&dst_path, &summary.run_id);
+            let task = progress.spawn(Arc::clone(&dst_store), location.clone(), every);
+            summary.progress = Some(Arc::clone(&progress));
+            (progress, location, task)
+        });
+        let start = Usage::now();
+        let result = Box::pin(self.run_summarized(src, dst, &mut summary)).await;
+        let usage = Usage::now().since(start);
+        usage.report(&prices);
+
+        if let Some((progress, location, task)) = progress {
+            task.abort();
+            let _ = task.await;
+            progress
+                .finish(dst_store.as_ref(), &location, result.as_ref().err())
+                .await?;
+            info!("Uploaded the progress to {location}");
+        }
+
+        summary.finish(usage, result.as_ref().err());
+        summary.print()?;
+        if let Some(webhook) = slack_webhook {
+            let message = slack::message(&summary, name.as_deref());
+            match slack::post(&webhook, &message).await {
+                Ok(()) => info!("Notified Slack"),
+                Err(e) => warning!("Cannot notify Slack: {e}"),
+            }
+        }
+        if upload_summary {
+            let location = summary.put(dst_store.as_ref(), &dst_path).await?;
+            info!("Uploaded the summary to {location}");
+        }
+        if let Some(audit) = summary.audit.take() {
+            let location = audit
+                .put(dst_store.as_ref(), &dst_path, &summary.run_id)
+                .await?;
+            info!("Uploaded the audit log to {location}");
+        }
+        result
+    }
+
+    /// Creates the destination bucket if asked to, runs the [`preflight`] checks and then the
+    /// job, recording what it did in `summary`.
+    async fn run_summarized(
+        self,
+        src: (Arc<dyn ObjectStore>, Path),
+        dst: (Arc<dyn ObjectStore>, Path),
+        summary: &mut RunSummary,
+    ) -> Result<()> {
+        if let Some(settings) = &self.create_dst_bucket {
+            create_dst_bucket(&self.dst, settings).await?;
+        }
+        self.preflight(&src, &dst).await?;
+        self.run_summarizing(src, dst, summary).await
+    }
+
+    /// Runs the [`preflight`] checks against the resolved stores, if enabled.
+    async fn preflight(
+        &self,
+        (src_store, src_path): &(Arc<dyn ObjectStore>, Path),
+        (dst_store, dst_path): &(Arc<dyn ObjectStore>, Path),
+    ) -> Result<()> {
+        if !self.preflight {
+            return Ok(());
+        }
+        let src_paths =
+            source_groups(&self.src, src_path.clone(), &self.extra_src, false)?.concat();
+        let delete = matches!(self.disposal, Disposal::Delete | Disposal::Trash(_));
+        preflight::check(
+            src_store.as_ref(),
+            &src_paths,
+            dst_store.as_ref(),
+            dst_path,
+            delete,
+        )
+        .await
+    }
+
+    const fn split(&self) -> Split {
+        Split {
+            group: match self.group_by {
+                Some(group_by) => Some((group_by, self.group_date)),
+                None => None,
+            },
+            target_size: self.target_archive_size,
+            max_entries: self.max_entries_per_archive,
+            order: self.order,
+        }
+    }
+
+    /// Where the listing starts and how far it goes, from `resume_cursor` and `max_objects`.
+    async fn drain(&self) -> Result<Drain> {
+        let drain = match &self.resume_cursor {
+            Some(url) => Drain::resume(url, &self.src, self.max_objects).await?,
+            None => Drain {
+                start_after: None,
+                max_objects: self.max_objects,
+            },
+        };
+        let drains = !drain.is_unbounded() || self.save_cursor.is_some();
+        if drains && (!self.extra_src.is_empty() || !self.split().is_single()) {
+            return Err(AppError::Unsupported(
+                "cursors and maximum objects need a single source archived in key order into a \
+                 single archive"
+                    .to_string(),
+            ));
+        }
+        // Listing after a key only resumes where a run stopped when keys are listed in order.
+        if drains && Url::parse(&self.src)?.scheme() == "file" {
+            return Err(AppError::Unsupported(
+                "cursors and maximum objects need a store listing keys in order, local \
+                 directories do not"
+                    .to_string(),
+            ));
+        }
+        Ok(drain)
+    }
+
+    /// Refuses part sizes S3 would reject, only once the parts are uploaded, and pipes
+    /// without room for a single byte.
+    fn check_buffers(&self) -> Result<()> {
+        if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&self.buffer_size) {
+            return Err(AppError::Config(format!(
+                "a part size of {} bytes is outside of the {MIN_PART_SIZE} to {MAX_PART_SIZE} \
+                 bytes multipart uploads allow",
+                self.buffer_size
+            )));
+        }
+        if self.io_buffer == 0 {
+            return Err(AppError::Config(
+                "the I/O buffer needs room for at least one byte".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where the upload of the archive saves its state, if `upload_state` is given. Resuming
+    /// an upload selects with the cutoff it was started with, which is saved along.
+    async fn upload_checkpoint(&mut self) -> Result<Option<UploadCheckpoint>> {
+        let Some(url) = &self.upload_state else {
+            return Ok(None);
+        };
+        if !self.extra_src.is_empty()
+            || !self.split().is_single()
+            || !matches!(self.entry_mode, EntryMode::Tar)
+        {
+            return Err(AppError::Unsupported(
+                "resumable uploads need a single source archived into a single tarball".to_string(),
+            ));
+        }
+        if self.flush_interval.is_some() {
+            return Err(AppError::Unsupported(
+                "flushed parts depend on timing, uploads flushing them cannot be resumed"
+                    .to_string(),
+            ));
+        }
+        if !self.gpg_recipients.is_empty() {
+            return Err(AppError::Unsupported(
+                "encrypted archives differ on every run, their uploads cannot be resumed"
+                    .to_string(),
+            ));
+        }
+        let client = S3Client::from_url(&self.dst)?.ok_or_else(|| {
+            AppError::Unsupported(format!(
+                "resumable uploads need an s3:// destination, not {}",
+                self.dst
+            ))
+        })?;
+        let state = ObjectStateStore::from_url(url)?;
+        let cutoff = match state.load().await? {
+            Some(state) => {
+                info!(
+                    "Selecting with the cutoff {} of the upload to resume",
+                    state.cutoff
+                );
+                *self.filter.cutoff.insert(state.cutoff)
+            }
+            None => *self
+                .filter
+                .cutoff
+                .get_or_insert_with(|| Utc::now() - Duration::seconds(1)),
+        };
+        Ok(Some(UploadCheckpoint {
+            client,
+            state: Arc::new(state),
+            cutoff,
+            run_id: self.run_id.clone(),
+        }))
+    }
+
+    /// ID of the run that started the upload saved in `upload_state`, if there is one.
+    async fn resumed_run_id(&self) -> Result<Option<String>> {
+        let Some(url) = &self.upload_state else {
+            return Ok(None);
+        };
+        let run_id = ObjectStateStore::from_url(url)?
+            .load()
+            .await?
+            .and_then(|state| state.run_id);
+        if let Some(run_id) = &run_id {
+            info!("Taking over the run ID {run_id} of the upload to resume");
+        }
+        Ok(run_id)
+    }
+
+    /// The keys of `keys_from`, if given.
+    fn keys(&self) -> Result<Option<Vec<Path>>> {
+        let Some(source) = &self.keys_from else {
+            return Ok(None);
+        };
+        let drains = self.resume_cursor.is_some()
+            || self.save_cursor.is_some()
+            || self.max_objects.is_some();
+        if drains || !self.split().is_single() {
+            return Err(AppError::Unsupported(
+                "a list of keys is archived into a single archive, without cursors".to_string(),
+            ));
+        }
+        if !self.owner_ids.is_empty() {
+            return Err(AppError::Unsupported(
+                "the owners of listed keys are not known, select by owner when listing".to_string(),
+            ));
+        }
+        keys::read_keys(source).map(Some)
+    }
+
+    /// The skip list of `skip_list` with its URL, if given, leaving the objects it skips
+    /// out of the selection unless retrying them.
+    async fn skip_list(&mut self) -> Result<Option<(String, SkipList)>> {
+        let Some(url) = &self.skip_list else {
+            return Ok(None);
+        };
+        let skips = SkipList::load(url).await?;
+        if !self.retry_skipped {
+            skips.exclude(&mut self.filter);
+        }
+        Ok(Some((url.clone(), skips)))
+    }
+
+    /// How the archive is compressed and uploaded, within the memory budget if there is one.
+    async fn compress_options(&mut self) -> Result<CompressOptions> {
+        let zstd_dict = match &self.zstd_dict {
+            Some(_) if self.codec != Codec::Zstd => {
+                return Err(AppError::Config(
+                    "zstd dictionaries only work with --codec zstd".to_string(),
+                ));
+            }
+            Some(url) => Some(ZstdDictionary::load(url).await?),
+            None => None,
+        };
+        if self.source_checksums && self.filter_cmd.is_some() {
+            return Err(AppError::Config(
+                "source checksums do not match objects piped through a filter command".to_string(),
+            ));
+        }
+        #[cfg(feature = "plugins")]
+        let plugin = self.filter.plugin.clone().filter(Plugin::transforms);
+        #[cfg(feature = "plugins")]
+        if self.source_checksums && plugin.is_some() {
+            return Err(AppError::Config(
+                "source checksums do not match objects transformed by a plugin".to_string(),
+            ));
+        }
+        #[cfg(feature = "plugins")]
+        let transforming = self.filter_cmd.is_some() || plugin.is_some();
+        #[cfg(not(feature = "plugins"))]
+        let transforming = self.filter_cmd.is_some();
+        // The output of each object is buffered whole, as tar entries need their size upfront;
+        // in memory, --max-memory could not bound it.
+        if transforming && self.spool_dir.is_none() {
+            return Err(AppError::Config(
+                "objects piped through a filter command or plugin are buffered whole, pass \
+                 --spool-dir to buffer them on disk"
+                    .to_string(),
+            ));
+        }
+        let source_s3 = if self.source_checksums || self.preserve_tags {
+            let s3 = S3Client::from_url(&self.src)?.ok_or_else(|| {
+                AppError::Config(format!(
+                    "source checksums and tags are only recorded for s3:// sources, not {}",
+                    self.src
+                ))
+            })?;
+            Some(s3)
+        } else {
+            None
+        };
+        let options = CompressOptions {
+            upload_concurrency: self.upload_concurrency,
+            spool_dir: self.spool_dir.clone(),
+            rewrites: self.rewrites.clone(),
+            errors: ErrorBudget::new(self.max_errors),
+            filter_cmd: self.filter_cmd.as_deref().map(FilterCommand::new),
+            #[cfg(feature = "plugins")]
+            plugin,
+            gpg_recipients: self.gpg_recipients.clone(),
+            zstd_dict,
+            store_precompressed: self.store_precompressed,
+            source_s3,
+            checksums: self.source_checksums,
+            tags: self.preserve_tags,
+            resumable: self.upload_checkpoint().await?,
+            io_buffer: self.io_buffer,
+            flush_interval: self.flush_interval,
+            ..CompressOptions::new(self.buffer_size, self.codec, self.level)
+        };
+        let Some(max_memory) = self.max_memory else {
+            return Ok(options);
+        };
+
+        // Every pipeline archiving concurrently gets its share of the budget.
+        let pipelines = self.pipelines();
+        let options = options
+            .within_memory(max_memory / pipelines)
+            .map_err(|e| match e {
+                AppError::Config(message) if pipelines > 1 => AppError::Config(format!(
+                    "{message}; {pipelines} --jobs share it, lower --jobs"
+                )),
+                e => e,
+            })?;
+        let share = if pipelines > 1 {
+            format!(
+                ", {} bytes for each of {pipelines} jobs",
+                max_memory / pipelines
+            )
+        } else {
+            String::new()
+        };
+        info!(
+            "Memory budget of {max_memory} bytes{share}: {} parallel uploads, prefetching \
+             objects up to {} bytes",
+            options.upload_concurrency, options.prefetch_size
+        );
+        Ok(options)
+    }
+
+    /// Archives written at the same time: up to `jobs`, one per source.
+    fn pipelines(&self) -> usize {
+        if self.archive_per_src {
+            self.jobs.clamp(1, self.extra_src.len() + 1)
+        } else {
+            1
+        }
+    }
+
+    /// Like [`ArchiveJob::run`], with the stores and paths behind `src` and `dst` already
+    /// resolved, e.g. to in-memory stores in tests. The URLs are still used for S3 specific
+    /// disposals and ACLs.
+    ///
+    /// # Errors
+    ///
+    /// See [`ArchiveJob::run`].
+    pub async fn run_with_stores(
+        mut self,
+        src: (Arc<dyn ObjectStore>, Path),
+        dst: (Arc<dyn ObjectStore>, Path),
+    ) -> Result<()> {
+        let mut summary = self.summary().await?;
+        let _current = run_id::enter(&summary.run_id);
+        self.run_summarizing(src, dst, &mut summary).await
+    }
+
+    /// Summary of a run starting now, under the ID of the job, the one of the upload it
+    /// resumes or a new one, which becomes the ID of the job.
+    async fn summary(&mut self) -> Result<RunSummary> {
+        let id = match &self.run_id {
+            Some(id) => {
+                run_id::check(id)?;
+                id.clone()
+            }
+            None => self
+                .resumed_run_id()
+                .await?
+                .unwrap_or_else(run_id::generate),
+        };
+        self.run_id = Some(id.clone());
+        Ok(RunSummary::new(
+            &id,
+            &self.src,
+            &self.dst,
+            self.disposal.name(),
+        ))
+    }
+
+    /// Like [`ArchiveJob::run_with_stores`], recording what the run did in `summary`.
+    async fn run_summarizing(
+        mut self,
+        (src_store, src_path): (Arc<dyn ObjectStore>, Path),
+        (dst_store, dst_path): (Arc<dyn ObjectStore>, Path),
+        summary: &mut RunSummary,
+    ) -> Result<()> {
+        self.check_buffers()?;
+        let mut options = self.compress_options().await?;
+        options.progress.clone_from(&summary.progress);
+        let split = self.split();
+        let jobs = self.pipelines();
+        let drain = self.drain().await?;
+        let skips = self.skip_list().await?;
+        let keys = self.keys()?;
+        let Self {
+            src,
+            extra_src,
+            archive_per_src,
+            dst,
+            mut filter,
+            delete_concurrency,
+            name_template,
+            entry_mode,
+            base_manifest,
+            disposal,
+            delete_verification,
+            dst_acl,
+            confirm,
+            save_cursor,
+            ..
+        } = self;
+
+        let dst_client = acl_client(&dst, dst_acl)?;
+
+        let (trash, mark, manifest) = disposal.disposers(&src)?;
+
+        let groups = source_groups(&src, src_path, &extra_src, archive_per_src)?;
+        let name_template = split.name_template(name_template);
+
+        info!("Archiving from {src} to {dst}");
+
+        // Earlier archives would otherwise end up in the next one when archiving in place.
+        if same_store(&src, &dst)? {
+            match entry_mode {
+                EntryMode::Tar => filter
+                    .exclude_prefixes
+                    .extend(output_prefixes(&dst_path, &name_template)),
+                EntryMode::Individual => check_outside(&dst_path, &groups)?,
+            }
+        }
+
+        if let Some(url) = &base_manifest {
+            filter.exclude_versions = Manifest::versions(url).await?;
+        }
+        let cutoff_dt = *filter.cutoff.get_or_insert_with(|| {
+            let now = Utc::now();
+            now - Duration::seconds(1)
+        });
+        let writer = Archiver {
+            src: &src,
+            src_store: src_store.as_ref(),
+            dst_store: &dst_store,
+            dst_path: &dst_path,
+            name_template: &name_template,
+            run_id: summary.run_id.clone(),
+            cutoff: cutoff_dt,
+            options: &options,
+            mark: mark.as_ref(),
+            acl: dst_client.as_ref().zip(dst_acl),
+            split,
+            drain: &drain,
+            keys: keys.as_deref(),
+            entry_mode,
+            base_manifest: base_manifest.as_deref(),
+            jobs,
+            catalog: futures::lock::Mutex::new(()),
+        };
+        writer.check_outputs(&groups)?;
+
+        let archived = writer.write_groups(&groups, &filter, summary).await;
+        update_skip_list(skips, &options.errors, &archived).await?;
+        let archived = archived?;
+        summary.record_archived(&archived);
+        summary.errors = options.errors.failed() as u64;
+        // Objects that failed are retried by the next run, which would list after them.
+        let cursor = drain
+            .next(&src, summary.matched, &archived)
+            .filter(|_| summary.errors == 0);
+
+        let (archived, changed) =
+            unchanged(src_store.as_ref(), archived, delete_verification).await?;
+        summary.record_kept(&changed);
+
+        if confirm && matches!(disposal, Disposal::Delete) && !confirm_deletion(&src, &archived)? {
+            summary!("Keeping the archived objects under {src}");
+            summary.record_kept(&archived);
+            return Ok(());
+        }
+
+        let (keys, removal) = dispose(
+            src_store.as_ref(),
+            archived,
+            (trash, mark, manifest),
+            delete_concurrency,
+        )
+        .await;
+        summary.record_disposal(&keys, disposal.action(), &removal);
+        let failed = removal.map_err(|e| AppError::Deletion(Box::new(e)))?;
+        if !failed.is_empty() {
+            return report_failed_deletes(dst_store.as_ref(), dst_path, &src, &failed).await;
+        }
+        if let Some((url, cursor)) = save_cursor.zip(cursor) {
+            cursor.save(&url).await?;
+            info!("Saved cursor {url} at {:?}", cursor.last_key);
+        }
+        Ok(())
+    }
+}
+
+/// Records the objects that failed to be read in the skip list at the URL, if one is kept,
+/// forgetting those that were archived.
+async fn update_skip_list(
+    skip_list: Option<(String, SkipList)>,
+    errors: &ErrorBudget,
+    archived: &Result<Vec<ObjectMeta>>,
+) -> Result<()> {
+    let Some((url, mut skips)) = skip_list else {
+        return Ok(());
+    };
+    if skips.update(&errors.failures(), archived.as_deref().unwrap_or_default()) {
+        skips.save(&url).await?;
+    }
+    Ok(())
+}
+
+/// Trashes `archived` with `trash`, tags it with `mark`, lists it in `manifest` or else
+/// deletes it. Returns the keys of the objects and those that could not be deleted.
+async fn dispose(
+    store: &dyn ObjectStore,
+    archived: Vec<ObjectMeta>,
+    (trash, mark, manifest): Disposers,
+    delete_concurrency: usize,
+) -> (Vec<Path>, Result<Vec<FailedDelete>>) {
+    let keys: Vec<Path> = archived.iter().map(|meta| meta.location.clone()).collect();
+    let removal = match (trash, mark, manifest) {
+        (Some(trash), _, _) => trash.discard(store, archived, delete_concurrency).await,
+        (None, Some(mark), _) => mark.mark_all(keys.clone()).await.map(|()| Vec::new()),
+        (None, None, Some(manifest)) => {
+            let listed: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+            manifest.put(&listed).await.map(|_| Vec::new())
+        }
+        (None, None, None) => {
+            Ok(delete_keys_reporting(store, keys.clone(), delete_concurrency).await)
+        }
+    };
+    (keys, removal)
+}
+
+/// Client applying `dst_acl` to archives written to `dst`, if any.
+fn acl_client(dst: &str, dst_acl: Option<CannedAcl>) -> Result<Option<S3Client>> {
+    if dst_acl.is_none() {
+        return Ok(None);
+    }
+    let client = S3Client::from_url(dst)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "ACLs can only be set on s3:// destinations, got {dst}"
+        ))
+    })?;
+    Ok(Some(client))
+}
+
+/// Creates the bucket of `dst` with `settings` unless it exists, so a missing bucket does not
+/// fail the run deep inside the first upload.
+async fn create_dst_bucket(dst: &str, settings: &NewBucket) -> Result<()> {
+    let client = S3Client::from_url(dst)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "only buckets of s3:// destinations can be created, got {dst}"
+        ))
+    })?;
+    if client.bucket_exists().await? {
+        return Ok(());
+    }
+    info!("Creating bucket {}", client.bucket());
+    client.create_bucket(settings).await
+}
+
+/// Writes `failed_deletes.json` below `dst_path`. The archive is complete at this point, so
+/// the leftovers are only reported for a later cleanup, failing the run.
+async fn report_failed_deletes(
+    dst_store: &dyn ObjectStore,
+    dst_path: Path,
+    src: &str,
+    failed: &[FailedDelete],
+) -> Result<()> {
+    let report_path = dst_path.join("failed_deletes.json");
+    dst_store
+        .put(&report_path, serde_json::to_vec_pretty(failed)?.into())
+        .await?;
+    Err(AppError::IncompleteDeletion(format!(
+        "{} archived objects are still in {src}, see {report_path}",
+        failed.len()
+    )))
+}
+
+/// Writes archives for one run, see [`ArchiveJob`] for the fields.
+struct Archiver<'a> {
+    src: &'a str,
+    src_store: &'a dyn ObjectStore,
+    dst_store: &'a Arc<dyn ObjectStore>,
+    dst_path: &'a Path,
+    name_template: &'a str,
+    run_id: String,
+    cutoff: DateTime<Utc>,
+    options: &'a CompressOptions,
+    mark: Option<&'a ArchiveMark>,
+    acl: Option<(&'a S3Client, CannedAcl)>,
+    split: Split,
+    drain: &'a Drain,
+    keys: Option<&'a [Path]>,
+    entry_mode: EntryMode,
+    base_manifest: Option<&'a str>,
+    /// Groups of prefixes archived concurrently.
+    jobs: usize,
+    /// Held while appending to the catalog, which not every store can update conditionally.
+    catalog: futures::lock::Mutex<()>,
+}
+
+impl Archiver<'_> {
+    fn names(&self, prefixes: &[Path], period: &str) -> Result<NameValues> {
+        NameValues::new(self.src, prefixes, self.cutoff, &self.run_id, period)
+    }
+
+    /// Fails upfront when the archives cannot be written as configured, e.g. when several
+    /// would end up with the same name or keys to archive are outside the sources.
+    fn check_outputs(&self, groups: &[Vec<Path>]) -> Result<()> {
+        if let Some(keys) = self.keys {
+            keys::check_below(keys, &groups.concat())?;
+        }
+        if matches!(self.entry_mode, EntryMode::Individual) {
+            if self.acl.is_some() {
+                return Err(AppError::Unsupported(
+                    "ACLs cannot be applied to individually archived objects".to_string(),
+                ));
+            }
+            if !self.options.gpg_recipients.is_empty() {
+                return Err(AppError::Unsupported(
+                    "only tarballs are encrypted, not individually archived objects".to_string(),
+                ));
+            }
+            return Ok(());
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for prefixes in groups {
+            let name = render_name(self.name_template, &self.names(prefixes, "")?, 1)?;
+            if !names.insert(name) {
+                return Err(AppError::Config(format!(
+                    "name template '{}' gives several archives the same name, add {{prefix}}",
+                    self.name_template
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Archives the objects of each of `groups` of prefixes, up to `jobs` of them at a time,
+    /// see [`Archiver::write_all`].
+    async fn write_groups(
+        &self,
+        groups: &[Vec<Path>],
+        filter: &ObjectFilter,
+        summary: &mut RunSummary,
+    ) -> Result<Vec<ObjectMeta>> {
+        let parts: Vec<_> = groups.iter().map(|group| (group, summary.part())).collect();
+        let mut written = futures::stream::iter(parts)
+            .map(|(prefixes, mut part)| async move {
+                let archived = self.write_all(prefixes, filter, &mut part).await;
+                (part, archived)
+            })
+            .buffered(self.jobs.max(1));
+        let mut archived = Vec::new();
+        while let Some((part, group)) = written.next().await {
+            summary.absorb(part);
+            archived.extend(group?);
+        }
+        Ok(archived)
+    }
+
+    /// Archives the objects under `prefixes` selected by `filter`, into several tarballs when
+    /// splitting by period or size. Returns what went into them.
+    async fn write_all(
+        &self,
+        prefixes: &[Path],
+        filter: &ObjectFilter,
+        summary: &mut RunSummary,
+    ) -> Result<Vec<ObjectMeta>> {
+        if self.split.is_single() {
+            let objects = self.keys.map_or_else(
+                || self.drain.selected(self.src_store, prefixes, filter),
+                |keys| keys::selected(self.src_store, keys, prefixes, filter),
+            );
+            return self.write(prefixes, "", objects, summary).await;
+        }
+
+        let mut archived = Vec::new();
+        for part in group::plan(self.src_store, prefixes, filter, self.split).await? {
+            let period = part.period.clone();
+            let objects = part.into_objects(self.src_store, prefixes, filter, self.split);
+            archived.extend(self.write(prefixes, &period, objects, summary).await?);
+        }
+        Ok(archived)
+    }
+
+    /// Archives `objects` from below `prefixes` into a new tarball, returning what went into it.
+    async fn write(
+        &self,
+        prefixes: &[Path],
+        period: &str,
+        objects: BoxStream<'_, Result<ObjectMeta>>,
+        summary: &mut RunSummary,
+    ) -> Result<Vec<ObjectMeta>> {
+        let mut listed = Vec::new();
+        let objects = objects
+            .inspect_ok(|meta| listed.push(meta.location.clone()))
+            .boxed();
+        let mut archived: Vec<ObjectMeta> = Vec::new();
+        if matches!(self.entry_mode, EntryMode::Individual) {
+            summary.bytes_out += compress_each(
+                self.src_store,
+                objects,
+                self.dst_store,
+                self.dst_path,
+                self.mark,
+                self.options,
+                &mut archived,
+            )
+            .await
+            .map_err(|e| AppError::Compression(Box::new(e)))?;
+            summary.record_written(&listed, &archived, |meta| {
+                individual_target(self.dst_path, meta.location.as_ref(), self.options).to_string()
+            });
+            return Ok(archived);
+        }
+
+        let dst_file_path = archive_path(
+            self.dst_store.as_ref(),
+            self.dst_path,
+            self.name_template,
+            &self.names(prefixes, period)?,
+            &self.options.extension(),
+        )
+        .await?;
+
+        let index = compress(
+            self.src_store,
+            objects,
+            Arc::clone(self.dst_store),
+            dst_file_path.clone(),
+            self.mark,
+            self.options,
+            &mut archived,
+        )
+        .await
+        .map_err(|e| AppError::Compression(Box::new(e)))?;
+        summary.bytes_out += index.size;
+        summary.archives.push(dst_file_path.to_string());
+        if let Some(progress) = &self.options.progress {
+            progress.completed(&dst_file_path);
+        }
+        summary.record_written(&listed, &archived, |_| dst_file_path.to_string());
+
+        if let Some((client, acl)) = self.acl {
+            client.put_object_acl(dst_file_path.as_ref(), acl).await?;
+            info!("Applied ACL {} to {dst_file_path}", acl.as_str());
+        }
+        let manifest = Manifest {
+            cutoff: Some(self.cutoff),
+            zstd_dict: self
+                .options
+                .zstd_dict
+                .as_ref()
+                .map(|dictionary| dictionary.url().to_string()),
+            run_id: Some(self.run_id.clone()),
+            ..Manifest::new(
+                &dst_file_path,
+                self.base_manifest,
+                &archived,
+                &index,
+                &self.options.rewrites,
+            )
+        };
+        let manifest_sha256 = manifest.put(self.dst_store.as_ref()).await?;
+        let _appending = self.catalog.lock().await;
+        catalog::append(
+            self.dst_store.as_ref(),
+            &catalog::location(self.dst_path),
+            &CatalogEntry::new(&manifest, manifest_sha256, &index),
+        )
+        .await?;
+        Ok(archived)
+    }
+}
+
+/// Prefixes of `src` and `extra_src` grouped by the archive they go into.
+fn source_groups(
+    src: &str,
+    src_path: Path,
+    extra_src: &[String],
+    archive_per_src: bool,
+) -> Result<Vec<Vec<Path>>> {
+    let mut src_paths = vec![src_path];
+    for url in extra_src {
+        if !same_store(src, url)? {
+            return Err(AppError::Config(format!(
+                "{url} is not in the same bucket as {src}"
+            )));
+        }
+        src_paths.push(get_store_and_path(url)?.1);
+    }
+    check_disjoint(&src_paths)?;
+
+    Ok(if archive_per_src {
+        src_paths.into_iter().map(|path| vec![path]).collect()
+    } else {
+        vec![src_paths]
+    })
+}
+
+/// Individually archived objects keep their key, so below a source prefix they would be
+/// archived again by the next run.
+fn check_outside(dst_path: &Path, groups: &[Vec<Path>]) -> Result<()> {
+    for prefix in groups.iter().flatten() {
+        if dst_path.prefix_matches(prefix) || prefix.prefix_matches(dst_path) {
+            return Err(AppError::Config(format!(
+                "individually archived objects need a destination outside the source, \
+                 '{dst_path}' overlaps '{prefix}'"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Overlapping source prefixes would archive the same objects twice.
+fn check_disjoint(prefixes: &[Path]) -> Result<()> {
+    for (i, a) in prefixes.iter().enumerate() {
+        for b in &prefixes[i + 1..] {
+            if a.prefix_matches(b) || b.prefix_matches(a) {
+                return Err(AppError::Config(format!(
+                    "source prefixes '{a}' and '{b}' overlap"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Values of the placeholders in [`ArchiveJob::name_template`], except `{seq}`.
+struct NameValues {
+    src_bucket: String,
+    prefix: String,
+    cutoff: String,
+    run_id: String,
+    period: String,
+}
+
+impl NameValues {
+    fn new(
+        src: &str,
+        prefixes: &[Path],
+        cutoff: DateTime<Utc>,
+        run_id: &str,
+        period: &str,
+    ) -> Result<Self> {
+        let prefixes: Vec<String> = prefixes
+            .iter()
+            .map(|prefix| prefix.as_ref().replace('/', "-"))
+            .collect();
+        Ok(Self {
+            src_bucket: Url::parse(src)?.host_str().unwrap_or_default().to_string(),
+            prefix: prefixes.join("+"),
+            cutoff: cutoff.format("%Y%m%d_%H%M%S").to_string(),
+            run_id: run_id.to_string(),
+            period: period.to_string(),
+        })
+    }
+}
+
+/// Fills in the placeholders of `template`.
+fn render_name(template: &str, values: &NameValues, seq: u32) -> Result<String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            AppError::Config(format!(
+                "unclosed placeholder in name template '{template}'"
+            ))
+        })? + start;
+        match &rest[start + 1..end] {
+            "src_bucket" => name.push_str(&values.src_bucket),
+            "prefix" => name.push_str(&values.prefix),
+            "cutoff" => name.push_str(&values.cutoff),
+            "run_id" => name.push_str(&values.run_id),
+            "period" => name.push_str(&values.period),
+            "seq" => name.push_str(&seq.to_string()),
+            other => {
+                return Err(AppError::Config(format!(
+                    "unknown placeholder {{{other}}} in name template '{template}'"
+                )));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Location of the archive below `dst_path`. With `{seq}` in the template, numbers are tried
+/// from 1 until no archive by that name exists.
+async fn archive_path(
+    store: &dyn ObjectStore,
+    dst_path: &Path,
+    template: &str,
+    values: &NameValues,
+    extension: &str,
+) -> Result<Path> {
+    let uses_seq = render_name(template, values, 1)? != render_name(template, values, 2)?;
+    let mut seq = 1;
+    loop {
+        let name = render_name(template, values, seq)?;
+        let path = dst_path.clone().join(format!("{name}.{extension}"));
+        if !uses_seq {
+            return Ok(path);
+        }
+        match store.head(&path).await {
+            Err(object_store::Error::NotFound { .. }) => return Ok(path),
+            Ok(_) => seq += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Key prefixes of everything archive runs write below `dst_path`. Archives are only covered
+/// when `name_template` starts with some fixed text.
+fn output_prefixes(dst_path: &Path, name_template: &str) -> Vec<String> {
+    let archives = name_template
+        .split('{')
+        .next()
+        .filter(|fixed| !fixed.is_empty());
+    if archives.is_none() {
+        warning!(
+            "Earlier archives below {dst_path} may be selected, as the name template starts \
+             with a placeholder"
+        );
+    }
+
+    archives
+        .into_iter()
+        .chain([
+            "failed_deletes.json",
+            catalog::CATALOG_NAME,
+            SUMMARIES_DIR,
+            AUDIT_DIR,
+            PROGRESS_PREFIX,
+        ])
+        .map(|name| {
+            if dst_path.as_ref().is_empty() {
+                name.to_string()
+            } else {
+                format!("{dst_path}/{name}")
+            }
+        })
+        .collect()
+}
+
+/// Splits off the objects that changed since they were listed, as their archived copy is
+/// stale. Returns the unchanged objects and the changed ones.
+async fn unchanged(
+    store: &dyn ObjectStore,
+    archived: Vec<ObjectMeta>,
+    verification: DeleteVerification,
+) -> Result<(Vec<ObjectMeta>, Vec<ObjectMeta>)> {
+    if verification == DeleteVerification::None {
+        return Ok((archived, Vec::new()));
+    }
+
+    let checked: Vec<(ObjectMeta, bool)> = futures::stream::iter(archived)
+        .map(|listed| async move {
+            let current = match store.head(&listed.location).await {
+                Ok(current) => current,
+                Err(object_store::Error::NotFound { .. }) => {
+                    warning!("{} is gone already", listed.location);
+                    return Ok((listed, false));
+                }
+                Err(e) => return Err(AppError::from(e)),
+            };
+
+            let same_size_and_time =
+                current.size == listed.size && current.last_modified == listed.last_modified;
+            let same = match (verification, &listed.e_tag, &current.e_tag) {
+                (DeleteVerification::Etag, Some(listed), Some(current)) => listed == current,
+                _ => same_size_and_time,
+            };
+            if !same {
+                warning!(
+                    "Keeping {}, it changed after it was archived",
+                    listed.location
+                );
+            }
+            Ok((listed, same))
+        })
+        .buffered(VERIFY_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let (same, changed): (Vec<_>, Vec<_>) = checked.into_iter().partition(|(_, same)| *same);
+    let metas = |checked: Vec<(ObjectMeta, bool)>| checked.into_iter().map(|(meta, _)| meta);
+    Ok((metas(same).collect(), metas(changed).collect()))
+}
+
+/// Shows what is about to be deleted and asks for a `y`, anything else declines.
+fn confirm_deletion(src: &str, archived: &[ObjectMeta]) -> Result<bool> {
+    let bytes: u64 = archived.iter().map(|meta| meta.size).sum();
+    ask(&format!(
+        "Delete the {} archived objects ({bytes} bytes) from {src}?",
+        archived.len()
+    ))
+}
+
+/// Asks `question` on the terminal, only `y` or `yes` agree.
+pub fn ask(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FixtureStore;
+    use futures::TryStreamExt;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_run_with_stores() -> Result<()> {
+        let src_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let dst_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        src_store
+            .put(&Path::from("audit/a.json"), "{}".into())
+            .await?;
+        src_store
+            .put(&Path::from("audit/b.tmp"), "tmp".into())
+            .await?;
+
+        let job = ArchiveJob {
+            filter: ObjectFilter {
+                cutoff: Some(Utc::now() + Duration::minutes(1)),
+                exclude: crate::filter::build_globset(&["**/*.tmp".to_string()])?,
+                ..ObjectFilter::default()
+            },
+            buffer_size: MIN_PART_SIZE,
+            codec: Codec::Zstd,
+            ..ArchiveJob::new("memory:///audit", "memory:///archive")
+        };
+        job.run_with_stores(
+            (src_store.clone(), Path::from("audit")),
+            (dst_store.clone(), Path::from("archive")),
+        )
+        .await?;
+
+        let remaining: Vec<_> = src_store.list(None).try_collect().await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].location, Path::from("audit/b.tmp"));
+
+        let archives: Vec<ObjectMeta> = dst_store.list(None).try_collect().await?;
+        assert_eq!(archives.len(), 3);
+        let archive = archives
+            .iter()
+            .find(|meta| meta.location.extension() == Some("zst"))
+            .ok_or_else(|| AppError::Archive("no archive written".to_string()))?;
+        let manifest = dst_store
+            .get(&Manifest::location(&archive.location))
+            .await?
+            .bytes()
+            .await?;
+        let manifest: Manifest = serde_json::from_slice(&manifest)?;
+        assert_eq!(manifest.archive, archive.location.as_ref());
+        assert_eq!(manifest.objects.len(), 1);
+        assert_eq!(manifest.objects[0].key, "audit/a.json");
+
+        let entries =
+            catalog::load(dst_store.as_ref(), &Path::from("archive/catalog.jsonl")).await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].archive, manifest.archive);
+        assert_eq!(entries[0].size, archive.size);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_summary() -> Result<()> {
+        let src_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let dst_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        for key in ["audit/a.log", "audit/b.log"] {
+            src_store
+                .put(&Path::from(key), "line\n".repeat(100).into())
+                .await?;
+        }
+
+        let mut job = ArchiveJob {
+            filter: ObjectFilter {
+                cutoff: Some(Utc::now() + Duration::minutes(1)),
+                ..ObjectFilter::default()
+            },
+            buffer_size: MIN_PART_SIZE,
+            codec: Codec::Zstd,
+            ..ArchiveJob::new("memory:///audit", "memory:///archive")
+        };
+        let mut summary = job.summary().await?;
+        summary.audit = Some(AuditLog::default());
+        job.run_summarizing(
+            (src_store.clone(), Path::from("audit")),
+            (dst_store.clone(), Path::from("archive")),
+            &mut summary,
+        )
+        .await?;
+
+        let archive = Path::from(summary.archives.concat());
+        assert_eq!(summary.archives.len(), 1);
+        assert_eq!(summary.bytes_out, dst_store.head(&archive).await?.size);
+        assert_eq!(
+            (summary.matched, summary.archived, summary.skipped),
+            (2, 2, 0)
+        );
+        assert_eq!((summary.disposed, summary.kept, summary.failed), (2, 0, 0));
+        assert_eq!(summary.bytes_in, 1000);
+
+        let audit = summary
+            .audit
+            .take()
+            .ok_or_else(|| AppError::Archive("no audit log".to_string()))?;
+        let location = audit
+            .put(dst_store.as_ref(), &Path::from("archive"), "run")
+            .await?;
+        assert_eq!(location, Path::from("archive/audit/run.jsonl"));
+        let log = dst_store.get(&location).await?.bytes().await?;
+        let entries: Vec<serde_json::Value> = serde_json::Deserializer::from_slice(&log)
+            .into_iter()
+            .collect::<std::result::Result<_, _>>()?;
+        let actions: Vec<(&str, &str, &str)> = entries
+            .iter()
+            .map(|entry| {
+                let field = |name: &str| entry[name].as_str().unwrap_or_default();
+                (field("key"), field("action"), field("outcome"))
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("audit/a.log", "archive", "ok"),
+                ("audit/b.log", "archive", "ok"),
+                ("audit/a.log", "delete", "ok"),
+                ("audit/b.log", "delete", "ok"),
+            ]
+        );
+        assert_eq!(entries[0]["archive"], summary.archives[0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_in_place_skips_earlier_archives() -> Result<()> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let earlier = Path::from("audit/archive_20240101_000000.tar.xz");
+        store.put(&earlier, "xz".into()).await?;
+        store.put(&Path::from("audit/a.json"), "{}".into()).await?;
+
+        let job = || ArchiveJob {
+            filter: ObjectFilter {
+                cutoff: Some(Utc::now() + Duration::minutes(1)),
+                ..ObjectFilter::default()
+            },
+            buffer_size: MIN_PART_SIZE,
+            ..ArchiveJob::new("memory:///audit", "memory:///audit")
+        };
+        job()
+            .run_with_stores(
+                (store.clone(), Path::from("audit")),
+                (store.clone(), Path::from("audit")),
+            )
+            .await?;
+
+        let remaining: Vec<ObjectMeta> = store.list(None).try_collect().await?;
+        assert_eq!(remaining.len(), 4);
+        assert!(remaining.iter().any(|meta| meta.location == earlier));
+        assert!(remaining.iter().all(|meta| {
+            meta.location.as_ref().starts_with("audit/archive_")
+                || meta.location.as_ref() == "audit/catalog.jsonl"
+        }));
+
+        // The catalog written by the first run is not archived by the next one.
+        job()
+            .run_with_stores(
+                (store.clone(), Path::from("audit")),
+                (store.clone(), Path::from("audit")),
+            )
+            .await?;
+        assert!(store.head(&Path::from("audit/catalog.jsonl")).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_with_several_sources() -> Result<()> {
+        let src_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let dst_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        for key in ["audit/a.json", "logs/b.log", "other/c.txt"] {
+            src_store.put(&Path::from(key), "x".into()).await?;
+        }
+
+        let job = |name_template: &str| ArchiveJob {
+            extra_src: vec!["memory:///logs".to_string()],
+            archive_per_src: true,
+            jobs: 2,
+            filter: ObjectFilter {
+                cutoff: Some(Utc::now() + Duration::minutes(1)),
+                ..ObjectFilter::default()
+            },
+            buffer_size: MIN_PART_SIZE,
+            name_template: name_template.to_string(),
+            ..ArchiveJob::new("memory:///audit", "memory:///archive")
+        };
+        let stores = || {
+            (
+                (src_store.clone(), Path::from("audit")),
+                (dst_store.clone(), Path::from("archive")),
+            )
+        };
+
+        let (src, dst) = stores();
+        assert!(
+            job(DEFAULT_NAME_TEMPLATE)
+                .run_with_stores(src, dst)
+                .await
+                .is_err()
+        );
+
+        let (src, dst) = stores();
+        job("{prefix}").run_with_stores(src, dst).await?;
+
+        let remaining: Vec<ObjectMeta> = src_store.list(None).try_collect().await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].location, Path::from("other/c.txt"));
+        let mut archives: Vec<String> = dst_store
+            .list(None)
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await?;
+        archives.sort();
+        assert_eq!(
+            archives,
+            vec![
+                "archive/audit.tar.xz",
+                "archive/audit.tar.xz.manifest.json",
+                "archive/catalog.jsonl",
+                "archive/logs.tar.xz",
+                "archive/logs.tar.xz.manifest.json"
+            ]
+        );
+        // Archives written at the same time both make it into the catalog.
+        let catalog = dst_store
+            .get(&Path::from("archive/catalog.jsonl"))
+            .await?
+            .bytes()
+            .await?;
+        assert_eq!(String::from_utf8_lossy(&catalog).lines().count(), 2);
+
+        assert!(check_disjoint(&[Path::from("logs"), Path::from("logs/app")]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_skips_reuploaded_objects() -> Result<()> {
+        let store = InMemory::new();
+        for key in ["audit/a.json", "audit/b.json", "audit/c.json"] {
+            store.put(&Path::from(key), "{}".into()).await?;
+        }
+        let archived: Vec<ObjectMeta> = store.list(None).try_collect().await?;
+        store
+            .put(&Path::from("audit/b.json"), "{\"v\":2}".into())
+            .await?;
+        store.delete(&Path::from("audit/c.json")).await?;
+
+        let (same, changed) = unchanged(&store, archived.clone(), DeleteVerification::Etag).await?;
+        assert_eq!(same.len(), 1);
+        assert_eq!(same[0].location, Path::from("audit/a.json"));
+        assert_eq!(changed.len(), 2);
+        assert_eq!(
+            unchanged(&store, archived, DeleteVerification::None)
+                .await?
+                .0
+                .len(),
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_name() -> Result<()> {
+        let noon = DateTime::from_timestamp(1_714_566_600, 0).unwrap_or_default();
+        let values = NameValues::new(
+            "s3://project/logs/app",
+            &[Path::from("logs/app")],
+            noon,
+            "run",
+            "2024-05",
+        )?;
+
+        assert_eq!(
+            render_name("{src_bucket}_{prefix}_{cutoff}_{seq}", &values, 3)?,
+            "project_logs-app_20240501_123000_3"
+        );
+        assert_eq!(render_name("{run_id}", &values, 1)?, "run");
+        assert_eq!(render_name("logs_{period}", &values, 1)?, "logs_2024-05");
+        assert!(render_name("{bucket}", &values, 1).is_err());
+        assert!(render_name("archive_{cutoff", &values, 1).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_archive_path_takes_next_seq() -> Result<()> {
+        let store = InMemory::new();
+        let values = NameValues::new(
+            "memory:///audit",
+            &[Path::from("audit")],
+            Utc::now(),
+            "run",
+            "",
+        )?;
+        let dst_path = Path::from("archive");
+        store
+            .put(&Path::from("archive/audit_1.tar.xz"), "xz".into())
+            .await?;
+
+        let path = archive_path(&store, &dst_path, "{prefix}_{seq}", &values, "tar.xz").await?;
+        assert_eq!(path, Path::from("archive/audit_2.tar.xz"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_rewrites() -> Result<()> {
+        let rewrites = vec![
+            "logs/2024/=2024/".parse::<KeyRewrite>()?,
+            KeyRewrite::strip("logs/"),
+        ];
+        assert_eq!(
+            KeyRewrite::apply(&rewrites, "logs/2024/a.log"),
+            "2024/a.log"
+        );
+        assert_eq!(
+            KeyRewrite::apply(&rewrites, "logs/2023/a.log"),
+            "2023/a.log"
+        );
+        assert_eq!(KeyRewrite::apply(&rewrites, "audit/a.json"), "audit/a.json");
+        assert_eq!(KeyRewrite::apply(&[KeyRewrite::strip("a")], "a"), "a");
+        assert!("logs/".parse::<KeyRewrite>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_buffers() {
+        let job = || ArchiveJob::new("s3://project/logs/", "s3://archive/logs/");
+        assert!(job().check_buffers().is_ok());
+        for buffer_size in [MIN_PART_SIZE - 1, MAX_PART_SIZE + 1] {
+            let job = ArchiveJob {
+                buffer_size,
+                ..job()
+            };
+            assert!(matches!(job.check_buffers(), Err(AppError::Config(_))));
+        }
+        let job = ArchiveJob {
+            io_buffer: 0,
+            ..job()
+        };
+        assert!(job.check_buffers().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resumed_upload_keeps_its_run_id() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("osm-run-id-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let url = format!("file://{}/upload.json", dir.display());
+        let state = crate::upload::UploadState {
+            key: "archive/audit_first-run.tar.xz".to_string(),
+            upload_id: "id".to_string(),
+            cutoff: Utc::now(),
+            part_size: MIN_PART_SIZE,
+            started: Utc::now(),
+            parts: Vec::new(),
+            run_id: Some("first-run".to_string()),
+        };
+        ObjectStateStore::from_url(&url)?.save(&state).await?;
+
+        let job = || ArchiveJob {
+            upload_state: Some(url.clone()),
+            ..ArchiveJob::new("s3://project/audit/", "s3://archive/")
+        };
+        let resumed = job().summary().await?.run_id;
+        let mut given = ArchiveJob {
+            run_id: Some("retry".to_string()),
+            ..job()
+        };
+        let given = given.summary().await?.run_id;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(resumed, "first-run");
+        assert_eq!(given, "retry");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_with_stores_checks_buffers() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let job = ArchiveJob {
+            buffer_size: 1024,
+            ..ArchiveJob::new("memory:///audit", "memory:///archive")
+        };
+        let run = job
+            .run_with_stores(
+                (store.clone(), Path::from("audit")),
+                (store, Path::from("archive")),
+            )
+            .await;
+        assert!(matches!(run, Err(AppError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_filter_cmd_needs_a_spool_dir() -> Result<()> {
+        let mut job = ArchiveJob {
+            filter_cmd: Some("cat".to_string()),
+            ..ArchiveJob::new("memory:///audit", "memory:///archive")
+        };
+        assert!(matches!(
+            job.compress_options().await,
+            Err(AppError::Config(_))
+        ));
+        job.spool_dir = Some(std::env::temp_dir());
+        job.compress_options().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no"));
+    }
+
+    #[tokio::test]
+    async fn test_run_with_stores_respects_cutoff() -> Result<()> {
+        let cutoff = Utc::now() - Duration::days(7);
+        let src_store = FixtureStore::with_objects(&[
+            ("audit/old.json", "old", cutoff - Duration::days(1)),
+            ("audit/new.json", "new", cutoff + Duration::days(1)),
+        ])
+        .await?;
+        let dst_store = Arc::new(FixtureStore::new());
+
+        let job = ArchiveJob {
+            filter: ObjectFilter {
+                cutoff: Some(cutoff),
+                ..ObjectFilter::default()
+            },
+            ..ArchiveJob::new("memory:///audit", "memory:///archive")
+        };
+        job.run_with_stores(
+            (src_store.clone(), Path::from("audit")),
+            (dst_store.clone(), Path::from("archive")),
+        )
+        .await?;
+
+        assert_eq!(src_store.keys().await?, vec!["audit/new.json"]);
+        let archive = format!("archive/archive_{}.tar.xz", cutoff.format("%Y%m%d_%H%M%S"));
+        assert_eq!(
+            dst_store.keys().await?,
+            vec![
+                archive.clone(),
+                format!("{archive}.manifest.json"),
+                "archive/catalog.jsonl".to_string()
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_with_group_by_month() -> Result<()> {
+        let noon = DateTime::from_timestamp(1_714_566_600, 0).unwrap_or_default();
+        let src_store = FixtureStore::with_objects(&[
+            ("logs/a.log", "a", noon),
+            ("logs/b.log", "b", noon + Duration::days(2)),
+            ("logs/c.log", "c", noon + Duration::days(40)),
+        ])
+        .await?;
+        let dst_store = Arc::new(FixtureStore::new());
+
+        let job = ArchiveJob {
+            filter: ObjectFilter {
+                cutoff: Some(noon + Duration::days(60)),
+                ..ObjectFilter::default()
+            },
+            name_template: "logs".to_string(),
+            group_by: Some(GroupBy::Month),
+            codec: Codec::Zstd,
+            ..ArchiveJob::new("memory:///logs", "memory:///archive")
+        };
+        job.run_with_stores(
+            (src_store.clone(), Path::from("logs")),
+            (dst_store.clone(), Path::from("archive")),
+        )
+        .await?;
+
+        assert!(src_store.keys().await?.is_empty());
+        assert_eq!(
+            dst_store.keys().await?,
+            vec![
+                "archive/catalog.jsonl",
+                "archive/logs_2024-05.tar.zst",
+                "archive/logs_2024-05.tar.zst.manifest.json",
+                "archive/logs_2024-06.tar.zst",
+                "archive/logs_2024-06.tar.zst.manifest.json"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_individually() -> Result<()> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        for key in ["logs/a.log", "logs/2024/b.log"] {
+            store.put(&Path::from(key), "x".into()).await?;
+        }
+        let job = |dst: &str| ArchiveJob {
+            filter: ObjectFilter {
+                cutoff: Some(Utc::now() + Duration::minutes(1)),
+                ..ObjectFilter::default()
+            },
+            buffer_size: MIN_PART_SIZE,
+            codec: Codec::Zstd,
+            entry_mode: EntryMode::Individual,
+            ..ArchiveJob::new("memory:///logs", dst)
+        };
+
+        let in_place = job("memory:///logs/archive").run_with_stores(
+            (store.clone(), Path::from("logs")),
+            (store.clone(), Path::from("logs/archive")),
+        );
+        assert!(in_place.await.is_err());
+
+        job("memory:///archive")
+            .run_with_stores(
+                (store.clone(), Path::from("logs")),
+                (store.clone(), Path::from("archive")),
+            )
+            .await?;
+
+        let mut keys: Vec<String> = store
+            .list(None)
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await?;
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec!["archive/logs/2024/b.log.zst", "archive/logs/a.log.zst"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! Per-object record of an archive run, uploaded as JSON lines next to the archives to prove
+//! what was archived and disposed of, and when.
+
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use object_store::{ObjectStore, ObjectStoreExt, path::Path};
+use serde::Serialize;
+
+/// Folder below the destination audit logs go into.
+pub const AUDIT_DIR: &str = "audit/";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Written into `archive`.
+    Archive,
+    /// Selected but left out, as it changed or vanished after being listed.
+    Skip,
+    /// Archived but left in place, as it changed afterwards or deletion was declined.
+    Keep,
+    Delete,
+    Trash,
+    Mark,
+    /// Listed in an S3 Batch Operations manifest for a job to dispose of.
+    Manifest,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    key: &'a str,
+    action: Action,
+    timestamp: DateTime<Utc>,
+    /// `ok`, or `failed` with the `error`. When disposing of a batch fails as a whole, all of
+    /// it is `failed`, though some objects may have been disposed of before.
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Lines of the audit log so far. They are kept in memory like the archived objects they
+/// describe, until [`AuditLog::put`] uploads them.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    lines: Vec<u8>,
+}
+
+impl AuditLog {
+    /// Records that `action` was taken on `key` just now, into `archive` if archiving, and
+    /// failed with `error` if given.
+    pub fn record(
+        &mut self,
+        key: &str,
+        action: Action,
+        archive: Option<&str>,
+        error: Option<&str>,
+    ) {
+        let entry = Entry {
+            key,
+            action,
+            timestamp: Utc::now(),
+            outcome: if error.is_some() { "failed" } else { "ok" },
+            archive,
+            error,
+        };
+        // Serializing plain strings and a timestamp cannot fail.
+        if serde_json::to_writer(&mut self.lines, &entry).is_ok() {
+            self.lines.push(b'\n');
+        }
+    }
+
+    /// Appends the lines of `other`, e.g. of another pipeline of the run.
+    pub fn append(&mut self, other: Self) {
+        self.lines.extend(other.lines);
+    }
+
+    /// Uploads the log to `audit/<run id>.jsonl` below `dst_path`.
+    pub async fn put(self, store: &dyn ObjectStore, dst_path: &Path, run_id: &str) -> Result<Path> {
+        let location = dst_path
+            .clone()
+            .join(AUDIT_DIR.trim_end_matches('/'))
+            .join(format!("{run_id}.jsonl"));
+        store.put(&location, self.lines.into()).await?;
+        Ok(location)
+    }
+}
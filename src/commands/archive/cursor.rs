@@ -0,0 +1,178 @@
+//! Draining huge sources over many short runs: each run archives up to a number of objects
+//! and leaves a cursor object behind, which the next run resumes the listing after.
+//!
+//! The cursor holds the last key archived rather than a `ListObjectsV2` continuation token:
+//! tokens are opaque to `object_store` and tied to the listing that issued them, while
+//! listing after a key works on every store and for as long as needed.
+
+use super::group;
+use crate::error::{AppError, Result};
+use crate::filter::{ObjectFilter, with_metadata};
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::{Deserialize, Serialize};
+
+/// State object written by `--save-cursor`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// Source the cursor was saved for.
+    pub src: String,
+    /// Key the next run lists after; `None` once the source was drained, so the next run
+    /// starts over and picks up what was too new before.
+    pub last_key: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+impl Cursor {
+    /// Reads the cursor at `url`, `None` when there is none yet.
+    pub async fn load(url: &str) -> Result<Option<Self>> {
+        let (store, path) = get_store_and_path(url)?;
+        match store.get(&path).await {
+            Ok(result) => Ok(Some(serde_json::from_slice(&result.bytes().await?)?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the cursor to `url`, replacing the one there.
+    pub async fn save(&self, url: &str) -> Result<()> {
+        let (store, path) = get_store_and_path(url)?;
+        store
+            .put(&path, serde_json::to_vec_pretty(self)?.into())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Where the listing of a run starts and how many selected objects it takes.
+#[derive(Debug, Default)]
+pub struct Drain {
+    pub start_after: Option<Path>,
+    pub max_objects: Option<usize>,
+}
+
+impl Drain {
+    /// Starts after the key of the cursor saved for `src` at `url`, if there is one.
+    pub async fn resume(url: &str, src: &str, max_objects: Option<usize>) -> Result<Self> {
+        let cursor = Cursor::load(url).await?;
+        if let Some(cursor) = &cursor
+            && cursor.src != src
+        {
+            return Err(AppError::Config(format!(
+                "cursor {url} was saved for {}, not {src}",
+                cursor.src
+            )));
+        }
+        Ok(Self {
+            start_after: cursor.and_then(|cursor| cursor.last_key).map(Path::from),
+            max_objects,
+        })
+    }
+
+    pub const fn is_unbounded(&self) -> bool {
+        self.start_after.is_none() && self.max_objects.is_none()
+    }
+
+    /// The objects under `prefixes` selected by `filter`, in listing order from the start of
+    /// the drain and up to its maximum.
+    pub fn selected<'a>(
+        &self,
+        store: &'a dyn ObjectStore,
+        prefixes: &'a [Path],
+        filter: &'a ObjectFilter,
+    ) -> BoxStream<'a, Result<ObjectMeta>> {
+        let objects = match self.start_after.clone() {
+            Some(offset) => {
+                let listed = futures::stream::iter(prefixes)
+                    .flat_map(move |prefix| {
+                        group::list(store, prefix, Some(offset.clone()), filter.max_depth)
+                    })
+                    .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+                    .boxed();
+                with_metadata(store, listed, filter)
+            }
+            None => group::selected(store, prefixes, filter),
+        };
+        match self.max_objects {
+            Some(max_objects) => objects.take(max_objects).boxed(),
+            None => objects,
+        }
+    }
+
+    /// Cursor for the run after one that selected `matched` objects and archived `archived`:
+    /// after the last key archived when the maximum was reached, otherwise starting over.
+    /// `None` when nothing was archived although there is more, leaving the cursor as it is.
+    pub fn next(&self, src: &str, matched: u64, archived: &[ObjectMeta]) -> Option<Cursor> {
+        let drained = self
+            .max_objects
+            .is_none_or(|max_objects| matched < max_objects as u64);
+        let last_key = if drained {
+            None
+        } else {
+            Some(
+                archived
+                    .iter()
+                    .map(|meta| &meta.location)
+                    .max()?
+                    .to_string(),
+            )
+        };
+        Some(Cursor {
+            src: src.to_string(),
+            last_key,
+            updated: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_drain_resumes_after_last_key() -> Result<()> {
+        let store = InMemory::new();
+        for key in ["logs/a", "logs/b", "logs/c", "logs/d", "logs/e"] {
+            store.put(&Path::from(key), "line".into()).await?;
+        }
+        let prefixes = [Path::from("logs")];
+        let filter = ObjectFilter::default();
+
+        let mut drain = Drain {
+            start_after: None,
+            max_objects: Some(2),
+        };
+        let mut runs = Vec::new();
+        loop {
+            let archived: Vec<ObjectMeta> = drain
+                .selected(&store, &prefixes, &filter)
+                .try_collect()
+                .await?;
+            let keys: Vec<String> = archived
+                .iter()
+                .map(|meta| meta.location.to_string())
+                .collect();
+            runs.push(keys);
+            let cursor = drain
+                .next("s3://bucket/logs/", archived.len() as u64, &archived)
+                .ok_or_else(|| AppError::Archive("no cursor".to_string()))?;
+            let Some(last_key) = cursor.last_key else {
+                break;
+            };
+            drain.start_after = Some(Path::from(last_key));
+        }
+        assert_eq!(
+            runs,
+            [
+                vec!["logs/a", "logs/b"],
+                vec!["logs/c", "logs/d"],
+                vec!["logs/e"]
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+//! Planning the archives of a run: splitting by date period and size, and the order objects
+//! go in.
+
+use crate::error::{AppError, Result};
+use crate::filter::{ObjectFilter, with_metadata};
+use crate::listing::list_shallow;
+use crate::output::warning;
+use chrono::NaiveDate;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, path::Path};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Length of the periods objects are grouped into, one archive each.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Day,
+    Month,
+    Year,
+}
+
+/// Where the date objects are grouped by comes from.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupDate {
+    /// The modification time.
+    Modified,
+    /// The first date in the key, e.g. `2024-06-01`, `2024/06/01` or `20240601`; for
+    /// months and years `2024-06` and `2024/06` will do.
+    Key,
+}
+
+impl GroupBy {
+    /// Name of the period `meta` belongs to, e.g. `2024-06` for months; `None` when the date
+    /// should come from the key and there is none.
+    #[must_use]
+    pub fn period(self, meta: &ObjectMeta, source: GroupDate) -> Option<String> {
+        let date = match source {
+            GroupDate::Modified => meta.last_modified.date_naive(),
+            GroupDate::Key => key_date(meta.location.as_ref(), self != Self::Day)?,
+        };
+        let format = match self {
+            Self::Day => "%Y-%m-%d",
+            Self::Month => "%Y-%m",
+            Self::Year => "%Y",
+        };
+        Some(date.format(format).to_string())
+    }
+}
+
+/// First date in `key`, falling back to the first year and month with `month_is_enough`.
+fn key_date(key: &str, month_is_enough: bool) -> Option<NaiveDate> {
+    let starts = || {
+        key.char_indices()
+            .map(|(i, _)| i)
+            .filter(|&i| i == 0 || !key.as_bytes()[i - 1].is_ascii_digit())
+    };
+    let date_at = |i: usize, len: usize, format: &str, suffix: &str| {
+        let candidate = key.get(i..i + len)?;
+        if key.as_bytes().get(i + len).is_some_and(u8::is_ascii_digit) {
+            return None;
+        }
+        NaiveDate::parse_from_str(&format!("{candidate}{suffix}"), format).ok()
+    };
+
+    let full = starts().find_map(|i| {
+        date_at(i, 10, "%Y-%m-%d", "")
+            .or_else(|| date_at(i, 10, "%Y/%m/%d", ""))
+            .or_else(|| date_at(i, 8, "%Y%m%d", ""))
+    });
+    if full.is_some() || !month_is_enough {
+        return full;
+    }
+    starts()
+        .find_map(|i| date_at(i, 7, "%Y-%m-%d", "-01").or_else(|| date_at(i, 7, "%Y/%m/%d", "/01")))
+}
+
+/// Order objects go into the archives of a run in.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// Listing order.
+    Key,
+    /// Oldest first, so an interrupted run has always archived the oldest objects. The
+    /// selected objects are listed upfront and kept in memory.
+    Mtime,
+}
+
+/// How the objects of a run are split into archives.
+#[derive(Debug, Clone, Copy)]
+pub struct Split {
+    pub group: Option<(GroupBy, GroupDate)>,
+    /// Start a new archive before the objects in it add up to more than this many bytes.
+    pub target_size: Option<u64>,
+    /// Start a new archive once one holds this many objects.
+    pub max_entries: Option<usize>,
+    pub order: Order,
+}
+
+impl Split {
+    /// Whether all selected objects go into a single archive in listing order.
+    pub const fn is_single(self) -> bool {
+        self.group.is_none()
+            && self.target_size.is_none()
+            && self.max_entries.is_none()
+            && matches!(self.order, Order::Key)
+    }
+
+    /// Adds the placeholders telling the archives of a split apart to `template` where missing.
+    pub fn name_template(self, mut template: String) -> String {
+        if self.group.is_some() && !template.contains("{period}") {
+            template.push_str("_{period}");
+        }
+        if (self.target_size.is_some() || self.max_entries.is_some()) && !template.contains("{seq}")
+        {
+            template.push_str("_{seq}");
+        }
+        template
+    }
+
+    fn period(self, meta: &ObjectMeta) -> Option<String> {
+        self.group.map_or_else(
+            || Some(String::new()),
+            |(group_by, source)| group_by.period(meta, source),
+        )
+    }
+}
+
+/// Where an object is in the listing of several prefixes, which goes one prefix after the other.
+type Position = (usize, String);
+
+fn position(prefixes: &[Path], meta: &ObjectMeta) -> Position {
+    let index = prefixes
+        .iter()
+        .position(|prefix| meta.location.prefix_matches(prefix))
+        .unwrap_or_default();
+    (index, meta.location.to_string())
+}
+
+/// The objects of one archive.
+#[derive(Debug)]
+enum Members {
+    /// In listing order from the object at `start` up to the one at `end`, which goes into the
+    /// next archive.
+    Range {
+        start: Option<Position>,
+        end: Option<Position>,
+    },
+    Listed(Vec<ObjectMeta>),
+}
+
+/// One archive of a run, `period` is empty without grouping.
+#[derive(Debug)]
+pub struct Part {
+    pub period: String,
+    members: Members,
+}
+
+impl Part {
+    fn new(period: String, split: Split, start: Option<Position>) -> Self {
+        let members = match split.order {
+            Order::Key => Members::Range { start, end: None },
+            Order::Mtime => Members::Listed(Vec::new()),
+        };
+        Self { period, members }
+    }
+
+    /// The objects under `prefixes` that go into this archive, listing them again unless they
+    /// were kept from planning.
+    pub fn into_objects<'a>(
+        self,
+        store: &'a dyn ObjectStore,
+        prefixes: &'a [Path],
+        filter: &'a ObjectFilter,
+        split: Split,
+    ) -> BoxStream<'a, Result<ObjectMeta>> {
+        let (start, end) = match self.members {
+            Members::Range { start, end } => (start, end),
+            Members::Listed(objects) => return futures::stream::iter(objects).map(Ok).boxed(),
+        };
+        selected(store, prefixes, filter)
+            .try_filter(move |meta| {
+                let position = position(prefixes, meta);
+                futures::future::ready(
+                    split.period(meta).as_ref() == Some(&self.period)
+                        && start.as_ref().is_none_or(|start| &position >= start)
+                        && end.as_ref().is_none_or(|end| &position < end),
+                )
+            })
+            .boxed()
+    }
+}
+
+/// The objects under `prefix`, after `offset` if given, in listing order. With a `max_depth`
+/// only those up to that many path segments below it are listed, in key order.
+pub fn list<'a>(
+    store: &'a dyn ObjectStore,
+    prefix: &Path,
+    offset: Option<Path>,
+    max_depth: Option<usize>,
+) -> BoxStream<'a, Result<ObjectMeta>> {
+    match (max_depth, offset) {
+        (Some(depth), offset) => list_shallow(store, prefix.clone(), depth)
+            .try_filter(move |meta| {
+                futures::future::ready(offset.as_ref().is_none_or(|offset| &meta.location > offset))
+            })
+            .boxed(),
+        (None, Some(offset)) => store
+            .list_with_offset(Some(prefix), &offset)
+            .map_err(AppError::from)
+            .boxed(),
+        (None, None) => store.list(Some(prefix)).map_err(AppError::from).boxed(),
+    }
+}
+
+/// The objects under `prefixes` selected by `filter`, in listing order.
+pub fn selected<'a>(
+    store: &'a dyn ObjectStore,
+    prefixes: &'a [Path],
+    filter: &'a ObjectFilter,
+) -> BoxStream<'a, Result<ObjectMeta>> {
+    let listed = futures::stream::iter(prefixes)
+        .flat_map(|prefix| list(store, prefix, None, filter.max_depth))
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+        .boxed();
+    with_metadata(store, listed, filter)
+}
+
+/// Lists the objects under `prefixes` selected by `filter` once to plan the archives of a run,
+/// oldest period first. Objects without a date in their key are reported and left alone.
+pub async fn plan(
+    store: &dyn ObjectStore,
+    prefixes: &[Path],
+    filter: &ObjectFilter,
+    split: Split,
+) -> Result<Vec<Part>> {
+    let mut objects = selected(store, prefixes, filter);
+    if matches!(split.order, Order::Mtime) {
+        let mut listed: Vec<ObjectMeta> = objects.try_collect().await?;
+        listed.sort_by(|a, b| (a.last_modified, &a.location).cmp(&(b.last_modified, &b.location)));
+        objects = futures::stream::iter(listed).map(Ok).boxed();
+    }
+
+    // Per period its archives, and the size and objects of the last one so far.
+    let mut periods: BTreeMap<String, (Vec<Part>, u64, usize)> = BTreeMap::new();
+    let mut undated = 0_usize;
+    while let Some(meta) = objects.try_next().await? {
+        let Some(period) = split.period(&meta) else {
+            undated += 1;
+            continue;
+        };
+
+        let (parts, size, entries) = periods
+            .entry(period)
+            .or_insert_with_key(|period| (vec![Part::new(period.clone(), split, None)], 0, 0));
+        let full = *size > 0
+            && split
+                .target_size
+                .is_some_and(|target| *size + meta.size > target);
+        if full || split.max_entries.is_some_and(|max| *entries >= max.max(1)) {
+            let start = position(prefixes, &meta);
+            if let Some(Part {
+                members: Members::Range { end, .. },
+                ..
+            }) = parts.last_mut()
+            {
+                *end = Some(start.clone());
+            }
+            let period = parts[0].period.clone();
+            parts.push(Part::new(period, split, Some(start)));
+            *size = 0;
+            *entries = 0;
+        }
+        *size += meta.size;
+        *entries += 1;
+        if let Some(Part {
+            members: Members::Listed(objects),
+            ..
+        }) = parts.last_mut()
+        {
+            objects.push(meta);
+        }
+    }
+
+    if undated > 0 {
+        warning!("Skipping {undated} objects without a date in their key");
+    }
+    Ok(periods
+        .into_values()
+        .flat_map(|(parts, _, _)| parts)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use object_store::ObjectStoreExt;
+
+    fn meta(key: &str) -> ObjectMeta {
+        ObjectMeta {
+            location: Path::from(key),
+            last_modified: DateTime::from_timestamp(1_714_566_600, 0).unwrap_or_default(),
+            size: 1,
+            e_tag: None,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn test_period_from_modification_time() {
+        let meta = meta("logs/app.log");
+        assert_eq!(
+            GroupBy::Day.period(&meta, GroupDate::Modified).as_deref(),
+            Some("2024-05-01")
+        );
+        assert_eq!(
+            GroupBy::Month.period(&meta, GroupDate::Modified).as_deref(),
+            Some("2024-05")
+        );
+        assert_eq!(
+            GroupBy::Year.period(&meta, GroupDate::Modified).as_deref(),
+            Some("2024")
+        );
+    }
+
+    #[test]
+    fn test_period_from_key() {
+        let period = |group_by: GroupBy, key: &str| group_by.period(&meta(key), GroupDate::Key);
+        assert_eq!(
+            period(GroupBy::Day, "logs/2024/06/03/app.log").as_deref(),
+            Some("2024-06-03")
+        );
+        assert_eq!(
+            period(GroupBy::Day, "logs/app-20240603.log").as_deref(),
+            Some("2024-06-03")
+        );
+        assert_eq!(
+            period(GroupBy::Month, "logs/2024-06/app.log").as_deref(),
+            Some("2024-06")
+        );
+        assert_eq!(period(GroupBy::Day, "logs/2024-06/app.log"), None);
+        assert_eq!(period(GroupBy::Year, "logs/build-12345678/app.log"), None);
+    }
+
+    /// Keys of the archives planned for `store`.
+    async fn planned(
+        store: &dyn ObjectStore,
+        prefixes: &[Path],
+        split: Split,
+    ) -> Result<Vec<Vec<String>>> {
+        let filter = ObjectFilter::default();
+        let mut keys = Vec::new();
+        for part in plan(store, prefixes, &filter, split).await? {
+            let objects: Vec<ObjectMeta> = part
+                .into_objects(store, prefixes, &filter, split)
+                .try_collect()
+                .await?;
+            keys.push(
+                objects
+                    .iter()
+                    .map(|meta| meta.location.to_string())
+                    .collect(),
+            );
+        }
+        Ok(keys)
+    }
+
+    #[tokio::test]
+    async fn test_plan_packs_objects_up_to_target_size() -> Result<()> {
+        let store = object_store::memory::InMemory::new();
+        for (key, size) in [
+            ("a/1", 40),
+            ("a/2", 40),
+            ("a/3", 40),
+            ("b/1", 100),
+            ("b/2", 10),
+        ] {
+            store.put(&Path::from(key), vec![0; size].into()).await?;
+        }
+        let prefixes = [Path::from("b"), Path::from("a")];
+        let split = |order| Split {
+            group: None,
+            target_size: Some(100),
+            max_entries: None,
+            order,
+        };
+
+        assert_eq!(
+            planned(&store, &prefixes, split(Order::Key)).await?,
+            vec![vec!["b/1"], vec!["b/2", "a/1", "a/2"], vec!["a/3"]]
+        );
+        // Oldest first, which is the order they were put in.
+        assert_eq!(
+            planned(&store, &prefixes, split(Order::Mtime)).await?,
+            vec![vec!["a/1", "a/2"], vec!["a/3"], vec!["b/1"], vec!["b/2"]]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_caps_entries_per_archive() -> Result<()> {
+        let store = object_store::memory::InMemory::new();
+        for key in ["a/1", "a/2", "a/3", "a/4", "a/5"] {
+            store.put(&Path::from(key), vec![0; 10].into()).await?;
+        }
+        let prefixes = [Path::from("a")];
+        let split = |target_size| Split {
+            group: None,
+            target_size,
+            max_entries: Some(2),
+            order: Order::Key,
+        };
+
+        assert_eq!(
+            planned(&store, &prefixes, split(None)).await?,
+            vec![vec!["a/1", "a/2"], vec!["a/3", "a/4"], vec!["a/5"]]
+        );
+        // Whichever limit is reached first starts the next archive.
+        assert_eq!(
+            planned(&store, &prefixes, split(Some(10))).await?,
+            vec![
+                vec!["a/1"],
+                vec!["a/2"],
+                vec!["a/3"],
+                vec!["a/4"],
+                vec!["a/5"]
+            ]
+        );
+        Ok(())
+    }
+}
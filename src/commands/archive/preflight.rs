@@ -0,0 +1,153 @@
+//! Checks run before any object is streamed, so a missing permission fails a run in seconds
+//! rather than hours into it.
+
+use crate::error::{AppError, Result};
+use crate::output::{info, warning};
+use futures::StreamExt;
+use object_store::{ObjectStore, ObjectStoreExt, PutPayload, path::Path};
+
+/// A permission a run needs, and what the store said when it was tried.
+struct Check {
+    permission: String,
+    result: Result<()>,
+}
+
+/// Name of the objects written to and deleted by the checks, never left behind.
+fn probe_name() -> String {
+    format!(".preflight-{}", std::process::id())
+}
+
+/// Lists each of `src_paths` and reads the first object found, writes to `dst_path` with a
+/// tiny multipart upload that is aborted again, and, with `delete`, deletes a key under the
+/// first source that does not exist. Prints a line per check.
+///
+/// # Errors
+///
+/// Fails naming every permission that is missing, with the error the store returned for it.
+pub async fn check(
+    src_store: &dyn ObjectStore,
+    src_paths: &[Path],
+    dst_store: &dyn ObjectStore,
+    dst_path: &Path,
+    delete: bool,
+) -> Result<()> {
+    let mut checks = Vec::new();
+    for path in src_paths {
+        let (first, result) = match src_store.list(Some(path)).next().await.transpose() {
+            Ok(first) => (first, Ok(())),
+            Err(e) => (None, Err(e.into())),
+        };
+        checks.push(Check {
+            permission: format!("list source '{path}'"),
+            result,
+        });
+        if let Some(meta) = first {
+            checks.push(Check {
+                permission: format!("read source '{}'", meta.location),
+                result: src_store
+                    .head(&meta.location)
+                    .await
+                    .map(|_| ())
+                    .map_err(Into::into),
+            });
+        }
+    }
+
+    checks.push(Check {
+        permission: format!("write destination '{dst_path}'"),
+        result: try_upload(dst_store, &dst_path.clone().join(probe_name())).await,
+    });
+
+    if delete && let Some(path) = src_paths.first() {
+        let result = match src_store.delete(&path.clone().join(probe_name())).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+        checks.push(Check {
+            permission: format!("delete from source '{path}'"),
+            result,
+        });
+    }
+
+    report(checks)
+}
+
+/// Starts a multipart upload at `location`, uploads a part and aborts it again.
+async fn try_upload(store: &dyn ObjectStore, location: &Path) -> Result<()> {
+    let mut upload = store.put_multipart(location).await?;
+    let written = upload.put_part(PutPayload::from_static(b"preflight")).await;
+    upload.abort().await?;
+    Ok(written?)
+}
+
+fn report(checks: Vec<Check>) -> Result<()> {
+    let mut missing = Vec::new();
+    for Check { permission, result } in checks {
+        match result {
+            Ok(()) => info!("Preflight: can {permission}"),
+            Err(e) => {
+                warning!("Preflight: cannot {permission}: {e}");
+                missing.push(permission);
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::Preflight(format!(
+        "cannot {}",
+        missing.join(", nor ")
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_check_leaves_nothing_behind() -> Result<()> {
+        let src = InMemory::new();
+        let dst = InMemory::new();
+        src.put(&Path::from("logs/a.log"), "line".into()).await?;
+
+        check(
+            &src,
+            &[Path::from("logs")],
+            &dst,
+            &Path::from("archive"),
+            true,
+        )
+        .await?;
+        assert_eq!(dst.list(None).count().await, 0);
+        assert_eq!(src.list(None).count().await, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_names_missing_permissions() {
+        let checks = vec![
+            Check {
+                permission: "list source 'logs'".to_string(),
+                result: Ok(()),
+            },
+            Check {
+                permission: "write destination 'archive'".to_string(),
+                result: Err(AppError::Archive("403 Forbidden".to_string())),
+            },
+            Check {
+                permission: "delete from source 'logs'".to_string(),
+                result: Err(AppError::Archive("403 Forbidden".to_string())),
+            },
+        ];
+        let error = report(checks).map_err(|e| e.to_string());
+        assert_eq!(
+            error,
+            Err(
+                "Preflight check failed: cannot write destination 'archive', nor delete from \
+                 source 'logs'"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Objects that keep failing to be read, e.g. corrupt ones or ones the credentials may not
+//! read, recorded in a state object so later runs leave them out instead of retrying them
+//! forever.
+
+use crate::error::Result;
+use crate::filter::ObjectFilter;
+use crate::output::info;
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use object_store::{ObjectMeta, ObjectStoreExt, path::Path};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Failures after which an object is left out of the runs that follow.
+pub const SKIP_AFTER: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    /// Runs the object failed in.
+    pub failures: u32,
+    /// Why it failed the last time.
+    pub reason: String,
+    pub last_failed: DateTime<Utc>,
+}
+
+/// State object written by `--skip-list`, with the objects that failed by key.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkipList {
+    pub objects: BTreeMap<String, Failure>,
+}
+
+impl SkipList {
+    /// Reads the skip list at `url`, an empty one when there is none yet.
+    pub async fn load(url: &str) -> Result<Self> {
+        let (store, path) = get_store_and_path(url)?;
+        match store.get(&path).await {
+            Ok(result) => Ok(serde_json::from_slice(&result.bytes().await?)?),
+            Err(object_store::Error::NotFound { .. }) => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the skip list to `url`, replacing the one there.
+    pub async fn save(&self, url: &str) -> Result<()> {
+        let (store, path) = get_store_and_path(url)?;
+        store
+            .put(&path, serde_json::to_vec_pretty(self)?.into())
+            .await?;
+        Ok(())
+    }
+
+    /// Leaves the objects that failed [`SKIP_AFTER`] times out of `filter`'s selection.
+    /// Returns how many there are.
+    pub fn exclude(&self, filter: &mut ObjectFilter) -> usize {
+        filter.exclude_keys = self
+            .objects
+            .iter()
+            .filter(|(_, failure)| failure.failures >= SKIP_AFTER)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let skipped = filter.exclude_keys.len();
+        if skipped > 0 {
+            info!("Leaving out {skipped} objects that failed {SKIP_AFTER} times before");
+        }
+        skipped
+    }
+
+    /// Records a run in which the objects `failed` with the reasons given, and `archived`
+    /// ones no longer fail. Returns whether the list changed.
+    pub fn update(&mut self, failed: &[(Path, String)], archived: &[ObjectMeta]) -> bool {
+        let before = self.objects.len();
+        for meta in archived {
+            self.objects.remove(meta.location.as_ref());
+        }
+        let changed = self.objects.len() != before || !failed.is_empty();
+        for (key, reason) in failed {
+            let failure = self
+                .objects
+                .entry(key.to_string())
+                .or_insert_with(|| Failure {
+                    failures: 0,
+                    reason: String::new(),
+                    last_failed: Utc::now(),
+                });
+            failure.failures += 1;
+            failure.reason.clone_from(reason);
+            failure.last_failed = Utc::now();
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_after_repeated_failures() {
+        let mut skips = SkipList::default();
+        let failed = [(Path::from("logs/corrupt"), "Access Denied".to_string())];
+        for _ in 1..SKIP_AFTER {
+            assert!(skips.update(&failed, &[]));
+        }
+        let mut filter = ObjectFilter::default();
+        assert_eq!(skips.exclude(&mut filter), 0);
+
+        assert!(skips.update(&failed, &[]));
+        assert_eq!(skips.exclude(&mut filter), 1);
+        assert!(filter.exclude_keys.contains("logs/corrupt"));
+        assert_eq!(skips.objects["logs/corrupt"].reason, "Access Denied");
+
+        // Once it was archived after all, e.g. with `--retry-skipped`, it is forgotten.
+        let archived = ObjectMeta {
+            location: Path::from("logs/corrupt"),
+            last_modified: Utc::now(),
+            size: 1,
+            e_tag: None,
+            version: None,
+        };
+        assert!(skips.update(&[], &[archived]));
+        assert!(!skips.update(&[], &[]));
+        assert_eq!(skips, SkipList::default());
+    }
+}
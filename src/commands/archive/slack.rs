@@ -0,0 +1,149 @@
+//! Slack message about a finished archive run, posted to an incoming webhook.
+
+use super::summary::RunSummary;
+use crate::error::{AppError, Result};
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request};
+use object_store::ClientOptions;
+use object_store::client::{HttpConnector, ReqwestConnector};
+use serde_json::{Value, json};
+
+/// Size with a binary unit, e.g. `1.5 GiB`.
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    #[allow(clippy::cast_precision_loss)] // Sizes are far below 2^52 bytes.
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} bytes")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Duration like `1h 2m 3s`, leaving out leading zero units.
+fn duration(secs: f64) -> String {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // Whole seconds are enough.
+    let secs = secs.round() as u64;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, m, s) => format!("{h}h {m}m {s}s"),
+    }
+}
+
+/// Block Kit message about the run `summary` describes, headed by `job` or else the source.
+pub fn message(summary: &RunSummary, job: Option<&str>) -> Value {
+    let name = job.map_or_else(|| summary.src.clone(), ToString::to_string);
+    let failed = summary.error.is_some() || summary.failed > 0;
+    let title = if failed {
+        format!(":x: Archive job {name} failed")
+    } else {
+        format!(":white_check_mark: Archive job {name} succeeded")
+    };
+    let field = |label: &str, value: String| json!({"type": "mrkdwn", "text": format!("*{label}*\n{value}")});
+
+    let mut blocks = vec![
+        json!({"type": "header", "text": {"type": "plain_text", "text": title, "emoji": true}}),
+        json!({
+            "type": "section",
+            "fields": [
+                field("Source", format!("`{}`", summary.src)),
+                field("Destination", format!("`{}`", summary.dst)),
+                field(
+                    "Archived",
+                    format!("{} objects, {}", summary.archived, size(summary.bytes_in)),
+                ),
+                field(
+                    "Archive size",
+                    format!(
+                        "{} in {} archives",
+                        size(summary.bytes_out),
+                        summary.archives.len()
+                    ),
+                ),
+                field("Duration", duration(summary.duration_secs)),
+                field(
+                    "Failures",
+                    format!(
+                        "{} failed to {}, {} skipped",
+                        summary.failed, summary.disposal, summary.skipped
+                    ),
+                ),
+            ],
+        }),
+    ];
+    if let Some(error) = &summary.error {
+        blocks.push(json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": format!("*Error*\n```{error}```")},
+        }));
+    }
+    blocks.push(json!({
+        "type": "context",
+        "elements": [{"type": "mrkdwn", "text": format!("Run {}", summary.run_id)}],
+    }));
+
+    json!({"text": title, "blocks": blocks})
+}
+
+/// Posts `message` to the Slack incoming webhook at `webhook`.
+///
+/// # Errors
+///
+/// Fails when the webhook cannot be reached or rejects the message. The URL is left out of
+/// the error, as it is a secret.
+pub async fn post(webhook: &str, message: &Value) -> Result<()> {
+    let http = ReqwestConnector::default().connect(&ClientOptions::new())?;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Bytes::from(serde_json::to_vec(message)?).into())?;
+    let response = http.execute(request).await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.into_body().bytes().await.unwrap_or_default();
+    Err(AppError::Notification(format!(
+        "Slack returned {status}: {}",
+        String::from_utf8_lossy(&body)
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::Usage;
+
+    #[test]
+    fn test_message() {
+        let mut summary = RunSummary::new("run", "s3://logs/app/", "s3://archive/app/", "delete");
+        summary.archived = 1200;
+        summary.bytes_in = 3 * 1024 * 1024 * 1024;
+        summary.bytes_out = 300 * 1024 * 1024;
+        summary
+            .archives
+            .push("app/archive_20250101_000000.tar.xz".to_string());
+        summary.failed = 2;
+        summary.finish(Usage::default(), None);
+
+        let message = message(&summary, Some("nightly-logs"));
+        assert_eq!(message["text"], ":x: Archive job nightly-logs failed");
+        let fields = &message["blocks"][1]["fields"];
+        assert_eq!(fields[2]["text"], "*Archived*\n1200 objects, 3.0 GiB");
+        assert_eq!(fields[3]["text"], "*Archive size*\n300.0 MiB in 1 archives");
+        assert_eq!(
+            fields[5]["text"],
+            "*Failures*\n2 failed to delete, 0 skipped"
+        );
+        assert_eq!(duration(3723.4), "1h 2m 3s");
+        assert_eq!(size(512), "512 bytes");
+    }
+}
@@ -0,0 +1,220 @@
+//! Machine readable account of an archive run, printed as a single JSON line at its end and
+//! optionally uploaded next to the archives, so automation can learn what a run did.
+
+use super::audit::{Action, AuditLog};
+use crate::error::{AppError, Result};
+use crate::object_storage::FailedDelete;
+use crate::output::summary;
+use crate::progress::Progress;
+use crate::usage::Usage;
+use chrono::{DateTime, Utc};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Folder below the destination uploaded summaries go into.
+pub const SUMMARIES_DIR: &str = "summaries/";
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub src: String,
+    pub dst: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub duration_secs: f64,
+    /// Objects the filter selected.
+    pub matched: u64,
+    /// Objects that went into an archive.
+    pub archived: u64,
+    /// Objects selected but left out, as they changed or vanished after being listed.
+    pub skipped: u64,
+    /// Objects of those skipped that failed to be read, see `--max-errors`.
+    pub errors: u64,
+    /// Size of the archived objects.
+    pub bytes_in: u64,
+    /// Size of what was written for them.
+    pub bytes_out: u64,
+    /// `bytes_in` divided by `bytes_out`.
+    pub compression_ratio: Option<f64>,
+    /// Tarballs written, empty when archiving objects individually.
+    pub archives: Vec<String>,
+    /// `delete`, `trash` or `mark`.
+    pub disposal: &'static str,
+    /// Archived objects deleted, trashed or tagged.
+    pub disposed: u64,
+    /// Archived objects kept, as they changed after being archived or deletion was declined.
+    pub kept: u64,
+    /// Archived objects that could not be disposed of.
+    pub failed: u64,
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+    /// Requests and bytes transferred.
+    pub usage: Usage,
+    /// Per-object log of the run, when one is kept.
+    #[serde(skip)]
+    pub audit: Option<AuditLog>,
+    /// Progress uploaded while the run goes on, when asked for.
+    #[serde(skip)]
+    pub progress: Option<Arc<Progress>>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl RunSummary {
+    pub fn new(run_id: &str, src: &str, dst: &str, disposal: &'static str) -> Self {
+        let started = Utc::now();
+        Self {
+            run_id: run_id.to_string(),
+            src: src.to_string(),
+            dst: dst.to_string(),
+            started,
+            finished: None,
+            duration_secs: 0.0,
+            matched: 0,
+            archived: 0,
+            skipped: 0,
+            errors: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            compression_ratio: None,
+            archives: Vec::new(),
+            disposal,
+            disposed: 0,
+            kept: 0,
+            failed: 0,
+            error: None,
+            usage: Usage::default(),
+            audit: None,
+            progress: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// An empty summary of the same run, for one of the pipelines archiving concurrently to
+    /// record into, see [`RunSummary::absorb`].
+    #[must_use]
+    pub fn part(&self) -> Self {
+        Self {
+            audit: self.audit.as_ref().map(|_| AuditLog::default()),
+            ..Self::new(&self.run_id, &self.src, &self.dst, self.disposal)
+        }
+    }
+
+    /// Adds the archives written and objects listed that `part` recorded.
+    pub fn absorb(&mut self, part: Self) {
+        self.matched += part.matched;
+        self.bytes_out += part.bytes_out;
+        self.archives.extend(part.archives);
+        if let (Some(audit), Some(lines)) = (&mut self.audit, part.audit) {
+            audit.append(lines);
+        }
+    }
+
+    /// Adds an entry to the audit log, if one is kept.
+    fn audit(&mut self, key: &str, action: Action, archive: Option<&str>, error: Option<&str>) {
+        if let Some(audit) = &mut self.audit {
+            audit.record(key, action, archive, error);
+        }
+    }
+
+    /// Records the objects `listed` for an archive, of which `written` went into the archive
+    /// `archive_of` names.
+    pub fn record_written(
+        &mut self,
+        listed: &[Path],
+        written: &[ObjectMeta],
+        archive_of: impl Fn(&ObjectMeta) -> String,
+    ) {
+        self.matched += listed.len() as u64;
+        if self.audit.is_none() {
+            return;
+        }
+        for meta in written {
+            let archive = archive_of(meta);
+            self.audit(
+                meta.location.as_ref(),
+                Action::Archive,
+                Some(&archive),
+                None,
+            );
+        }
+        let written: HashSet<&Path> = written.iter().map(|meta| &meta.location).collect();
+        for key in listed.iter().filter(|key| !written.contains(key)) {
+            self.audit(key.as_ref(), Action::Skip, None, None);
+        }
+    }
+
+    /// Records archived objects left in place.
+    pub fn record_kept(&mut self, kept: &[ObjectMeta]) {
+        self.kept += kept.len() as u64;
+        for meta in kept {
+            self.audit(meta.location.as_ref(), Action::Keep, None, None);
+        }
+    }
+
+    /// Records how disposing of `keys` with `action` went.
+    pub fn record_disposal(
+        &mut self,
+        keys: &[Path],
+        action: Action,
+        result: &Result<Vec<FailedDelete>>,
+    ) {
+        if let Ok(failed) = result {
+            self.failed = failed.len() as u64;
+            self.disposed = keys.len() as u64 - self.failed;
+        }
+        if self.audit.is_none() {
+            return;
+        }
+        let batch_error = result.as_ref().err().map(ToString::to_string);
+        let failed: HashMap<&str, &str> = result
+            .iter()
+            .flatten()
+            .map(|failed| (failed.key.as_str(), failed.error.as_str()))
+            .collect();
+        for key in keys {
+            let key = key.as_ref();
+            let error = batch_error.as_deref().or_else(|| failed.get(key).copied());
+            self.audit(key, action, None, error);
+        }
+    }
+
+    /// Records the objects that went into archives, out of those matched.
+    pub fn record_archived(&mut self, archived: &[ObjectMeta]) {
+        self.archived = archived.len() as u64;
+        self.skipped = self.matched.saturating_sub(self.archived);
+        self.bytes_in = archived.iter().map(|meta| meta.size).sum();
+    }
+
+    /// Records the end of the run, with the `usage` it came to and how it ended.
+    pub fn finish(&mut self, usage: Usage, error: Option<&AppError>) {
+        self.finished = Some(Utc::now());
+        self.duration_secs = self.start.elapsed().as_secs_f64();
+        #[allow(clippy::cast_precision_loss)] // Sizes are far below 2^52 bytes.
+        let ratio = (self.bytes_out > 0).then(|| self.bytes_in as f64 / self.bytes_out as f64);
+        self.compression_ratio = ratio;
+        self.usage = usage;
+        self.error = error.map(ToString::to_string);
+    }
+
+    /// Prints the summary as a single JSON line, at every verbosity.
+    pub fn print(&self) -> Result<()> {
+        summary!("{}", serde_json::to_string(self)?);
+        Ok(())
+    }
+
+    /// Uploads the summary to `summaries/<run id>.json` below `dst_path`.
+    pub async fn put(&self, store: &dyn ObjectStore, dst_path: &Path) -> Result<Path> {
+        let location = dst_path
+            .clone()
+            .join(SUMMARIES_DIR.trim_end_matches('/'))
+            .join(format!("{}.json", self.run_id));
+        store
+            .put(&location, serde_json::to_vec_pretty(self)?.into())
+            .await?;
+        Ok(location)
+    }
+}
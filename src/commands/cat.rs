@@ -0,0 +1,73 @@
+use crate::codec::Codec;
+use crate::dictionary::ZstdDictionary;
+use crate::error::Result;
+use crate::storage::get_store_and_path;
+use object_store::ObjectStoreExt;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+
+pub async fn cat(src: String, decompress: bool, zstd_dict: Option<String>) -> Result<()> {
+    let (store, path) = get_store_and_path(&src)?;
+    let dictionary = match zstd_dict {
+        Some(url) => Some(ZstdDictionary::load(&url).await?),
+        None => None,
+    };
+
+    let result = store.get(&path).await?;
+    let body = StreamReader::new(result.into_stream());
+
+    let mut reader: Box<dyn AsyncRead + Unpin + Send> = match dictionary {
+        Some(dictionary) if decompress => dictionary.decoder(body)?,
+        _ if decompress => decoder_for(path.extension(), body),
+        _ => Box::new(body),
+    };
+
+    let mut stdout = tokio::io::stdout();
+    tokio::io::copy(&mut reader, &mut stdout).await?;
+    stdout.flush().await?;
+
+    Ok(())
+}
+
+/// Picks a decoder from the object's extension, passing unknown extensions through untouched.
+fn decoder_for<R>(extension: Option<&str>, reader: R) -> Box<dyn AsyncRead + Unpin + Send>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    match Codec::from_extension(extension) {
+        Some(codec) => codec.decoder(reader),
+        None => Box::new(reader),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::write::XzEncoder;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_decoder_for_xz() -> Result<()> {
+        let mut encoder = XzEncoder::new(Vec::new());
+        encoder.write_all(b"hello").await?;
+        encoder.shutdown().await?;
+        let compressed = encoder.into_inner();
+
+        let mut decoded = String::new();
+        decoder_for(Some("xz"), std::io::Cursor::new(compressed))
+            .read_to_string(&mut decoded)
+            .await?;
+        assert_eq!(decoded, "hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decoder_for_unknown_extension() -> Result<()> {
+        let mut decoded = String::new();
+        decoder_for(Some("log"), b"plain".as_slice())
+            .read_to_string(&mut decoded)
+            .await?;
+        assert_eq!(decoded, "plain");
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use crate::error::Result;
+use crate::filter::ObjectFilter;
+use crate::object_storage::sha256_object;
+use crate::output::warning;
+use crate::s3::S3Client;
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct ChecksumRecord {
+    key: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    sha256: String,
+    /// `s3` when the digest was taken from S3 object attributes, `computed` otherwise.
+    source: &'static str,
+}
+
+async fn checksum_object(
+    store: &dyn ObjectStore,
+    s3: Option<&S3Client>,
+    meta: ObjectMeta,
+) -> Result<ChecksumRecord> {
+    let native = match s3 {
+        Some(s3) => s3.get_object_sha256(meta.location.as_ref()).await?,
+        None => None,
+    };
+
+    let (sha256, source) = match native {
+        Some(sha256) => (sha256, "s3"),
+        None => (sha256_object(store, &meta.location).await?, "computed"),
+    };
+
+    println!("{sha256}  {}", meta.location);
+
+    Ok(ChecksumRecord {
+        key: meta.location.to_string(),
+        size: meta.size,
+        last_modified: meta.last_modified,
+        sha256,
+        source,
+    })
+}
+
+pub async fn checksum(
+    src: String,
+    dst: String,
+    filter: ObjectFilter,
+    concurrency: usize,
+    use_s3_checksums: bool,
+) -> Result<()> {
+    let (src_store, src_path) = get_store_and_path(&src)?;
+    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+
+    let s3 = if use_s3_checksums {
+        S3Client::from_url(&src)?
+    } else {
+        None
+    };
+    if use_s3_checksums && s3.is_none() {
+        warning!("S3 checksums are only available for s3:// sources, computing all digests.");
+    }
+
+    let mut records: Vec<ChecksumRecord> = src_store
+        .list(Some(&src_path))
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+        .map_ok(|meta| checksum_object(src_store.as_ref(), s3.as_ref(), meta))
+        .map_err(Into::into)
+        .try_buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+    records.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for record in &records {
+        writer.serialize(record)?;
+    }
+    let report = writer
+        .into_inner()
+        .map_err(csv::IntoInnerError::into_error)?;
+
+    let report_path = dst_path.join(format!(
+        "checksums_{}.csv",
+        Utc::now().format("%Y%m%d_%H%M%S")
+    ));
+    dst_store.put(&report_path, report.into()).await?;
+
+    println!(
+        "Wrote checksum report for {} objects to {report_path}",
+        records.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use crate::chunker::Chunker;
+use crate::codec::Codec;
+use crate::commands::ask;
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::object_storage::{DELETE_CONCURRENCY, delete_keys};
+use crate::output::{info, summary, verbose, warning};
+use crate::storage::get_store_and_path;
+use async_compression::Level;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use object_store::buffered::BufWriter;
+use object_store::{GetOptions, ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+
+pub struct DedupOptions {
+    /// Average chunk size in bytes.
+    pub chunk_size: usize,
+    pub codec: Codec,
+    pub level: Level,
+    /// Objects processed in parallel.
+    pub concurrency: usize,
+    pub keep_sources: bool,
+    /// Ask on the terminal before deleting the archived sources.
+    pub confirm: bool,
+}
+
+/// Lists the chunks an object is made of, in order, written as JSON below `recipes/`.
+#[derive(Debug, Serialize)]
+struct Recipe {
+    key: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    e_tag: Option<String>,
+    /// Extension of the codec the chunks are compressed with.
+    codec: &'static str,
+    chunks: Vec<String>,
+}
+
+/// Chunk store below `dst_path`, shared by the objects of a run.
+struct ChunkStore {
+    store: Arc<dyn ObjectStore>,
+    dst_path: Path,
+    chunk_size: usize,
+    codec: Codec,
+    level: Level,
+    /// Chunks known to be stored, saving a `HEAD` request each.
+    known: Mutex<HashSet<String>>,
+}
+
+#[derive(Debug, Default)]
+struct Stored {
+    chunks: usize,
+    bytes: u64,
+}
+
+/// Object whose chunks and recipe were stored, with the chunks new to the store.
+#[derive(Debug)]
+struct Archived {
+    location: Path,
+    recipe: Recipe,
+    stored: Stored,
+}
+
+impl ChunkStore {
+    /// `chunks/ab/abcd….zst` for a chunk with the SHA-256 `abcd…`.
+    fn chunk_key(&self, hash: &str) -> Path {
+        self.dst_path
+            .clone()
+            .join("chunks")
+            .join(&hash[..2])
+            .join(format!("{hash}.{}", self.codec.extension()))
+    }
+
+    fn recipe_key(&self, location: &Path) -> Path {
+        self.dst_path
+            .clone()
+            .join("recipes")
+            .parts()
+            .chain(Path::from(format!("{location}.json")).parts())
+            .collect()
+    }
+
+    fn is_known(&self, hash: &str) -> bool {
+        self.known.lock().is_ok_and(|known| known.contains(hash))
+    }
+
+    fn remember(&self, hash: String) {
+        if let Ok(mut known) = self.known.lock() {
+            known.insert(hash);
+        }
+    }
+
+    /// Uploads `chunk` unless a chunk with the same content is already stored, returning its
+    /// hash and whether it was uploaded.
+    async fn put(&self, chunk: &[u8]) -> Result<(String, bool)> {
+        let hash = hex::encode(Sha256::digest(chunk));
+        if self.is_known(&hash) {
+            return Ok((hash, false));
+        }
+
+        let key = self.chunk_key(&hash);
+        let uploaded = match self.store.head(&key).await {
+            Ok(_) => false,
+            Err(object_store::Error::NotFound { .. }) => {
+                let sink = BufWriter::new(Arc::clone(&self.store), key);
+                let mut encoder = self.codec.encoder(sink, self.level);
+                encoder.write_all(chunk).await?;
+                encoder.shutdown().await?;
+                true
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.remember(hash.clone());
+        Ok((hash, uploaded))
+    }
+
+    /// Stores the chunks of `meta` and its recipe, `None` when it changed since it was listed.
+    async fn archive(
+        &self,
+        src_store: &dyn ObjectStore,
+        meta: ObjectMeta,
+    ) -> Result<Option<Archived>> {
+        let unmodified = GetOptions {
+            if_unmodified_since: Some(meta.last_modified),
+            ..GetOptions::default()
+        };
+        let mut body = match src_store.get_opts(&meta.location, unmodified).await {
+            Ok(result) => result.into_stream(),
+            Err(object_store::Error::Precondition { .. }) => {
+                warning!("Skipping {}, it changed since it was listed", meta.location);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut chunker = Chunker::new(self.chunk_size);
+        let mut hashes = Vec::new();
+        let mut stored = Stored::default();
+        let mut store_chunk = async |chunk: &[u8]| {
+            let (hash, uploaded) = self.put(chunk).await?;
+            if uploaded {
+                stored.chunks += 1;
+                stored.bytes += chunk.len() as u64;
+            }
+            hashes.push(hash);
+            Ok::<_, AppError>(())
+        };
+        while let Some(piece) = body.try_next().await? {
+            for chunk in chunker.push(&piece) {
+                store_chunk(&chunk).await?;
+            }
+        }
+        if let Some(chunk) = chunker.finish() {
+            store_chunk(&chunk).await?;
+        }
+
+        let recipe = Recipe {
+            key: meta.location.to_string(),
+            size: meta.size,
+            last_modified: meta.last_modified,
+            e_tag: meta.e_tag,
+            codec: self.codec.extension(),
+            chunks: hashes,
+        };
+        self.store
+            .put(
+                &self.recipe_key(&meta.location),
+                serde_json::to_vec_pretty(&recipe)?.into(),
+            )
+            .await?;
+        verbose!(
+            "Archived {} in {} chunks, {} new",
+            meta.location,
+            recipe.chunks.len(),
+            stored.chunks
+        );
+        Ok(Some(Archived {
+            location: meta.location,
+            recipe,
+            stored,
+        }))
+    }
+
+    /// Leaves out the `archived` objects whose recipe, or a chunk it lists, is not stored, e.g.
+    /// as another writer removed a chunk after it was known to be stored, so their sources are
+    /// kept. Looks up `concurrency` objects at a time, and each distinct chunk once.
+    async fn verify(&self, archived: Vec<Archived>, concurrency: usize) -> Result<Vec<Archived>> {
+        let hashes: HashSet<String> = archived
+            .iter()
+            .flat_map(|archived| archived.recipe.chunks.iter().cloned())
+            .collect();
+        let missing: HashSet<String> = futures::stream::iter(hashes)
+            .map(|hash| async move {
+                match self.store.head(&self.chunk_key(&hash)).await {
+                    Ok(_) => Ok(None),
+                    Err(object_store::Error::NotFound { .. }) => Ok(Some(hash)),
+                    Err(e) => Err(AppError::from(e)),
+                }
+            })
+            .buffer_unordered(concurrency)
+            .try_filter_map(futures::future::ok)
+            .try_collect()
+            .await?;
+
+        let missing = &missing;
+        futures::stream::iter(archived)
+            .map(|archived| async move {
+                if let Some(hash) = archived.recipe.chunks.iter().find(|h| missing.contains(*h)) {
+                    warning!("Keeping {}, its chunk {hash} is missing", archived.location);
+                    return Ok(None);
+                }
+                match self.store.head(&self.recipe_key(&archived.location)).await {
+                    Ok(_) => Ok(Some(archived)),
+                    Err(object_store::Error::NotFound { .. }) => {
+                        warning!("Keeping {}, its recipe is missing", archived.location);
+                        Ok(None)
+                    }
+                    Err(e) => Err(AppError::from(e)),
+                }
+            })
+            .buffered(concurrency)
+            .try_filter_map(futures::future::ok)
+            .try_collect()
+            .await
+    }
+}
+
+/// Archives the objects under `src` selected by `filter` into a deduplicating chunk store at
+/// `dst`, deleting them afterwards unless `keep_sources` is set.
+///
+/// Object bodies are split into content-defined chunks, each distinct chunk is stored once
+/// below `chunks/` named after its SHA-256, and `recipes/<key>.json` lists the chunks of every
+/// object. With `confirm`, the deletion is confirmed on the terminal first.
+pub async fn dedup_archive(
+    src: String,
+    dst: String,
+    filter: ObjectFilter,
+    options: DedupOptions,
+) -> Result<()> {
+    let (src_store, src_path) = get_store_and_path(&src)?;
+    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+    let chunks = ChunkStore {
+        store: dst_store,
+        dst_path,
+        chunk_size: options.chunk_size,
+        codec: options.codec,
+        level: options.level,
+        known: Mutex::new(HashSet::new()),
+    };
+
+    info!("Archiving from {src} into chunk store {dst}");
+    let archived: Vec<Archived> = src_store
+        .list(Some(&src_path))
+        .map_err(AppError::from)
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+        .map_ok(|meta| chunks.archive(src_store.as_ref(), meta))
+        .try_buffer_unordered(options.concurrency.max(1))
+        .try_filter_map(futures::future::ok)
+        .try_collect()
+        .await?;
+
+    let (new_chunks, new_bytes) = archived.iter().fold((0, 0), |(count, bytes), archived| {
+        (
+            count + archived.stored.chunks,
+            bytes + archived.stored.bytes,
+        )
+    });
+    println!(
+        "Archived {} objects, storing {new_chunks} new chunks of {new_bytes} bytes",
+        archived.len()
+    );
+
+    if options.keep_sources {
+        return Ok(());
+    }
+    let archived = chunks.verify(archived, options.concurrency.max(1)).await?;
+    let bytes: u64 = archived.iter().map(|archived| archived.recipe.size).sum();
+    let question = format!(
+        "Delete the {} archived objects ({bytes} bytes) from {src}?",
+        archived.len()
+    );
+    if options.confirm && !archived.is_empty() && !ask(&question)? {
+        summary!("Keeping the archived objects under {src}");
+        return Ok(());
+    }
+    let keys = archived
+        .into_iter()
+        .map(|archived| archived.location)
+        .collect();
+    delete_keys(src_store.as_ref(), keys, DELETE_CONCURRENCY)
+        .await
+        .map_err(|e| AppError::Deletion(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_archive_stores_shared_chunks_once() -> Result<()> {
+        let src_store = InMemory::new();
+        let content: Vec<u8> = (0..32 * 1024_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13).to_le_bytes()[0])
+            .collect();
+        for key in ["logs/a.bin", "logs/b.bin"] {
+            src_store
+                .put(&Path::from(key), content.clone().into())
+                .await?;
+        }
+        let chunks = ChunkStore {
+            store: Arc::new(InMemory::new()),
+            dst_path: Path::from("dedup"),
+            chunk_size: 4096,
+            codec: Codec::Zstd,
+            level: Level::Fastest,
+            known: Mutex::new(HashSet::new()),
+        };
+
+        let mut new_chunks = Vec::new();
+        for meta in src_store.list(None).try_collect::<Vec<_>>().await? {
+            if let Some(archived) = chunks.archive(&src_store, meta).await? {
+                new_chunks.push(archived.stored.chunks);
+            }
+        }
+        assert!(new_chunks[0] > 1);
+        assert_eq!(new_chunks[1], 0);
+
+        let recipe = chunks
+            .store
+            .get(&Path::from("dedup/recipes/logs/b.bin.json"))
+            .await?
+            .bytes()
+            .await?;
+        let recipe: serde_json::Value = serde_json::from_slice(&recipe)?;
+        assert_eq!(recipe["size"], 32 * 1024);
+        assert_eq!(
+            recipe["chunks"].as_array().map(Vec::len),
+            Some(new_chunks[0])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_keeps_objects_with_missing_chunks() -> Result<()> {
+        let src_store = InMemory::new();
+        for (key, seed) in [("logs/a.bin", 1_u32), ("logs/b.bin", 2)] {
+            let content: Vec<u8> = (0..16 * 1024_u32)
+                .map(|i| (i.wrapping_add(seed).wrapping_mul(2_654_435_761) >> 13).to_le_bytes()[0])
+                .collect();
+            src_store.put(&Path::from(key), content.into()).await?;
+        }
+        let chunks = ChunkStore {
+            store: Arc::new(InMemory::new()),
+            dst_path: Path::from("dedup"),
+            chunk_size: 4096,
+            codec: Codec::Zstd,
+            level: Level::Fastest,
+            known: Mutex::new(HashSet::new()),
+        };
+        let mut archived = Vec::new();
+        for meta in src_store.list(None).try_collect::<Vec<_>>().await? {
+            archived.extend(chunks.archive(&src_store, meta).await?);
+        }
+        archived.sort_by(|a, b| a.location.cmp(&b.location));
+        let removed = chunks.chunk_key(&archived[0].recipe.chunks[0]);
+        assert!(
+            !archived[1]
+                .recipe
+                .chunks
+                .contains(&archived[0].recipe.chunks[0])
+        );
+        chunks.store.delete(&removed).await?;
+
+        let verified = chunks.verify(archived, 4).await?;
+        let verified: Vec<&str> = verified
+            .iter()
+            .map(|archived| archived.location.as_ref())
+            .collect();
+        assert_eq!(verified, ["logs/b.bin"]);
+        Ok(())
+    }
+}
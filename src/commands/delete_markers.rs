@@ -0,0 +1,88 @@
+use crate::error::{AppError, Result};
+use crate::object_storage::{DELETE_CONCURRENCY, delete_key_versions};
+use crate::s3::{ObjectVersion, S3Client};
+use crate::storage::get_store_and_path;
+use futures::TryStreamExt;
+use object_store::path::Path;
+
+/// A delete marker is expired when it is the only thing left of its key: every version it
+/// was hiding is gone, yet it still slows down listings. `versions` holds all versions and
+/// markers of a single key.
+fn expired_marker(versions: &[ObjectVersion]) -> Option<&ObjectVersion> {
+    match versions {
+        [marker] if marker.delete_marker && marker.is_latest => Some(marker),
+        _ => None,
+    }
+}
+
+pub async fn clean_delete_markers(src: String, dry_run: bool) -> Result<()> {
+    let (_, src_path) = get_store_and_path(&src)?;
+    let client = S3Client::from_url(&src)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "delete markers only exist in s3:// buckets, got {src}"
+        ))
+    })?;
+
+    // Listing is in key order, so versions of a key arrive next to each other.
+    let mut versions = client.list_versions(src_path.to_string());
+    let mut expired: Vec<ObjectVersion> = Vec::new();
+    let mut group: Vec<ObjectVersion> = Vec::new();
+
+    while let Some(version) = versions.try_next().await? {
+        if group.first().is_some_and(|first| first.key != version.key) {
+            expired.extend(expired_marker(&group).cloned());
+            group.clear();
+        }
+        group.push(version);
+    }
+    expired.extend(expired_marker(&group).cloned());
+
+    if dry_run {
+        for marker in &expired {
+            println!(
+                "Would remove delete marker {} ({}, deleted {})",
+                marker.key,
+                marker.version_id,
+                marker.last_modified.to_rfc3339()
+            );
+        }
+        println!("Dry run: {} expired delete markers found.", expired.len());
+        return Ok(());
+    }
+
+    println!(
+        "Removing {} expired delete markers under {src}",
+        expired.len()
+    );
+
+    let markers = expired
+        .into_iter()
+        .map(|marker| (Path::from(marker.key), Some(marker.version_id)))
+        .collect();
+    delete_key_versions(&client, markers, DELETE_CONCURRENCY).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn version(delete_marker: bool) -> ObjectVersion {
+        ObjectVersion {
+            key: "a.log".to_string(),
+            version_id: "v1".to_string(),
+            is_latest: true,
+            last_modified: Utc::now(),
+            delete_marker,
+        }
+    }
+
+    #[test]
+    fn test_expired_marker() {
+        assert!(expired_marker(&[version(true)]).is_some());
+        assert!(expired_marker(&[version(false)]).is_none());
+        assert!(expired_marker(&[version(true), version(false)]).is_none());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::codec::Codec;
+use crate::compressor::Counted;
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::storage::get_store_and_path;
+use async_compression::Level;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::StreamReader;
+
+/// The listing is split into at least this many prefixes per sampled one, where the key
+/// layout allows.
+const MIN_SAMPLES: usize = 10;
+
+/// Levels of the key hierarchy descended into while splitting the listing.
+const MAX_DEPTH: usize = 4;
+
+pub struct EstimateOptions {
+    /// Only every this many prefixes are listed.
+    pub sample_every: usize,
+    pub codec: Codec,
+    pub level: Level,
+    /// Bytes of sampled objects downloaded and compressed to measure the compression ratio
+    /// and throughput.
+    pub sample_bytes: u64,
+    pub concurrency: usize,
+}
+
+/// Objects matching the filter and their size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Totals {
+    objects: u64,
+    bytes: u64,
+}
+
+impl Totals {
+    const fn add(&mut self, meta: &ObjectMeta) {
+        self.objects += 1;
+        self.bytes += meta.size;
+    }
+
+    /// Projects totals seen in `seen` out of `of` equally sized parts to all of them.
+    fn scaled(self, of: usize, seen: usize) -> Self {
+        Self {
+            objects: ratio_of(self.objects, of as u64, seen as u64),
+            bytes: ratio_of(self.bytes, of as u64, seen as u64),
+        }
+    }
+}
+
+/// What a sampled listing found.
+#[derive(Debug)]
+struct Sample {
+    /// Prefixes the listing was split into.
+    prefixes: usize,
+    /// Of which were listed.
+    sampled: usize,
+    /// Matching objects found above the prefixes while splitting, counted exactly.
+    direct: Totals,
+    /// Matching objects below the sampled prefixes.
+    listed: Totals,
+    /// Time spent listing the sampled prefixes.
+    elapsed: Duration,
+    /// Matching objects to measure compression on, up to the sample size.
+    objects: Vec<ObjectMeta>,
+}
+
+impl Sample {
+    fn projected(&self) -> Totals {
+        let below = self.listed.scaled(self.prefixes, self.sampled);
+        Totals {
+            objects: self.direct.objects + below.objects,
+            bytes: self.direct.bytes + below.bytes,
+        }
+    }
+}
+
+/// Descends into the key hierarchy below `prefix` until it is split into enough prefixes to
+/// sample from, counting the matching objects met on the way into `direct`.
+async fn split(
+    store: &dyn ObjectStore,
+    prefix: &Path,
+    filter: &ObjectFilter,
+    options: &EstimateOptions,
+    direct: &mut Totals,
+) -> Result<Vec<Path>> {
+    let wanted = options.sample_every.max(1) * MIN_SAMPLES;
+    let mut level = vec![prefix.clone()];
+    for _ in 0..MAX_DEPTH {
+        if level.len() >= wanted {
+            break;
+        }
+        let listings: Vec<_> = futures::stream::iter(&level)
+            .map(|prefix| store.list_with_delimiter(Some(prefix)))
+            .buffer_unordered(options.concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        level = Vec::new();
+        for listing in listings {
+            for meta in listing.objects.iter().filter(|meta| filter.matches(meta)) {
+                direct.add(meta);
+            }
+            level.extend(listing.common_prefixes);
+        }
+        // Listings finish in any order, sorted the sample is the same on every run.
+        level.sort();
+    }
+    Ok(level)
+}
+
+/// Lists every `sample_every`-th prefix of `prefix`, see [`split`].
+async fn sample(
+    store: &dyn ObjectStore,
+    prefix: &Path,
+    filter: &ObjectFilter,
+    options: &EstimateOptions,
+) -> Result<Sample> {
+    let mut direct = Totals::default();
+    let prefixes = split(store, prefix, filter, options, &mut direct).await?;
+    let sampled: Vec<&Path> = prefixes
+        .iter()
+        .step_by(options.sample_every.max(1))
+        .collect();
+
+    let start = Instant::now();
+    let mut listed = Totals::default();
+    let mut objects = Vec::new();
+    let mut sample_bytes = 0;
+    let mut matching = futures::stream::iter(&sampled)
+        .map(|prefix| store.list(Some(prefix)))
+        .flatten_unordered(options.concurrency.max(1))
+        .map_err(AppError::from)
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)));
+    while let Some(meta) = matching.try_next().await? {
+        listed.add(&meta);
+        if sample_bytes < options.sample_bytes {
+            sample_bytes += meta.size;
+            objects.push(meta);
+        }
+    }
+
+    Ok(Sample {
+        prefixes: prefixes.len(),
+        sampled: sampled.len(),
+        direct,
+        listed,
+        elapsed: start.elapsed(),
+        objects,
+    })
+}
+
+/// Downloads and compresses `objects` as one stream, returning the original and compressed
+/// sizes and the time it took.
+async fn measure(
+    store: &dyn ObjectStore,
+    objects: &[ObjectMeta],
+    codec: Codec,
+    level: Level,
+) -> Result<(u64, u64, Duration)> {
+    let start = Instant::now();
+    let mut compressed = Counted::new(tokio::io::sink());
+    let mut encoder = codec.encoder(&mut compressed, level);
+    let mut original = 0;
+    for meta in objects {
+        let body = store.get(&meta.location).await?.into_stream();
+        original += tokio::io::copy(&mut StreamReader::new(body), &mut encoder).await?;
+    }
+    encoder.shutdown().await?;
+    drop(encoder);
+    Ok((original, compressed.written, start.elapsed()))
+}
+
+/// `numerator / denominator` of `n`, saturating.
+fn ratio_of(n: u64, numerator: u64, denominator: u64) -> u64 {
+    let projected = u128::from(n) * u128::from(numerator) / u128::from(denominator.max(1));
+    u64::try_from(projected).unwrap_or(u64::MAX)
+}
+
+/// Projects what archiving the objects under `src` selected by `filter` would take from a
+/// sample of the listing, without listing all of it.
+pub async fn estimate(src: String, filter: ObjectFilter, options: EstimateOptions) -> Result<()> {
+    let (store, prefix) = get_store_and_path(&src)?;
+    let sample = sample(store.as_ref(), &prefix, &filter, &options).await?;
+    let projected = sample.projected();
+    println!(
+        "Listed {} of {} prefixes under {src} in {:.1}s",
+        sample.sampled,
+        sample.prefixes,
+        sample.elapsed.as_secs_f32()
+    );
+    println!("Matching objects:  ~{}", projected.objects);
+    println!("Matching bytes:    ~{}", projected.bytes);
+
+    let (original, compressed, elapsed) = measure(
+        store.as_ref(),
+        &sample.objects,
+        options.codec,
+        options.level,
+    )
+    .await?;
+    if original == 0 {
+        println!("No objects sampled, compressed size and run time are unknown");
+        return Ok(());
+    }
+    println!(
+        "Compressed size:   ~{} ({}% of {original} sampled bytes with {:?})",
+        ratio_of(projected.bytes, compressed, original),
+        ratio_of(100, compressed, original),
+        options.codec
+    );
+
+    let millis = |elapsed: Duration| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+    let listing = Duration::from_millis(ratio_of(
+        millis(sample.elapsed),
+        sample.prefixes as u64,
+        sample.sampled as u64,
+    ));
+    let archiving = Duration::from_millis(ratio_of(projected.bytes, millis(elapsed), original));
+    println!(
+        "Run time:          ~{}s (listing ~{}s, downloading and compressing ~{}s)",
+        (listing + archiving).as_secs(),
+        listing.as_secs(),
+        archiving.as_secs()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_sample_projects_totals() -> Result<()> {
+        let store = InMemory::new();
+        store.put(&Path::from("logs/top.log"), "top".into()).await?;
+        for day in 0..40 {
+            for hour in ["00", "12"] {
+                let key = format!("logs/2024-06-{day:02}/{hour}.log");
+                store.put(&Path::from(key), "line".into()).await?;
+            }
+        }
+        let options = EstimateOptions {
+            sample_every: 4,
+            codec: Codec::Gzip,
+            level: Level::Fastest,
+            sample_bytes: 8,
+            concurrency: 4,
+        };
+
+        let sample = sample(
+            &store,
+            &Path::from("logs"),
+            &ObjectFilter::default(),
+            &options,
+        )
+        .await?;
+        assert_eq!((sample.prefixes, sample.sampled), (40, 10));
+        assert_eq!(
+            sample.direct,
+            Totals {
+                objects: 1,
+                bytes: 3
+            }
+        );
+        assert_eq!(
+            sample.listed,
+            Totals {
+                objects: 20,
+                bytes: 80
+            }
+        );
+        assert_eq!(sample.objects.len(), 2);
+        assert_eq!(
+            sample.projected(),
+            Totals {
+                objects: 81,
+                bytes: 323
+            }
+        );
+
+        let (original, compressed, _) =
+            measure(&store, &sample.objects, Codec::Gzip, Level::Fastest).await?;
+        assert_eq!(original, 8);
+        assert!(compressed > 0);
+        Ok(())
+    }
+}
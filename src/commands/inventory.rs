@@ -0,0 +1,295 @@
+use crate::error::Result;
+use crate::listing::{list_concurrent, list_s3_concurrent};
+use crate::output::info;
+use crate::s3::{S3Client, S3Object};
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+use object_store::buffered::BufWriter;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+/// Number of records encoded between uploads of the buffered output.
+const RECORDS_PER_CHUNK: usize = 64 * 1024;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum InventoryFormat {
+    Csv,
+    Parquet,
+}
+
+#[derive(Debug, Serialize)]
+struct InventoryRecord {
+    key: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    e_tag: Option<String>,
+    storage_class: Option<String>,
+}
+
+impl From<ObjectMeta> for InventoryRecord {
+    fn from(meta: ObjectMeta) -> Self {
+        Self {
+            key: meta.location.to_string(),
+            size: meta.size,
+            last_modified: meta.last_modified,
+            e_tag: meta.e_tag,
+            storage_class: None,
+        }
+    }
+}
+
+impl From<S3Object> for InventoryRecord {
+    fn from(object: S3Object) -> Self {
+        Self {
+            storage_class: Some(object.storage_class().to_string()),
+            key: object.key,
+            size: object.size,
+            last_modified: object.last_modified,
+            e_tag: object.e_tag,
+        }
+    }
+}
+
+enum RecordWriter {
+    Csv(csv::Writer<Vec<u8>>),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_writer::ParquetRecordWriter),
+}
+
+impl RecordWriter {
+    fn new(format: InventoryFormat) -> Result<Self> {
+        match format {
+            InventoryFormat::Csv => Ok(Self::Csv(csv::Writer::from_writer(Vec::new()))),
+            #[cfg(feature = "parquet")]
+            InventoryFormat::Parquet => {
+                Ok(Self::Parquet(parquet_writer::ParquetRecordWriter::new()?))
+            }
+            #[cfg(not(feature = "parquet"))]
+            InventoryFormat::Parquet => Err(crate::error::AppError::Unsupported(
+                "Parquet output requires building with the `parquet` feature".to_string(),
+            )),
+        }
+    }
+
+    fn write(&mut self, record: InventoryRecord) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.serialize(record)?,
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => writer.write(record)?,
+        }
+        Ok(())
+    }
+
+    /// Encoded bytes that are complete and can be uploaded already.
+    fn take_output(&mut self) -> Result<Vec<u8>> {
+        match self {
+            Self::Csv(writer) => {
+                // Continue with a fresh buffer; only the first chunk carries the header row.
+                let rest = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                let chunk = std::mem::replace(writer, rest);
+                Ok(chunk
+                    .into_inner()
+                    .map_err(csv::IntoInnerError::into_error)?)
+            }
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => writer.take_output(),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            Self::Csv(writer) => Ok(writer
+                .into_inner()
+                .map_err(csv::IntoInnerError::into_error)?),
+            #[cfg(feature = "parquet")]
+            Self::Parquet(writer) => writer.finish(),
+        }
+    }
+}
+
+pub async fn inventory(
+    src: String,
+    dst: String,
+    format: InventoryFormat,
+    concurrency: usize,
+    buffer_size: usize,
+) -> Result<()> {
+    let (src_store, src_path) = get_store_and_path(&src)?;
+    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+
+    let mut writer = RecordWriter::new(format)?;
+
+    info!("Writing inventory of {src} to {dst}");
+
+    let mut records = match S3Client::from_url(&src)? {
+        Some(client) => list_s3_concurrent(client, src_path.to_string(), concurrency)
+            .map_ok(InventoryRecord::from)
+            .boxed(),
+        None => list_concurrent(src_store, src_path, concurrency)
+            .map_ok(InventoryRecord::from)
+            .boxed(),
+    };
+
+    let mut sink = BufWriter::with_capacity(dst_store, dst_path, buffer_size);
+    let mut count = 0usize;
+
+    while let Some(record) = records.next().await {
+        writer.write(record?)?;
+        count += 1;
+
+        if count.is_multiple_of(RECORDS_PER_CHUNK) {
+            sink.put(writer.take_output()?.into()).await?;
+            info!("Listed {count} objects");
+        }
+    }
+
+    sink.put(writer.finish()?.into()).await?;
+    sink.shutdown().await?;
+
+    println!("Inventory of {count} objects written to {dst}");
+
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use super::{InventoryRecord, RECORDS_PER_CHUNK};
+    use crate::error::Result;
+    use arrow_array::{ArrayRef, RecordBatch, StringArray, TimestampMillisecondArray, UInt64Array};
+    use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    pub struct ParquetRecordWriter {
+        schema: SchemaRef,
+        writer: ArrowWriter<Vec<u8>>,
+        pending: Vec<InventoryRecord>,
+    }
+
+    impl ParquetRecordWriter {
+        pub fn new() -> Result<Self> {
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("key", DataType::Utf8, false),
+                Field::new("size", DataType::UInt64, false),
+                Field::new(
+                    "last_modified",
+                    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                    false,
+                ),
+                Field::new("e_tag", DataType::Utf8, true),
+                Field::new("storage_class", DataType::Utf8, true),
+            ]));
+            let writer = ArrowWriter::try_new(Vec::new(), schema.clone(), None)?;
+
+            Ok(Self {
+                schema,
+                writer,
+                pending: Vec::with_capacity(RECORDS_PER_CHUNK),
+            })
+        }
+
+        pub fn write(&mut self, record: InventoryRecord) -> Result<()> {
+            self.pending.push(record);
+            if self.pending.len() >= RECORDS_PER_CHUNK {
+                self.write_batch()?;
+            }
+            Ok(())
+        }
+
+        /// Writes pending records as a row group, making its bytes available in the buffer.
+        fn write_batch(&mut self) -> Result<()> {
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+            let records = std::mem::take(&mut self.pending);
+
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(StringArray::from_iter_values(
+                    records.iter().map(|r| r.key.as_str()),
+                )),
+                Arc::new(UInt64Array::from_iter_values(
+                    records.iter().map(|r| r.size),
+                )),
+                Arc::new(
+                    TimestampMillisecondArray::from_iter_values(
+                        records.iter().map(|r| r.last_modified.timestamp_millis()),
+                    )
+                    .with_timezone("UTC"),
+                ),
+                Arc::new(
+                    records
+                        .iter()
+                        .map(|r| r.e_tag.as_deref())
+                        .collect::<StringArray>(),
+                ),
+                Arc::new(
+                    records
+                        .iter()
+                        .map(|r| r.storage_class.as_deref())
+                        .collect::<StringArray>(),
+                ),
+            ];
+
+            self.writer
+                .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+            self.writer.flush()?;
+            Ok(())
+        }
+
+        pub fn take_output(&mut self) -> Result<Vec<u8>> {
+            self.write_batch()?;
+            Ok(std::mem::take(self.writer.inner_mut()))
+        }
+
+        pub fn finish(mut self) -> Result<Vec<u8>> {
+            self.write_batch()?;
+            Ok(self.writer.into_inner()?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::path::Path;
+
+    fn record(key: &str) -> InventoryRecord {
+        InventoryRecord::from(ObjectMeta {
+            location: Path::from(key),
+            last_modified: Utc::now(),
+            size: 3,
+            e_tag: Some("\"abc\"".to_string()),
+            version: None,
+        })
+    }
+
+    #[test]
+    fn test_csv_output_is_chunked() -> Result<()> {
+        let mut writer = RecordWriter::new(InventoryFormat::Csv)?;
+        writer.write(record("a.txt"))?;
+        let first = String::from_utf8_lossy(&writer.take_output()?).into_owned();
+        writer.write(record("b.txt"))?;
+        let rest = String::from_utf8_lossy(&writer.finish()?).into_owned();
+
+        assert!(first.starts_with("key,size,last_modified,e_tag,storage_class\na.txt,3,"));
+        assert!(rest.starts_with("b.txt,3,"));
+        Ok(())
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_output_has_magic() -> Result<()> {
+        let mut writer = RecordWriter::new(InventoryFormat::Parquet)?;
+        writer.write(record("a.txt"))?;
+        let mut output = writer.take_output()?;
+        output.extend(writer.finish()?);
+
+        assert!(output.starts_with(b"PAR1"));
+        assert!(output.ends_with(b"PAR1"));
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use crate::codec::Codec;
+use crate::commands::OutputFormat;
+use crate::error::{AppError, Result};
+use crate::manifest::Manifest;
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, path::Path};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Manifests are fetched this many at a time.
+const MANIFEST_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Serialize)]
+struct ArchiveSummary {
+    key: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    /// Key of the manifest, `None` for archives without one.
+    manifest: Option<String>,
+    cutoff: Option<DateTime<Utc>>,
+    parent: Option<String>,
+    objects: Option<usize>,
+    /// Total size of the archived objects.
+    original_size: Option<u64>,
+    /// Original size divided by the size of the archive.
+    ratio: Option<f64>,
+}
+
+impl ArchiveSummary {
+    fn new(meta: &ObjectMeta, manifest: Option<&Manifest>) -> Self {
+        let manifest_key = manifest.map(|_| Manifest::location(&meta.location).to_string());
+        let original_size =
+            manifest.map(|manifest| manifest.objects.iter().map(|entry| entry.size).sum());
+        #[allow(clippy::cast_precision_loss)] // Sizes are far below 2^52 bytes.
+        let ratio = original_size
+            .filter(|_| meta.size > 0)
+            .map(|original: u64| original as f64 / meta.size as f64);
+        Self {
+            key: meta.location.to_string(),
+            size: meta.size,
+            last_modified: meta.last_modified,
+            manifest: manifest_key,
+            cutoff: manifest.and_then(|manifest| manifest.cutoff),
+            parent: manifest.and_then(|manifest| manifest.parent.clone()),
+            objects: manifest.map(|manifest| manifest.objects.len()),
+            original_size,
+            ratio,
+        }
+    }
+}
+
+/// Whether `location` is named like a compressed tarball, e.g. `archive.tar.zst`, or an
+/// encrypted one like `archive.tar.zst.gpg`.
+fn is_archive(location: &Path) -> bool {
+    let Some(name) = location.filename() else {
+        return false;
+    };
+    let name = name.strip_suffix(".gpg").unwrap_or(name);
+    name.rsplit_once('.').is_some_and(|(stem, extension)| {
+        Codec::from_extension(Some(extension)).is_some()
+            && std::path::Path::new(stem)
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("tar"))
+    })
+}
+
+/// Summaries of the archives below `dst_path`, read from their manifests, in key order.
+async fn summarize(store: &dyn ObjectStore, dst_path: &Path) -> Result<Vec<ArchiveSummary>> {
+    let listed: Vec<ObjectMeta> = store.list(Some(dst_path)).try_collect().await?;
+    let keys: HashSet<&Path> = listed.iter().map(|meta| &meta.location).collect();
+    let mut archives: Vec<&ObjectMeta> = listed
+        .iter()
+        .filter(|meta| is_archive(&meta.location))
+        .collect();
+    archives.sort_by(|a, b| a.location.cmp(&b.location));
+
+    futures::stream::iter(archives)
+        .map(|meta| async {
+            let manifest = if keys.contains(&Manifest::location(&meta.location)) {
+                Manifest::find(store, &meta.location).await?
+            } else {
+                None
+            };
+            Ok::<_, AppError>(ArchiveSummary::new(meta, manifest.as_ref()))
+        })
+        .buffered(MANIFEST_CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// Lists the archives below `dst` with what their manifests tell about them.
+pub async fn list_archives(dst: String, format: OutputFormat) -> Result<()> {
+    let (store, dst_path) = get_store_and_path(&dst)?;
+    let summaries = summarize(store.as_ref(), &dst_path).await?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&summaries)?),
+        OutputFormat::Human => print_human(&summaries),
+    }
+    Ok(())
+}
+
+fn print_human(summaries: &[ArchiveSummary]) {
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+
+    println!("CUTOFF\tOBJECTS\tSIZE\tORIGINAL\tRATIO\tARCHIVE");
+    for summary in summaries {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            or_dash(summary.cutoff.map(|cutoff| cutoff.to_rfc3339())),
+            or_dash(summary.objects.map(|objects| objects.to_string())),
+            summary.size,
+            or_dash(summary.original_size.map(|size| size.to_string())),
+            or_dash(summary.ratio.map(|ratio| format!("{ratio:.2}"))),
+            summary.key
+        );
+    }
+    let total: u64 = summaries.iter().map(|summary| summary.size).sum();
+    println!("{} archives, {total} bytes", summaries.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::ArchiveIndex;
+    use object_store::ObjectStoreExt;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_summarize_reads_manifests() -> Result<()> {
+        let store = InMemory::new();
+        let archive = Path::from("archive/archive_1.tar.zst");
+        store.put(&archive, vec![0; 10].into()).await?;
+        store
+            .put(&Path::from("archive/archive_0.tar.gz"), vec![0; 4].into())
+            .await?;
+        store
+            .put(&Path::from("archive/logs/a.log.zst"), vec![0; 4].into())
+            .await?;
+        store
+            .put(
+                &Path::from("archive/archive_2.tar.zst.gpg"),
+                vec![0; 4].into(),
+            )
+            .await?;
+        let object = ObjectMeta {
+            location: Path::from("logs/a.log"),
+            last_modified: Utc::now(),
+            size: 25,
+            e_tag: None,
+            version: None,
+        };
+        let cutoff = Utc::now();
+        Manifest {
+            cutoff: Some(cutoff),
+            ..Manifest::new(&archive, None, &[object], &ArchiveIndex::default(), &[])
+        }
+        .put(&store)
+        .await?;
+
+        let summaries = summarize(&store, &Path::from("archive")).await?;
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].key, "archive/archive_0.tar.gz");
+        assert_eq!(summaries[2].key, "archive/archive_2.tar.zst.gpg");
+        assert!(summaries[0].manifest.is_none());
+        assert_eq!(summaries[1].objects, Some(1));
+        assert_eq!(summaries[1].original_size, Some(25));
+        assert_eq!(summaries[1].ratio, Some(2.5));
+        assert_eq!(summaries[1].cutoff, Some(cutoff));
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use crate::error::Result;
+use crate::filter::ObjectFilter;
+use crate::storage::get_store_and_path;
+use futures::StreamExt;
+use object_store::path::Path;
+use std::io::{self, Write};
+
+pub async fn ls(
+    src: String,
+    filter: ObjectFilter,
+    start_after: Option<String>,
+    limit: Option<usize>,
+) -> Result<()> {
+    let (store, prefix) = get_store_and_path(&src)?;
+
+    let mut list_stream = start_after.map_or_else(
+        || store.list(Some(&prefix)),
+        |offset| store.list_with_offset(Some(&prefix), &Path::from(offset)),
+    );
+
+    let mut stdout = io::stdout();
+    let mut printed = 0;
+
+    while limit.is_none_or(|limit| printed < limit) {
+        let Some(meta) = list_stream.next().await else {
+            break;
+        };
+
+        let meta = meta?;
+        if !filter.matches(&meta) {
+            continue;
+        }
+
+        writeln!(
+            stdout,
+            "{}\t{}\t{}",
+            meta.last_modified.to_rfc3339(),
+            meta.size,
+            meta.location
+        )?;
+        printed += 1;
+    }
+
+    Ok(())
+}
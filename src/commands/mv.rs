@@ -0,0 +1,87 @@
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::object_storage::{DELETE_CONCURRENCY, ServerSideCopy, delete_keys, rebase_key};
+use crate::output::{info, verbose};
+use crate::storage::{get_store_and_path, same_store};
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, path::Path};
+
+pub async fn mv(
+    src: String,
+    dst: String,
+    filter: ObjectFilter,
+    concurrency: usize,
+    dry_run: bool,
+) -> Result<()> {
+    let (src_store, src_path) = get_store_and_path(&src)?;
+    let (_, dst_path) = get_store_and_path(&dst)?;
+    // Copying objects onto themselves and deleting the source would delete the only copy.
+    if same_store(&src, &dst)? && src_path == dst_path {
+        return Err(AppError::Config(format!(
+            "{src} and {dst} are the same prefix, nothing to move"
+        )));
+    }
+
+    let copier = ServerSideCopy::between(&src, &dst)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "cannot move from {src} to {dst} server-side, use `sync` and delete the source instead"
+        ))
+    })?;
+
+    let pending: Vec<(ObjectMeta, Path)> = src_store
+        .list(Some(&src_path))
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+        .try_filter_map(|meta| {
+            let target = rebase_key(&meta.location, &src_path, &dst_path);
+            futures::future::ready(Ok(target.map(|target| (meta, target))))
+        })
+        .try_collect()
+        .await?;
+
+    if dry_run {
+        for (meta, target) in &pending {
+            println!("Would move {} -> {target}", meta.location);
+        }
+        println!("Dry run: {} objects would be moved.", pending.len());
+        return Ok(());
+    }
+
+    info!("Moving {} objects from {src} to {dst}", pending.len());
+
+    let moved: Vec<Path> = futures::stream::iter(pending)
+        .map(|(meta, target)| {
+            let (store, copier) = (src_store.as_ref(), &copier);
+            async move {
+                verbose!("Moving {} -> {target}", meta.location);
+                copier.copy(store, &meta, &target).await?;
+                Ok::<_, AppError>(meta.location)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    delete_keys(src_store.as_ref(), moved, DELETE_CONCURRENCY)
+        .await
+        .map_err(|e| AppError::Deletion(Box::new(e)))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mv_refuses_moving_a_prefix_onto_itself() {
+        let moved = mv(
+            "memory:///logs".to_string(),
+            "memory:///logs/".to_string(),
+            ObjectFilter::default(),
+            1,
+            false,
+        )
+        .await;
+        assert!(matches!(moved, Err(AppError::Config(_))));
+    }
+}
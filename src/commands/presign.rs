@@ -0,0 +1,29 @@
+use crate::error::Result;
+use crate::storage::get_signer_and_path;
+use http::Method;
+use std::time::Duration;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum PresignMethod {
+    Get,
+    Put,
+}
+
+impl From<PresignMethod> for Method {
+    fn from(method: PresignMethod) -> Self {
+        match method {
+            PresignMethod::Get => Self::GET,
+            PresignMethod::Put => Self::PUT,
+        }
+    }
+}
+
+/// Prints a URL granting `method` access to a single object for `expires_in`.
+pub async fn presign(src: String, method: PresignMethod, expires_in: Duration) -> Result<()> {
+    let (signer, path) = get_signer_and_path(&src)?;
+
+    let url = signer.signed_url(method.into(), &path, expires_in).await?;
+    println!("{url}");
+
+    Ok(())
+}
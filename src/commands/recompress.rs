@@ -0,0 +1,187 @@
+use crate::codec::Codec;
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::object_storage::{DELETE_CONCURRENCY, delete_keys};
+use crate::output::verbose;
+use crate::storage::get_store_and_path;
+use async_compression::Level;
+use futures::{StreamExt, TryStreamExt};
+use object_store::buffered::BufWriter;
+use object_store::{ObjectStore, ObjectStoreExt, path::Path};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+
+pub struct RecompressOptions {
+    pub codec: Codec,
+    pub level: Level,
+    pub concurrency: usize,
+    pub buffer_size: usize,
+    pub dry_run: bool,
+}
+
+/// `logs/a.json.gz` becomes `logs/a.json.zst` when converting from gzip to zstd.
+fn target_key(location: &Path, from: Codec, to: Codec) -> Path {
+    let key = location.as_ref();
+    let stem = key
+        .strip_suffix(from.extension())
+        .and_then(|stem| stem.strip_suffix('.'))
+        .unwrap_or(key);
+    Path::from(format!("{stem}.{}", to.extension()))
+}
+
+/// Copies `reader` to `writer`, returning the SHA-256 of everything that passed through.
+async fn copy_hashed<R, W>(reader: &mut R, writer: &mut W) -> Result<String>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n]).await?;
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+async fn recompress_object(
+    store: Arc<dyn ObjectStore>,
+    location: Path,
+    from: Codec,
+    options: &RecompressOptions,
+) -> Result<Path> {
+    let target = target_key(&location, from, options.codec);
+    verbose!("Recompressing {location} -> {target}");
+
+    let original = store.get(&location).await?;
+    let attributes = options.codec.attributes(&original.attributes);
+    let mut reader = from.decoder(StreamReader::new(original.into_stream()));
+
+    let sink = BufWriter::with_capacity(store.clone(), target.clone(), options.buffer_size)
+        .with_attributes(attributes);
+    let mut writer = options.codec.encoder(sink, options.level);
+    let expected = copy_hashed(&mut reader, &mut writer).await?;
+    writer.shutdown().await?;
+
+    // Decode what was uploaded before the original goes away.
+    let uploaded = store.get(&target).await?;
+    let mut reader = options
+        .codec
+        .decoder(StreamReader::new(uploaded.into_stream()));
+    let actual = copy_hashed(&mut reader, &mut tokio::io::sink()).await?;
+
+    if actual != expected {
+        store.delete(&target).await?;
+        return Err(AppError::Verification(format!(
+            "content of {target} does not match {location}, original kept"
+        )));
+    }
+
+    Ok(location)
+}
+
+pub async fn recompress(
+    src: String,
+    filter: ObjectFilter,
+    options: RecompressOptions,
+) -> Result<()> {
+    let (store, src_path) = get_store_and_path(&src)?;
+
+    let pending: Vec<(Path, Codec)> = store
+        .list(Some(&src_path))
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+        .try_filter_map(|meta| {
+            let codec = Codec::from_extension(meta.location.extension())
+                .filter(|codec| *codec != options.codec);
+            futures::future::ready(Ok(codec.map(|codec| (meta.location, codec))))
+        })
+        .try_collect()
+        .await?;
+
+    if options.dry_run {
+        for (location, from) in &pending {
+            println!(
+                "Would recompress {location} -> {}",
+                target_key(location, *from, options.codec)
+            );
+        }
+        println!("Dry run: {} objects would be recompressed.", pending.len());
+        return Ok(());
+    }
+
+    println!(
+        "Recompressing {} objects under {src} to {:?}",
+        pending.len(),
+        options.codec
+    );
+
+    let converted: Vec<Path> = futures::stream::iter(pending)
+        .map(|(location, from)| recompress_object(store.clone(), location, from, &options))
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    delete_keys(store.as_ref(), converted, DELETE_CONCURRENCY)
+        .await
+        .map_err(|e| AppError::Deletion(Box::new(e)))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::Attribute;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_target_key() {
+        assert_eq!(
+            target_key(&Path::from("logs/a.json.gz"), Codec::Gzip, Codec::Zstd),
+            Path::from("logs/a.json.zst")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recompress_object() -> Result<()> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let location = Path::from("logs/a.json.gz");
+
+        let mut encoder = Codec::Gzip.encoder(
+            BufWriter::new(store.clone(), location.clone()),
+            Level::Fastest,
+        );
+        encoder.write_all(b"{\"hello\":1}").await?;
+        encoder.shutdown().await?;
+
+        let options = RecompressOptions {
+            codec: Codec::Zstd,
+            level: Level::Fastest,
+            concurrency: 1,
+            buffer_size: 1024,
+            dry_run: false,
+        };
+        recompress_object(store.clone(), location, Codec::Gzip, &options).await?;
+
+        let converted = store.get(&Path::from("logs/a.json.zst")).await?;
+        assert_eq!(
+            converted.attributes.get(&Attribute::ContentType),
+            Some(&"application/zstd".into())
+        );
+        let mut decoded = String::new();
+        Codec::Zstd
+            .decoder(StreamReader::new(converted.into_stream()))
+            .read_to_string(&mut decoded)
+            .await?;
+        assert_eq!(decoded, "{\"hello\":1}");
+        Ok(())
+    }
+}
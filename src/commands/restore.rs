@@ -0,0 +1,654 @@
+use crate::catalog;
+use crate::codec::Codec;
+use crate::commands::KeyRewrite;
+use crate::compressor::{SUMS_NAME, Source};
+use crate::dictionary::ZstdDictionary;
+use crate::error::{AppError, Result};
+use crate::manifest::Manifest;
+use crate::output::info;
+use crate::storage::get_store_and_path;
+use futures::{StreamExt, TryStreamExt};
+use globset::GlobBuilder;
+use object_store::buffered::BufWriter;
+use object_store::{GetOptions, GetRange, ObjectStore, path::Path};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tar::Entry;
+use tokio_util::io::StreamReader;
+use url::Url;
+
+/// Bytes of an entry restored at a time.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Part of an archive holding a single entry: the compressed bytes from `start` up to `end`
+/// decode to a tar stream in which the entry starts `skip` bytes in.
+#[derive(Debug, PartialEq, Eq)]
+struct Span {
+    start: u64,
+    end: Option<u64>,
+    skip: u64,
+}
+
+impl Span {
+    /// Located through the offsets and frames in the manifest, `None` for archives written
+    /// before they were recorded.
+    fn of(manifest: &Manifest, key: &str) -> Option<Self> {
+        let position = manifest.objects.iter().position(|entry| entry.key == key)?;
+        let offset = manifest.objects[position].offset?;
+        let frame = manifest
+            .frames
+            .iter()
+            .rev()
+            .find(|frame| frame.tar_offset <= offset)?;
+        // The entry ends where the next one starts, the last one with the archive.
+        let end = manifest
+            .objects
+            .get(position + 1)
+            .and_then(|next| next.offset)
+            .and_then(|next| {
+                manifest
+                    .frames
+                    .iter()
+                    .find(|frame| frame.tar_offset >= next)
+            })
+            .map(|frame| frame.offset);
+        Some(Self {
+            start: frame.offset,
+            end,
+            skip: offset - frame.tar_offset,
+        })
+    }
+
+    fn range(&self) -> GetRange {
+        self.end.map_or(GetRange::Offset(self.start), |end| {
+            GetRange::Bounded(self.start..end)
+        })
+    }
+}
+
+/// The codec of the archive at `archive` as told by its name.
+fn codec_of(archive: &Path) -> Result<Codec> {
+    if archive.extension() == Some("gpg") {
+        return Err(AppError::Unsupported(format!(
+            "{archive} is encrypted with OpenPGP, decrypt it with gpg first"
+        )));
+    }
+    Codec::from_extension(archive.extension()).ok_or_else(|| {
+        AppError::Archive(format!("{archive} is not named like a compressed tarball"))
+    })
+}
+
+/// The tar stream of the archive at `archive`, or of the frames in `range`, decoded with the
+/// dictionary `manifest` refers to if any.
+async fn tar_stream(
+    store: &dyn ObjectStore,
+    archive: &Path,
+    codec: Codec,
+    manifest: Option<&Manifest>,
+    range: Option<GetRange>,
+) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let options = GetOptions {
+        range,
+        ..GetOptions::default()
+    };
+    let result = store.get_opts(archive, options).await?;
+    let body = StreamReader::new(result.into_stream());
+    match manifest.and_then(|manifest| manifest.zstd_dict.as_deref()) {
+        Some(url) => ZstdDictionary::load(url).await?.decoder(body),
+        None => Ok(codec.decoder(body)),
+    }
+}
+
+/// Writes the content of `entry`, the object archived as `key`, to `target`, with the
+/// attributes and tags its tar header records when `metadata` is set. Nothing is written when
+/// its SHA-256 differs from `sha256`, or from the one in the tar header without it.
+async fn write_entry<R: AsyncRead + Unpin>(
+    entry: &mut Entry<R>,
+    archive: &Path,
+    key: &str,
+    sha256: Option<String>,
+    dst_store: Arc<dyn ObjectStore>,
+    target: Path,
+    metadata: bool,
+) -> Result<()> {
+    let source = Source::read(entry).await?;
+    let expected = sha256.or_else(|| source.sha256.clone());
+    let mut sink = BufWriter::new(dst_store, target);
+    if metadata {
+        sink = sink
+            .with_tags(source.tag_set())
+            .with_attributes(source.attributes);
+    }
+    let restored = copy_digesting(entry, &mut sink).await?;
+    if let Some(expected) = expected
+        && restored != expected
+    {
+        sink.abort().await?;
+        return Err(AppError::Archive(format!(
+            "{key} from {archive} has SHA-256 {restored} instead of the {expected} it was \
+             archived with"
+        )));
+    }
+    sink.shutdown().await?;
+    Ok(())
+}
+
+/// `dst_path` joined with `key` as rewritten by the first matching of `rewrites`.
+fn target_path(dst_path: &Path, rewrites: &[KeyRewrite], key: &str) -> Path {
+    dst_path
+        .parts()
+        .chain(Path::from(KeyRewrite::apply(rewrites, key).as_ref()).parts())
+        .collect()
+}
+
+/// Extracts `key` from the archive at `archive` into `dst_path`, below the key as rewritten by
+/// the first matching of `rewrites`, with the attributes and tags its tar header records when
+/// `metadata` is set. Only the frames holding the entry are downloaded when the manifest of the
+/// archive locates it, otherwise the archive is read up to the entry.
+pub async fn restore_key(
+    store: &dyn ObjectStore,
+    archive: &Path,
+    key: &str,
+    dst_store: Arc<dyn ObjectStore>,
+    dst_path: &Path,
+    rewrites: &[KeyRewrite],
+    metadata: bool,
+) -> Result<Path> {
+    let codec = codec_of(archive)?;
+    let manifest = Manifest::find(store, archive).await?;
+    // Without a manifest the entry is looked for under the key itself.
+    let (name, span, sha256) = match &manifest {
+        Some(manifest) => {
+            let entry = manifest
+                .objects
+                .iter()
+                .find(|entry| entry.key == key)
+                .ok_or_else(|| AppError::Archive(format!("{key} is not in {archive}")))?;
+            (
+                entry.path.as_str(),
+                Span::of(manifest, key),
+                entry.sha256.clone(),
+            )
+        }
+        None => (key, None, None),
+    };
+    if span.is_none() {
+        info!("{archive} has no index for {key}, reading it up to the entry");
+    }
+
+    let range = span.as_ref().map(Span::range);
+    let mut tar = tar_stream(store, archive, codec, manifest.as_ref(), range).await?;
+    let skip = span.map_or(0, |span| span.skip);
+    tokio::io::copy(&mut (&mut tar).take(skip), &mut tokio::io::sink()).await?;
+
+    let mut entries = tokio_tar::Archive::new(tar).entries()?;
+    while let Some(mut entry) = entries.try_next().await? {
+        if entry.path()?.as_ref() != std::path::Path::new(name) {
+            continue;
+        }
+        let target = target_path(dst_path, rewrites, key);
+        write_entry(
+            &mut entry,
+            archive,
+            key,
+            sha256,
+            dst_store,
+            target.clone(),
+            metadata,
+        )
+        .await?;
+        return Ok(target);
+    }
+    Err(AppError::Archive(format!("{key} is not in {archive}")))
+}
+
+/// Extracts every object in the archive at `archive` into `dst_path` like [`restore_key`],
+/// reading the archive once from start to end, and returns how many there were.
+async fn restore_archive(
+    store: &dyn ObjectStore,
+    archive: &Path,
+    dst_store: Arc<dyn ObjectStore>,
+    dst_path: &Path,
+    rewrites: &[KeyRewrite],
+    metadata: bool,
+) -> Result<usize> {
+    let codec = codec_of(archive)?;
+    let manifest = Manifest::find(store, archive).await?;
+    // Without a manifest the entries are restored under their names.
+    let listed: HashMap<&str, (&str, Option<&String>)> = manifest
+        .iter()
+        .flat_map(|manifest| &manifest.objects)
+        .map(|entry| {
+            (
+                entry.path.as_str(),
+                (entry.key.as_str(), entry.sha256.as_ref()),
+            )
+        })
+        .collect();
+
+    let tar = tar_stream(store, archive, codec, manifest.as_ref(), None).await?;
+    let mut entries = tokio_tar::Archive::new(tar).entries()?;
+    let mut restored = 0;
+    while let Some(mut entry) = entries.try_next().await? {
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if name == SUMS_NAME {
+            continue;
+        }
+        let (key, sha256) = listed
+            .get(name.as_str())
+            .map_or((name.as_str(), None), |&(key, sha256)| {
+                (key, sha256.cloned())
+            });
+        let target = target_path(dst_path, rewrites, key);
+        write_entry(
+            &mut entry,
+            archive,
+            key,
+            sha256,
+            Arc::clone(&dst_store),
+            target,
+            metadata,
+        )
+        .await?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// Copies `reader` into `writer`, returning the hex encoded SHA-256 of what was copied.
+pub(super) async fn copy_digesting<R, W>(reader: &mut R, writer: &mut W) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut digest = Sha256::new();
+    let mut chunk = vec![0; COPY_CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(hex::encode(digest.finalize()));
+        }
+        digest.update(&chunk[..read]);
+        writer.write_all(&chunk[..read]).await?;
+    }
+}
+
+/// The newest archive in the catalog at `location` whose manifest lists `key`.
+async fn find_archive(store: &dyn ObjectStore, location: &Path, key: &str) -> Result<Path> {
+    let entries = catalog::load(store, location).await?;
+    for entry in entries.iter().rev().filter(|entry| entry.covers(key)) {
+        let archive = Path::from(entry.archive.as_str());
+        if let Some(manifest) = Manifest::find(store, &archive).await?
+            && manifest.objects.iter().any(|object| object.key == key)
+        {
+            return Ok(archive);
+        }
+    }
+    Err(AppError::Archive(format!(
+        "no archive in {location} holds {key}"
+    )))
+}
+
+/// Whether `url` holds glob wildcards, see [`expand`].
+fn is_pattern(url: &str) -> bool {
+    url.contains(WILDCARDS)
+}
+
+/// Characters starting a glob wildcard.
+const WILDCARDS: [char; 4] = ['*', '?', '[', '{'];
+
+/// The archives matching `url`, which may be a glob pattern over the names below a prefix,
+/// e.g. `s3://archive/audit/archive_2024*.tar.zst`, sorted by key, which orders them by time
+/// with the default name template. `*` does not match across `/`, and other objects than
+/// archives, such as manifests, are left out.
+async fn expand(url: &str) -> Result<(Arc<dyn ObjectStore>, Vec<Path>)> {
+    let Some(wildcard) = url.find(WILDCARDS) else {
+        let (store, path) = get_store_and_path(url)?;
+        return Ok((store, vec![path]));
+    };
+    let (prefix_url, pattern) = url.split_at(url[..wildcard].rfind('/').map_or(0, |i| i + 1));
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+    let (store, prefix) = get_store_and_path(prefix_url)?;
+    let mut archives: Vec<Path> = store
+        .list(Some(&prefix))
+        .map_ok(|meta| meta.location)
+        .try_filter(|location| {
+            let name = location.prefix_match(&prefix).map(|parts| {
+                parts
+                    .map(|part| part.as_ref().to_string())
+                    .collect::<Vec<_>>()
+            });
+            let archive = location.extension() == Some("gpg")
+                || Codec::from_extension(location.extension()).is_some();
+            futures::future::ready(
+                archive && name.is_some_and(|name| matcher.is_match(name.join("/"))),
+            )
+        })
+        .try_collect()
+        .await?;
+    if archives.is_empty() {
+        return Err(AppError::Archive(format!("no archive matches {url}")));
+    }
+    archives.sort();
+    Ok((store, archives))
+}
+
+/// The newest of `archives`, sorted oldest first, whose manifest lists `key`. Archives
+/// without a manifest are not searched.
+async fn newest_holding(
+    store: &dyn ObjectStore,
+    archives: &[Path],
+    manifests: &mut HashMap<Path, Option<Manifest>>,
+    key: &str,
+) -> Result<Path> {
+    for archive in archives.iter().rev() {
+        if !manifests.contains_key(archive) {
+            let manifest = Manifest::find(store, archive).await?;
+            manifests.insert(archive.clone(), manifest);
+        }
+        if manifests[archive]
+            .as_ref()
+            .is_some_and(|manifest| manifest.objects.iter().any(|object| object.key == key))
+        {
+            return Ok(archive.clone());
+        }
+    }
+    Err(AppError::Archive(format!(
+        "none of the {} archives given holds {key}",
+        archives.len()
+    )))
+}
+
+/// What to restore from which archive: the keys, and each from the newest archive holding it
+/// when there are several, or every object of every archive without any.
+async fn plan(
+    archives: Vec<String>,
+    catalog: Option<String>,
+    keys: Vec<String>,
+) -> Result<Vec<(Arc<dyn ObjectStore>, Path, Option<String>)>> {
+    let mut planned = Vec::new();
+    if let Some(catalog) = catalog {
+        if keys.is_empty() {
+            return Err(AppError::Config(
+                "restoring from a catalog needs the keys to restore".to_string(),
+            ));
+        }
+        let (store, location) = get_store_and_path(&catalog)?;
+        for key in keys {
+            let archive = find_archive(store.as_ref(), &location, &key).await?;
+            planned.push((Arc::clone(&store), archive, Some(key)));
+        }
+        return Ok(planned);
+    }
+    if archives.is_empty() {
+        return Err(AppError::Config(
+            "either an archive or a catalog is needed".to_string(),
+        ));
+    }
+
+    let single = archives.len() == 1 && !is_pattern(&archives[0]);
+    for url in archives {
+        let (store, paths) = expand(&url).await?;
+        if keys.is_empty() {
+            planned.extend(
+                paths
+                    .into_iter()
+                    .map(|path| (Arc::clone(&store), path, None)),
+            );
+        } else if single {
+            let archive = &paths[0];
+            planned.extend(
+                keys.iter()
+                    .map(|key| (Arc::clone(&store), archive.clone(), Some(key.clone()))),
+            );
+        } else {
+            let mut manifests = HashMap::new();
+            for key in &keys {
+                let archive = newest_holding(store.as_ref(), &paths, &mut manifests, key).await?;
+                planned.push((Arc::clone(&store), archive, Some(key.clone())));
+            }
+        }
+    }
+    Ok(planned)
+}
+
+/// Restores each of `keys` to `dst` joined with the key, rewritten by the first matching of
+/// `rewrites`, e.g. into a staging prefix. `dst` can be a `file://` URL to write them locally.
+///
+/// The content headers, user metadata and tags recorded when they were archived are set on
+/// the restored objects, except for local files, which have none.
+///
+/// The keys are taken from the newest of `archives` holding them, each of which can be a glob
+/// pattern, or from the newest archive holding them according to the catalog at `catalog`.
+/// Without keys every object of every archive is restored. Up to `concurrency` keys, or
+/// archives, are restored at a time.
+pub async fn restore(
+    archives: Vec<String>,
+    catalog: Option<String>,
+    keys: Vec<String>,
+    dst: String,
+    rewrites: Vec<KeyRewrite>,
+    concurrency: usize,
+) -> Result<()> {
+    let planned = plan(archives, catalog, keys).await?;
+    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+    let metadata = Url::parse(&dst)?.scheme() != "file";
+    let (dst, dst_path, rewrites) = (dst.as_str(), &dst_path, rewrites.as_slice());
+    futures::stream::iter(planned)
+        .map(|(store, archive, key)| {
+            let dst_store = Arc::clone(&dst_store);
+            async move {
+                if let Some(key) = key {
+                    let target = restore_key(
+                        store.as_ref(),
+                        &archive,
+                        &key,
+                        dst_store,
+                        dst_path,
+                        rewrites,
+                        metadata,
+                    )
+                    .await?;
+                    println!("Restored {key} from {archive} to {target}");
+                } else {
+                    let restored = restore_archive(
+                        store.as_ref(),
+                        &archive,
+                        dst_store,
+                        dst_path,
+                        rewrites,
+                        metadata,
+                    )
+                    .await?;
+                    println!("Restored {restored} objects from {archive} to {dst}");
+                }
+                Ok::<_, AppError>(())
+            }
+        })
+        .buffered(concurrency.max(1))
+        .try_collect::<()>()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::{CompressOptions, compress};
+    use async_compression::Level;
+    use object_store::memory::InMemory;
+    use object_store::{Attribute, Attributes, ObjectStoreExt, PutOptions};
+
+    #[tokio::test]
+    async fn test_restore_reads_only_the_frames_of_the_entry() -> Result<()> {
+        let src_store = InMemory::new();
+        let dst_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let content = |seed: u32| -> String { (0..2000).map(|i| (i * seed).to_string()).collect() };
+        let attributes = Attributes::from_iter([
+            (Attribute::ContentType, "text/plain"),
+            (Attribute::Metadata("team".into()), "payments"),
+        ]);
+        for (key, seed) in [("logs/a.log", 3), ("logs/b.log", 5), ("logs/c.log", 7)] {
+            let options = PutOptions::from(attributes.clone());
+            src_store
+                .put_opts(&Path::from(key), content(seed).into(), options)
+                .await?;
+        }
+
+        let archive = Path::from("archive.tar.zst");
+        let mut archived = Vec::new();
+        let index = compress(
+            &src_store,
+            src_store.list(None).map_err(AppError::from).boxed(),
+            Arc::clone(&dst_store),
+            archive.clone(),
+            None,
+            &CompressOptions {
+                frame_size: 1024,
+                ..CompressOptions::new(1024 * 1024, Codec::Zstd, Level::Fastest)
+            },
+            &mut archived,
+        )
+        .await?;
+        let mut manifest = Manifest::new(&archive, None, &archived, &index, &[]);
+        manifest.objects[1].sha256 = Some(hex::encode(Sha256::digest(content(5))));
+        manifest.put(dst_store.as_ref()).await?;
+
+        let span = Span::of(&manifest, "logs/b.log");
+        assert!(span.as_ref().is_some_and(|span| span.start > 0));
+        assert!(span.as_ref().is_some_and(|span| span.end.is_some()));
+
+        let target = restore_key(
+            dst_store.as_ref(),
+            &archive,
+            "logs/b.log",
+            Arc::clone(&dst_store),
+            &Path::from("restored"),
+            &[],
+            false,
+        )
+        .await?;
+        assert_eq!(target, Path::from("restored/logs/b.log"));
+        let restored = dst_store.get(&target).await?.bytes().await?;
+        assert_eq!(restored, content(5).as_bytes());
+        let restored = dst_store.get(&target).await?;
+        assert!(restored.attributes.is_empty());
+
+        // Restored under a rewritten key, e.g. into staging, with the attributes recorded.
+        let target = restore_key(
+            dst_store.as_ref(),
+            &archive,
+            "logs/b.log",
+            Arc::clone(&dst_store),
+            &Path::from("restored"),
+            &["logs/=staging/".parse()?],
+            true,
+        )
+        .await?;
+        assert_eq!(target, Path::from("restored/staging/b.log"));
+        assert_eq!(dst_store.get(&target).await?.attributes, attributes);
+
+        // Content other than what was archived is refused.
+        manifest.objects[1].sha256 = Some(hex::encode(Sha256::digest("other")));
+        manifest.put(dst_store.as_ref()).await?;
+        assert!(
+            restore_key(
+                dst_store.as_ref(),
+                &archive,
+                "logs/b.log",
+                Arc::clone(&dst_store),
+                &Path::from("refused"),
+                &[],
+                false,
+            )
+            .await
+            .is_err()
+        );
+        assert!(
+            dst_store
+                .head(&Path::from("refused/logs/b.log"))
+                .await
+                .is_err()
+        );
+
+        assert!(
+            restore_key(
+                dst_store.as_ref(),
+                &archive,
+                "logs/missing.log",
+                Arc::clone(&dst_store),
+                &Path::from("restored"),
+                &[],
+                false,
+            )
+            .await
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_archive_restores_every_object() -> Result<()> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        for (key, body) in [("logs/a.log", "first"), ("logs/b.log", "second")] {
+            store.put(&Path::from(key), body.into()).await?;
+        }
+        let archive = Path::from("archive.tar.gz");
+        let mut archived = Vec::new();
+        compress(
+            store.as_ref(),
+            store.list(None).map_err(AppError::from).boxed(),
+            Arc::clone(&store),
+            archive.clone(),
+            None,
+            &CompressOptions::new(1024 * 1024, Codec::Gzip, Level::Fastest),
+            &mut archived,
+        )
+        .await?;
+
+        let restored = restore_archive(
+            store.as_ref(),
+            &archive,
+            Arc::clone(&store),
+            &Path::from("restored"),
+            &[],
+            false,
+        )
+        .await?;
+        assert_eq!(restored, 2);
+        let restored = store.get(&Path::from("restored/logs/b.log")).await?;
+        assert_eq!(restored.bytes().await?, "second");
+        // The checksums closing the tarball are not an object.
+        let sums = Path::from("restored").join(SUMS_NAME);
+        assert!(store.head(&sums).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expand_matches_archives_by_name() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("osm-expand-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested"))?;
+        for name in [
+            "archive_20240102.tar.zst",
+            "archive_20240101.tar.zst",
+            "archive_20240101.tar.zst.manifest.json",
+            "archive_20231231.tar.zst",
+            "nested/archive_20240103.tar.zst",
+        ] {
+            std::fs::write(dir.join(name), "")?;
+        }
+        let expanded = expand(&format!("file://{}/archive_2024*", dir.display())).await;
+        std::fs::remove_dir_all(&dir)?;
+        let (_, archives) = expanded?;
+        let names: Vec<_> = archives.iter().filter_map(Path::filename).collect();
+        assert_eq!(
+            names,
+            ["archive_20240101.tar.zst", "archive_20240102.tar.zst"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+//! End-to-end check of a store: synthetic objects are written below a scratch prefix,
+//! archived, verified, restored and deleted again, reporting each step as it passes or fails.
+
+use super::restore::restore_key;
+use super::{ArchiveJob, DeleteVerification};
+use crate::catalog;
+use crate::codec::Codec;
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::manifest::Manifest;
+use crate::object_storage::{DELETE_CONCURRENCY, delete_keys, sha256_object};
+use crate::output::info;
+use crate::storage::get_store_and_path;
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use std::sync::Arc;
+
+/// Part size of the archive upload, the smallest S3 accepts, so the large object makes the
+/// archive a multipart upload of two parts.
+const PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Names and contents of the synthetic objects: some text, an empty object and incompressible
+/// bytes larger than [`PART_SIZE`].
+fn synthetic_objects() -> Vec<(&'static str, Vec<u8>)> {
+    // xorshift, so the large object does not compress below a part.
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let noise = std::iter::repeat_with(|| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state.to_le_bytes()
+    })
+    .flatten()
+    .take(PART_SIZE + PART_SIZE / 5)
+    .collect();
+    vec![
+        ("logs/app.log", b"self-test\n".repeat(100)),
+        ("logs/empty", Vec::new()),
+        ("data/noise.bin", noise),
+    ]
+}
+
+/// Runs `step`, printing whether it passed.
+async fn step<T>(name: &str, step: impl Future<Output = Result<T>>) -> Result<T> {
+    match step.await {
+        Ok(value) => {
+            println!("PASS {name}");
+            Ok(value)
+        }
+        Err(e) => {
+            println!("FAIL {name}: {e}");
+            Err(AppError::Verification(format!(
+                "self-test step '{name}' failed: {e}"
+            )))
+        }
+    }
+}
+
+async fn write(
+    store: &dyn ObjectStore,
+    src_path: &Path,
+    objects: &[(&str, Vec<u8>)],
+) -> Result<()> {
+    for (name, content) in objects {
+        store
+            .put(&join(src_path, name), content.clone().into())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Archives everything below `{root}/src/` into `{root}/archive/` like `archive` would,
+/// deleting the sources once they are verified unchanged.
+async fn archive(root: &str) -> Result<()> {
+    let job = ArchiveJob {
+        filter: ObjectFilter {
+            // Everything below the scratch prefix is synthetic, so a clock running behind the
+            // store must not leave any of it out.
+            cutoff: Some(Utc::now() + Duration::hours(1)),
+            ..ObjectFilter::default()
+        },
+        buffer_size: PART_SIZE,
+        codec: Codec::Zstd,
+        delete_verification: DeleteVerification::Etag,
+        ..ArchiveJob::new(format!("{root}/src/"), format!("{root}/archive/"))
+    };
+    Box::pin(job.run()).await
+}
+
+/// Checks that the sources are gone and the catalog names a single archive with the
+/// checksum it has, whose manifest lists all objects. Returns the archive.
+async fn verify(
+    store: &dyn ObjectStore,
+    root_path: &Path,
+    objects: &[(&str, Vec<u8>)],
+) -> Result<Path> {
+    let src_path = root_path.clone().join("src");
+    let left: Vec<ObjectMeta> = store.list(Some(&src_path)).try_collect().await?;
+    if !left.is_empty() {
+        return Err(AppError::Verification(format!(
+            "{} sources left after archiving",
+            left.len()
+        )));
+    }
+
+    let archive_path = root_path.clone().join("archive");
+    let entries = catalog::load(store, &catalog::location(&archive_path)).await?;
+    let [entry] = entries.as_slice() else {
+        return Err(AppError::Verification(format!(
+            "expected one archive in the catalog, found {}",
+            entries.len()
+        )));
+    };
+    let archive = Path::from(entry.archive.as_str());
+    let sha256 = sha256_object(store, &archive).await?;
+    if sha256 != entry.sha256 {
+        return Err(AppError::Verification(format!(
+            "{archive} has SHA-256 {sha256}, the catalog says {}",
+            entry.sha256
+        )));
+    }
+
+    let manifest = Manifest::find(store, &archive)
+        .await?
+        .ok_or_else(|| AppError::Verification(format!("{archive} has no manifest")))?;
+    let mut archived: Vec<String> = manifest.objects.into_iter().map(|e| e.key).collect();
+    let mut expected: Vec<String> = objects
+        .iter()
+        .map(|(name, _)| join(&src_path, name).to_string())
+        .collect();
+    archived.sort_unstable();
+    expected.sort_unstable();
+    if archived != expected {
+        return Err(AppError::Verification(format!(
+            "the manifest lists {archived:?} instead of {expected:?}"
+        )));
+    }
+    Ok(archive)
+}
+
+/// Restores every object from `archive` below `{root}/restored/` and compares it with what
+/// was written.
+async fn restore(
+    store: &Arc<dyn ObjectStore>,
+    root_path: &Path,
+    archive: &Path,
+    objects: &[(&str, Vec<u8>)],
+) -> Result<()> {
+    let src_path = root_path.clone().join("src");
+    let restored_path = root_path.clone().join("restored");
+    for (name, content) in objects {
+        let key = join(&src_path, name);
+        let target = restore_key(
+            store.as_ref(),
+            archive,
+            key.as_ref(),
+            Arc::clone(store),
+            &restored_path,
+            &[],
+            false,
+        )
+        .await?;
+        let restored = store.get(&target).await?.bytes().await?;
+        if restored != content.as_slice() {
+            return Err(AppError::Verification(format!(
+                "{key} restored as {} bytes differing from the {} written",
+                restored.len(),
+                content.len()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Deletes everything below `root_path`, checking nothing is left.
+async fn clean_up(store: &dyn ObjectStore, root_path: &Path) -> Result<()> {
+    let keys: Vec<Path> = store
+        .list(Some(root_path))
+        .map_ok(|meta| meta.location)
+        .try_collect()
+        .await?;
+    delete_keys(store, keys, DELETE_CONCURRENCY).await?;
+    let left: Vec<ObjectMeta> = store.list(Some(root_path)).try_collect().await?;
+    if !left.is_empty() {
+        return Err(AppError::Verification(format!(
+            "{} objects left after deleting",
+            left.len()
+        )));
+    }
+    Ok(())
+}
+
+fn join(path: &Path, name: &str) -> Path {
+    name.split('/').fold(path.clone(), Path::join)
+}
+
+/// Writes synthetic objects below a fresh prefix under `prefix`, archives, verifies, restores
+/// and deletes them.
+///
+/// Prints `PASS` or `FAIL` per step. Steps after a failing one are skipped, except deleting,
+/// which always cleans up.
+pub async fn self_test(prefix: String) -> Result<()> {
+    let root = format!(
+        "{}/self-test-{}-{}",
+        prefix.trim_end_matches('/'),
+        Utc::now().format("%Y%m%dT%H%M%S"),
+        std::process::id()
+    );
+    let (store, root_path) = get_store_and_path(&root)?;
+    let objects = synthetic_objects();
+    info!("Self-test below {root}/");
+
+    let result = async {
+        let src_path = root_path.clone().join("src");
+        step("write", write(store.as_ref(), &src_path, &objects)).await?;
+        step("archive", archive(&root)).await?;
+        let archive = step("verify", verify(store.as_ref(), &root_path, &objects)).await?;
+        step("restore", restore(&store, &root_path, &archive, &objects)).await
+    }
+    .await;
+    let cleaned = step("delete", clean_up(store.as_ref(), &root_path)).await;
+    result.and(cleaned)?;
+
+    println!("Self-test passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_self_test_passes_on_local_files() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("osm-self-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root)?;
+        let url = Url::from_directory_path(&root)
+            .map_err(|()| AppError::Unsupported(root.display().to_string()))?;
+
+        let result = self_test(url.to_string()).await;
+        let (store, path) = get_store_and_path(url.as_str())?;
+        let left: Vec<ObjectMeta> = store.list(Some(&path)).try_collect().await?;
+        // Deleting the last objects may remove the emptied directories as well.
+        if root.exists() {
+            std::fs::remove_dir_all(&root)?;
+        }
+        result?;
+        assert!(left.is_empty(), "{left:?}");
+        Ok(())
+    }
+}
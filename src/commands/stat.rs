@@ -0,0 +1,116 @@
+use crate::commands::OutputFormat;
+use crate::error::Result;
+use crate::s3::S3Client;
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use object_store::{Attribute, GetOptions};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const CHECKSUM_HEADER_PREFIX: &str = "x-amz-checksum-";
+
+#[derive(Debug, Default, Serialize)]
+struct ObjectStat {
+    key: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    e_tag: Option<String>,
+    version: Option<String>,
+    storage_class: Option<String>,
+    content_type: Option<String>,
+    metadata: BTreeMap<String, String>,
+    tags: BTreeMap<String, String>,
+    checksums: BTreeMap<String, String>,
+}
+
+pub async fn stat(src: String, format: OutputFormat) -> Result<()> {
+    let (store, path) = get_store_and_path(&src)?;
+
+    let result = store
+        .get_opts(&path, GetOptions::new().with_head(true))
+        .await?;
+
+    let mut stat = ObjectStat {
+        key: result.meta.location.to_string(),
+        size: result.meta.size,
+        last_modified: result.meta.last_modified,
+        e_tag: result.meta.e_tag,
+        version: result.meta.version,
+        ..ObjectStat::default()
+    };
+
+    for (attribute, value) in &result.attributes {
+        match attribute {
+            Attribute::ContentType => stat.content_type = Some(value.to_string()),
+            Attribute::StorageClass => stat.storage_class = Some(value.to_string()),
+            Attribute::Metadata(key) => {
+                stat.metadata.insert(key.to_string(), value.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    // Storage class, tags and checksums are only exposed by the S3 API itself.
+    if let Some(s3) = S3Client::from_url(&src)? {
+        let headers = s3.head_object(path.as_ref()).await?;
+
+        stat.storage_class = Some(
+            headers
+                .get("x-amz-storage-class")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("STANDARD")
+                .to_string(),
+        );
+
+        for (name, value) in &headers {
+            if let (Some(algorithm), Ok(value)) = (
+                name.as_str().strip_prefix(CHECKSUM_HEADER_PREFIX),
+                value.to_str(),
+            ) && algorithm != "type"
+            {
+                stat.checksums
+                    .insert(algorithm.to_string(), value.to_string());
+            }
+        }
+
+        stat.tags = s3
+            .get_object_tagging(path.as_ref())
+            .await?
+            .into_iter()
+            .collect();
+    }
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&stat)?),
+        OutputFormat::Human => print_human(&stat),
+    }
+
+    Ok(())
+}
+
+fn print_human(stat: &ObjectStat) {
+    let or_dash = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+
+    println!("Key:           {}", stat.key);
+    println!("Size:          {}", stat.size);
+    println!("Last modified: {}", stat.last_modified.to_rfc3339());
+    println!("ETag:          {}", or_dash(&stat.e_tag));
+    println!("Version:       {}", or_dash(&stat.version));
+    println!("Storage class: {}", or_dash(&stat.storage_class));
+    println!("Content type:  {}", or_dash(&stat.content_type));
+
+    for (title, map) in [
+        ("Metadata", &stat.metadata),
+        ("Tags", &stat.tags),
+        ("Checksums", &stat.checksums),
+    ] {
+        if map.is_empty() {
+            println!("{title}: -");
+            continue;
+        }
+        println!("{title}:");
+        for (key, value) in map {
+            println!("  {key}: {value}");
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::object_storage::{DELETE_CONCURRENCY, copy_object, delete_keys, rebase_key};
+use crate::output::{info, verbose};
+use crate::storage::{get_store_and_path, same_store};
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+use object_store::path::Path;
+use std::collections::{HashMap, HashSet};
+
+pub struct MirrorOptions {
+    /// Delete destination objects that no longer exist at the source.
+    pub delete: bool,
+    /// Refuse to run when more than this many destination objects would be deleted.
+    pub max_delete: Option<usize>,
+}
+
+pub async fn sync(
+    src: String,
+    dst: String,
+    filter: ObjectFilter,
+    concurrency: usize,
+    dry_run: bool,
+    buffer_size: usize,
+    mirror: MirrorOptions,
+) -> Result<()> {
+    let (src_store, src_path) = get_store_and_path(&src)?;
+    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+    let server_side = same_store(&src, &dst)?;
+
+    info!("Syncing from {src} to {dst}");
+
+    let existing: HashMap<Path, ObjectMeta> = dst_store
+        .list(Some(&dst_path))
+        .map_ok(|meta| (meta.location.clone(), meta))
+        .try_collect()
+        .await?;
+
+    let mut pending = Vec::new();
+    // Every source key, filtered or not, protects its destination counterpart from deletion.
+    let mut present = HashSet::new();
+    let mut list_stream = src_store.list(Some(&src_path));
+    while let Some(meta) = list_stream.next().await {
+        let meta = meta?;
+        let Some(target) = rebase_key(&meta.location, &src_path, &dst_path) else {
+            continue;
+        };
+        present.insert(target.clone());
+
+        if filter.matches(&meta) && needs_copy(&meta, existing.get(&target)) {
+            pending.push((meta, target));
+        }
+    }
+
+    let extraneous: Vec<Path> = if mirror.delete {
+        existing
+            .into_keys()
+            .filter(|key| !present.contains(key))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    if let Some(max_delete) = mirror.max_delete
+        && extraneous.len() > max_delete
+    {
+        return Err(AppError::SafetyLimit(format!(
+            "{} destination objects would be deleted, but --max-delete is {max_delete}",
+            extraneous.len()
+        )));
+    }
+
+    let total_bytes: u64 = pending.iter().map(|(meta, _)| meta.size).sum();
+
+    if dry_run {
+        for (meta, target) in &pending {
+            println!("Would copy {} -> {target}", meta.location);
+        }
+        for key in &extraneous {
+            println!("Would delete {key}");
+        }
+        println!(
+            "Dry run: {} objects ({total_bytes} bytes) would be copied, {} deleted.",
+            pending.len(),
+            extraneous.len()
+        );
+        return Ok(());
+    }
+
+    let copied = futures::stream::iter(pending)
+        .map(|(meta, target)| {
+            let (src_store, dst_store) = (src_store.as_ref(), dst_store.clone());
+            async move {
+                verbose!("Copying {} -> {target}", meta.location);
+                copy_object(
+                    src_store,
+                    dst_store,
+                    &meta.location,
+                    &target,
+                    server_side,
+                    buffer_size,
+                )
+                .await
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_fold(0usize, |count, ()| async move { Ok(count + 1) })
+        .await?;
+
+    println!("Successfully copied {copied} objects ({total_bytes} bytes).");
+
+    delete_keys(dst_store.as_ref(), extraneous, DELETE_CONCURRENCY)
+        .await
+        .map_err(|e| AppError::Deletion(Box::new(e)))?;
+
+    Ok(())
+}
+
+/// An object is copied when it is missing or differs in size, unless matching entity tags prove
+/// the content is identical; otherwise a newer source modification time wins.
+fn needs_copy(src: &ObjectMeta, dst: Option<&ObjectMeta>) -> bool {
+    let Some(dst) = dst else {
+        return true;
+    };
+    if src.size != dst.size {
+        return true;
+    }
+    if src.e_tag.is_some() && src.e_tag == dst.e_tag {
+        return false;
+    }
+    src.last_modified > dst.last_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    fn meta(size: u64, e_tag: Option<&str>, age_secs: i64) -> ObjectMeta {
+        ObjectMeta {
+            location: Path::from("key"),
+            last_modified: Utc::now() - Duration::seconds(age_secs),
+            size,
+            e_tag: e_tag.map(str::to_string),
+            version: None,
+        }
+    }
+
+    #[test]
+    fn test_needs_copy() {
+        assert!(needs_copy(&meta(1, None, 0), None));
+        assert!(needs_copy(&meta(1, None, 100), Some(&meta(2, None, 0))));
+        assert!(!needs_copy(
+            &meta(1, Some("a"), 0),
+            Some(&meta(1, Some("a"), 100))
+        ));
+        assert!(needs_copy(
+            &meta(1, Some("a"), 0),
+            Some(&meta(1, Some("b"), 100))
+        ));
+        assert!(!needs_copy(&meta(1, None, 100), Some(&meta(1, None, 0))));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::output::{info, verbose};
+use crate::s3::{RestoreStatus, S3Client};
+use crate::storage::get_store_and_path;
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+use std::time::Duration;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum RestoreTier {
+    Expedited,
+    Standard,
+    Bulk,
+}
+
+impl RestoreTier {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Expedited => "Expedited",
+            Self::Standard => "Standard",
+            Self::Bulk => "Bulk",
+        }
+    }
+}
+
+pub struct ThawOptions {
+    pub days: u32,
+    pub tier: RestoreTier,
+    pub concurrency: usize,
+    pub dry_run: bool,
+    /// Block until every object is readable, checking every `poll_interval`.
+    pub wait: bool,
+    pub poll_interval: Duration,
+}
+
+async fn restore_status(client: &S3Client, key: &str) -> Result<RestoreStatus> {
+    Ok(RestoreStatus::from_headers(&client.head_object(key).await?))
+}
+
+/// Requests a restore unless one was already requested; returns whether the object is
+/// still waiting to become readable.
+async fn thaw_object(
+    client: &S3Client,
+    key: String,
+    options: &ThawOptions,
+) -> Result<Option<String>> {
+    match restore_status(client, &key).await? {
+        RestoreStatus::Restored => Ok(None),
+        RestoreStatus::InProgress => Ok(Some(key)),
+        RestoreStatus::NotRequested => {
+            verbose!("Restoring {key}");
+            client
+                .restore_object(&key, options.days, options.tier.as_str())
+                .await?;
+            Ok(Some(key))
+        }
+    }
+}
+
+async fn wait_for_restores(
+    client: &S3Client,
+    mut pending: Vec<String>,
+    options: &ThawOptions,
+) -> Result<()> {
+    let total = pending.len();
+
+    while !pending.is_empty() {
+        info!(
+            "{} of {total} objects restored, checking again in {}s",
+            total - pending.len(),
+            options.poll_interval.as_secs()
+        );
+        tokio::time::sleep(options.poll_interval).await;
+
+        pending = futures::stream::iter(pending)
+            .map(|key| async move {
+                let status = restore_status(client, &key).await?;
+                Ok::<_, AppError>((status == RestoreStatus::InProgress).then_some(key))
+            })
+            .buffer_unordered(options.concurrency.max(1))
+            .try_filter_map(|key| futures::future::ready(Ok(key)))
+            .try_collect()
+            .await?;
+    }
+
+    println!("All {total} objects restored");
+    Ok(())
+}
+
+pub async fn thaw(src: String, filter: ObjectFilter, options: ThawOptions) -> Result<()> {
+    let (_, src_path) = get_store_and_path(&src)?;
+    let client = S3Client::from_url(&src)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "restoring archived objects requires an s3:// source, got {src}"
+        ))
+    })?;
+
+    let archived: Vec<String> = client
+        .list_objects(src_path.to_string())
+        .try_filter(|object| {
+            futures::future::ready(
+                object.is_archived() && filter.matches(&ObjectMeta::from(object)),
+            )
+        })
+        .map_ok(|object| object.key)
+        .try_collect()
+        .await?;
+
+    if options.dry_run {
+        for key in &archived {
+            println!("Would restore {key}");
+        }
+        println!("Dry run: {} archived objects match.", archived.len());
+        return Ok(());
+    }
+
+    println!(
+        "Requesting restore of {} archived objects under {src}",
+        archived.len()
+    );
+
+    let pending: Vec<String> = futures::stream::iter(archived)
+        .map(|key| thaw_object(&client, key, &options))
+        .buffer_unordered(options.concurrency.max(1))
+        .try_filter_map(|key| futures::future::ready(Ok(key)))
+        .try_collect()
+        .await?;
+
+    if options.wait {
+        wait_for_restores(&client, pending, &options).await?;
+    } else {
+        println!("{} objects are being restored", pending.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use crate::dictionary::{measure, train};
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::output::{info, summary};
+use crate::storage::get_store_and_path;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+
+pub struct TrainOptions {
+    /// Objects sampled at most.
+    pub max_samples: usize,
+    /// Bytes of sampled objects at most, all held in memory while training.
+    pub sample_bytes: u64,
+    /// Size of the dictionary at most.
+    pub dict_size: usize,
+    /// Objects downloaded in parallel.
+    pub concurrency: usize,
+}
+
+/// The first objects below `prefix` selected by `filter`, up to the limits of `options`.
+/// Empty objects and those beyond the bytes left are passed over.
+async fn sample(
+    store: &dyn ObjectStore,
+    prefix: &Path,
+    filter: &ObjectFilter,
+    options: &TrainOptions,
+) -> Result<Vec<Bytes>> {
+    let mut objects: Vec<ObjectMeta> = Vec::new();
+    let mut bytes = 0;
+    let mut matching = store
+        .list(Some(prefix))
+        .map_err(AppError::from)
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)));
+    while objects.len() < options.max_samples && bytes < options.sample_bytes {
+        let Some(meta) = matching.try_next().await? else {
+            break;
+        };
+        if meta.size > 0 && bytes + meta.size <= options.sample_bytes {
+            bytes += meta.size;
+            objects.push(meta);
+        }
+    }
+
+    futures::stream::iter(objects)
+        .map(|meta| async move { Ok(store.get(&meta.location).await?.bytes().await?) })
+        .buffered(options.concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+/// Trains a zstd dictionary on a sample of the objects under `src` selected by `filter`, and
+/// writes it to `dst` for `archive --zstd-dict`.
+pub async fn train_zstd_dict(
+    src: String,
+    dst: String,
+    filter: ObjectFilter,
+    options: TrainOptions,
+) -> Result<()> {
+    let (store, prefix) = get_store_and_path(&src)?;
+    let (dst_store, dst_path) = get_store_and_path(&dst)?;
+    let samples = sample(store.as_ref(), &prefix, &filter, &options).await?;
+    let bytes: usize = samples.iter().map(Bytes::len).sum();
+    info!(
+        "Training on {} objects ({bytes} bytes) under {src}",
+        samples.len()
+    );
+
+    // Training is CPU bound and takes a while on large samples.
+    let dict_size = options.dict_size;
+    let (dictionary, (with, without)) = tokio::task::spawn_blocking(move || {
+        let dictionary = train(&samples, dict_size)?;
+        let sizes = measure(&samples, &dictionary)?;
+        Ok::<_, AppError>((dictionary, sizes))
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+
+    let size = dictionary.len();
+    dst_store.put(&dst_path, dictionary.into()).await?;
+    summary!("Wrote a zstd dictionary of {size} bytes to {dst}");
+    summary!(
+        "The sampled objects compress one by one to {with} bytes with it, {without} bytes \
+         without"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_sample_within_limits() -> Result<()> {
+        let store = InMemory::new();
+        store.put(&Path::from("logs/empty"), "".into()).await?;
+        for (key, body) in [("logs/a", "aaaa"), ("logs/b", "bbbbbbbb"), ("logs/c", "cc")] {
+            store.put(&Path::from(key), body.into()).await?;
+        }
+        let options = TrainOptions {
+            max_samples: 10,
+            sample_bytes: 7,
+            dict_size: 1024,
+            concurrency: 2,
+        };
+
+        let samples = sample(
+            &store,
+            &Path::from("logs"),
+            &ObjectFilter::default(),
+            &options,
+        )
+        .await?;
+        assert_eq!(samples, ["aaaa", "cc"]);
+
+        let options = TrainOptions {
+            max_samples: 1,
+            ..options
+        };
+        let samples = sample(
+            &store,
+            &Path::from("logs"),
+            &ObjectFilter::default(),
+            &options,
+        )
+        .await?;
+        assert_eq!(samples, ["aaaa"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::batch::BatchManifest;
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::output::{info, summary, verbose, warning};
+use crate::s3::{CopyJob, S3Client, S3Object};
+use crate::storage::get_store_and_path;
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+
+/// Where the selected objects go instead of being copied: an S3 Batch Operations manifest,
+/// and the role to create a job transitioning them with.
+#[derive(Debug, Default)]
+pub struct BatchOptions {
+    pub manifest: Option<String>,
+    pub role_arn: Option<String>,
+}
+
+pub async fn transition(
+    src: String,
+    storage_class: String,
+    filter: ObjectFilter,
+    concurrency: usize,
+    dry_run: bool,
+    batch: BatchOptions,
+) -> Result<()> {
+    let (_, src_path) = get_store_and_path(&src)?;
+    let client = S3Client::from_url(&src)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "changing storage classes requires an s3:// source, got {src}"
+        ))
+    })?;
+    let storage_class = storage_class.to_uppercase();
+    let manifest = match &batch.manifest {
+        Some(url) => Some(BatchManifest::new(&src, url)?),
+        None => None,
+    };
+
+    let selected: Vec<S3Object> = client
+        .list_objects(src_path.to_string())
+        .try_filter(|object| {
+            futures::future::ready(
+                object.storage_class() != storage_class
+                    && filter.matches(&ObjectMeta::from(object)),
+            )
+        })
+        .try_collect()
+        .await?;
+
+    // Archived objects cannot be copied until restored.
+    let (archived, pending): (Vec<S3Object>, Vec<S3Object>) =
+        selected.into_iter().partition(S3Object::is_archived);
+    if !archived.is_empty() {
+        warning!(
+            "Skipping {} objects in GLACIER or DEEP_ARCHIVE, restore them with `thaw` first.",
+            archived.len()
+        );
+    }
+
+    if dry_run {
+        for object in &pending {
+            println!(
+                "Would transition {} ({} -> {storage_class})",
+                object.key,
+                object.storage_class()
+            );
+        }
+        println!("Dry run: {} objects would be transitioned.", pending.len());
+        return Ok(());
+    }
+
+    if let Some(manifest) = manifest {
+        let keys: Vec<&str> = pending.iter().map(|object| object.key.as_str()).collect();
+        let manifest = manifest.put(&keys).await?;
+        let Some(role_arn) = &batch.role_arn else {
+            return Ok(());
+        };
+        let job = CopyJob {
+            role_arn,
+            manifest_arn: manifest.object_arn,
+            manifest_e_tag: &manifest.e_tag,
+            storage_class: &storage_class,
+            description: format!("Transition {src} to {storage_class}"),
+        };
+        let job_id = client.create_copy_job(&job).await?;
+        summary!("Created S3 Batch Operations job {job_id}");
+        return Ok(());
+    }
+
+    info!(
+        "Transitioning {} objects under {src} to {storage_class}",
+        pending.len()
+    );
+
+    futures::stream::iter(pending)
+        .map(|object| {
+            let (client, storage_class) = (&client, &storage_class);
+            async move {
+                verbose!("Transitioning {}", object.key);
+                client
+                    .copy_object(
+                        client,
+                        &object.key,
+                        &object.key,
+                        object.size,
+                        Some(storage_class),
+                    )
+                    .await
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect::<()>()
+        .await?;
+
+    Ok(())
+}
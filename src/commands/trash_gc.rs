@@ -0,0 +1,40 @@
+use crate::error::{AppError, Result};
+use crate::object_storage::{DELETE_CONCURRENCY, delete_keys};
+use crate::storage::get_store_and_path;
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use object_store::path::Path;
+
+/// Permanently deletes trash entries that have been in the trash for longer than
+/// `retention_days`. Server-side copies reset the modification time, so it tells when an
+/// object was trashed.
+pub async fn trash_gc(trash: String, retention_days: u32, dry_run: bool) -> Result<()> {
+    let (store, trash_path) = get_store_and_path(&trash)?;
+    let cutoff = Utc::now() - Duration::days(i64::from(retention_days));
+
+    let expired: Vec<Path> = store
+        .list(Some(&trash_path))
+        .try_filter(|meta| futures::future::ready(meta.last_modified < cutoff))
+        .map_ok(|meta| meta.location)
+        .try_collect()
+        .await?;
+
+    if dry_run {
+        for location in &expired {
+            println!("Would delete {location}");
+        }
+        println!("Dry run: {} trash entries would be deleted.", expired.len());
+        return Ok(());
+    }
+
+    println!(
+        "Deleting {} trash entries older than {retention_days} days from {trash}",
+        expired.len()
+    );
+
+    delete_keys(store.as_ref(), expired, DELETE_CONCURRENCY)
+        .await
+        .map_err(|e| AppError::Deletion(Box::new(e)))?;
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::object_storage::{DELETE_CONCURRENCY, ServerSideCopy, delete_keys, rebase_key};
+use crate::output::{info, verbose};
+use crate::storage::get_store_and_path;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, path::Path};
+
+/// Puts objects moved into `trash` by `archive --trash-prefix` back below `dst`, which is
+/// normally the root of the bucket they came from. Filters apply to the original keys.
+pub async fn untrash(
+    trash: String,
+    dst: String,
+    filter: ObjectFilter,
+    concurrency: usize,
+    dry_run: bool,
+) -> Result<()> {
+    let (trash_store, trash_path) = get_store_and_path(&trash)?;
+    let (_, dst_path) = get_store_and_path(&dst)?;
+
+    let copier = ServerSideCopy::between(&trash, &dst)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "cannot copy objects from {trash} to {dst} server-side"
+        ))
+    })?;
+
+    let pending: Vec<(ObjectMeta, Path)> = trash_store
+        .list(Some(&trash_path))
+        .try_filter_map(|meta| {
+            let original = rebase_key(&meta.location, &trash_path, &Path::default());
+            let restore = original
+                .filter(|original| {
+                    filter.matches(&ObjectMeta {
+                        location: original.clone(),
+                        ..meta.clone()
+                    })
+                })
+                .and_then(|original| rebase_key(&original, &Path::default(), &dst_path));
+            futures::future::ready(Ok(restore.map(|target| (meta, target))))
+        })
+        .try_collect()
+        .await?;
+
+    if dry_run {
+        for (meta, target) in &pending {
+            println!("Would restore {} -> {target}", meta.location);
+        }
+        println!("Dry run: {} objects would be restored.", pending.len());
+        return Ok(());
+    }
+
+    info!("Restoring {} objects from {trash} to {dst}", pending.len());
+
+    let restored: Vec<Path> = futures::stream::iter(pending)
+        .map(|(meta, target)| {
+            let (store, copier) = (trash_store.as_ref(), &copier);
+            async move {
+                verbose!("Restoring {} -> {target}", meta.location);
+                copier.copy(store, &meta, &target).await?;
+                Ok::<_, AppError>(meta.location)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    delete_keys(trash_store.as_ref(), restored, DELETE_CONCURRENCY)
+        .await
+        .map_err(|e| AppError::Deletion(Box::new(e)))?;
+
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use super::restore::copy_digesting;
+use crate::codec::Codec;
+use crate::commands::OutputFormat;
+use crate::compressor::{SUMS_NAME, Source};
+use crate::dictionary::ZstdDictionary;
+use crate::error::{AppError, Result};
+use crate::manifest::Manifest;
+use crate::output::warning;
+use crate::storage::get_store_and_path;
+use futures::TryStreamExt;
+use object_store::{ObjectStore, ObjectStoreExt, path::Path};
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+/// What reading an archive through found.
+#[derive(Debug, Default, Serialize)]
+struct Verification {
+    archive: String,
+    /// Entries in the tar stream, `SHA256SUMS` left out.
+    entries: usize,
+    /// Total size of their content.
+    bytes: u64,
+    /// Entries whose content matched a SHA-256 recorded for it.
+    checked: usize,
+    problems: Vec<String>,
+}
+
+/// Undoes `\\` and `\n` in a name escaped by `sha256sum`.
+fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escaped = match (c, chars.peek()) {
+            ('\\', Some('n')) => '\n',
+            ('\\', Some('\\')) => '\\',
+            _ => {
+                unescaped.push(c);
+                continue;
+            }
+        };
+        unescaped.push(escaped);
+        chars.next();
+    }
+    unescaped
+}
+
+/// Parses the lines of a `SHA256SUMS` file into names and hex encoded digests, undoing the
+/// escaping of names `sha256sum` marks with a leading backslash.
+fn parse_sums(sums: &str) -> HashMap<String, String> {
+    sums.lines()
+        .filter_map(|line| {
+            let (escaped, line) = line
+                .strip_prefix('\\')
+                .map_or((false, line), |line| (true, line));
+            let (sha256, name) = line.split_once("  ")?;
+            let name = if escaped {
+                unescape(name)
+            } else {
+                name.to_string()
+            };
+            Some((name, sha256.to_string()))
+        })
+        .collect()
+}
+
+/// Walks the tar stream in `tar`, reading every entry through and checking it against the
+/// SHA-256 recorded in its tar header, in `manifest` and in the trailing `SHA256SUMS`. Tar
+/// header checksums are checked while reading.
+async fn walk<R>(tar: R, manifest: Option<&Manifest>, verification: &mut Verification) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut recorded: HashMap<String, String> = manifest
+        .map(|manifest| {
+            manifest
+                .objects
+                .iter()
+                .filter_map(|entry| Some((entry.path.clone(), entry.sha256.clone()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut digests = Vec::new();
+    let mut sums = None;
+
+    let mut entries = tokio_tar::Archive::new(tar).entries()?;
+    while let Some(mut entry) = entries.try_next().await? {
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if name == SUMS_NAME {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).await?;
+            sums = Some(parse_sums(&content));
+            continue;
+        }
+        if let Some(sha256) = Source::read(&mut entry).await?.sha256 {
+            recorded.insert(name.clone(), sha256);
+        }
+        let sha256 = copy_digesting(&mut entry, &mut tokio::io::sink()).await?;
+        verification.entries += 1;
+        verification.bytes += entry.header().size()?;
+        digests.push((name, sha256));
+    }
+
+    let expected = manifest.map_or(0, |manifest| manifest.objects.len());
+    if manifest.is_some() && expected != verification.entries {
+        verification.problems.push(format!(
+            "the manifest lists {expected} objects, the archive holds {}",
+            verification.entries
+        ));
+    }
+    for (name, sha256) in &digests {
+        let recorded = [
+            recorded.get(name),
+            sums.as_ref().and_then(|sums| sums.get(name)),
+        ];
+        if recorded
+            .iter()
+            .flatten()
+            .any(|recorded| *recorded != sha256)
+        {
+            verification
+                .problems
+                .push(format!("{name} has SHA-256 {sha256}, not the one recorded"));
+        } else if recorded.iter().any(Option::is_some) {
+            verification.checked += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the archive at `path` through without writing anything, see [`walk`].
+async fn verify(store: &dyn ObjectStore, path: &Path) -> Result<Verification> {
+    if path.extension() == Some("gpg") {
+        return Err(AppError::Unsupported(format!(
+            "{path} is encrypted with OpenPGP, decrypt it with gpg first"
+        )));
+    }
+    let codec = Codec::from_extension(path.extension()).ok_or_else(|| {
+        AppError::Archive(format!("{path} is not named like a compressed tarball"))
+    })?;
+    let manifest = Manifest::find(store, path).await?;
+
+    let body = StreamReader::new(store.get(path).await?.into_stream());
+    let tar = match manifest
+        .as_ref()
+        .and_then(|manifest| manifest.zstd_dict.as_deref())
+    {
+        Some(url) => ZstdDictionary::load(url).await?.decoder(body)?,
+        None => codec.decoder(body),
+    };
+    let mut verification = Verification {
+        archive: path.to_string(),
+        ..Verification::default()
+    };
+    walk(tar, manifest.as_ref(), &mut verification).await?;
+    Ok(verification)
+}
+
+/// Decompresses the archive at `archive` and reads every entry, checking the tar headers and
+/// the recorded checksums, and reports the entries found. Fails when anything is off.
+pub async fn verify_archive(archive: String, format: OutputFormat) -> Result<()> {
+    let (store, path) = get_store_and_path(&archive)?;
+    let verification = verify(store.as_ref(), &path).await?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&verification)?),
+        OutputFormat::Human => {
+            for problem in &verification.problems {
+                warning!("{problem}");
+            }
+            println!(
+                "{}: {} entries, {} bytes, {} checked against recorded checksums",
+                verification.archive,
+                verification.entries,
+                verification.bytes,
+                verification.checked
+            );
+        }
+    }
+    if !verification.problems.is_empty() {
+        return Err(AppError::Archive(format!(
+            "{archive} failed verification with {} problems",
+            verification.problems.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::{CompressOptions, compress};
+    use async_compression::Level;
+    use futures::StreamExt;
+    use object_store::memory::InMemory;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_sums_unescapes_names() {
+        let sums = parse_sums("ab  logs/a.log\n\\cd  a\\\\nb\\nc\nnot a sum\n");
+        assert_eq!(sums.get("logs/a.log").map(String::as_str), Some("ab"));
+        assert_eq!(sums.get("a\\nb\nc").map(String::as_str), Some("cd"));
+        assert_eq!(sums.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_verify_reads_entries_and_checks_sums() -> Result<()> {
+        let store = Arc::new(InMemory::new());
+        for (key, body) in [("logs/a.log", "first"), ("logs/b.log", "second")] {
+            store.put(&Path::from(key), body.into()).await?;
+        }
+        let archive = Path::from("archive.tar.zst");
+        let mut archived = Vec::new();
+        let index = compress(
+            store.as_ref(),
+            store.list(None).map_err(AppError::from).boxed(),
+            store.clone(),
+            archive.clone(),
+            None,
+            &CompressOptions::new(1024 * 1024, Codec::Zstd, Level::Fastest),
+            &mut archived,
+        )
+        .await?;
+        let mut manifest = Manifest::new(&archive, None, &archived, &index, &[]);
+        manifest.put(store.as_ref()).await?;
+
+        let verification = verify(store.as_ref(), &archive).await?;
+        assert_eq!(verification.entries, 2);
+        assert_eq!(verification.bytes, 11);
+        assert_eq!(verification.checked, 2);
+        assert!(verification.problems.is_empty());
+
+        // A checksum recorded in the manifest that does not match is reported.
+        manifest.objects[0].sha256 = Some("0".repeat(64));
+        manifest.objects.pop();
+        manifest.put(store.as_ref()).await?;
+        let verification = verify(store.as_ref(), &archive).await?;
+        assert_eq!(verification.checked, 1);
+        assert_eq!(verification.problems.len(), 2);
+        Ok(())
+    }
+}
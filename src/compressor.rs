@@ -1,22 +1,392 @@
-use crate::error::Result;
+use crate::codec::{Codec, has_compressed_magic, is_precompressed};
+use crate::commands::{KeyRewrite, MIN_PART_SIZE};
+use crate::dictionary::ZstdDictionary;
+use crate::error::{AppError, Result};
+use crate::mark::ArchiveMark;
+use crate::output::{verbose, warning};
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugin;
+use crate::progress::Progress;
+use crate::s3::S3Client;
+use crate::spool::SpoolFile;
+use crate::upload::{PartedUpload, ResumableUpload, UploadCheckpoint};
 use async_compression::Level;
-use async_compression::tokio::write::XzEncoder;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use chrono::{DateTime, Utc};
-use futures::StreamExt;
+use futures::channel::oneshot;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt, future};
 use object_store::buffered::BufWriter;
-use object_store::{ObjectStore, ObjectStoreExt, path::Path};
-use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio_tar::{Builder, Header};
+use object_store::{Attributes, GetOptions, GetResult, ObjectMeta, ObjectStore, path::Path};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, SimplexStream,
+    WriteHalf,
+};
+use tokio::runtime::Handle;
+use tokio::time::Instant;
+use tokio_tar::{Builder, EntryType, Header};
+use tokio_util::io::{ReaderStream, StreamReader};
 
+mod command;
+mod pax;
+
+pub use command::FilterCommand;
+use command::{gpg_encrypt, pipe};
+pub use pax::Source;
+
+/// How the archive is compressed and uploaded.
+#[derive(Debug, Clone)]
+pub struct CompressOptions {
+    /// Part size of the multipart upload.
+    pub buffer_size: usize,
+    /// Bytes buffered in each of the pipes between the tar, compression and upload stages.
+    pub io_buffer: usize,
+    pub upload_concurrency: usize,
+    pub codec: Codec,
+    pub level: Level,
+    /// Directory buffering prefetched objects and the part being filled instead of memory.
+    pub spool_dir: Option<PathBuf>,
+    /// Objects up to this size are downloaded completely ahead of time, larger ones are
+    /// streamed once it is their turn.
+    pub prefetch_size: u64,
+    /// Turn object keys into tar entry paths.
+    pub rewrites: Vec<KeyRewrite>,
+    /// Bytes of the tar stream compressed into each independent frame of the archive.
+    pub frame_size: u64,
+    /// Objects that may fail to be read, and are skipped, before the run aborts. Objects
+    /// streamed into the tarball rather than downloaded ahead still abort it when failing
+    /// midway, as the tar stream is broken by then.
+    pub errors: ErrorBudget,
+    /// Command each object is piped through before it is archived.
+    pub filter_cmd: Option<FilterCommand>,
+    /// WebAssembly plugin each object is transformed with before it is archived, ahead of
+    /// [`CompressOptions::filter_cmd`].
+    #[cfg(feature = "plugins")]
+    pub plugin: Option<Plugin>,
+    /// PGP keys the archive is encrypted to with `gpg`, none to leave it unencrypted.
+    /// Encrypted archives have no frames to read single entries from.
+    pub gpg_recipients: Vec<String>,
+    /// Dictionary each frame, or each object compressed on its own, is compressed with, which
+    /// is for zstd only.
+    pub zstd_dict: Option<ZstdDictionary>,
+    /// Store objects that are compressed already, e.g. images or gzip files, as told by their
+    /// attributes, extension or magic bytes, with [`Codec::store_encoder`] in frames of their
+    /// own unless small, instead of spending CPU on them.
+    pub store_precompressed: bool,
+    /// Client for the S3 APIs of the source, which [`CompressOptions::checksums`] and
+    /// [`CompressOptions::tags`] need.
+    pub source_s3: Option<S3Client>,
+    /// Look up the full-object SHA-256 S3 recorded for each object, see
+    /// [`S3Client::get_object_sha256`], kept in the tar headers and the manifest of the
+    /// archive. Tarballs only.
+    pub checksums: bool,
+    /// Look up the tags of each object, kept in the tar headers so `restore` puts them back.
+    /// Tarballs only.
+    pub tags: bool,
+    /// Upload through a [`ResumableUpload`] saving its state as given, instead of
+    /// `object_store`, so a later run can pick it up where it stopped.
+    pub resumable: Option<UploadCheckpoint>,
+    /// Upload the part being filled as it is once no part went out for this long, through a
+    /// [`PartedUpload`], rather than holding a slowly filling part in memory.
+    pub flush_interval: Option<Duration>,
+    /// Where the objects appended and the bytes uploaded are counted for the progress object.
+    pub progress: Option<Arc<Progress>>,
+}
+
+impl CompressOptions {
+    #[must_use]
+    pub const fn new(buffer_size: usize, codec: Codec, level: Level) -> Self {
+        Self {
+            buffer_size,
+            io_buffer: PIPE_CAPACITY,
+            upload_concurrency: 8,
+            codec,
+            level,
+            spool_dir: None,
+            prefetch_size: PREFETCH_MAX_SIZE,
+            rewrites: Vec::new(),
+            frame_size: FRAME_SIZE,
+            errors: ErrorBudget::new(0),
+            filter_cmd: None,
+            #[cfg(feature = "plugins")]
+            plugin: None,
+            gpg_recipients: Vec::new(),
+            zstd_dict: None,
+            store_precompressed: false,
+            source_s3: None,
+            checksums: false,
+            tags: false,
+            resumable: None,
+            flush_interval: None,
+            progress: None,
+        }
+    }
+
+    /// Extension of the archives written, e.g. `tar.zst` or `tar.zst.gpg`.
+    #[must_use]
+    pub fn extension(&self) -> String {
+        let extension = format!("tar.{}", self.codec.extension());
+        if self.gpg_recipients.is_empty() {
+            extension
+        } else {
+            extension + ".gpg"
+        }
+    }
+
+    /// What the tar headers record about the object at `meta` with `attributes`, looking up
+    /// its checksum and tags as configured.
+    async fn source(&self, meta: &ObjectMeta, attributes: &Attributes) -> Result<Source> {
+        let mut source = Source {
+            e_tag: meta.e_tag.clone(),
+            attributes: attributes.clone(),
+            ..Source::default()
+        };
+        let Some(s3) = &self.source_s3 else {
+            return Ok(source);
+        };
+        if self.checksums {
+            source.sha256 = s3.get_object_sha256(meta.location.as_ref()).await?;
+        }
+        if self.tags {
+            source.tags = s3.get_object_tagging(meta.location.as_ref()).await?;
+        }
+        Ok(source)
+    }
+
+    /// Shrinks the upload concurrency and the prefetch size so that the encoder, the pipes
+    /// between stages, the upload parts and the prefetched objects fit into `max_memory`.
+    /// Uploads get priority, as they cannot go below one part in flight.
+    pub fn within_memory(self, max_memory: usize) -> Result<Self> {
+        let encoder = self.codec.encoder_memory(self.level);
+        let fixed = encoder + 2 * self.io_buffer + UPLOAD_CHUNK_SIZE;
+        // The part being filled lives in the spool file when there is one.
+        let filling = if self.spool_dir.is_some() {
+            0
+        } else {
+            self.buffer_size
+        };
+
+        let parts = max_memory.saturating_sub(fixed + filling) / self.buffer_size.max(1);
+        if parts == 0 {
+            return Err(AppError::Config(format!(
+                "a memory budget of {max_memory} bytes cannot hold the encoder ({encoder} bytes) \
+                 and upload parts of {} bytes, raise --max-memory or lower --part-size, \
+                 --io-buffer or --compression",
+                self.buffer_size
+            )));
+        }
+        let upload_concurrency = self.upload_concurrency.clamp(1, parts);
+
+        // Spooled objects are prefetched to disk, only their read buffers take memory.
+        let prefetch_size = if self.spool_dir.is_some() {
+            self.prefetch_size
+        } else {
+            let rest = max_memory - fixed - filling - upload_concurrency * self.buffer_size;
+            self.prefetch_size.min((rest / FETCH_AHEAD) as u64)
+        };
+
+        Ok(Self {
+            upload_concurrency,
+            prefetch_size,
+            ..self
+        })
+    }
+}
+
+/// Per-object failures tolerated in a run, counted across all its archives.
+#[derive(Debug, Default)]
+pub struct ErrorBudget {
+    max: usize,
+    /// Objects that failed, with why.
+    failures: Mutex<Vec<(Path, String)>>,
+}
+
+impl ErrorBudget {
+    #[must_use]
+    pub const fn new(max: usize) -> Self {
+        Self {
+            max,
+            failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Objects that failed so far, with why.
+    pub fn failures(&self) -> Vec<(Path, String)> {
+        self.failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Number of objects that failed so far.
+    pub fn failed(&self) -> usize {
+        self.failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Skips the object at `location` that failed with `error`, unless that is one failure
+    /// too many.
+    fn tolerate(&self, location: &Path, error: &AppError) -> Result<()> {
+        let failed = {
+            let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+            failures.push((location.clone(), error.to_string()));
+            failures.len()
+        };
+        if failed > self.max {
+            return Err(AppError::SafetyLimit(format!(
+                "{failed} objects failed, more than the {} tolerated; the last, {location}: \
+                 {error}",
+                self.max
+            )));
+        }
+        warning!(
+            "Skipping {location} ({failed} of {} tolerated failures): {error}",
+            self.max
+        );
+        Ok(())
+    }
+}
+
+impl Clone for ErrorBudget {
+    fn clone(&self) -> Self {
+        Self {
+            max: self.max,
+            failures: Mutex::new(self.failures()),
+        }
+    }
+}
+
+/// Tar stream handed to the compression thread.
+type TarBuilder = Builder<Counted<WriteHalf<SimplexStream>>>;
+
+/// Bytes buffered between the tar, compression and upload stages by default.
+pub const PIPE_CAPACITY: usize = 1024 * 1024;
+
+/// Objects downloaded ahead of the one being appended to the tar stream.
+const FETCH_AHEAD: usize = 16;
+
+/// Default of [`CompressOptions::prefetch_size`], bounding the memory of the fetch stage.
+const PREFETCH_MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Compressed bytes collected before they are handed to the upload.
+const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Default of [`CompressOptions::frame_size`]. Reading a single entry starts at the frame it
+/// is in, so smaller frames mean shorter reads and a worse compression ratio.
+const FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Objects compressed already get frames of their own from this size, see
+/// [`CompressOptions::store_precompressed`]; smaller ones are compressed along with their
+/// neighbours, as a frame of their own would cost more than it saves.
+const STORED_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Name of the last entry of every tarball, see [`append_sums`].
+pub const SUMS_NAME: &str = "SHA256SUMS";
+
+/// Start of an independently compressed frame of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Offset in the tar stream.
+    pub tar_offset: u64,
+    /// Offset in the archive.
+    pub offset: u64,
+}
+
+/// What was written: where things are in the archive, for reading single entries without
+/// the rest, and its size and checksum.
+#[derive(Debug, Default)]
+pub struct ArchiveIndex {
+    /// Tar stream offset of the header of each archived object, in order.
+    pub entries: Vec<u64>,
+    /// Hex encoded SHA-256 of each archived object as S3 recorded it, in order, see
+    /// [`CompressOptions::checksums`].
+    pub checksums: Vec<Option<String>>,
+    pub frames: Vec<Frame>,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the archive.
+    pub sha256: String,
+}
+
+/// Passes writes through to `inner`, counting them.
+pub struct Counted<W> {
+    pub inner: W,
+    pub written: u64,
+}
+
+impl<W> Counted<W> {
+    pub const fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+/// Shutting down only flushes, so an encoder finishing its frame leaves `inner` open for the
+/// next one.
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Object content on its way into the tar stream.
+type Body = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Records where an object came from in a PAX extended header ahead of its entry, see
+/// [`Source`].
+async fn append_source(
+    source: &Source,
+    last_modified: DateTime<Utc>,
+    tar_builder: &mut TarBuilder,
+) -> std::io::Result<()> {
+    let records = source.records();
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut header = Header::new_ustar();
+    header.set_path("././@PaxHeader")?;
+    header.set_entry_type(EntryType::XHeader);
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(last_modified.timestamp().cast_unsigned());
+    header.set_cksum();
+    tar_builder.append(&header, records.as_slice()).await
+}
+
+/// Appends an object to the tar stream as `name`, returning the hex encoded SHA-256 of its
+/// content.
 async fn compress_object(
-    stream: futures::stream::BoxStream<'static, object_store::Result<Bytes>>,
+    stream: Body,
     size: u64,
     last_modified: DateTime<Utc>,
     location: Path,
-    tar_builder: &mut Builder<XzEncoder<BufWriter>>,
-) -> Result<()> {
+    name: &str,
+    tar_builder: &mut TarBuilder,
+) -> Result<String> {
     let mut header = Header::new_gnu();
     header.set_size(size);
     header.set_mode(0o644);
@@ -24,12 +394,18 @@ async fn compress_object(
     header.set_cksum();
 
     // Adapt the stream to AsyncRead
-    let async_read = tokio_util::io::StreamReader::new(stream);
+    let mut digest = Sha256::new();
+    let async_read =
+        tokio_util::io::StreamReader::new(stream.inspect_ok(|chunk| digest.update(chunk)));
 
-    println!("Archiving {}", location.as_ref());
+    if name == location.as_ref() {
+        verbose!("Archiving {location}");
+    } else {
+        verbose!("Archiving {location} as {name}");
+    }
 
     tar_builder
-        .append_data(&mut header, location.as_ref(), async_read)
+        .append_data(&mut header, name, async_read)
         .await
         .map_err(|e| {
             std::io::Error::new(
@@ -41,70 +417,725 @@ async fn compress_object(
             )
         })?;
 
-    Ok(())
+    Ok(hex::encode(digest.finalize()))
+}
+
+/// A line of a `SHA256SUMS` file as `sha256sum` writes it, escaping names with backslashes
+/// or newlines and marking the line as escaped.
+fn sums_line(sha256: &str, name: &str) -> String {
+    if name.contains(['\\', '\n']) {
+        let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{sha256}  {escaped}\n")
+    } else {
+        format!("{sha256}  {name}\n")
+    }
+}
+
+/// Ends the tar stream with [`SUMS_NAME`], the SHA-256 of every entry before it, so the
+/// archive can be checked with `sha256sum -c` once extracted, manifest or not.
+/// It is dated like the newest entry, `last_modified`, so the same objects always make the
+/// same archive.
+async fn append_sums(
+    sums: &str,
+    last_modified: DateTime<Utc>,
+    tar_builder: &mut TarBuilder,
+) -> std::io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(sums.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(last_modified.timestamp().cast_unsigned());
+    header.set_cksum();
+    tar_builder
+        .append_data(&mut header, SUMS_NAME, sums.as_bytes())
+        .await
+}
+
+/// S3 refuses to read `GLACIER`/`DEEP_ARCHIVE` objects with an `InvalidObjectState` error;
+/// point at `thaw` instead of surfacing the bare 403.
+fn archived_object_error(location: &Path, err: object_store::Error) -> AppError {
+    if format!("{err:?}").contains("InvalidObjectState") {
+        AppError::Archive(format!(
+            "'{location}' is in an archive storage class and must be restored first \
+             (see the `thaw` command): {err}"
+        ))
+    } else {
+        err.into()
+    }
+}
+
+/// Starts downloading an object, or `None` when it was archived before or changed since it
+/// was listed. The tar header is written from the listing, so a newer object would end up
+/// torn or truncated in the archive.
+async fn open_object(
+    store: &dyn ObjectStore,
+    meta: &ObjectMeta,
+    mark: Option<&ArchiveMark>,
+) -> Result<Option<GetResult>> {
+    // Archived by an earlier run that kept its sources.
+    if let Some(mark) = mark
+        && mark.is_marked(&meta.location).await?
+    {
+        return Ok(None);
+    }
+
+    let unmodified = GetOptions {
+        if_unmodified_since: Some(meta.last_modified),
+        ..GetOptions::default()
+    };
+    match store.get_opts(&meta.location, unmodified).await {
+        Ok(result) => Ok(Some(result)),
+        Err(object_store::Error::Precondition { .. }) => {
+            warning!("Skipping {}, it changed since it was listed", meta.location);
+            Ok(None)
+        }
+        Err(e) => Err(archived_object_error(&meta.location, e)),
+    }
+}
+
+/// An object downloaded for the tar stage.
+struct Fetched {
+    meta: ObjectMeta,
+    /// Size of its entry.
+    size: u64,
+    body: Body,
+    /// Whether to store it, see [`CompressOptions::store_precompressed`].
+    stored: bool,
+    source: Source,
+}
+
+/// Downloads an object for the tar stage, see [`open_object`]. Objects failing to download
+/// are skipped within [`CompressOptions::errors`].
+async fn fetch_object(
+    store: &dyn ObjectStore,
+    meta: ObjectMeta,
+    mark: Option<&ArchiveMark>,
+    options: &CompressOptions,
+) -> Result<Option<Fetched>> {
+    let location = meta.location.clone();
+    match download(store, meta, mark, options).await {
+        Err(e) => options.errors.tolerate(&location, &e).map(|()| None),
+        fetched => fetched,
+    }
+}
+
+async fn download(
+    store: &dyn ObjectStore,
+    meta: ObjectMeta,
+    mark: Option<&ArchiveMark>,
+    options: &CompressOptions,
+) -> Result<Option<Fetched>> {
+    let Some(result) = open_object(store, &meta, mark).await? else {
+        return Ok(None);
+    };
+    let source = options.source(&meta, &result.attributes).await?;
+    let sniffing = options.store_precompressed && meta.size >= STORED_MIN_SIZE;
+    let described = sniffing && is_precompressed(meta.location.as_ref(), &result.attributes);
+
+    let body = match &options.spool_dir {
+        _ if meta.size > options.prefetch_size => {
+            result.into_stream().map_err(std::io::Error::from).boxed()
+        }
+        None => {
+            let bytes = result.bytes().await?;
+            futures::stream::once(async move { Ok(bytes) }).boxed()
+        }
+        Some(dir) => {
+            let mut spool = SpoolFile::create(dir).await?;
+            let mut chunks = result.into_stream();
+            while let Some(chunk) = chunks.try_next().await? {
+                spool.write_all(&chunk).await?;
+            }
+            ReaderStream::new(spool.into_reader().await?).boxed()
+        }
+    };
+    #[cfg(feature = "plugins")]
+    let (size, body) = match &options.plugin {
+        None => (meta.size, body),
+        Some(plugin) => {
+            plugin
+                .apply(meta.location.as_ref(), body, options.spool_dir.as_deref())
+                .await?
+        }
+    };
+    #[cfg(not(feature = "plugins"))]
+    let size = meta.size;
+    let (size, body) = match &options.filter_cmd {
+        None => (size, body),
+        Some(command) => {
+            command
+                .apply(meta.location.as_ref(), body, options.spool_dir.as_deref())
+                .await?
+        }
+    };
+    let (stored, body) = if sniffing {
+        sniff(described, body).await?
+    } else {
+        (false, body)
+    };
+    Ok(Some(Fetched {
+        meta,
+        size,
+        body,
+        stored,
+        source,
+    }))
 }
 
+/// Whether `body` is compressed already, `described` as such or starting with the magic
+/// bytes of a compressed format, see [`has_compressed_magic`]. The first chunk is read to
+/// tell and put back in front of the rest.
+async fn sniff(described: bool, mut body: Body) -> std::io::Result<(bool, Body)> {
+    if described {
+        return Ok((true, body));
+    }
+    let Some(first) = body.try_next().await? else {
+        return Ok((false, body));
+    };
+    let stored = has_compressed_magic(&first);
+    Ok((
+        stored,
+        futures::stream::once(future::ok(first)).chain(body).boxed(),
+    ))
+}
+
+/// Fetch and tar stage: `objects` are downloaded up to [`FETCH_AHEAD`] at a time, in order,
+/// while earlier ones are appended to the tar stream. Where entries start or stop being
+/// stored goes to `changes` before they are written. Returns the `SHA256SUMS` lines of the
+/// entries.
+#[allow(clippy::too_many_arguments)]
 async fn process_objects(
     store: &dyn ObjectStore,
-    prefix: Path,
-    cutoff_dt: DateTime<Utc>,
-    tar_builder: &mut Builder<XzEncoder<BufWriter>>,
-    processed_keys: &mut Vec<Path>,
-) -> Result<()> {
-    let mut list_stream = store.list(Some(&prefix));
-
-    while let Some(meta_res) = list_stream.next().await {
-        match meta_res {
-            Ok(meta) if meta.last_modified < cutoff_dt => {
-                let result = store.get(&meta.location).await?;
-                compress_object(
-                    result.into_stream(),
-                    meta.size,
-                    meta.last_modified,
-                    meta.location.clone(),
-                    tar_builder,
+    objects: BoxStream<'_, Result<ObjectMeta>>,
+    mark: Option<&ArchiveMark>,
+    options: &CompressOptions,
+    tar_builder: &mut TarBuilder,
+    processed: &mut Vec<ObjectMeta>,
+    entries: &mut Vec<(u64, Option<String>)>,
+    changes: &mpsc::Sender<(u64, bool)>,
+) -> Result<String> {
+    let mut fetched = objects
+        .map_ok(|meta| fetch_object(store, meta, mark, options))
+        .try_buffered(FETCH_AHEAD)
+        .try_filter_map(future::ok)
+        .boxed();
+
+    let mut storing = false;
+    let mut sums = String::new();
+    while let Some(Fetched {
+        meta,
+        size,
+        body,
+        stored,
+        source,
+    }) = fetched.try_next().await?
+    {
+        let offset = tar_builder.get_ref().written;
+        if stored != storing {
+            // The compression stage is gone when failing, which the pipe reports as well.
+            let _ = changes.send((offset, stored));
+            storing = stored;
+        }
+        append_source(&source, meta.last_modified, tar_builder).await?;
+        let name = KeyRewrite::apply(&options.rewrites, meta.location.as_ref());
+        let content_sha256 = compress_object(
+            body,
+            size,
+            meta.last_modified,
+            meta.location.clone(),
+            &name,
+            tar_builder,
+        )
+        .await?;
+        sums.push_str(&sums_line(&content_sha256, &name));
+
+        if let Some(progress) = &options.progress {
+            progress.archived(&meta);
+        }
+        processed.push(meta);
+        entries.push((offset, source.sha256));
+    }
+    Ok(sums)
+}
+
+/// Encoder of `codec` at `level` writing to `writer`, with `dictionary` when given.
+fn encoder<'a, W>(
+    writer: W,
+    codec: Codec,
+    level: Level,
+    dictionary: Option<&ZstdDictionary>,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send + 'a>>
+where
+    W: AsyncWrite + Unpin + Send + 'a,
+{
+    match dictionary {
+        Some(dictionary) => dictionary.encoder(writer, level),
+        None => Ok(codec.encoder(writer, level)),
+    }
+}
+
+/// Compression stage: compresses the tar stream read from `tar` into `compressed`, as
+/// independent frames of `frame_size` tar bytes each. Decoders read the frames as one stream,
+/// while an entry can also be decoded starting at the frame it is in. Frames also end where
+/// `changes` has entries start or stop being stored, which go through
+/// [`Codec::store_encoder`].
+#[allow(clippy::too_many_arguments)]
+async fn encode<R, W>(
+    tar: R,
+    compressed: W,
+    codec: Codec,
+    level: Level,
+    dictionary: Option<&ZstdDictionary>,
+    frame_size: u64,
+    changes: mpsc::Receiver<(u64, bool)>,
+) -> Result<Vec<Frame>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut tar = BufReader::new(tar);
+    let mut compressed = Counted::new(compressed);
+    let mut frames = Vec::new();
+    let mut tar_offset = 0;
+    let mut pending = VecDeque::new();
+    let mut stored = false;
+    // Even an empty stream gets a frame, so the archive is valid for the codec.
+    while frames.is_empty() || !tar.fill_buf().await?.is_empty() {
+        // Changes are sent before the entries they are about are written, so those up to
+        // the bytes buffered are in.
+        pending.extend(changes.try_iter());
+        while let Some(&(_, next)) = pending.front().filter(|(at, _)| *at <= tar_offset) {
+            stored = next;
+            pending.pop_front();
+        }
+        frames.push(Frame {
+            tar_offset,
+            offset: compressed.written,
+        });
+        let mut encoder = if stored {
+            codec.store_encoder(&mut compressed)
+        } else {
+            encoder(&mut compressed, codec, level, dictionary)?
+        };
+        let mut end = tar_offset + frame_size;
+        loop {
+            let buffered = tar.fill_buf().await?;
+            if buffered.is_empty() {
+                break;
+            }
+            pending.extend(changes.try_iter());
+            if let Some(&(at, _)) = pending.front() {
+                end = end.min(at);
+            }
+            let take = buffered
+                .len()
+                .min(usize::try_from(end - tar_offset).unwrap_or(usize::MAX));
+            encoder.write_all(&buffered[..take]).await?;
+            tar.consume(take);
+            tar_offset += take as u64;
+            if tar_offset == end {
+                break;
+            }
+        }
+        encoder.shutdown().await?;
+    }
+    compressed.inner.shutdown().await?;
+    Ok(frames)
+}
+
+/// Where the upload stage writes the archive.
+enum Sink {
+    Buffered(BufWriter),
+    Resumable(ResumableUpload),
+    Parted(PartedUpload),
+}
+
+impl Sink {
+    /// Multipart upload to `dst_path`, resumable or flushed in time if `options` say so.
+    async fn new(
+        dst_store: Arc<dyn ObjectStore>,
+        dst_path: Path,
+        options: &CompressOptions,
+    ) -> Result<Self> {
+        let part_size = options.buffer_size;
+        Ok(match (&options.resumable, options.flush_interval) {
+            (Some(checkpoint), _) => Self::Resumable(
+                ResumableUpload::start(
+                    checkpoint,
+                    dst_path.as_ref(),
+                    part_size,
+                    options.upload_concurrency,
                 )
-                .await?;
+                .await?,
+            ),
+            (None, Some(_)) => Self::Parted(
+                PartedUpload::start(
+                    dst_store.as_ref(),
+                    dst_path,
+                    part_size,
+                    options.upload_concurrency,
+                )
+                .await?,
+            ),
+            (None, None) => Self::Buffered(
+                BufWriter::with_capacity(dst_store, dst_path, part_size)
+                    .with_max_concurrency(options.upload_concurrency.max(1)),
+            ),
+        })
+    }
+
+    async fn put(&mut self, data: Bytes) -> Result<()> {
+        match self {
+            Self::Buffered(sink) => sink.put(data).await?,
+            Self::Resumable(upload) => upload.put(data).await?,
+            Self::Parted(upload) => upload.put(data).await?,
+        }
+        Ok(())
+    }
+
+    /// When the part being filled is flushed if nothing goes out before, for sinks that can.
+    fn flush_deadline(&self, interval: Option<Duration>) -> Option<Instant> {
+        match self {
+            Self::Parted(upload) => Some(upload.flush_deadline(interval?)),
+            Self::Buffered(_) | Self::Resumable(_) => None,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if let Self::Parted(upload) = self {
+            upload.flush().await?;
+        }
+        Ok(())
+    }
 
-                processed_keys.push(meta.location);
+    async fn finish(self) -> Result<()> {
+        match self {
+            Self::Buffered(mut sink) => sink.shutdown().await?,
+            Self::Resumable(upload) => upload.finish().await?,
+            Self::Parted(upload) => upload.finish().await?,
+        }
+        Ok(())
+    }
+
+    /// Gives up on the upload, keeping a resumable one for the next run.
+    async fn abort(self) -> Result<()> {
+        match self {
+            Self::Buffered(mut sink) => sink.abort().await?,
+            Self::Resumable(upload) => upload.keep().await,
+            Self::Parted(upload) => upload.abort().await?,
+        }
+        Ok(())
+    }
+}
+
+/// Upload stage: moves the compressed stream into `sink`, collecting whole parts in `spool`
+/// when given. Once no part went out for the flush interval of `options`, what is collected
+/// is flushed as a part of its own. The upload is only completed once `commit`
+/// confirms the earlier stages succeeded, otherwise it is aborted. Returns the size and hex
+/// encoded SHA-256 of the stream.
+async fn upload<R>(
+    mut compressed: R,
+    mut sink: Sink,
+    options: &CompressOptions,
+    mut spool: Option<SpoolFile>,
+    commit: oneshot::Receiver<bool>,
+) -> Result<(u64, String)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = BytesMut::new();
+    let mut size = 0;
+    let mut digest = Sha256::new();
+    loop {
+        chunk.reserve(UPLOAD_CHUNK_SIZE);
+        let filled = chunk.len();
+        let deadline = sink.flush_deadline(options.flush_interval);
+        let read = compressed.read_buf(&mut chunk);
+        let done = match deadline {
+            // Nothing is read when the deadline passes first.
+            Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+                Ok(read) => read? == 0,
+                Err(_) => false,
+            },
+            None => read.await? == 0,
+        };
+        digest.update(&chunk[filled..]);
+        size += (chunk.len() - filled) as u64;
+        if let Some(progress) = &options.progress {
+            progress.written((chunk.len() - filled) as u64);
+        }
+
+        if let Some(spool) = &mut spool {
+            spool.write_all(&chunk).await?;
+            chunk.clear();
+            if done || spool.len() >= options.buffer_size as u64 {
+                sink.put(spool.take().await?).await?;
+            }
+        } else if done || chunk.len() >= UPLOAD_CHUNK_SIZE {
+            sink.put(chunk.split().freeze()).await?;
+        }
+
+        if done {
+            break;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            match &mut spool {
+                Some(spool) if spool.len() >= MIN_PART_SIZE as u64 => {
+                    sink.put(spool.take().await?).await?;
+                }
+                Some(_) => {}
+                None => sink.put(chunk.split().freeze()).await?,
             }
-            Ok(_) => {}
-            Err(e) => return Err(e.into()),
+            sink.flush().await?;
         }
     }
-    Ok(())
+
+    if commit.await.unwrap_or(false) {
+        sink.finish().await?;
+    } else {
+        sink.abort().await?;
+    }
+    Ok((size, hex::encode(digest.finalize())))
 }
 
+/// Streams `objects` from `src_store` into a compressed tarball at `dst_path`, returning its
+/// [`ArchiveIndex`]. Fetching, tar, compression and upload run as concurrent stages connected
+/// by bounded pipes, so network and CPU work overlap.
 #[allow(clippy::too_many_arguments)]
 pub async fn compress(
     src_store: &dyn ObjectStore,
-    src_path: Path,
+    objects: BoxStream<'_, Result<ObjectMeta>>,
     dst_store: Arc<dyn ObjectStore>,
     dst_path: Path,
-    cutoff_dt: DateTime<Utc>,
-    buffer_size: usize,
-    level: Level,
-    processed_keys: &mut Vec<Path>,
-) -> Result<()> {
-    let sink = BufWriter::with_capacity(dst_store, dst_path, buffer_size);
-    let encoder = XzEncoder::with_quality(sink, level);
-    let mut tar_builder = Builder::new(encoder);
-
-    process_objects(
-        src_store,
-        src_path,
-        cutoff_dt,
-        &mut tar_builder,
-        processed_keys,
-    )
-    .await?;
-
-    tar_builder.finish().await?;
-    let mut encoder = tar_builder.into_inner().await?;
-
-    encoder.shutdown().await?;
-
-    Ok(())
+    mark: Option<&ArchiveMark>,
+    options: &CompressOptions,
+    processed: &mut Vec<ObjectMeta>,
+) -> Result<ArchiveIndex> {
+    let CompressOptions {
+        io_buffer,
+        codec,
+        level,
+        ref spool_dir,
+        frame_size,
+        ..
+    } = *options;
+    let spool = match spool_dir {
+        Some(dir) => Some(SpoolFile::create(dir).await?),
+        None => None,
+    };
+
+    let sink = Sink::new(dst_store, dst_path, options).await?;
+    let (tar_reader, tar_writer) = tokio::io::simplex(io_buffer);
+    let (compressed_reader, compressed_writer) = tokio::io::simplex(io_buffer);
+    let (commit, committed) = oneshot::channel();
+    let (compressed_reader, encryption) = if options.gpg_recipients.is_empty() {
+        (compressed_reader, None)
+    } else {
+        let (encrypted, encryption) =
+            pipe(gpg_encrypt(&options.gpg_recipients), compressed_reader)?;
+        (encrypted, Some(encryption))
+    };
+
+    // Compression is CPU bound: on its own thread it neither stalls nor waits for the
+    // listing, downloads and uploads driven by the runtime.
+    let runtime = Handle::current();
+    let dictionary = options.zstd_dict.clone();
+    let (changes, encoder_changes) = mpsc::channel();
+    let encoding = tokio::task::spawn_blocking(move || {
+        runtime.block_on(encode(
+            tar_reader,
+            compressed_writer,
+            codec,
+            level,
+            dictionary.as_ref(),
+            frame_size.max(1),
+            encoder_changes,
+        ))
+    });
+
+    let produce = async {
+        let archived = async {
+            // Owned here, so the pipe is closed on errors and the encoder does not wait forever.
+            let mut tar_builder = Builder::new(Counted::new(tar_writer));
+            let mut entries = Vec::new();
+            let sums = process_objects(
+                src_store,
+                objects,
+                mark,
+                options,
+                &mut tar_builder,
+                processed,
+                &mut entries,
+                &changes,
+            )
+            .await?;
+
+            let newest = processed.iter().map(|meta| meta.last_modified).max();
+            append_sums(&sums, newest.unwrap_or_default(), &mut tar_builder).await?;
+            tar_builder.finish().await?;
+            tar_builder.into_inner().await?.inner.shutdown().await?;
+            Ok::<_, AppError>(entries)
+        }
+        .await;
+
+        let encoded = match encoding.await {
+            Ok(encoded) => encoded,
+            Err(e) => Err(std::io::Error::other(e).into()),
+        };
+        let encrypted = match encryption {
+            Some(encryption) => encryption
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e).into())),
+            None => Ok(()),
+        };
+
+        // A failed send means the upload already gave up, its error is reported below.
+        let _ = commit.send(archived.is_ok() && encoded.is_ok() && encrypted.is_ok());
+        let (frames, (entries, checksums)) = (encoded?, archived?.into_iter().unzip());
+        encrypted?;
+        Ok(ArchiveIndex {
+            // Frames are offsets into the compressed stream, which encryption does not keep.
+            frames: if options.gpg_recipients.is_empty() {
+                frames
+            } else {
+                Vec::new()
+            },
+            entries,
+            checksums,
+            ..ArchiveIndex::default()
+        })
+    };
+
+    match tokio::join!(
+        produce,
+        upload(compressed_reader, sink, options, spool, committed)
+    ) {
+        // A failed upload closes the pipes, making the earlier stages fail as well.
+        (_, Err(e)) => Err(e),
+        (produced, Ok((size, sha256))) => produced.map(|index| ArchiveIndex {
+            size,
+            sha256,
+            ..index
+        }),
+    }
+}
+
+/// Where [`compress_single`] writes the object at `key`.
+pub fn individual_target(dst_path: &Path, key: &str, options: &CompressOptions) -> Path {
+    let name = KeyRewrite::apply(&options.rewrites, key);
+    dst_path
+        .parts()
+        .chain(Path::from(format!("{name}.{}", options.codec.extension())).parts())
+        .collect()
+}
+
+/// Compresses a single object into `dst_path`, below its (rewritten) key with the extension of
+/// the codec added, keeping its attributes. Returns the size of the compressed copy, skipped
+/// objects are `None`, see [`open_object`].
+async fn compress_single(
+    src_store: &dyn ObjectStore,
+    meta: ObjectMeta,
+    dst_store: Arc<dyn ObjectStore>,
+    dst_path: &Path,
+    mark: Option<&ArchiveMark>,
+    options: &CompressOptions,
+) -> Result<Option<(ObjectMeta, u64)>> {
+    let Some(result) = open_object(src_store, &meta, mark).await? else {
+        return Ok(None);
+    };
+
+    let target = individual_target(dst_path, meta.location.as_ref(), options);
+    verbose!("Archiving {} as {target}", meta.location);
+
+    let sink = BufWriter::with_capacity(dst_store, target.clone(), options.buffer_size)
+        .with_attributes(options.codec.attributes(&result.attributes));
+    let mut sink = Counted::new(sink);
+    let written = async {
+        let described = is_precompressed(meta.location.as_ref(), &result.attributes);
+        let mut body: Body = result.into_stream().map_err(std::io::Error::from).boxed();
+        #[cfg(feature = "plugins")]
+        if let Some(plugin) = &options.plugin {
+            let key = meta.location.as_ref();
+            (_, body) = plugin
+                .apply(key, body, options.spool_dir.as_deref())
+                .await?;
+        }
+        if let Some(command) = &options.filter_cmd {
+            let key = meta.location.as_ref();
+            (_, body) = command
+                .apply(key, body, options.spool_dir.as_deref())
+                .await?;
+        }
+        let stored = if options.store_precompressed {
+            let sniffed;
+            (sniffed, body) = sniff(described, body).await?;
+            sniffed
+        } else {
+            false
+        };
+        let mut encoder = if stored {
+            options.codec.store_encoder(&mut sink)
+        } else {
+            encoder(
+                &mut sink,
+                options.codec,
+                options.level,
+                options.zstd_dict.as_ref(),
+            )?
+        };
+        tokio::io::copy(&mut StreamReader::new(body), &mut encoder).await?;
+        encoder.shutdown().await?;
+        drop(encoder);
+        sink.inner.shutdown().await?;
+        Ok::<_, AppError>(())
+    }
+    .await;
+    if let Err(e) = written {
+        // The object may be skipped, which must not leave its multipart upload behind.
+        if let Err(abort) = sink.inner.abort().await {
+            warning!("Cannot abort the upload of {target}: {abort}");
+        }
+        return Err(e);
+    }
+
+    Ok(Some((meta, sink.written)))
+}
+
+/// Compresses each of `objects` on its own instead of into a tarball, up to
+/// `upload_concurrency` at a time, see [`compress_single`]. Objects failing to be compressed
+/// are skipped within [`CompressOptions::errors`]. Returns the total size of the compressed
+/// copies.
+pub async fn compress_each(
+    src_store: &dyn ObjectStore,
+    objects: BoxStream<'_, Result<ObjectMeta>>,
+    dst_store: &Arc<dyn ObjectStore>,
+    dst_path: &Path,
+    mark: Option<&ArchiveMark>,
+    options: &CompressOptions,
+    processed: &mut Vec<ObjectMeta>,
+) -> Result<u64> {
+    let mut compressed = objects
+        .map_ok(|meta| async move {
+            let location = meta.location.clone();
+            let dst_store = Arc::clone(dst_store);
+            match compress_single(src_store, meta, dst_store, dst_path, mark, options).await {
+                Err(e) => options.errors.tolerate(&location, &e).map(|()| None),
+                compressed => compressed,
+            }
+        })
+        .try_buffer_unordered(options.upload_concurrency.max(1))
+        .try_filter_map(future::ok)
+        .boxed();
+
+    let mut written = 0;
+    while let Some((meta, size)) = compressed.try_next().await? {
+        if let Some(progress) = &options.progress {
+            progress.archived(&meta);
+            progress.written(size);
+        }
+        processed.push(meta);
+        written += size;
+    }
+    Ok(written)
 }
 
 #[cfg(test)]
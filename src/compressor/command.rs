@@ -0,0 +1,216 @@
+//! External commands objects are piped through on their way into an archive, e.g. to scrub
+//! personal data or normalize formats, and archives through on their way to the store, e.g.
+//! to encrypt them with `gpg`.
+
+use super::Body;
+use crate::error::{AppError, Result};
+use crate::output::verbose;
+use crate::spool::SpoolFile;
+use futures::{StreamExt, TryStreamExt};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, SimplexStream};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio_util::io::ReaderStream;
+
+/// Bytes of the command's output read at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Shell command run once per object with the object on standard input, whatever it writes
+/// to standard output is archived instead. The key of the object is in `OBJECT_KEY`, and
+/// standard error goes to ours.
+#[derive(Debug, Clone)]
+pub struct FilterCommand {
+    command: String,
+}
+
+/// Output of a command, in memory or in a spool file.
+enum Output {
+    Memory(Vec<u8>),
+    Spooled(SpoolFile),
+}
+
+impl FilterCommand {
+    #[must_use]
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+        }
+    }
+
+    /// Pipes `body`, the content of the object at `key`, through the command. Returns the size
+    /// of the output and the output, buffered in memory or in `spool_dir` as tar entries need
+    /// their size upfront.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the command cannot be started, exits unsuccessfully, or `body` fails.
+    pub async fn apply(
+        &self,
+        key: &str,
+        body: Body,
+        spool_dir: Option<&Path>,
+    ) -> Result<(u64, Body)> {
+        verbose!("Filtering {key} through {}", self.command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("OBJECT_KEY", key)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                AppError::Config(format!("cannot run filter command '{}': {e}", self.command))
+            })?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(AppError::Archive(
+                "the filter command has no pipes".to_string(),
+            ));
+        };
+        let spool = match spool_dir {
+            Some(dir) => Some(SpoolFile::create(dir).await?),
+            None => None,
+        };
+
+        // The pipes block, so both ends are served from their own threads.
+        let feeding = tokio::task::spawn_blocking({
+            let runtime = Handle::current();
+            move || runtime.block_on(feed(body, stdin))
+        });
+        let reading = tokio::task::spawn_blocking({
+            let runtime = Handle::current();
+            move || runtime.block_on(read(stdout, spool))
+        });
+        let (fed, read) = tokio::join!(feeding, reading);
+        let status = tokio::task::spawn_blocking(move || child.wait())
+            .await
+            .map_err(std::io::Error::other)??;
+        fed.map_err(std::io::Error::other)??;
+        let output = read.map_err(std::io::Error::other)??;
+        if !status.success() {
+            return Err(AppError::Archive(format!(
+                "filter command '{}' failed for {key} with {status}",
+                self.command
+            )));
+        }
+
+        Ok(match output {
+            Output::Memory(bytes) => (
+                bytes.len() as u64,
+                futures::stream::once(async move { Ok(bytes.into()) }).boxed(),
+            ),
+            Output::Spooled(spool) => (
+                spool.len(),
+                ReaderStream::new(spool.into_reader().await?).boxed(),
+            ),
+        })
+    }
+}
+
+/// `gpg` encrypting standard input to each of `recipients`, whose keys must be valid in the
+/// keyring of the user running it.
+pub fn gpg_encrypt(recipients: &[String]) -> Command {
+    let mut command = Command::new("gpg");
+    command.args(["--batch", "--no-tty", "--encrypt", "--output", "-"]);
+    for recipient in recipients {
+        command.args(["--recipient", recipient]);
+    }
+    command
+}
+
+/// Streams `input` through `command`, returning what it writes to standard output and the
+/// task waiting for it, which fails unless the command succeeded.
+///
+/// # Errors
+///
+/// Fails when the command cannot be started.
+pub fn pipe<R>(
+    mut command: Command,
+    mut input: R,
+) -> Result<(ReadHalf<SimplexStream>, JoinHandle<Result<()>>)>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| AppError::Config(format!("cannot run {program}: {e}")))?;
+    let (Some(mut stdin), Some(mut stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(AppError::Archive(format!("{program} has no pipes")));
+    };
+    let (output, mut output_writer) = tokio::io::simplex(READ_SIZE);
+
+    let feeding = tokio::task::spawn_blocking({
+        let runtime = Handle::current();
+        move || {
+            let mut chunk = vec![0; READ_SIZE];
+            loop {
+                let read = runtime.block_on(input.read(&mut chunk))?;
+                if read == 0 {
+                    return Ok::<_, std::io::Error>(());
+                }
+                stdin.write_all(&chunk[..read])?;
+            }
+        }
+    });
+    let reading = tokio::task::spawn_blocking({
+        let runtime = Handle::current();
+        move || {
+            let mut chunk = vec![0; READ_SIZE];
+            loop {
+                let read = stdout.read(&mut chunk)?;
+                if read == 0 {
+                    return runtime.block_on(output_writer.shutdown());
+                }
+                runtime.block_on(output_writer.write_all(&chunk[..read]))?;
+            }
+        }
+    });
+    let waiting = tokio::spawn(async move {
+        let (fed, read) = tokio::join!(feeding, reading);
+        let status = tokio::task::spawn_blocking(move || child.wait())
+            .await
+            .map_err(std::io::Error::other)??;
+        fed.map_err(std::io::Error::other)??;
+        read.map_err(std::io::Error::other)??;
+        if !status.success() {
+            return Err(AppError::Archive(format!("{program} failed with {status}")));
+        }
+        Ok(())
+    });
+    Ok((output, waiting))
+}
+
+/// Writes `body` to the standard input of a command, closing it at the end.
+async fn feed(mut body: Body, mut stdin: impl Write) -> std::io::Result<()> {
+    while let Some(chunk) = body.try_next().await? {
+        match stdin.write_all(&chunk) {
+            // The command stopped reading, e.g. as it only needs the start; its exit status
+            // tells whether that is fine.
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            written => written?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the standard output of a command to its end, into `spool` if given.
+async fn read(mut stdout: impl Read, spool: Option<SpoolFile>) -> std::io::Result<Output> {
+    let Some(mut spool) = spool else {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output)?;
+        return Ok(Output::Memory(output));
+    };
+    let mut chunk = vec![0; READ_SIZE];
+    loop {
+        let read = stdout.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(Output::Spooled(spool));
+        }
+        spool.write_all(&chunk[..read]).await?;
+    }
+}
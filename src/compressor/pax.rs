@@ -0,0 +1,202 @@
+//! PAX extended headers recording where each entry of a tarball came from: the entity tag,
+//! SHA-256, attributes and tags of the source object, so `restore` can put back an object
+//! that is functionally identical rather than only byte-identical.
+//!
+//! The records are extended attributes under `SCHILY.xattr.user.source.`, which `tar` and
+//! `bsdtar` know, rather than keys of our own they would warn about.
+
+use object_store::{Attribute, AttributeValue, Attributes, TagSet};
+use tokio::io::AsyncRead;
+use tokio_tar::Entry;
+
+/// Prefix of the keys of all records.
+const PREFIX: &str = "SCHILY.xattr.user.source.";
+/// Prefix of user metadata below [`PREFIX`].
+const METADATA: &str = "meta.";
+
+/// What is known about the source object of an entry.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub e_tag: Option<String>,
+    /// Hex encoded SHA-256 as S3 recorded it.
+    pub sha256: Option<String>,
+    /// Content headers and user metadata.
+    pub attributes: Attributes,
+    pub tags: Vec<(String, String)>,
+}
+
+/// A PAX extended header record, `<length> <key>=<value>` where the length counts the whole
+/// record, newline included.
+fn record(key: &str, value: &str) -> Vec<u8> {
+    let content = key.len() + value.len() + 3;
+    let mut length = content + content.to_string().len();
+    // A longer length can take another digit.
+    length = content + length.to_string().len();
+    format!("{length} {key}={value}\n").into_bytes()
+}
+
+/// Name of the record of `attribute` below [`PREFIX`], `None` for those not kept, e.g. the
+/// storage class, which is up to the destination.
+fn attribute_name(attribute: &Attribute) -> Option<String> {
+    let name = match attribute {
+        Attribute::ContentType => "content-type",
+        Attribute::ContentEncoding => "content-encoding",
+        Attribute::ContentDisposition => "content-disposition",
+        Attribute::ContentLanguage => "content-language",
+        Attribute::CacheControl => "cache-control",
+        Attribute::Metadata(name) => return Some(format!("{METADATA}{name}")),
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// The attribute recorded under `name`, see [`attribute_name`].
+fn attribute(name: &str) -> Option<Attribute> {
+    Some(match name {
+        "content-type" => Attribute::ContentType,
+        "content-encoding" => Attribute::ContentEncoding,
+        "content-disposition" => Attribute::ContentDisposition,
+        "content-language" => Attribute::ContentLanguage,
+        "cache-control" => Attribute::CacheControl,
+        _ => Attribute::Metadata(name.strip_prefix(METADATA)?.to_string().into()),
+    })
+}
+
+impl Source {
+    /// The records of the PAX extended header, empty when nothing is known.
+    #[must_use]
+    pub fn records(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        let mut push = |name: &str, value: &str| {
+            records.extend(record(&format!("{PREFIX}{name}"), value));
+        };
+        if let Some(e_tag) = &self.e_tag {
+            push("etag", e_tag);
+        }
+        if let Some(sha256) = &self.sha256 {
+            push("sha256", sha256);
+        }
+        // Sorted, as the same object must make the same archive.
+        let mut attributes: Vec<_> = self
+            .attributes
+            .iter()
+            .filter_map(|(attribute, value)| Some((attribute_name(attribute)?, value)))
+            .collect();
+        attributes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, value) in attributes {
+            push(&name, value.as_ref());
+        }
+        if !self.tags.is_empty() {
+            let tags = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&self.tags)
+                .finish();
+            push("tags", &tags);
+        }
+        records
+    }
+
+    /// Reads what the PAX extended header of `entry` records, nothing for entries without
+    /// one, e.g. in archives written before they were recorded.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the header cannot be read.
+    pub async fn read<R: AsyncRead + Unpin>(entry: &mut Entry<R>) -> std::io::Result<Self> {
+        let mut source = Self::default();
+        let Some(extensions) = entry.pax_extensions().await? else {
+            return Ok(source);
+        };
+        for extension in extensions {
+            let extension = extension?;
+            let (Ok(key), Ok(value)) = (extension.key(), extension.value()) else {
+                continue;
+            };
+            let Some(name) = key.strip_prefix(PREFIX) else {
+                continue;
+            };
+            match name {
+                "etag" => source.e_tag = Some(value.to_string()),
+                "sha256" => source.sha256 = Some(value.to_string()),
+                "tags" => {
+                    source.tags = url::form_urlencoded::parse(value.as_bytes())
+                        .into_owned()
+                        .collect();
+                }
+                _ => {
+                    if let Some(attribute) = attribute(name) {
+                        source
+                            .attributes
+                            .insert(attribute, AttributeValue::from(value.to_string()));
+                    }
+                }
+            }
+        }
+        Ok(source)
+    }
+
+    /// The tags, for writing them back.
+    #[must_use]
+    pub fn tag_set(&self) -> TagSet {
+        let mut tags = TagSet::default();
+        for (key, value) in &self.tags {
+            tags.push(key, value);
+        }
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tar::{Builder, EntryType, Header};
+
+    #[test]
+    fn test_record_counts_itself() {
+        assert_eq!(record("a", "b"), b"6 a=b\n");
+        // 9 bytes without the length, 10 with one digit, so it takes two.
+        assert_eq!(record("key", "val"), b"11 key=val\n");
+    }
+
+    #[tokio::test]
+    async fn test_source_round_trip() -> std::io::Result<()> {
+        let source = Source {
+            e_tag: Some("\"abc\"".to_string()),
+            sha256: Some("0".repeat(64)),
+            attributes: Attributes::from_iter([
+                (Attribute::ContentType, "application/json".to_string()),
+                (Attribute::Metadata("team".into()), "a=b & c".to_string()),
+                (Attribute::StorageClass, "GLACIER".to_string()),
+            ]),
+            tags: vec![("state".to_string(), "live & well".to_string())],
+        };
+        let records = source.records();
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_ustar();
+        header.set_path("././@PaxHeader")?;
+        header.set_entry_type(EntryType::XHeader);
+        header.set_size(records.len() as u64);
+        header.set_cksum();
+        builder.append(&header, records.as_slice()).await?;
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        header.set_cksum();
+        builder.append_data(&mut header, "a.json", &[][..]).await?;
+        let tar = builder.into_inner().await?;
+
+        let mut entries = tokio_tar::Archive::new(tar.as_slice()).entries()?;
+        let Some(mut entry) = futures::TryStreamExt::try_next(&mut entries).await? else {
+            return Err(std::io::Error::other("no entry"));
+        };
+        let read = Source::read(&mut entry).await?;
+        // The storage class is up to the destination.
+        let expected = Source {
+            attributes: Attributes::from_iter([
+                (Attribute::ContentType, "application/json".to_string()),
+                (Attribute::Metadata("team".into()), "a=b & c".to_string()),
+            ]),
+            ..source
+        };
+        assert_eq!(read, expected);
+        Ok(())
+    }
+}
@@ -2,8 +2,43 @@ use super::*;
 use chrono::Utc;
 use object_store::memory::InMemory;
 use object_store::path::Path;
+use object_store::{Attribute, ObjectStoreExt};
 use std::sync::Arc;
 
+fn options(codec: Codec) -> CompressOptions {
+    CompressOptions {
+        upload_concurrency: 2,
+        ..CompressOptions::new(1024 * 1024, codec, Level::Fastest)
+    }
+}
+
+#[test]
+fn test_within_memory() -> crate::error::Result<()> {
+    const MIB: usize = 1024 * 1024;
+    let options = CompressOptions::new(100 * MIB, Codec::Xz, Level::Fastest);
+
+    let fitted = options.clone().within_memory(256 * MIB)?;
+    assert_eq!(fitted.upload_concurrency, 1);
+    assert!(fitted.prefetch_size < PREFETCH_MAX_SIZE);
+
+    // Spooling the part being filled leaves room for a second one in flight.
+    let spooled = CompressOptions {
+        spool_dir: Some(std::env::temp_dir()),
+        ..options.clone()
+    }
+    .within_memory(256 * MIB)?;
+    assert_eq!(spooled.upload_concurrency, 2);
+    assert_eq!(spooled.prefetch_size, PREFETCH_MAX_SIZE);
+
+    assert!(options.within_memory(150 * MIB).is_err());
+    assert!(
+        CompressOptions::new(100 * MIB, Codec::Xz, Level::Best)
+            .within_memory(512 * MIB)
+            .is_err()
+    );
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_compress_basic() -> crate::error::Result<()> {
     let src_store = Arc::new(InMemory::new());
@@ -15,20 +50,24 @@ async fn test_compress_basic() -> crate::error::Result<()> {
     src_store.put(&path2, "content2".into()).await?;
 
     let cutoff = Utc::now();
-    let mut processed_keys = Vec::new();
+    let mut processed = Vec::new();
 
     compress(
         src_store.as_ref(),
-        Path::from(""),
+        src_store
+            .list(None)
+            .map_err(AppError::from)
+            .try_filter(|meta| future::ready(meta.last_modified < cutoff))
+            .boxed(),
         dst_store.clone(),
         Path::from("archive.tar.xz"),
-        cutoff,
-        1024 * 1024,
-        Level::Fastest,
-        &mut processed_keys,
+        None,
+        &options(Codec::Xz),
+        &mut processed,
     )
     .await?;
 
+    let processed_keys: Vec<Path> = processed.into_iter().map(|meta| meta.location).collect();
     assert_eq!(processed_keys.len(), 2);
     assert!(processed_keys.contains(&Path::from("file1.txt")));
     assert!(processed_keys.contains(&Path::from("file2.txt")));
@@ -38,3 +77,381 @@ async fn test_compress_basic() -> crate::error::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_fetch_skips_objects_changed_since_listing() -> crate::error::Result<()> {
+    let store = InMemory::new();
+    let path = Path::from("file.txt");
+    store.put(&path, "old".into()).await?;
+    let listed = store.head(&path).await?;
+
+    let options = options(Codec::Gzip);
+    assert!(
+        fetch_object(&store, listed.clone(), None, &options)
+            .await?
+            .is_some()
+    );
+
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    store.put(&path, "newer and longer".into()).await?;
+    assert!(
+        fetch_object(&store, listed, None, &options)
+            .await?
+            .is_none()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_tolerates_failures_within_budget() -> crate::error::Result<()> {
+    let store = InMemory::new();
+    let path = Path::from("gone.txt");
+    store.put(&path, "soon deleted".into()).await?;
+    let listed = store.head(&path).await?;
+    store.delete(&path).await?;
+
+    let options = CompressOptions {
+        errors: ErrorBudget::new(1),
+        ..options(Codec::Gzip)
+    };
+    assert!(
+        fetch_object(&store, listed.clone(), None, &options)
+            .await?
+            .is_none()
+    );
+    assert_eq!(options.errors.failed(), 1);
+    assert!(matches!(
+        fetch_object(&store, listed, None, &options).await,
+        Err(AppError::SafetyLimit(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_pipes_through_filter_cmd() -> crate::error::Result<()> {
+    let store = InMemory::new();
+    let path = Path::from("users/alice.csv");
+    store.put(&path, "alice,alice@example.com\n".into()).await?;
+    let listed = store.head(&path).await?;
+
+    let options = CompressOptions {
+        filter_cmd: Some(FilterCommand::new(
+            r##"sed "s/[^,]*@[^,]*/<redacted>/"; echo "# $OBJECT_KEY""##,
+        )),
+        errors: ErrorBudget::new(1),
+        ..options(Codec::Gzip)
+    };
+    let Some(Fetched { size, body, .. }) =
+        fetch_object(&store, listed.clone(), None, &options).await?
+    else {
+        return Err(AppError::Archive("not fetched".to_string()));
+    };
+    let filtered: Vec<Bytes> = body.try_collect().await?;
+    assert_eq!(
+        filtered.concat(),
+        b"alice,<redacted>\n# users/alice.csv\n".to_vec()
+    );
+    assert_eq!(size, filtered.concat().len() as u64);
+
+    // A failing command fails the object, within the error budget.
+    let options = CompressOptions {
+        filter_cmd: Some(FilterCommand::new("cat > /dev/null; exit 3")),
+        ..options
+    };
+    assert!(
+        fetch_object(&store, listed, None, &options)
+            .await?
+            .is_none()
+    );
+    assert_eq!(options.errors.failed(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compress_round_trip() -> crate::error::Result<()> {
+    for spool_dir in [None, Some(std::env::temp_dir())] {
+        let restored = compress_and_restore(spool_dir).await?;
+        assert_eq!(restored, "x".repeat(3 * PIPE_CAPACITY));
+    }
+    Ok(())
+}
+
+async fn compress_and_restore(
+    spool_dir: Option<std::path::PathBuf>,
+) -> crate::error::Result<String> {
+    use futures::TryStreamExt;
+    use object_store::ObjectStoreExt;
+    use tokio::io::AsyncReadExt;
+
+    let src_store = Arc::new(InMemory::new());
+    let dst_store = Arc::new(InMemory::new());
+    let content = "x".repeat(3 * PIPE_CAPACITY);
+    src_store
+        .put(&Path::from("big.log"), content.into())
+        .await?;
+
+    compress(
+        src_store.as_ref(),
+        src_store.list(None).map_err(AppError::from).boxed(),
+        dst_store.clone(),
+        Path::from("archive.tar.gz"),
+        None,
+        &CompressOptions {
+            spool_dir: spool_dir.clone(),
+            ..options(Codec::Gzip)
+        },
+        &mut Vec::new(),
+    )
+    .await?;
+
+    let archive = dst_store.get(&Path::from("archive.tar.gz")).await?;
+    let reader = tokio_util::io::StreamReader::new(archive.into_stream());
+    let mut entries = tokio_tar::Archive::new(Codec::Gzip.decoder(reader)).entries()?;
+    let mut entry = entries
+        .try_next()
+        .await?
+        .ok_or_else(|| crate::error::AppError::Archive("archive has no entries".to_string()))?;
+    let mut restored = String::new();
+    entry.read_to_string(&mut restored).await?;
+
+    assert_eq!(entry.path()?.to_string_lossy(), "big.log");
+    Ok(restored)
+}
+
+#[tokio::test]
+async fn test_compress_stores_precompressed_entries() -> crate::error::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    // Compressible, so it only takes up space when stored, and told apart by its magic bytes
+    // alone.
+    let mut photo = b"\xff\xd8\xff".to_vec();
+    photo.extend("jpeg".repeat(512 * 1024).as_bytes());
+    let src_store = Arc::new(InMemory::new());
+    src_store
+        .put(&Path::from("a.log"), "line\n".repeat(1000).into())
+        .await?;
+    src_store
+        .put(&Path::from("b.bin"), photo.clone().into())
+        .await?;
+    src_store
+        .put(&Path::from("c.log"), "line\n".repeat(1000).into())
+        .await?;
+
+    for codec in [Codec::Gzip, Codec::Zstd] {
+        let dst_store = Arc::new(InMemory::new());
+        let index = compress(
+            src_store.as_ref(),
+            src_store.list(None).map_err(AppError::from).boxed(),
+            dst_store.clone(),
+            Path::from("archive"),
+            None,
+            &CompressOptions {
+                store_precompressed: true,
+                ..options(codec)
+            },
+            &mut Vec::new(),
+        )
+        .await?;
+
+        // The photo has a frame of its own, stored rather than compressed.
+        let starts: Vec<u64> = index.frames.iter().map(|frame| frame.tar_offset).collect();
+        assert_eq!(starts, [0, index.entries[1], index.entries[2]], "{codec:?}");
+        assert!(index.frames[2].offset - index.frames[1].offset > photo.len() as u64);
+        assert!(index.frames[1].offset < 1024);
+
+        let archive = dst_store.get(&Path::from("archive")).await?;
+        let reader = tokio_util::io::StreamReader::new(archive.into_stream());
+        let mut entries = tokio_tar::Archive::new(codec.decoder(reader)).entries()?;
+        let mut restored = Vec::new();
+        while let Some(mut entry) = entries.try_next().await? {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).await?;
+            restored.push(content);
+        }
+        assert_eq!(restored[1], photo, "{codec:?}");
+        assert_eq!(restored[2].len(), 5000);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sums_line_escapes_names() {
+    assert_eq!(sums_line("ab", "logs/a.log"), "ab  logs/a.log\n");
+    assert_eq!(sums_line("ab", "a\\b\nc"), "\\ab  a\\\\b\\nc\n");
+}
+
+#[tokio::test]
+async fn test_compress_records_e_tags_and_sums() -> crate::error::Result<()> {
+    let src_store = Arc::new(InMemory::new());
+    let dst_store = Arc::new(InMemory::new());
+    src_store.put(&Path::from("a.log"), "line".into()).await?;
+    let e_tag = src_store.head(&Path::from("a.log")).await?.e_tag;
+
+    compress(
+        src_store.as_ref(),
+        src_store.list(None).map_err(AppError::from).boxed(),
+        dst_store.clone(),
+        Path::from("archive.tar.gz"),
+        None,
+        &options(Codec::Gzip),
+        &mut Vec::new(),
+    )
+    .await?;
+
+    let archive = dst_store.get(&Path::from("archive.tar.gz")).await?;
+    let reader = tokio_util::io::StreamReader::new(archive.into_stream());
+    let mut entries = tokio_tar::Archive::new(Codec::Gzip.decoder(reader)).entries()?;
+    let Some(mut entry) = entries.try_next().await? else {
+        return Err(AppError::Archive("no entry".to_string()));
+    };
+    assert_eq!(entry.path()?.as_ref(), std::path::Path::new("a.log"));
+    let recorded = Source::read(&mut entry).await?.e_tag;
+    assert_eq!(recorded, e_tag);
+
+    // The sums come last.
+    let Some(mut sums) = entries.try_next().await? else {
+        return Err(AppError::Archive("no sums".to_string()));
+    };
+    assert_eq!(sums.path()?.as_ref(), std::path::Path::new(SUMS_NAME));
+    let mut content = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut sums, &mut content).await?;
+    assert_eq!(
+        content,
+        format!("{}  a.log\n", hex::encode(Sha256::digest("line")))
+    );
+    assert!(entries.try_next().await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compress_is_reproducible() -> crate::error::Result<()> {
+    // Resuming an upload relies on the same objects making the same archive.
+    let src_store = Arc::new(InMemory::new());
+    let dst_store = Arc::new(InMemory::new());
+    let attributes = Attributes::from_iter([
+        (Attribute::ContentType, "text/plain"),
+        (Attribute::CacheControl, "no-cache"),
+        (Attribute::Metadata("team".into()), "payments"),
+        (Attribute::Metadata("owner".into()), "ops"),
+    ]);
+    for key in ["a.log", "b.log"] {
+        let options = object_store::PutOptions::from(attributes.clone());
+        src_store
+            .put_opts(&Path::from(key), "line".into(), options)
+            .await?;
+    }
+
+    let mut archives = Vec::new();
+    for name in ["first.tar.zst", "second.tar.zst"] {
+        compress(
+            src_store.as_ref(),
+            src_store.list(None).map_err(AppError::from).boxed(),
+            dst_store.clone(),
+            Path::from(name),
+            None,
+            &options(Codec::Zstd),
+            &mut Vec::new(),
+        )
+        .await?;
+        archives.push(dst_store.get(&Path::from(name)).await?.bytes().await?);
+    }
+    assert_eq!(archives[0], archives[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipe_streams_through_command() -> crate::error::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let input = std::io::Cursor::new(b"compressed archive".to_vec());
+    let mut upper = std::process::Command::new("tr");
+    upper.args(["a-z", "A-Z"]);
+    let (mut output, waiting) = command::pipe(upper, input)?;
+    let mut piped = String::new();
+    output.read_to_string(&mut piped).await?;
+    waiting.await.map_err(std::io::Error::other)??;
+    assert_eq!(piped, "COMPRESSED ARCHIVE");
+
+    let mut failing = std::process::Command::new("sh");
+    failing.args(["-c", "cat > /dev/null; exit 2"]);
+    let (mut output, waiting) = command::pipe(failing, std::io::Cursor::new(vec![0; 10]))?;
+    output.read_to_end(&mut Vec::new()).await?;
+    assert!(waiting.await.map_err(std::io::Error::other)?.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_aborts_without_commit() -> crate::error::Result<()> {
+    let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+    let location = Path::from("archive.tar.xz");
+
+    let options = CompressOptions::new(1024, Codec::Xz, Level::Fastest);
+    for (commit, parted) in [(false, false), (true, false), (false, true), (true, true)] {
+        let (sent, committed) = oneshot::channel();
+        sent.send(commit).ok();
+        let sink = if parted {
+            Sink::Parted(PartedUpload::start(store.as_ref(), location.clone(), 1024, 1).await?)
+        } else {
+            Sink::Buffered(BufWriter::with_capacity(
+                store.clone(),
+                location.clone(),
+                1024,
+            ))
+        };
+        upload(&b"partial"[..], sink, &options, None, committed).await?;
+
+        assert_eq!(store.head(&location).await.is_ok(), commit);
+        store.delete(&location).await.ok();
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compress_each_keeps_key_and_attributes() -> crate::error::Result<()> {
+    use object_store::{Attribute, Attributes, PutOptions};
+    use tokio::io::AsyncReadExt;
+
+    let src_store = Arc::new(InMemory::new());
+    let dst_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+    let attributes =
+        Attributes::from_iter([(Attribute::Metadata("origin".into()), "app".to_string())]);
+    src_store
+        .put_opts(
+            &Path::from("logs/a.log"),
+            "line".into(),
+            PutOptions::from(attributes),
+        )
+        .await?;
+
+    let mut processed = Vec::new();
+    compress_each(
+        src_store.as_ref(),
+        src_store.list(None).map_err(AppError::from).boxed(),
+        &dst_store,
+        &Path::from("archive"),
+        None,
+        &options(Codec::Zstd),
+        &mut processed,
+    )
+    .await?;
+    assert_eq!(processed.len(), 1);
+
+    let compressed = dst_store.get(&Path::from("archive/logs/a.log.zst")).await?;
+    assert_eq!(
+        compressed
+            .attributes
+            .get(&Attribute::Metadata("origin".into())),
+        Some(&"app".into())
+    );
+    assert_eq!(
+        compressed.attributes.get(&Attribute::ContentType),
+        Some(&"application/zstd".into())
+    );
+    let mut restored = String::new();
+    Codec::Zstd
+        .decoder(StreamReader::new(compressed.into_stream()))
+        .read_to_string(&mut restored)
+        .await?;
+    assert_eq!(restored, "line");
+    Ok(())
+}
@@ -0,0 +1,577 @@
+//! Named archive jobs loaded from a TOML file, for fleets of buckets that don't fit on a
+//! command line. The file also holds the retention [`rules`](crate::rules).
+//!
+//! ```toml
+//! [endpoints.minio]
+//! endpoint = "http://localhost:9000"
+//! region = "us-east-1"
+//! access_key_id_env = "MINIO_ACCESS_KEY"
+//! secret_access_key_env = "MINIO_SECRET_KEY"
+//! allow_http = true
+//!
+//! [jobs.nightly-logs]
+//! endpoint = "minio"
+//! src = "s3://project/logs/"
+//! dst = "s3://archive/logs/"
+//! older_than_days = 30
+//! include = ["**/*.log"]
+//! codec = "zstd"
+//! ```
+
+use crate::codec::{Codec, Compression};
+use crate::commands::{
+    ArchiveJob, DeleteVerification, Disposal, EntryMode, GroupBy, GroupDate, KeyRewrite, Order,
+};
+use crate::error::{AppError, Result};
+use crate::filter::{ObjectFilter, build_etags, build_globset, parse_metadata};
+use crate::output::info;
+use crate::progress;
+use crate::rules::{self, Rule};
+use crate::s3::{BucketEncryption, CannedAcl, ListApi, NewBucket};
+use crate::storage::use_s3_endpoint;
+use crate::usage::Prices;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub endpoints: BTreeMap<String, Endpoint>,
+    #[serde(default)]
+    pub jobs: BTreeMap<String, JobConfig>,
+    /// Retention rules, applied in order by [`Config::apply_rules`].
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Prices the runs of all jobs are reported with.
+    #[serde(default)]
+    pub prices: Prices,
+}
+
+/// An S3 compatible endpoint. Credentials are referenced by the name of the environment
+/// variable holding them, so the file itself can be checked in.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id_env: Option<String>,
+    pub secret_access_key_env: Option<String>,
+    #[serde(default)]
+    pub allow_http: bool,
+    /// `v1` for gateways that only page through `ListObjects` correctly.
+    pub list_api: Option<ListApi>,
+    /// Send requests unsigned, for public buckets.
+    #[serde(default)]
+    pub no_sign_request: bool,
+}
+
+/// Settings of a single `archive` run, named like the command line flags.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)] // One per switch of `archive`.
+pub struct JobConfig {
+    pub src: String,
+    /// More sources in the same bucket as `src`.
+    #[serde(default)]
+    pub extra_src: Vec<String>,
+    #[serde(default)]
+    pub archive_per_src: bool,
+    pub dst: String,
+    /// Name of the entry in `endpoints` used for `s3://` URLs.
+    pub endpoint: Option<String>,
+    pub cutoff: Option<DateTime<Utc>>,
+    /// Relative alternative to `cutoff`, evaluated when the job starts.
+    pub older_than_days: Option<u32>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub exclude_prefix: Vec<String>,
+    #[serde(default)]
+    pub exclude_suffix: Vec<String>,
+    #[serde(default)]
+    pub etag: Vec<String>,
+    pub etag_file: Option<PathBuf>,
+    #[serde(default)]
+    pub exclude_etag: Vec<String>,
+    pub exclude_etag_file: Option<PathBuf>,
+    #[serde(alias = "buffer")]
+    pub part_size: Option<usize>,
+    pub io_buffer: Option<usize>,
+    pub upload_concurrency: Option<usize>,
+    pub delete_concurrency: Option<usize>,
+    pub spool_dir: Option<PathBuf>,
+    pub max_memory: Option<usize>,
+    pub codec: Option<Codec>,
+    pub compression: Option<Compression>,
+    pub name_template: Option<String>,
+    pub strip_prefix: Option<String>,
+    #[serde(default)]
+    pub rewrite: Vec<KeyRewrite>,
+    pub group_by: Option<GroupBy>,
+    pub group_date: Option<GroupDate>,
+    pub target_archive_size: Option<u64>,
+    pub max_entries_per_archive: Option<NonZeroUsize>,
+    pub order: Option<Order>,
+    pub entry_mode: Option<EntryMode>,
+    pub base_manifest: Option<String>,
+    pub trash_prefix: Option<String>,
+    pub mark_instead_of_delete: Option<String>,
+    pub emit_batch_manifest: Option<String>,
+    pub delete_verification: Option<DeleteVerification>,
+    pub dst_acl: Option<CannedAcl>,
+    #[serde(default)]
+    pub accelerate: bool,
+    #[serde(default)]
+    pub create_dst_bucket: bool,
+    #[serde(default)]
+    pub dst_bucket_versioning: bool,
+    pub dst_bucket_encryption: Option<BucketEncryption>,
+    pub dst_bucket_kms_key_id: Option<String>,
+    #[serde(default)]
+    pub skip_preflight: bool,
+    #[serde(default)]
+    pub upload_summary: bool,
+    #[serde(default)]
+    pub audit_log: bool,
+    /// Environment variable holding the Slack incoming webhook URL, a secret like credentials.
+    pub notify_slack_webhook_env: Option<String>,
+    pub resume_cursor: Option<String>,
+    pub save_cursor: Option<String>,
+    pub max_objects: Option<usize>,
+    #[serde(default)]
+    pub max_errors: usize,
+    pub skip_list: Option<String>,
+    #[serde(default)]
+    pub retry_skipped: bool,
+    pub keys_from: Option<String>,
+    #[serde(default)]
+    pub filter_metadata: Vec<String>,
+    #[serde(default)]
+    pub owner_id: Vec<String>,
+    pub max_depth: Option<NonZeroUsize>,
+    pub filter_cmd: Option<String>,
+    pub plugin: Option<PathBuf>,
+    #[serde(default)]
+    pub gpg_recipient: Vec<String>,
+    pub zstd_dict: Option<String>,
+    #[serde(default)]
+    pub source_checksums: bool,
+    #[serde(default)]
+    pub preserve_tags: bool,
+    pub upload_state: Option<String>,
+    pub flush_interval: Option<u64>,
+    pub progress_every: Option<u64>,
+    pub jobs: Option<usize>,
+}
+
+impl JobConfig {
+    /// The cutoff the job sets, absolute or relative to now.
+    fn cutoff(&self, name: &str) -> Result<Option<DateTime<Utc>>> {
+        match (self.cutoff, self.older_than_days) {
+            (Some(_), Some(_)) => Err(AppError::Config(format!(
+                "job '{name}' sets both cutoff and older_than_days"
+            ))),
+            (cutoff, None) => Ok(cutoff),
+            (None, Some(days)) => Ok(Some(Utc::now() - Duration::days(i64::from(days)))),
+        }
+    }
+
+    /// The objects the job selects.
+    fn filter(&self, name: &str) -> Result<ObjectFilter> {
+        let filter = ObjectFilter {
+            cutoff: self.cutoff(name)?,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            include: build_globset(&self.include)?,
+            exclude: build_globset(&self.exclude)?,
+            exclude_prefixes: self.exclude_prefix.clone(),
+            exclude_suffixes: self.exclude_suffix.clone(),
+            etags: build_etags(&self.etag, self.etag_file.as_deref())?,
+            exclude_etags: build_etags(&self.exclude_etag, self.exclude_etag_file.as_deref())?,
+            metadata: parse_metadata(&self.filter_metadata)?,
+            max_depth: self.max_depth.map(NonZeroUsize::get),
+            ..ObjectFilter::default()
+        };
+        match &self.plugin {
+            Some(path) => filter.with_plugin(path),
+            None => Ok(filter),
+        }
+    }
+
+    /// What happens to the archived objects, of which the job sets at most one.
+    fn disposal(&self, name: &str) -> Result<Disposal> {
+        match (
+            &self.trash_prefix,
+            &self.mark_instead_of_delete,
+            &self.emit_batch_manifest,
+        ) {
+            (Some(trash), None, None) => Ok(Disposal::Trash(trash.clone())),
+            (None, Some(tag), None) => Ok(Disposal::Mark(tag.clone())),
+            (None, None, Some(url)) => Ok(Disposal::BatchManifest(url.clone())),
+            (None, None, None) => Ok(Disposal::Delete),
+            _ => Err(AppError::Config(format!(
+                "job '{name}' sets more than one of trash_prefix, mark_instead_of_delete and \
+                 emit_batch_manifest"
+            ))),
+        }
+    }
+}
+
+impl Config {
+    /// # Errors
+    ///
+    /// Fails when the file cannot be read or is not a valid configuration.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AppError::Config(format!("cannot read {}: {e}", path.display())))?;
+        content.parse()
+    }
+
+    fn job(&self, name: &str) -> Result<&JobConfig> {
+        self.jobs.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.jobs.keys().map(String::as_str).collect();
+            AppError::Config(format!(
+                "no job named '{name}', known jobs: {}",
+                known.join(", ")
+            ))
+        })
+    }
+
+    /// Object store options of the endpoint called `name`, with credentials resolved.
+    fn endpoint_options(&self, name: &str) -> Result<Vec<(String, String)>> {
+        let endpoint = self
+            .endpoints
+            .get(name)
+            .ok_or_else(|| AppError::Config(format!("no endpoint named '{name}'")))?;
+
+        let mut options = Vec::new();
+        for (key, value) in [
+            ("endpoint", endpoint.endpoint.clone()),
+            ("region", endpoint.region.clone()),
+        ] {
+            options.extend(value.map(|value| (key.to_string(), value)));
+        }
+        for (key, env_var) in [
+            ("access_key_id", &endpoint.access_key_id_env),
+            ("secret_access_key", &endpoint.secret_access_key_env),
+        ] {
+            if let Some(env_var) = env_var {
+                let value = std::env::var(env_var).map_err(|_| {
+                    AppError::Config(format!(
+                        "endpoint '{name}' reads {key} from {env_var}, which is not set"
+                    ))
+                })?;
+                options.push((key.to_string(), value));
+            }
+        }
+        if endpoint.allow_http {
+            options.push(("allow_http".to_string(), "true".to_string()));
+        }
+        if endpoint.no_sign_request {
+            options.push(("skip_signature".to_string(), "true".to_string()));
+        }
+        if let Some(list_api) = endpoint.list_api {
+            options.push((ListApi::OPTION.to_string(), list_api.as_str().to_string()));
+        }
+        Ok(options)
+    }
+
+    /// The archive run configured as job `name`.
+    ///
+    /// # Errors
+    ///
+    /// Fails for unknown jobs, conflicting settings or invalid glob patterns.
+    pub fn archive_job(&self, name: &str) -> Result<ArchiveJob> {
+        let job = self.job(name)?;
+        let filter = job.filter(name)?;
+        let disposal = job.disposal(name)?;
+
+        let new_bucket = NewBucket {
+            versioning: job.dst_bucket_versioning,
+            encryption: job.dst_bucket_encryption,
+            kms_key_id: job.dst_bucket_kms_key_id.clone(),
+        };
+        if !job.create_dst_bucket && new_bucket != NewBucket::default() {
+            return Err(AppError::Config(format!(
+                "job '{name}' sets dst_bucket_* settings without create_dst_bucket"
+            )));
+        }
+
+        let notify_slack_webhook = match &job.notify_slack_webhook_env {
+            Some(env_var) => Some(std::env::var(env_var).map_err(|_| {
+                AppError::Config(format!(
+                    "job '{name}' reads the Slack webhook from {env_var}, which is not set"
+                ))
+            })?),
+            None => None,
+        };
+
+        let defaults = ArchiveJob::new(&job.src, &job.dst);
+        Ok(ArchiveJob {
+            name: Some(name.to_string()),
+            extra_src: job.extra_src.clone(),
+            archive_per_src: job.archive_per_src,
+            jobs: job.jobs.unwrap_or(defaults.jobs),
+            filter,
+            buffer_size: job.part_size.unwrap_or(defaults.buffer_size),
+            io_buffer: job.io_buffer.unwrap_or(defaults.io_buffer),
+            upload_concurrency: job
+                .upload_concurrency
+                .unwrap_or(defaults.upload_concurrency),
+            delete_concurrency: job
+                .delete_concurrency
+                .unwrap_or(defaults.delete_concurrency),
+            codec: job.codec.unwrap_or(defaults.codec),
+            level: job.compression.map_or(defaults.level, Compression::level),
+            store_precompressed: job.compression == Some(Compression::Auto),
+            name_template: job.name_template.clone().unwrap_or(defaults.name_template),
+            rewrites: job
+                .rewrite
+                .iter()
+                .cloned()
+                .chain(job.strip_prefix.clone().map(KeyRewrite::strip))
+                .collect(),
+            group_by: job.group_by,
+            group_date: job.group_date.unwrap_or(defaults.group_date),
+            target_archive_size: job.target_archive_size,
+            max_entries_per_archive: job.max_entries_per_archive.map(NonZeroUsize::get),
+            order: job.order.unwrap_or(defaults.order),
+            entry_mode: job.entry_mode.unwrap_or(defaults.entry_mode),
+            base_manifest: job.base_manifest.clone(),
+            disposal,
+            delete_verification: job
+                .delete_verification
+                .unwrap_or(defaults.delete_verification),
+            dst_acl: job.dst_acl,
+            accelerate: job.accelerate,
+            create_dst_bucket: job.create_dst_bucket.then_some(new_bucket),
+            preflight: !job.skip_preflight,
+            upload_summary: job.upload_summary,
+            audit_log: job.audit_log,
+            notify_slack_webhook,
+            resume_cursor: job.resume_cursor.clone(),
+            save_cursor: job.save_cursor.clone(),
+            max_objects: job.max_objects,
+            max_errors: job.max_errors,
+            skip_list: job.skip_list.clone(),
+            retry_skipped: job.retry_skipped,
+            keys_from: job.keys_from.clone(),
+            owner_ids: job.owner_id.clone(),
+            filter_cmd: job.filter_cmd.clone(),
+            gpg_recipients: job.gpg_recipient.clone(),
+            zstd_dict: job.zstd_dict.clone(),
+            source_checksums: job.source_checksums,
+            preserve_tags: job.preserve_tags,
+            upload_state: job.upload_state.clone(),
+            flush_interval: job.flush_interval.map(std::time::Duration::from_secs),
+            progress_every: job.progress_every.map(progress::interval).transpose()?,
+            spool_dir: job.spool_dir.clone(),
+            max_memory: job.max_memory,
+            prices: self.prices.clone(),
+            ..defaults
+        })
+    }
+
+    /// Runs job `name`, pointing `s3://` URLs at its endpoint; environment variables and
+    /// [`override_s3_options`](crate::storage::override_s3_options) still take precedence over
+    /// its settings. Only one job with an endpoint can run per process, as the endpoint
+    /// applies process wide. With `confirm`, deleting the archived sources is confirmed on the
+    /// terminal first.
+    ///
+    /// # Errors
+    ///
+    /// See [`Config::archive_job`] and [`ArchiveJob::run`]; also fails when a referenced
+    /// credential variable is not set.
+    pub async fn run(&self, name: &str, confirm: bool) -> Result<()> {
+        let job = ArchiveJob {
+            confirm,
+            ..self.archive_job(name)?
+        };
+        if let Some(endpoint) = &self.job(name)?.endpoint {
+            use_s3_endpoint(self.endpoint_options(endpoint)?)?;
+        }
+        info!("Running job {name}");
+        job.run().await
+    }
+
+    /// Applies all retention rules, see [`rules::apply`]. As with [`Config::run`], only one
+    /// endpoint applies per process, so the rules must not name different ones.
+    ///
+    /// # Errors
+    ///
+    /// Fails when rules name different endpoints, a referenced credential variable is not
+    /// set, or a rule fails.
+    pub async fn apply_rules(&self, confirm: bool) -> Result<()> {
+        let mut endpoints: Vec<&String> = self
+            .rules
+            .iter()
+            .filter_map(|rule| rule.endpoint.as_ref())
+            .collect();
+        endpoints.dedup();
+        match endpoints.as_slice() {
+            [] => {}
+            [endpoint] => use_s3_endpoint(self.endpoint_options(endpoint)?)?,
+            _ => {
+                return Err(AppError::Config(
+                    "rules must not use different endpoints".to_string(),
+                ));
+            }
+        }
+        rules::apply(&self.rules, &self.prices, confirm).await
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = AppError;
+
+    fn from_str(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        for (name, job) in &config.jobs {
+            if let Some(endpoint) = &job.endpoint
+                && !config.endpoints.contains_key(endpoint)
+            {
+                return Err(AppError::Config(format!(
+                    "job '{name}' uses unknown endpoint '{endpoint}'"
+                )));
+            }
+        }
+        for rule in &config.rules {
+            rule.validate()?;
+            if let Some(endpoint) = &rule.endpoint
+                && !config.endpoints.contains_key(endpoint)
+            {
+                return Err(AppError::Config(format!(
+                    "rule '{}' uses unknown endpoint '{endpoint}'",
+                    rule.name()
+                )));
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[endpoints.minio]
+endpoint = "http://localhost:9000"
+allow_http = true
+list_api = "v1"
+
+[jobs.nightly-logs]
+endpoint = "minio"
+src = "s3://project/logs/"
+dst = "s3://archive/logs/"
+older_than_days = 30
+include = ["**/*.log"]
+codec = "zstd"
+compression = "best"
+dst_acl = "bucket-owner-full-control"
+rewrite = ["logs/2024/=2024/"]
+strip_prefix = "logs/"
+create_dst_bucket = true
+dst_bucket_encryption = "sse-s3"
+"#;
+
+    #[test]
+    fn test_archive_job_from_config() -> Result<()> {
+        let config: Config = CONFIG.parse()?;
+        let job = config.archive_job("nightly-logs")?;
+
+        assert_eq!(job.src, "s3://project/logs/");
+        assert_eq!(job.codec, Codec::Zstd);
+        assert_eq!(
+            job.rewrites,
+            vec![
+                KeyRewrite {
+                    from: "logs/2024/".to_string(),
+                    to: "2024/".to_string(),
+                },
+                KeyRewrite::strip("logs/"),
+            ]
+        );
+        assert!(matches!(job.disposal, Disposal::Delete));
+        assert!(matches!(
+            job.dst_acl,
+            Some(CannedAcl::BucketOwnerFullControl)
+        ));
+        assert_eq!(
+            job.create_dst_bucket,
+            Some(NewBucket {
+                encryption: Some(BucketEncryption::SseS3),
+                ..NewBucket::default()
+            })
+        );
+        assert!(
+            job.filter
+                .cutoff
+                .is_some_and(|cutoff| cutoff < Utc::now() - Duration::days(29))
+        );
+        assert_eq!(
+            config.endpoint_options("minio")?,
+            vec![
+                ("endpoint".to_string(), "http://localhost:9000".to_string()),
+                ("allow_http".to_string(), "true".to_string()),
+                ("list_api".to_string(), "v1".to_string()),
+            ]
+        );
+        assert!(config.archive_job("weekly").is_err());
+
+        let config: Config = CONFIG.replace("create_dst_bucket = true", "").parse()?;
+        assert!(config.archive_job("nightly-logs").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_job_reads_flush_interval() -> Result<()> {
+        let config: Config =
+            "[jobs.slow]\nsrc = \"s3://a/\"\ndst = \"s3://b/\"\nflush_interval = 90".parse()?;
+        let job = config.archive_job("slow")?;
+        assert_eq!(job.flush_interval, Some(std::time::Duration::from_secs(90)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_rejects_unknown_endpoint() {
+        let config = CONFIG.replace("endpoint = \"minio\"", "endpoint = \"ceph\"");
+        assert!(config.parse::<Config>().is_err());
+        assert!(
+            "[jobs.a]\nsrc = \"s3://a/\"\ndst = \"s3://b/\"\nolder_than = 3"
+                .parse::<Config>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_config_validates_rules() -> Result<()> {
+        let rules = format!(
+            "{CONFIG}\n[[rules]]\nprefix = \"s3://project/tmp/\"\nolder_than_days = 7\n\
+             action = \"expire\"\nendpoint = \"minio\"\n"
+        );
+        let config: Config = rules.parse()?;
+        assert_eq!(config.rules.len(), 1);
+        assert!(
+            rules
+                .replace("\"minio\"\n", "\"ceph\"\n")
+                .parse::<Config>()
+                .is_err()
+        );
+        assert!(
+            format!("{rules}storage_class = \"GLACIER\"\n")
+                .parse::<Config>()
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//! Zstandard dictionaries: trained on a sample of similar objects, e.g. small JSON documents
+//! or log lines, they let each frame start out knowing their common strings, which markedly
+//! improves ratios on inputs too small to build their own context.
+
+use crate::error::{AppError, Result};
+use crate::storage::get_store_and_path;
+use async_compression::Level;
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use bytes::Bytes;
+use object_store::ObjectStoreExt;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+/// A dictionary stored at `url`, which frames compressed with it need to be decompressed.
+#[derive(Debug, Clone)]
+pub struct ZstdDictionary {
+    url: String,
+    bytes: Bytes,
+}
+
+impl ZstdDictionary {
+    /// Reads the dictionary at `url`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when it cannot be read or is empty.
+    pub async fn load(url: &str) -> Result<Self> {
+        let (store, path) = get_store_and_path(url)?;
+        let bytes = store.get(&path).await?.bytes().await?;
+        if bytes.is_empty() {
+            return Err(AppError::Config(format!(
+                "the zstd dictionary {url} is empty"
+            )));
+        }
+        Ok(Self {
+            url: url.to_string(),
+            bytes,
+        })
+    }
+
+    #[must_use]
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Shutting the encoder down finishes the frame and shuts `writer` down as well.
+    ///
+    /// # Errors
+    ///
+    /// Fails when zstd rejects the dictionary.
+    pub fn encoder<'a, W>(
+        &self,
+        writer: W,
+        level: Level,
+    ) -> Result<Box<dyn AsyncWrite + Unpin + Send + 'a>>
+    where
+        W: AsyncWrite + Unpin + Send + 'a,
+    {
+        Ok(Box::new(ZstdEncoder::with_dict(
+            writer,
+            level,
+            &self.bytes,
+        )?))
+    }
+
+    /// Decoder of a series of frames compressed with the dictionary.
+    ///
+    /// # Errors
+    ///
+    /// Fails when zstd rejects the dictionary.
+    pub fn decoder<R>(&self, reader: R) -> Result<Box<dyn AsyncRead + Unpin + Send>>
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+    {
+        let mut decoder = ZstdDecoder::with_dict(reader, &self.bytes)?;
+        decoder.multiple_members(true);
+        Ok(Box::new(decoder))
+    }
+}
+
+/// Trains a dictionary of up to `max_size` bytes on `samples`, each one object.
+///
+/// # Errors
+///
+/// Fails when zstd cannot train one, e.g. as there are too few or too small samples.
+pub fn train(samples: &[Bytes], max_size: usize) -> Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size).map_err(|e| {
+        AppError::Config(format!(
+            "cannot train a zstd dictionary on {} samples: {e}",
+            samples.len()
+        ))
+    })
+}
+
+/// Sizes of `samples` compressed one by one at the default level of zstd, with `dictionary`
+/// and without any, to tell what it gains.
+///
+/// # Errors
+///
+/// Fails when zstd rejects the dictionary.
+pub fn measure(samples: &[Bytes], dictionary: &[u8]) -> Result<(u64, u64)> {
+    let mut with =
+        zstd::bulk::Compressor::with_dictionary(zstd::DEFAULT_COMPRESSION_LEVEL, dictionary)?;
+    let mut without = zstd::bulk::Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    let (mut with_size, mut without_size) = (0, 0);
+    for sample in samples {
+        with_size += with.compress(sample)?.len() as u64;
+        without_size += without.compress(sample)?.len() as u64;
+    }
+    Ok((with_size, without_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn compressed(sample: &[u8], dictionary: Option<&ZstdDictionary>) -> Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        let mut encoder = match dictionary {
+            Some(dictionary) => dictionary.encoder(&mut compressed, Level::Default)?,
+            None => Codec::Zstd.encoder(&mut compressed, Level::Default),
+        };
+        encoder.write_all(sample).await?;
+        encoder.shutdown().await?;
+        drop(encoder);
+        Ok(compressed)
+    }
+
+    #[tokio::test]
+    async fn test_trained_dictionary_round_trip() -> Result<()> {
+        let samples: Vec<Bytes> = (0..500)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{i},"level":"info","service":"checkout","message":"order {i} paid"}}"#
+                )
+                .into()
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("osm-dict-{}", std::process::id()));
+        std::fs::write(&path, train(&samples, 4096)?)?;
+        let dictionary = ZstdDictionary::load(&format!("file://{}", path.display())).await;
+        std::fs::remove_file(&path)?;
+        let dictionary = dictionary?;
+        let (with, without) = measure(&samples, &dictionary.bytes)?;
+        assert!(with < without, "{with} >= {without}");
+
+        let with = compressed(&samples[42], Some(&dictionary)).await?;
+        let without = compressed(&samples[42], None).await?;
+        assert!(
+            with.len() < without.len(),
+            "{} >= {}",
+            with.len(),
+            without.len()
+        );
+
+        let mut decoded = Vec::new();
+        dictionary
+            .decoder(std::io::Cursor::new(with))?
+            .read_to_end(&mut decoded)
+            .await?;
+        assert_eq!(decoded, samples[42]);
+        Ok(())
+    }
+}
@@ -11,14 +11,71 @@ pub enum AppError {
     #[error("Object store error: {0}")]
     ObjectStore(#[from] object_store::Error),
 
+    #[error("HTTP error: {0}")]
+    Http(#[from] object_store::client::HttpError),
+
+    #[error("HTTP request error: {0}")]
+    HttpRequest(#[from] http::Error),
+
+    #[error("Invalid header value: {0}")]
+    HeaderValue(#[from] http::header::InvalidHeaderValue),
+
+    #[error("S3 API error: {0}")]
+    S3Api(String),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    #[error("TOML error: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Glob pattern error: {0}")]
+    Glob(#[from] globset::Error),
+
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    #[cfg(feature = "parquet")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+
+    #[cfg(feature = "plugins")]
+    #[error("Plugin error: {0}")]
+    Plugin(String),
+
     #[error("Compression error: {0}")]
     Compression(#[source] Box<Self>),
 
     #[error("Deletion error: {0}")]
     Deletion(#[source] Box<Self>),
 
+    #[error("Incomplete deletion: {0}")]
+    IncompleteDeletion(String),
+
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+
+    #[error("Safety limit exceeded: {0}")]
+    SafetyLimit(String),
+
     #[error("Archive error: {0}")]
     Archive(String),
+
+    #[error("Verification failed: {0}")]
+    Verification(String),
+
+    #[error("Preflight check failed: {0}")]
+    Preflight(String),
+
+    #[error("Notification failed: {0}")]
+    Notification(String),
 }
 
 impl From<AppError> for std::io::Error {
@@ -0,0 +1,445 @@
+use crate::error::{AppError, Result};
+#[cfg(feature = "plugins")]
+use crate::output::warning;
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugin;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use object_store::{Attribute, Attributes, GetOptions, ObjectMeta, ObjectStore, path::Path};
+use std::collections::{HashMap, HashSet};
+
+/// Objects looked up at a time for their user metadata.
+const HEAD_CONCURRENCY: usize = 32;
+
+/// Selection criteria shared by every command that walks a prefix.
+///
+/// Glob patterns are matched against the full object key.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectFilter {
+    pub cutoff: Option<DateTime<Utc>>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub include: Option<GlobSet>,
+    pub exclude: Option<GlobSet>,
+    /// Keys starting with any of these are skipped, like S3 prefixes they need not end at a `/`.
+    pub exclude_prefixes: Vec<String>,
+    /// Keys ending in any of these are skipped, e.g. markers of uploads still in flight.
+    pub exclude_suffixes: Vec<String>,
+    /// Entity tags by key of objects to skip, e.g. the ones already in an earlier archive.
+    pub exclude_versions: HashMap<String, HashSet<String>>,
+    /// Keys to skip, e.g. the ones that kept failing in earlier runs.
+    pub exclude_keys: HashSet<String>,
+    /// Entity tags of the objects to select, see [`build_etags`].
+    pub etags: Option<HashSet<String>>,
+    /// Entity tags of objects to skip, see [`build_etags`].
+    pub exclude_etags: Option<HashSet<String>>,
+    /// User metadata the objects must carry, as names without `x-amz-meta-` and values. Listings
+    /// do not return it, so only [`with_metadata`] checks it, looking up each object.
+    pub metadata: Vec<(String, String)>,
+    /// Path segments below the prefix objects are selected down to, leaving deeper
+    /// "subdirectories" alone; see [`ObjectFilter::within_depth`].
+    pub max_depth: Option<usize>,
+    /// WebAssembly plugin whose `select` hook has the last word on the objects matching
+    /// everything else.
+    #[cfg(feature = "plugins")]
+    pub plugin: Option<Plugin>,
+}
+
+impl ObjectFilter {
+    /// Whether `location` is at most [`ObjectFilter::max_depth`] path segments below
+    /// `prefix`. Listings with a maximum depth only go that deep in the first place.
+    #[must_use]
+    pub fn within_depth(&self, prefix: &Path, location: &Path) -> bool {
+        self.max_depth.is_none_or(|depth| {
+            location
+                .prefix_match(prefix)
+                .is_some_and(|parts| parts.count() <= depth)
+        })
+    }
+
+    #[must_use]
+    pub fn matches(&self, meta: &ObjectMeta) -> bool {
+        if self
+            .cutoff
+            .is_some_and(|cutoff| meta.last_modified >= cutoff)
+        {
+            return false;
+        }
+        if self.min_size.is_some_and(|min| meta.size < min) {
+            return false;
+        }
+        if self.max_size.is_some_and(|max| meta.size > max) {
+            return false;
+        }
+
+        let key = meta.location.as_ref();
+        if self.include.as_ref().is_some_and(|set| !set.is_match(key)) {
+            return false;
+        }
+        if self.exclude.as_ref().is_some_and(|set| set.is_match(key)) {
+            return false;
+        }
+        if self
+            .exclude_prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+        {
+            return false;
+        }
+        if self
+            .exclude_suffixes
+            .iter()
+            .any(|suffix| key.ends_with(suffix.as_str()))
+        {
+            return false;
+        }
+        if self.exclude_keys.contains(key) {
+            return false;
+        }
+        if self.etags.is_some() || self.exclude_etags.is_some() {
+            let e_tag = meta.e_tag.as_deref().map(normalize_etag);
+            let listed =
+                |etags: &HashSet<String>| e_tag.as_ref().is_some_and(|e| etags.contains(e));
+            if self.etags.as_ref().is_some_and(|etags| !listed(etags)) {
+                return false;
+            }
+            if self.exclude_etags.as_ref().is_some_and(listed) {
+                return false;
+            }
+        }
+        if meta.e_tag.as_ref().is_some_and(|e_tag| {
+            self.exclude_versions
+                .get(key)
+                .is_some_and(|e_tags| e_tags.contains(e_tag))
+        }) {
+            return false;
+        }
+
+        #[cfg(feature = "plugins")]
+        if let Some(plugin) = &self.plugin {
+            // Objects are left alone when in doubt, rather than archived or deleted.
+            return plugin.select(meta).unwrap_or_else(|e| {
+                warning!("Skipping {}: {e}", meta.location);
+                false
+            });
+        }
+        true
+    }
+
+    /// Has the WebAssembly plugin at `path` select the objects as well, see
+    /// `crate::plugin`; `archive` transforms them with it too. Only builds with the `plugins`
+    /// feature have plugins.
+    ///
+    /// # Errors
+    ///
+    /// Fails without the `plugins` feature, or when the plugin cannot be loaded.
+    #[cfg(feature = "plugins")]
+    pub fn with_plugin(self, path: &std::path::Path) -> Result<Self> {
+        Ok(Self {
+            plugin: Some(Plugin::load(path)?),
+            ..self
+        })
+    }
+
+    /// Refuses plugins, as this build has no `plugins` feature.
+    ///
+    /// # Errors
+    ///
+    /// Always.
+    #[cfg(not(feature = "plugins"))]
+    pub fn with_plugin(self, _path: &std::path::Path) -> Result<Self> {
+        Err(AppError::Unsupported(
+            "plugins need a build with the `plugins` feature".to_string(),
+        ))
+    }
+}
+
+/// Compiles glob patterns for [`ObjectFilter::include`] or [`ObjectFilter::exclude`],
+/// `None` when there are none.
+///
+/// # Errors
+///
+/// Fails on an invalid pattern.
+pub fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+
+    Ok(Some(builder.build()?))
+}
+
+/// Entity tag without quotes, in lower case.
+fn normalize_etag(e_tag: &str) -> String {
+    e_tag.trim().trim_matches('"').to_ascii_lowercase()
+}
+
+/// Collects entity tags for [`ObjectFilter::etags`] or [`ObjectFilter::exclude_etags`].
+///
+/// They come from `values` and the lines of `file`; `None` when neither is given. They are
+/// compared without quotes and case, so the MD5 sums of objects uploaded in a single part
+/// match as well.
+///
+/// # Errors
+///
+/// Fails when the file cannot be read.
+pub fn build_etags(
+    values: &[String],
+    file: Option<&std::path::Path>,
+) -> Result<Option<HashSet<String>>> {
+    let mut etags: HashSet<String> = values.iter().map(|value| normalize_etag(value)).collect();
+    if let Some(file) = file {
+        etags.extend(
+            std::fs::read_to_string(file)?
+                .lines()
+                .map(normalize_etag)
+                .filter(|e_tag| !e_tag.is_empty()),
+        );
+    }
+    Ok((!etags.is_empty() || file.is_some()).then_some(etags))
+}
+
+/// Parses `--filter-metadata` values of the form `NAME=VALUE` for [`ObjectFilter::metadata`].
+/// Names are case-insensitive and may carry the `x-amz-meta-` prefix of the S3 header.
+///
+/// # Errors
+///
+/// Fails on a value without `=` or with an empty name.
+pub fn parse_metadata(values: &[String]) -> Result<Vec<(String, String)>> {
+    values
+        .iter()
+        .map(|value| {
+            let (name, expected) = value
+                .split_once('=')
+                .filter(|(name, _)| !name.trim().is_empty())
+                .ok_or_else(|| {
+                    AppError::Config(format!("expected NAME=VALUE for metadata, got {value}"))
+                })?;
+            let name = name.trim().to_ascii_lowercase();
+            let name = name
+                .strip_prefix("x-amz-meta-")
+                .unwrap_or(&name)
+                .to_string();
+            Ok((name, expected.to_string()))
+        })
+        .collect()
+}
+
+/// Whether `attributes` carry each of the user metadata `expected`.
+fn has_metadata(attributes: &Attributes, expected: &[(String, String)]) -> bool {
+    expected.iter().all(|(name, value)| {
+        attributes.iter().any(|(attribute, actual)| {
+            matches!(attribute, Attribute::Metadata(key) if key.eq_ignore_ascii_case(name))
+                && actual.as_ref() == value
+        })
+    })
+}
+
+/// Leaves the `objects` without the [`ObjectFilter::metadata`] of `filter` out, looking them
+/// up a few at a time. Objects gone since they were listed are left out as well.
+pub fn with_metadata<'a>(
+    store: &'a dyn ObjectStore,
+    objects: BoxStream<'a, Result<ObjectMeta>>,
+    filter: &'a ObjectFilter,
+) -> BoxStream<'a, Result<ObjectMeta>> {
+    if filter.metadata.is_empty() {
+        return objects;
+    }
+    objects
+        .map_ok(move |meta| async move {
+            let head = GetOptions {
+                head: true,
+                ..GetOptions::default()
+            };
+            match store.get_opts(&meta.location, head).await {
+                Ok(result) => {
+                    Ok(has_metadata(&result.attributes, &filter.metadata).then_some(meta))
+                }
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(AppError::from(e)),
+            }
+        })
+        .try_buffered(HEAD_CONCURRENCY)
+        .try_filter_map(futures::future::ok)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use object_store::{ObjectStoreExt, PutOptions};
+
+    fn meta(key: &str, size: u64, last_modified: DateTime<Utc>) -> ObjectMeta {
+        ObjectMeta {
+            location: Path::from(key),
+            last_modified,
+            size,
+            e_tag: None,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn test_default_filter_matches_everything() {
+        let filter = ObjectFilter::default();
+        assert!(filter.matches(&meta("a/b.log", 0, Utc::now())));
+    }
+
+    #[test]
+    fn test_cutoff_is_exclusive() {
+        let cutoff = Utc::now();
+        let filter = ObjectFilter {
+            cutoff: Some(cutoff),
+            ..ObjectFilter::default()
+        };
+        assert!(filter.matches(&meta("old", 1, cutoff - chrono::Duration::seconds(1))));
+        assert!(!filter.matches(&meta("new", 1, cutoff)));
+    }
+
+    #[test]
+    fn test_size_bounds_are_inclusive() {
+        let filter = ObjectFilter {
+            min_size: Some(10),
+            max_size: Some(20),
+            ..ObjectFilter::default()
+        };
+        let now = Utc::now();
+        assert!(!filter.matches(&meta("a", 9, now)));
+        assert!(filter.matches(&meta("a", 10, now)));
+        assert!(filter.matches(&meta("a", 20, now)));
+        assert!(!filter.matches(&meta("a", 21, now)));
+    }
+
+    #[test]
+    fn test_include_and_exclude_globs() -> Result<()> {
+        let filter = ObjectFilter {
+            include: build_globset(&["logs/**/*.log".to_string()])?,
+            exclude: build_globset(&["**/debug-*".to_string()])?,
+            ..ObjectFilter::default()
+        };
+        let now = Utc::now();
+        assert!(filter.matches(&meta("logs/2024/app.log", 1, now)));
+        assert!(!filter.matches(&meta("logs/2024/debug-app.log", 1, now)));
+        assert!(!filter.matches(&meta("logs/2024/app.txt", 1, now)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_prefixes() {
+        let filter = ObjectFilter {
+            exclude_prefixes: vec!["logs/archive_".to_string()],
+            ..ObjectFilter::default()
+        };
+        let now = Utc::now();
+        assert!(!filter.matches(&meta("logs/archive_20240101_000000.tar.xz", 1, now)));
+        assert!(filter.matches(&meta("logs/app.log", 1, now)));
+    }
+
+    #[test]
+    fn test_exclude_suffixes() {
+        let filter = ObjectFilter {
+            exclude_suffixes: vec![".tmp".to_string(), "_SUCCESS".to_string()],
+            ..ObjectFilter::default()
+        };
+        let now = Utc::now();
+        assert!(!filter.matches(&meta("spark/out/part-0001.parquet.tmp", 1, now)));
+        assert!(!filter.matches(&meta("spark/out/_SUCCESS", 1, now)));
+        assert!(filter.matches(&meta("spark/out/part-0001.parquet", 1, now)));
+    }
+
+    #[test]
+    fn test_exclude_versions() {
+        let filter = ObjectFilter {
+            exclude_versions: HashMap::from([(
+                "logs/app.log".to_string(),
+                HashSet::from(["\"v1\"".to_string()]),
+            )]),
+            ..ObjectFilter::default()
+        };
+        let with_e_tag = |e_tag: &str| ObjectMeta {
+            e_tag: Some(e_tag.to_string()),
+            ..meta("logs/app.log", 1, Utc::now())
+        };
+        assert!(!filter.matches(&with_e_tag("\"v1\"")));
+        assert!(filter.matches(&with_e_tag("\"v2\"")));
+        assert!(filter.matches(&meta("logs/app.log", 1, Utc::now())));
+    }
+
+    #[test]
+    fn test_etags() -> Result<()> {
+        let bad = "\"D41D8CD98F00B204E9800998ECF8427E\"".to_string();
+        let with_e_tag = |e_tag: &str| ObjectMeta {
+            e_tag: Some(e_tag.to_string()),
+            ..meta("dist/app.js", 1, Utc::now())
+        };
+        let only = ObjectFilter {
+            etags: build_etags(std::slice::from_ref(&bad), None)?,
+            ..ObjectFilter::default()
+        };
+        assert!(only.matches(&with_e_tag("\"d41d8cd98f00b204e9800998ecf8427e\"")));
+        assert!(!only.matches(&with_e_tag("\"0cc175b9c0f1b6a831c399e269772661\"")));
+        assert!(!only.matches(&meta("dist/app.js", 1, Utc::now())));
+
+        let without = ObjectFilter {
+            exclude_etags: build_etags(&[bad], None)?,
+            ..ObjectFilter::default()
+        };
+        assert!(!without.matches(&with_e_tag("\"d41d8cd98f00b204e9800998ecf8427e\"")));
+        assert!(without.matches(&meta("dist/app.js", 1, Utc::now())));
+        assert_eq!(build_etags(&[], None)?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_metadata() -> Result<()> {
+        let store = InMemory::new();
+        for (key, team) in [
+            ("logs/a", "growth"),
+            ("logs/b", "ads"),
+            ("logs/c", "growth"),
+        ] {
+            let attributes =
+                Attributes::from_iter([(Attribute::Metadata("team".into()), team.to_string())]);
+            store
+                .put_opts(
+                    &Path::from(key),
+                    "line".into(),
+                    PutOptions::from(attributes),
+                )
+                .await?;
+        }
+        store.put(&Path::from("logs/d"), "line".into()).await?;
+        let filter = ObjectFilter {
+            metadata: parse_metadata(&["X-Amz-Meta-Team=growth".to_string()])?,
+            ..ObjectFilter::default()
+        };
+        assert_eq!(
+            filter.metadata,
+            [("team".to_string(), "growth".to_string())]
+        );
+
+        let listed = store
+            .list(Some(&Path::from("logs")))
+            .map_err(AppError::from);
+        let mut selected: Vec<String> = with_metadata(&store, listed.boxed(), &filter)
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await?;
+        selected.sort();
+        assert_eq!(selected, ["logs/a", "logs/c"]);
+        assert!(parse_metadata(&["team".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_glob_is_rejected() {
+        assert!(build_globset(&["a[".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Heartbeat file for liveness probes and watchdogs of long runs.
+//!
+//! The file is rewritten with the progress of the run, as JSON, every interval in which the
+//! run sent requests or moved bytes. A stuck run stops updating it, so its age alone tells
+//! stuck from slowly working through a huge bucket.
+
+use crate::error::{AppError, Result};
+use crate::output::warning;
+use crate::run_id;
+use crate::usage::{Counters, Usage};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Serialize)]
+struct Heartbeat {
+    pid: u32,
+    /// ID of the archive run in progress, if any.
+    run_id: Option<String>,
+    started: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    /// Requests and bytes of the run so far.
+    usage: Usage,
+}
+
+/// Replaces `path` with `heartbeat` at once, so readers never see half a file.
+fn write(path: &Path, heartbeat: &Heartbeat) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, serde_json::to_vec_pretty(heartbeat)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Writes the heartbeat file at `path` right away, then every `interval` in which the run
+/// made progress, until the returned task is aborted or the process exits.
+///
+/// # Errors
+///
+/// Fails on a zero interval and when the first heartbeat cannot be written; later failures
+/// are printed and retried at the next interval.
+pub fn spawn(path: PathBuf, interval: Duration) -> Result<JoinHandle<()>> {
+    spawn_counting(path, interval, Counters::global())
+}
+
+fn spawn_counting(
+    path: PathBuf,
+    interval: Duration,
+    counters: &'static Counters,
+) -> Result<JoinHandle<()>> {
+    if interval.is_zero() {
+        return Err(AppError::Config(
+            "the heartbeat interval must be at least a second".to_string(),
+        ));
+    }
+    let started = Utc::now();
+    let heartbeat = move |usage| Heartbeat {
+        pid: std::process::id(),
+        run_id: run_id::current(),
+        started,
+        updated: Utc::now(),
+        usage,
+    };
+    let mut last = counters.snapshot();
+    write(&path, &heartbeat(last))?;
+
+    Ok(tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let usage = counters.snapshot();
+            if usage == last {
+                continue;
+            }
+            last = usage;
+            if let Err(e) = write(&path, &heartbeat(usage)) {
+                warning!("Cannot write heartbeat file {}: {e}", path.display());
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::Request;
+
+    #[tokio::test]
+    async fn test_heartbeat_only_follows_progress() -> Result<()> {
+        static COUNTERS: Counters = Counters::new();
+        let path = std::env::temp_dir().join(format!("osm-heartbeat-{}.json", std::process::id()));
+        let task = spawn_counting(path.clone(), Duration::from_millis(50), &COUNTERS)?;
+        let read =
+            || -> Result<serde_json::Value> { Ok(serde_json::from_slice(&std::fs::read(&path)?)?) };
+        let first = read()?;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let idle = read()?;
+        COUNTERS.request(Request::Put);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let busy = read()?;
+
+        task.abort();
+        std::fs::remove_file(&path)?;
+        assert_eq!(idle["updated"], first["updated"]);
+        assert_ne!(busy["updated"], first["updated"]);
+        assert!(busy["usage"]["put"].as_u64() > first["usage"]["put"].as_u64());
+        Ok(())
+    }
+
+    #[test]
+    fn test_heartbeat_needs_an_interval() {
+        static COUNTERS: Counters = Counters::new();
+        let path =
+            std::env::temp_dir().join(format!("osm-no-heartbeat-{}.json", std::process::id()));
+        assert!(spawn_counting(path.clone(), Duration::ZERO, &COUNTERS).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,117 @@
+//! Exact sets of keys to work on, e.g. selected in a data catalog, instead of listing a prefix.
+
+use crate::error::{AppError, Result};
+use crate::filter::{ObjectFilter, with_metadata};
+use crate::output::warning;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use std::io::{BufRead, BufReader};
+
+/// Objects looked up at a time.
+const HEAD_CONCURRENCY: usize = 32;
+
+/// Reads the keys in the file at `source`, or on standard input for `-`, one per line.
+/// Surrounding whitespace and blank lines are ignored.
+///
+/// # Errors
+///
+/// Fails when the file cannot be read or holds no keys.
+pub fn read_keys(source: &str) -> Result<Vec<Path>> {
+    let reader: Box<dyn BufRead> = if source == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(std::fs::File::open(source)?))
+    };
+    let mut keys = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let key = line.trim();
+        if !key.is_empty() {
+            keys.push(Path::from(key));
+        }
+    }
+    if keys.is_empty() {
+        return Err(AppError::Config(format!("no keys in {source}")));
+    }
+    Ok(keys)
+}
+
+/// Fails unless each of `keys` is below one of `prefixes`, so nothing outside the sources is
+/// touched.
+///
+/// # Errors
+///
+/// Names the first key outside them.
+pub fn check_below(keys: &[Path], prefixes: &[Path]) -> Result<()> {
+    let outside = keys
+        .iter()
+        .find(|key| !prefixes.iter().any(|prefix| key.prefix_matches(prefix)));
+    if let Some(key) = outside {
+        return Err(AppError::Config(format!(
+            "key {key} is outside of the sources"
+        )));
+    }
+    Ok(())
+}
+
+/// The objects at those of `keys` below `prefixes` selected by `filter`, in the order of the
+/// keys. Keys without an object are reported and left out.
+pub fn selected<'a>(
+    store: &'a dyn ObjectStore,
+    keys: &'a [Path],
+    prefixes: &'a [Path],
+    filter: &'a ObjectFilter,
+) -> BoxStream<'a, Result<ObjectMeta>> {
+    let found = futures::stream::iter(keys)
+        .filter(move |key| {
+            futures::future::ready(
+                prefixes
+                    .iter()
+                    .any(|prefix| key.prefix_matches(prefix) && filter.within_depth(prefix, key)),
+            )
+        })
+        .map(move |key| async move {
+            match store.head(key).await {
+                Ok(meta) => Ok(Some(meta)),
+                Err(object_store::Error::NotFound { .. }) => {
+                    warning!("Skipping {key}, there is no such object");
+                    Ok(None)
+                }
+                Err(e) => Err(AppError::from(e)),
+            }
+        })
+        .buffered(HEAD_CONCURRENCY)
+        .try_filter_map(move |meta| futures::future::ok(meta.filter(|meta| filter.matches(meta))))
+        .boxed();
+    with_metadata(store, found, filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_selected_keeps_key_order() -> Result<()> {
+        let store = InMemory::new();
+        for key in ["logs/a", "logs/b", "logs/c", "other/d"] {
+            store.put(&Path::from(key), "line".into()).await?;
+        }
+        let keys: Vec<Path> = ["logs/c", "logs/missing", "logs/a", "other/d"]
+            .into_iter()
+            .map(Path::from)
+            .collect();
+        let prefixes = [Path::from("logs")];
+        let filter = ObjectFilter::default();
+
+        let selected: Vec<String> = selected(&store, &keys, &prefixes, &filter)
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await?;
+        assert_eq!(selected, ["logs/c", "logs/a"]);
+        assert!(check_below(&keys, &prefixes).is_err());
+        assert!(check_below(&keys[..3], &prefixes).is_ok());
+        Ok(())
+    }
+}
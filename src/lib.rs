@@ -0,0 +1,65 @@
+//! Streaming maintenance of object storage, the library behind the
+//! `object-storage-maintenance` binary.
+//!
+//! [`ArchiveJob`] runs the archiving pipeline: objects selected by an [`ObjectFilter`] are
+//! streamed into a single compressed tarball (`tar.xz` by default) uploaded through a [`MultipartUploadSink`], then the
+//! originals are deleted, trashed or tagged. [`list_concurrent`] and [`get_store_and_path`]
+//! are the building blocks shared by all commands, which live in [`commands`].
+//!
+//! ```no_run
+//! use object_storage_maintenance::{ArchiveJob, ObjectFilter};
+//!
+//! # async fn run() -> object_storage_maintenance::Result<()> {
+//! ArchiveJob {
+//!     filter: ObjectFilter {
+//!         cutoff: Some(chrono::Utc::now() - chrono::Duration::days(90)),
+//!         ..ObjectFilter::default()
+//!     },
+//!     ..ArchiveJob::new("s3://project/audit/", "s3://archive/audit/")
+//! }
+//! .run()
+//! .await
+//! # }
+//! ```
+
+mod batch;
+mod catalog;
+mod chunker;
+pub mod codec;
+pub mod commands;
+mod compressor;
+pub mod config;
+mod dictionary;
+pub mod error;
+pub mod filter;
+pub mod heartbeat;
+pub mod keys;
+pub mod listing;
+mod manifest;
+mod mark;
+mod object_storage;
+pub mod output;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod progress;
+pub mod rules;
+pub mod run_id;
+mod s3;
+mod spool;
+pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod trash;
+mod upload;
+pub mod usage;
+
+pub use commands::{ArchiveJob, Disposal};
+pub use error::{AppError, Result};
+pub use filter::ObjectFilter;
+pub use listing::list_concurrent;
+pub use s3::{BucketEncryption, CannedAcl, ListApi, NewBucket};
+pub use storage::get_store_and_path;
+
+/// Multipart upload writer archives are streamed into; parts of the configured buffer size
+/// are uploaded as soon as they fill up.
+pub use object_store::buffered::BufWriter as MultipartUploadSink;
@@ -0,0 +1,397 @@
+use crate::error::{AppError, Result};
+use crate::s3::{ListPage, S3Client, S3Object};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    CopyOptions, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOptions, PutOptions, PutPayload, PutResult, path::Path,
+};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// Lists everything under `prefix`, running one listing per top-level "directory"
+/// concurrently instead of a single sequential paginated listing.
+pub fn list_concurrent(
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    concurrency: usize,
+) -> BoxStream<'static, Result<ObjectMeta>> {
+    futures::stream::once(async move {
+        let top = store.list_with_delimiter(Some(&prefix)).await?;
+
+        let nested = futures::stream::iter(top.common_prefixes)
+            .map(move |shard| store.list(Some(&shard)).map_err(AppError::from))
+            .flatten_unordered(concurrency.max(1));
+
+        Ok::<_, AppError>(futures::stream::iter(top.objects.into_iter().map(Ok)).chain(nested))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Lists the objects at most `depth` path segments below `prefix`, in key order. Each
+/// "directory" is listed on its own with a delimiter, so deeper ones are never listed.
+pub fn list_shallow(
+    store: &dyn ObjectStore,
+    prefix: Path,
+    depth: usize,
+) -> BoxStream<'_, Result<ObjectMeta>> {
+    futures::stream::once(async move {
+        let listed = store.list_with_delimiter(Some(&prefix)).await?;
+        // Objects and directories by the keys they start at, so the merge keeps key order.
+        let mut entries: Vec<(String, Option<ObjectMeta>)> = listed
+            .objects
+            .into_iter()
+            .map(|meta| (meta.location.to_string(), Some(meta)))
+            .collect();
+        if depth > 1 {
+            entries.extend(
+                listed
+                    .common_prefixes
+                    .into_iter()
+                    .map(|directory| (format!("{directory}/"), None)),
+            );
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let objects = futures::stream::iter(entries).flat_map(move |(key, meta)| {
+            meta.map_or_else(
+                || list_shallow(store, Path::from(key), depth - 1),
+                |meta| futures::stream::once(futures::future::ok(meta)).boxed(),
+            )
+        });
+        Ok::<_, AppError>(objects)
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Same as [`list_concurrent`] using raw S3 listing pages, which also report storage classes.
+pub(crate) fn list_s3_concurrent(
+    client: S3Client,
+    prefix: String,
+    concurrency: usize,
+) -> BoxStream<'static, Result<S3Object>> {
+    futures::stream::once(async move {
+        let pages: Vec<ListPage> = client
+            .list_pages(prefix, Some("/".to_string()))
+            .try_collect()
+            .await?;
+
+        let mut objects = Vec::new();
+        let mut shards = Vec::new();
+        for page in pages {
+            objects.extend(page.objects);
+            shards.extend(page.common_prefixes);
+        }
+
+        let nested = futures::stream::iter(shards)
+            .map(move |shard| client.list_objects(shard))
+            .flatten_unordered(concurrency.max(1));
+
+        Ok::<_, AppError>(futures::stream::iter(objects.into_iter().map(Ok)).chain(nested))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Hands errors of the S3 client back to `object_store` callers.
+fn store_error(error: AppError) -> object_store::Error {
+    match error {
+        AppError::ObjectStore(error) => error,
+        error => object_store::Error::Generic {
+            store: "S3",
+            source: Box::new(error),
+        },
+    }
+}
+
+/// How a [`ListedBy`] store lists an S3 bucket with its own requests.
+pub(crate) trait S3Listing: fmt::Debug + Send + Sync + 'static {
+    /// Name the store is displayed with.
+    const NAME: &'static str;
+
+    fn client(&self) -> &S3Client;
+
+    /// The objects under `prefix`, starting after `offset` if given.
+    fn objects(
+        &self,
+        prefix: Option<&Path>,
+        offset: Option<&Path>,
+    ) -> BoxStream<'static, object_store::Result<ObjectMeta>>;
+
+    /// Whether `object` of a listing page is listed.
+    fn lists(&self, _object: &S3Object) -> bool {
+        true
+    }
+}
+
+/// S3 store listing as `listing` says, everything else goes to `inner`.
+#[derive(Debug)]
+pub(crate) struct ListedBy<L> {
+    inner: Arc<dyn ObjectStore>,
+    listing: L,
+}
+
+impl<L: S3Listing> fmt::Display for ListedBy<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", L::NAME, self.inner)
+    }
+}
+
+#[async_trait]
+impl<L: S3Listing> ObjectStore for ListedBy<L> {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> object_store::Result<PutResult> {
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> object_store::Result<Box<dyn MultipartUpload>> {
+        self.inner.put_multipart_opts(location, opts).await
+    }
+
+    async fn get_opts(
+        &self,
+        location: &Path,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        self.inner.get_opts(location, options).await
+    }
+
+    fn delete_stream(
+        &self,
+        locations: BoxStream<'static, object_store::Result<Path>>,
+    ) -> BoxStream<'static, object_store::Result<Path>> {
+        self.inner.delete_stream(locations)
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        self.listing.objects(prefix, None)
+    }
+
+    fn list_with_offset(
+        &self,
+        prefix: Option<&Path>,
+        offset: &Path,
+    ) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        self.listing.objects(prefix, Some(offset))
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        let prefix = prefix.map(ToString::to_string).unwrap_or_default();
+        let pages: Vec<ListPage> = self
+            .listing
+            .client()
+            .list_pages(prefix, Some("/".to_string()))
+            .try_collect()
+            .await
+            .map_err(store_error)?;
+
+        let mut result = ListResult {
+            common_prefixes: Vec::new(),
+            objects: Vec::new(),
+        };
+        for page in pages {
+            result.objects.extend(
+                page.objects
+                    .iter()
+                    .filter(|object| self.listing.lists(object))
+                    .map(ObjectMeta::from),
+            );
+            result
+                .common_prefixes
+                .extend(page.common_prefixes.into_iter().map(Path::from));
+        }
+        Ok(result)
+    }
+
+    async fn copy_opts(
+        &self,
+        from: &Path,
+        to: &Path,
+        options: CopyOptions,
+    ) -> object_store::Result<()> {
+        self.inner.copy_opts(from, to, options).await
+    }
+}
+
+/// Listing with `client`, which pages through `ListObjects` with markers where the store
+/// would use the continuation tokens of `ListObjectsV2`.
+#[derive(Debug)]
+pub(crate) struct LegacyListing {
+    client: S3Client,
+}
+
+impl LegacyListing {
+    pub(crate) const fn new(inner: Arc<dyn ObjectStore>, client: S3Client) -> ListedBy<Self> {
+        ListedBy {
+            inner,
+            listing: Self { client },
+        }
+    }
+}
+
+impl S3Listing for LegacyListing {
+    const NAME: &'static str = "LegacyListing";
+
+    fn client(&self) -> &S3Client {
+        &self.client
+    }
+
+    fn objects(
+        &self,
+        prefix: Option<&Path>,
+        offset: Option<&Path>,
+    ) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        let prefix = prefix.map(ToString::to_string).unwrap_or_default();
+        // Markers are keys to start after, just like the offset.
+        let marker = offset.map(ToString::to_string);
+        self.client
+            .list_pages_from(prefix, None, marker)
+            .map_ok(|page| {
+                futures::stream::iter(page.objects).map(|object| Ok(ObjectMeta::from(&object)))
+            })
+            .map_err(store_error)
+            .try_flatten()
+            .boxed()
+    }
+}
+
+/// Listing only the objects owned by one of `owners`, by canonical user ID, with `client`
+/// fetching the owner of each object.
+#[derive(Debug)]
+pub(crate) struct OwnedListing {
+    client: S3Client,
+    owners: Arc<HashSet<String>>,
+}
+
+impl OwnedListing {
+    pub(crate) fn new(
+        inner: Arc<dyn ObjectStore>,
+        client: S3Client,
+        owners: HashSet<String>,
+    ) -> ListedBy<Self> {
+        ListedBy {
+            inner,
+            listing: Self {
+                client: client.fetching_owner(),
+                owners: Arc::new(owners),
+            },
+        }
+    }
+}
+
+fn is_owned(owners: &HashSet<String>, object: &S3Object) -> bool {
+    object
+        .owner
+        .as_ref()
+        .is_some_and(|owner| owners.contains(&owner.id))
+}
+
+impl S3Listing for OwnedListing {
+    const NAME: &'static str = "OwnedListing";
+
+    fn client(&self) -> &S3Client {
+        &self.client
+    }
+
+    fn objects(
+        &self,
+        prefix: Option<&Path>,
+        offset: Option<&Path>,
+    ) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        let prefix = prefix.map(ToString::to_string).unwrap_or_default();
+        let owners = Arc::clone(&self.owners);
+        // Continuation tokens cannot start after a key, so this lists from the start.
+        let offset = offset.cloned();
+        self.client
+            .list_objects(prefix)
+            .try_filter(move |object| futures::future::ready(is_owned(&owners, object)))
+            .map_ok(|object| ObjectMeta::from(&object))
+            .map_err(store_error)
+            .try_filter(move |meta| {
+                futures::future::ready(offset.as_ref().is_none_or(|offset| meta.location > *offset))
+            })
+            .boxed()
+    }
+
+    fn lists(&self, object: &S3Object) -> bool {
+        is_owned(&self.owners, object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::ObjectStoreExt;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_list_concurrent_lists_all_levels() -> Result<()> {
+        let store = Arc::new(InMemory::new());
+        for key in [
+            "root/a.txt",
+            "root/x/b.txt",
+            "root/x/y/c.txt",
+            "root/z/d.txt",
+            "other/e.txt",
+        ] {
+            store.put(&Path::from(key), "data".into()).await?;
+        }
+
+        let mut keys: Vec<String> = list_concurrent(store, Path::from("root"), 2)
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await?;
+        keys.sort();
+
+        assert_eq!(
+            keys,
+            vec![
+                "root/a.txt",
+                "root/x/b.txt",
+                "root/x/y/c.txt",
+                "root/z/d.txt"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_shallow_keeps_key_order() -> Result<()> {
+        let store = InMemory::new();
+        for key in [
+            "root/x.txt",
+            "root/x/b.txt",
+            "root/x/y/c.txt",
+            "root/a.txt",
+            "root/z/d.txt",
+        ] {
+            store.put(&Path::from(key), "data".into()).await?;
+        }
+
+        let store = &store;
+        let listed = |depth| async move {
+            list_shallow(store, Path::from("root"), depth)
+                .map_ok(|meta| meta.location.to_string())
+                .try_collect::<Vec<_>>()
+                .await
+        };
+        assert_eq!(listed(1).await?, ["root/a.txt", "root/x.txt"]);
+        assert_eq!(
+            listed(2).await?,
+            ["root/a.txt", "root/x.txt", "root/x/b.txt", "root/z/d.txt"]
+        );
+        Ok(())
+    }
+}
@@ -1,46 +1,867 @@
-mod commands;
-mod compressor;
-mod error;
-mod object_storage;
-mod storage;
-
-use crate::commands::archive;
-use crate::error::Result;
-use async_compression::Level;
 use chrono::{DateTime, Utc};
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Parser, Subcommand};
+use object_storage_maintenance::codec::{Codec, Compression};
+use object_storage_maintenance::commands::{
+    AdviceFormat, AdviseOptions, ArchiveJob, BatchOptions, DEFAULT_NAME_TEMPLATE, DedupOptions,
+    DeleteVerification, Disposal, EntryMode, EstimateOptions, GroupBy, GroupDate, InventoryFormat,
+    KeyRewrite, MirrorOptions, Order, OutputFormat, PresignMethod, RecompressOptions, RestoreTier,
+    ThawOptions, TrainOptions, advise, cat, checksum, clean_delete_markers, dedup_archive,
+    estimate, inventory, list_archives, ls, mv, presign, recompress, restore, self_test, stat,
+    sync, thaw, train_zstd_dict, transition, trash_gc, untrash, verify_archive,
+};
+use object_storage_maintenance::config::Config;
+use object_storage_maintenance::error::Result;
+use object_storage_maintenance::filter::{
+    ObjectFilter, build_etags, build_globset, parse_metadata,
+};
+use object_storage_maintenance::heartbeat;
+use object_storage_maintenance::keys::read_keys;
+use object_storage_maintenance::output::{self, RotateEvery, Rotation, Verbosity};
+use object_storage_maintenance::progress;
+use object_storage_maintenance::storage::{override_s3_options, use_fips_crypto};
+use object_storage_maintenance::usage::Prices;
+use object_storage_maintenance::{BucketEncryption, CannedAcl, ListApi, NewBucket};
 use std::io;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(ValueEnum, Debug, Clone)]
-enum Compression {
-    Fastest,
-    Best,
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    #[arg(long)]
+    cutoff: Option<DateTime<Utc>>,
+
+    #[arg(long)]
+    min_size: Option<u64>,
+
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    #[arg(long)]
+    include: Vec<String>,
+
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Skip keys starting with this prefix (repeatable).
+    #[arg(long)]
+    exclude_prefix: Vec<String>,
+
+    /// Skip keys ending in any of these comma-separated suffixes, e.g. `.tmp,_SUCCESS`
+    /// (repeatable).
+    #[arg(long, value_delimiter = ',')]
+    exclude_suffix: Vec<String>,
+
+    /// Only select objects with this entity tag, e.g. an MD5 sum (repeatable).
+    #[arg(long)]
+    etag: Vec<String>,
+
+    /// Only select objects with one of the entity tags in this file, one per line.
+    #[arg(long, value_name = "FILE")]
+    etag_file: Option<PathBuf>,
+
+    /// Skip objects with this entity tag (repeatable).
+    #[arg(long)]
+    exclude_etag: Vec<String>,
+
+    /// Skip objects with one of the entity tags in this file, one per line.
+    #[arg(long, value_name = "FILE")]
+    exclude_etag_file: Option<PathBuf>,
+
+    /// Only select objects this WebAssembly plugin's `select` hook selects; `archive`
+    /// transforms them with its `transform` hook as well. Needs a build with the `plugins`
+    /// feature.
+    #[arg(long, value_name = "FILE")]
+    plugin: Option<PathBuf>,
+}
+
+impl FilterArgs {
+    fn into_filter(self) -> Result<ObjectFilter> {
+        let filter = ObjectFilter {
+            cutoff: self.cutoff,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            include: build_globset(&self.include)?,
+            exclude: build_globset(&self.exclude)?,
+            exclude_prefixes: self.exclude_prefix,
+            exclude_suffixes: self.exclude_suffix,
+            etags: build_etags(&self.etag, self.etag_file.as_deref())?,
+            exclude_etags: build_etags(&self.exclude_etag, self.exclude_etag_file.as_deref())?,
+            ..ObjectFilter::default()
+        };
+        match &self.plugin {
+            Some(path) => filter.with_plugin(path),
+            None => Ok(filter),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)] // Parsed once per process.
 enum Commands {
     Archive {
+        /// Source bucket and prefix; repeat for more prefixes of the same bucket.
+        #[arg(long, required = true)]
+        src: Vec<String>,
+
+        /// Write one archive per --src instead of a single one.
+        #[arg(long)]
+        archive_per_src: bool,
+
+        /// Write up to this many of the archives of --archive-per-src at the same time, sharing
+        /// connections and --max-memory.
+        #[arg(long, default_value_t = 1, requires = "archive_per_src")]
+        jobs: usize,
+
+        #[arg(long)]
+        dst: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Only select objects with this user metadata, e.g. `x-amz-meta-team=growth`
+        /// (repeatable). Looks up each candidate, as listings do not return metadata.
+        #[arg(long, value_name = "NAME=VALUE")]
+        filter_metadata: Vec<String>,
+
+        /// Only select objects owned by the account with this canonical user ID (repeatable),
+        /// for buckets shared by tenants.
+        #[arg(long, value_name = "ID", conflicts_with = "keys_from")]
+        owner_id: Vec<String>,
+
+        /// Only select objects up to this many path segments below the source prefix, leaving
+        /// deeper "subdirectories" alone; 1 selects the objects right below it.
+        #[arg(long, value_name = "N")]
+        max_depth: Option<NonZeroUsize>,
+
+        /// Pipe each object through this shell command before archiving it, e.g. to scrub
+        /// personal data; the key is in `OBJECT_KEY`. The output is buffered in --spool-dir.
+        #[arg(long, value_name = "COMMAND", requires = "spool_dir")]
+        filter_cmd: Option<String>,
+
+        /// Encrypt the archives to this PGP key with `gpg`, writing `.tar.*.gpg`
+        /// (repeatable).
+        #[arg(long, value_name = "KEYID")]
+        gpg_recipient: Vec<String>,
+
+        /// Compress each frame, or object with `--entry-mode individual`, with this zstd
+        /// dictionary, e.g. written by `train-zstd-dict`.
+        #[arg(long, value_name = "URL")]
+        zstd_dict: Option<String>,
+
+        /// Record the SHA-256 S3 keeps for each object, looked up with one
+        /// `GetObjectAttributes` request per object, in the tar headers and manifests;
+        /// restoring checks it.
+        #[arg(long, conflicts_with = "filter_cmd")]
+        source_checksums: bool,
+
+        /// Record the tags of each object, looked up with one `GetObjectTagging` request per
+        /// object, in the tar headers; restoring puts them back.
+        #[arg(long)]
+        preserve_tags: bool,
+
+        /// Save the multipart upload of the archive in this state object while it is in
+        /// progress, so a run after an interruption resumes it instead of starting over.
+        #[arg(long, value_name = "URL")]
+        upload_state: Option<String>,
+
+        /// Upload the part being filled as it is once no part went out for this many seconds,
+        /// rather than holding it in memory while the archive trickles in.
+        #[arg(long, value_name = "SECONDS")]
+        flush_interval: Option<u64>,
+
+        /// Upload `run_<id>.progress.json` below --dst every this many minutes, with the
+        /// objects archived so far, the last key and the bytes written.
+        #[arg(long, value_name = "MINUTES")]
+        progress_every: Option<u64>,
+
+        /// ID of the run, in the archive names through `{run_id}`, the manifests, summaries,
+        /// audit log, progress object, log file and heartbeat. A new one by default; pass the
+        /// same one when retrying a run, so its outputs can be found and cleaned up together.
+        #[arg(long, value_name = "ID")]
+        run_id: Option<String>,
+
+        /// Part size of the multipart upload of the archive, between 5MiB and 5GiB. Every part
+        /// in flight is held in memory.
+        #[arg(long, alias = "buffer", value_name = "BYTES", default_value_t = 100 * 1024 * 1024)]
+        part_size: usize,
+
+        /// Bytes buffered in each pipe between the tar, compression and upload stages.
+        #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+        io_buffer: usize,
+
+        /// Parts of the archive uploaded in parallel, each holding a part in memory.
+        #[arg(long, default_value_t = 8)]
+        upload_concurrency: usize,
+
+        /// Batches of up to 1000 archived objects deleted in parallel.
+        #[arg(long, default_value_t = 8)]
+        delete_concurrency: usize,
+
+        /// Buffer prefetched objects and the part being filled in this directory, not in memory.
+        #[arg(long)]
+        spool_dir: Option<PathBuf>,
+
+        /// Memory budget in bytes; upload concurrency and prefetching are lowered to fit.
+        #[arg(long)]
+        max_memory: Option<usize>,
+
+        #[arg(long, value_enum, default_value_t = Codec::Xz)]
+        codec: Codec,
+
+        #[arg(long, value_enum, default_value_t = Compression::Fastest)]
+        compression: Compression,
+
+        /// Archive name without extension; `{src_bucket}`, `{prefix}`, `{cutoff}`, `{run_id}`,
+        /// `{period}` and `{seq}` are replaced.
+        #[arg(long, default_value = DEFAULT_NAME_TEMPLATE)]
+        name_template: String,
+
+        /// Drop this prefix from object keys inside the archive.
+        #[arg(long)]
+        strip_prefix: Option<String>,
+
+        /// Replace a key prefix inside the archive, e.g. `logs/2024/=2024/` (repeatable).
+        #[arg(long, value_name = "FROM=TO")]
+        rewrite: Vec<KeyRewrite>,
+
+        /// One archive per day, month or year; `_{period}` is appended to the name template
+        /// unless it has one.
+        #[arg(long, value_enum)]
+        group_by: Option<GroupBy>,
+
+        /// Take the date to group by from the modification time or from the key.
+        #[arg(long, value_enum, default_value_t = GroupDate::Modified, requires = "group_by")]
+        group_date: GroupDate,
+
+        /// Pack objects into archives of about this many bytes each; `_{seq}` is appended to
+        /// the name template unless it has one.
+        #[arg(long, value_name = "BYTES")]
+        target_archive_size: Option<u64>,
+
+        /// Start a new archive once one holds this many objects, whatever their size; `_{seq}`
+        /// is appended to the name template unless it has one.
+        #[arg(long, value_name = "N")]
+        max_entries_per_archive: Option<NonZeroUsize>,
+
+        /// Order objects are archived in; "mtime" archives the oldest first, so interrupted runs
+        /// always make progress on retention.
+        #[arg(long, value_enum, default_value_t = Order::Key)]
+        order: Order,
+
+        /// "individual" compresses each object on its own below `--dst`, keeping its key and
+        /// metadata, instead of writing tarballs.
+        #[arg(long, value_enum, default_value_t = EntryMode::Tar, conflicts_with = "dst_acl")]
+        entry_mode: EntryMode,
+
+        /// Only archive objects missing from this manifest of an earlier archive, or its parents.
+        #[arg(long, value_name = "URL")]
+        base_manifest: Option<String>,
+
+        /// Move archived objects below this prefix instead of deleting them.
+        #[arg(long)]
+        trash_prefix: Option<String>,
+
+        /// Tag archived objects with `key=value` instead of deleting them (S3 only).
+        #[arg(long, value_name = "TAG", conflicts_with = "trash_prefix")]
+        mark_instead_of_delete: Option<String>,
+
+        /// List archived objects in this S3 Batch Operations manifest for a job to delete,
+        /// instead of deleting them.
+        #[arg(
+            long,
+            value_name = "URL",
+            conflicts_with_all = ["trash_prefix", "mark_instead_of_delete"]
+        )]
+        emit_batch_manifest: Option<String>,
+
+        /// Check archived objects for changes since listing before disposing of them.
+        #[arg(long, value_enum, default_value_t = DeleteVerification::None)]
+        delete_verification: DeleteVerification,
+
+        /// Canned ACL applied to the uploaded archive (S3 only).
+        #[arg(long, value_enum)]
+        dst_acl: Option<CannedAcl>,
+
+        /// Upload through the S3 Transfer Acceleration endpoint of the destination bucket.
+        #[arg(long)]
+        accelerate: bool,
+
+        /// Create the destination bucket in the region of `--dst` when it does not exist (S3
+        /// only).
+        #[arg(long)]
+        create_dst_bucket: bool,
+
+        /// Turn on versioning of the bucket created by `--create-dst-bucket`.
+        #[arg(long, requires = "create_dst_bucket")]
+        dst_bucket_versioning: bool,
+
+        /// Default encryption of the bucket created by `--create-dst-bucket`.
+        #[arg(long, value_enum, requires = "create_dst_bucket")]
+        dst_bucket_encryption: Option<BucketEncryption>,
+
+        /// KMS key of `--dst-bucket-encryption sse-kms`, the AWS managed key when left out.
+        #[arg(long, value_name = "KEY_ID", requires = "dst_bucket_encryption")]
+        dst_bucket_kms_key_id: Option<String>,
+
+        /// Start right away instead of first checking that the sources can be listed, read and
+        /// deleted and the destination written.
+        #[arg(long)]
+        skip_preflight: bool,
+
+        /// Upload the JSON summary printed at the end to `summaries/<run id>.json` below the
+        /// destination as well.
+        #[arg(long)]
+        upload_summary: bool,
+
+        /// Upload a log of what happened to each object, and when, to `audit/<run id>.jsonl`
+        /// below the destination.
+        #[arg(long)]
+        audit_log: bool,
+
+        /// Slack incoming webhook URL to post how the run went to.
+        #[arg(long, value_name = "URL")]
+        notify_slack_webhook: Option<String>,
+
+        /// Resume the listing after the last key in this cursor object, if it exists.
+        #[arg(long, value_name = "URL")]
+        resume_cursor: Option<String>,
+
+        /// Save where the listing got to in this cursor object once the run succeeded.
+        #[arg(long, value_name = "URL")]
+        save_cursor: Option<String>,
+
+        /// Stop selecting objects after this many, draining huge sources over several runs.
+        #[arg(long, value_name = "COUNT")]
+        max_objects: Option<usize>,
+
+        /// Skip up to this many objects that fail to be read, instead of aborting the run.
+        #[arg(long, value_name = "COUNT", default_value_t = 0)]
+        max_errors: usize,
+
+        /// Archive the keys in this file, one per line, or `-` for standard input, instead of
+        /// listing the sources.
+        #[arg(long, value_name = "FILE")]
+        keys_from: Option<String>,
+
+        /// Record objects that fail to be read in this state object, leaving out those that
+        /// failed repeatedly.
+        #[arg(long, value_name = "URL")]
+        skip_list: Option<String>,
+
+        /// Retry the objects the skip list leaves out.
+        #[arg(long, requires = "skip_list")]
+        retry_skipped: bool,
+
+        /// TOML price sheet the estimated cost of the run is reported with, per 1000 requests
+        /// (`list`, `get`, `put`, `delete`) and per GiB (`download_per_gib`, `upload_per_gib`).
+        #[arg(long)]
+        price_sheet: Option<PathBuf>,
+
+        /// Delete archived objects without asking, even on a terminal.
+        #[arg(long)]
+        yes: bool,
+    },
+    Ls {
+        #[arg(long)]
+        src: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long)]
+        start_after: Option<String>,
+
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    Stat {
+        #[arg(long)]
+        src: String,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+        format: OutputFormat,
+    },
+    /// Lists the archives below a destination prefix with what their manifests record.
+    ListArchives {
+        #[arg(long)]
+        dst: String,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+        format: OutputFormat,
+    },
+    /// Reads an archive through without extracting anything, checking its tar headers and the
+    /// recorded checksums of its entries.
+    VerifyArchive {
+        #[arg(long)]
+        archive: String,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+        format: OutputFormat,
+    },
+    Cat {
+        #[arg(long)]
+        src: String,
+
+        #[arg(long)]
+        decompress: bool,
+
+        /// Decompress with this zstd dictionary, the one the object was compressed with.
+        #[arg(long, value_name = "URL", requires = "decompress")]
+        zstd_dict: Option<String>,
+    },
+    /// Writes synthetic objects below a scratch prefix, then archives, verifies, restores and
+    /// deletes them, reporting each step as passed or failed.
+    SelfTest {
+        /// Scratch prefix the test runs below, e.g. `s3://bucket/tmp/`; nothing outside a
+        /// fresh prefix under it is touched.
+        #[arg(long)]
+        prefix: String,
+    },
+    #[command(visible_alias = "mirror")]
+    Sync {
+        #[arg(long)]
+        src: String,
+
+        #[arg(long)]
+        dst: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long)]
+        dry_run: bool,
+
+        #[arg(long, default_value_t = 100 * 1024 * 1024)] // 100MB
+        buffer: usize,
+
+        #[arg(long)]
+        delete: bool,
+
+        #[arg(long, requires = "delete")]
+        max_delete: Option<usize>,
+    },
+    #[command(visible_alias = "rename-prefix")]
+    Mv {
+        #[arg(long)]
+        src: String,
+
+        #[arg(long)]
+        dst: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Checksum {
         #[arg(long)]
         src: String,
 
         #[arg(long)]
         dst: String,
 
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long)]
+        use_s3_checksums: bool,
+    },
+    Inventory {
+        #[arg(long)]
+        src: String,
+
+        /// Full URL of the inventory file to write.
+        #[arg(long)]
+        dst: String,
+
+        #[arg(long, value_enum, default_value_t = InventoryFormat::Csv)]
+        format: InventoryFormat,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long, default_value_t = 100 * 1024 * 1024)] // 100MB
+        buffer: usize,
+    },
+    #[command(visible_alias = "restore-from-glacier")]
+    Thaw {
+        #[arg(long)]
+        src: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Days restored copies stay readable.
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+
+        #[arg(long, value_enum, default_value_t = RestoreTier::Standard)]
+        tier: RestoreTier,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long)]
+        dry_run: bool,
+
+        #[arg(long)]
+        wait: bool,
+
+        /// Seconds between restore status checks while waiting.
+        #[arg(long, default_value_t = 300)]
+        poll_interval: u64,
+
+        /// Archive the objects to this destination once they are restored (implies --wait).
+        #[arg(long)]
+        archive_to: Option<String>,
+
+        #[arg(long, default_value_t = 100 * 1024 * 1024)] // 100MB
+        buffer: usize,
+
+        #[arg(long, value_enum, default_value_t = Compression::Fastest)]
+        compression: Compression,
+
+        /// Delete the objects archived by --archive-to without asking, even on a terminal.
+        #[arg(long)]
+        yes: bool,
+    },
+    Transition {
+        #[arg(long)]
+        src: String,
+
+        /// Target S3 storage class, e.g. `STANDARD_IA` or `GLACIER_IR`.
+        #[arg(long)]
+        storage_class: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Write the selected objects to this S3 Batch Operations manifest instead of copying
+        /// them.
+        #[arg(long, value_name = "URL")]
+        emit_batch_manifest: Option<String>,
+
+        /// Create an S3 Batch Operations job running as this IAM role to transition the
+        /// objects of the manifest.
+        #[arg(long, value_name = "ARN", requires = "emit_batch_manifest")]
+        batch_role_arn: Option<String>,
+    },
+    Untrash {
+        /// Trash prefix given to `archive --trash-prefix`.
+        #[arg(long)]
+        trash: String,
+
+        /// Where original keys are restored to, normally the source bucket root.
+        #[arg(long)]
+        dst: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    TrashGc {
+        #[arg(long)]
+        trash: String,
+
+        /// Days objects are kept in the trash before being deleted for good.
+        #[arg(long, default_value_t = 30)]
+        retention_days: u32,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    CleanDeleteMarkers {
+        #[arg(long)]
+        src: String,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Presign {
+        #[arg(long)]
+        src: String,
+
+        #[arg(long, value_enum, default_value_t = PresignMethod::Get)]
+        method: PresignMethod,
+
+        /// Seconds the URL stays valid.
+        #[arg(long, default_value_t = 3600)]
+        expires: u64,
+    },
+    Recompress {
         #[arg(long)]
-        cutoff: Option<DateTime<Utc>>,
+        src: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Codec to convert compressed objects to.
+        #[arg(long, value_enum)]
+        to: Codec,
+
+        #[arg(long, value_enum, default_value_t = Compression::Fastest)]
+        compression: Compression,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
 
         #[arg(long, default_value_t = 100 * 1024 * 1024)] // 100MB
         buffer: usize,
 
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Trains a zstd dictionary on a sample of similar objects, for `archive --zstd-dict`.
+    TrainZstdDict {
+        #[arg(long)]
+        src: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Where the dictionary is written.
+        #[arg(long)]
+        dst: String,
+
+        /// Objects sampled at most.
+        #[arg(long, default_value_t = 10_000)]
+        max_samples: usize,
+
+        /// Bytes of sampled objects at most, held in memory while training.
+        #[arg(long, default_value_t = 100 * 1024 * 1024)]
+        sample_bytes: u64,
+
+        /// Size of the dictionary at most, by default the one of the `zstd` CLI.
+        #[arg(long, default_value_t = 110 * 1024)]
+        dict_size: usize,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+    },
+    /// Projects what an archive run would take from a sample of the listing.
+    Estimate {
+        #[arg(long)]
+        src: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// List only every this many prefixes of the key hierarchy.
+        #[arg(long, default_value_t = 10)]
+        sample_every: usize,
+
+        /// Bytes of sampled objects compressed to measure the compression ratio and speed.
+        #[arg(long, default_value_t = 64 * 1024 * 1024)]
+        sample_bytes: u64,
+
+        #[arg(long, value_enum, default_value_t = Codec::Xz)]
+        codec: Codec,
+
         #[arg(long, value_enum, default_value_t = Compression::Fastest)]
         compression: Compression,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+    },
+    /// Advises per prefix between native lifecycle transitions and archiving, from the ages
+    /// and sizes of the objects.
+    Advise {
+        #[arg(long)]
+        src: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Objects older than this many days are the ones advised on.
+        #[arg(long, default_value_t = 30)]
+        older_than_days: u32,
+
+        /// Levels of the key hierarchy below `--src` the advice is given for.
+        #[arg(long, default_value_t = 1)]
+        depth: usize,
+
+        /// "lifecycle" prints S3 Lifecycle configuration JSON, "rules" retention rules for
+        /// the configuration file.
+        #[arg(long, value_enum, default_value_t = AdviceFormat::Human)]
+        format: AdviceFormat,
+
+        /// Destination of the archives of suggested rules.
+        #[arg(long, required_if_eq("format", "rules"))]
+        archive_dst: Option<String>,
+    },
+    /// Archives into a deduplicating chunk store instead of tarballs.
+    DedupArchive {
+        #[arg(long)]
+        src: String,
+
+        /// Bucket and prefix of the chunk store.
+        #[arg(long)]
+        dst: String,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Average chunk size in bytes; chunks are between a quarter and four times as large.
+        #[arg(long, default_value_t = 1024 * 1024)]
+        chunk_size: usize,
+
+        #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+        codec: Codec,
+
+        #[arg(long, value_enum, default_value_t = Compression::Fastest)]
+        compression: Compression,
+
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+
+        /// Keep the archived objects instead of deleting them.
+        #[arg(long)]
+        keep_sources: bool,
+
+        /// Delete the archived objects without asking, even on a terminal.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Extracts a single key from an archive, downloading only the frames it is in.
+    Restore {
+        /// URL of the archive, or a glob pattern over archive names such as
+        /// `s3://archive/audit/archive_2024*.tar.zst` (repeatable).
+        #[arg(long, required_unless_present = "catalog", conflicts_with = "catalog")]
+        archive: Vec<String>,
+
+        /// URL of a `catalog.jsonl`; the newest archive holding the key is restored from.
+        #[arg(long)]
+        catalog: Option<String>,
+
+        /// Original key of the object, as listed in the manifest of the archive; without it or
+        /// `--keys-from` every object of the archives is restored.
+        #[arg(long, conflicts_with = "keys_from")]
+        key: Option<String>,
+
+        /// File with the keys to restore, one per line, or `-` for standard input.
+        #[arg(long, value_name = "FILE")]
+        keys_from: Option<String>,
+
+        /// Prefix the key is restored below; a `file://` URL writes it locally.
+        #[arg(long)]
+        dst: String,
+
+        /// Restore keys starting with FROM as starting with TO instead, e.g.
+        /// `prod/=staging/` (repeatable, the first match wins).
+        #[arg(long, value_name = "FROM=TO")]
+        rewrite_prefix: Vec<KeyRewrite>,
+
+        /// Keys, or whole archives, restored in parallel; with 1 archives are restored oldest
+        /// first, so newer copies of a key win.
+        #[arg(long, default_value_t = 1)]
+        concurrency: usize,
+    },
+    /// Runs a job defined in the configuration file.
+    Run {
+        #[arg(long)]
+        job: String,
+
+        /// Delete archived objects without asking, even on a terminal.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Applies all retention rules of the configuration file.
+    ApplyRules {
+        /// Delete archived and expired objects without asking, even on a terminal.
+        #[arg(long)]
+        yes: bool,
     },
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Object storage maintenance tool", long_about = None)]
 struct Args {
+    /// TOML file defining endpoints and named jobs.
+    #[arg(long, global = true, default_value = "maintenance.toml")]
+    config: PathBuf,
+
+    /// S3 endpoint, taking precedence over the environment and the configuration file.
+    #[arg(long, global = true)]
+    endpoint_url: Option<String>,
+
+    /// S3 region, taking precedence over the environment and the configuration file.
+    #[arg(long, global = true)]
+    region: Option<String>,
+
+    /// S3 listing API; "v1" pages with markers, for legacy gateways mishandling the
+    /// continuation tokens of `ListObjectsV2`.
+    #[arg(long, global = true, value_enum)]
+    list_api: Option<ListApi>,
+
+    /// Send S3 requests without credentials, for public buckets such as open data sets.
+    #[arg(long, global = true)]
+    no_sign_request: bool,
+
+    /// Use the FIPS validated crypto module for TLS; needs a build with the `fips` feature.
+    #[arg(long, global = true)]
+    fips: bool,
+
+    /// Only print results, final summaries and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print an action per object; twice, the parts of multipart uploads as well.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Also append all output, with timestamps and whatever the verbosity, to this file.
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file before it grows beyond this many bytes.
+    #[arg(long, global = true, value_name = "BYTES", requires = "log_file")]
+    log_max_size: Option<u64>,
+
+    /// Rotate the log file every UTC hour or day.
+    #[arg(long, global = true, value_enum, requires = "log_file")]
+    log_rotate_every: Option<RotateEvery>,
+
+    /// Rewrite this file with the progress of the run as JSON every `--heartbeat-interval`
+    /// seconds in which the run made progress, for liveness probes.
+    #[arg(long, global = true)]
+    heartbeat_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 30,
+        requires = "heartbeat_file",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    heartbeat_interval: u64,
+
+    /// Rotated log files kept as `<log-file>.1`, `.2`, ...
+    #[arg(long, global = true, default_value_t = Rotation::default().keep)]
+    log_keep: usize,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -48,28 +869,471 @@ struct Args {
 #[tokio::main]
 async fn main() {
     if let Err(e) = run().await {
-        eprintln!("Error: {e}");
+        output::eprint(format_args!("Error: {e}"));
         std::process::exit(1);
     }
 }
 
+#[allow(clippy::too_many_lines)]
 async fn run() -> Result<()> {
     let args = Args::parse();
+    Verbosity::from_flags(args.quiet, args.verbose).set();
+    if let Some(path) = &args.log_file {
+        let rotation = Rotation {
+            max_size: args.log_max_size,
+            every: args.log_rotate_every,
+            keep: args.log_keep,
+        };
+        output::log_to_file(path, rotation)?;
+    }
+    if let Some(path) = &args.heartbeat_file {
+        heartbeat::spawn(path.clone(), Duration::from_secs(args.heartbeat_interval))?;
+    }
+    if args.fips {
+        use_fips_crypto()?;
+    }
+
+    let list_api = args.list_api.map(|api| api.as_str().to_string());
+    let s3_flags: Vec<(String, String)> = [
+        ("endpoint", args.endpoint_url),
+        ("region", args.region),
+        (ListApi::OPTION, list_api),
+        (
+            "skip_signature",
+            args.no_sign_request.then(|| "true".to_string()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key.to_string(), value?)))
+    .collect();
+    if !s3_flags.is_empty() {
+        override_s3_options(s3_flags)?;
+    }
 
     match args.command {
         Some(Commands::Archive {
+            src: mut extra_src,
+            archive_per_src,
+            jobs,
+            dst,
+            filter,
+            part_size,
+            io_buffer,
+            upload_concurrency,
+            delete_concurrency,
+            spool_dir,
+            max_memory,
+            codec,
+            compression,
+            name_template,
+            strip_prefix,
+            mut rewrite,
+            group_by,
+            group_date,
+            target_archive_size,
+            max_entries_per_archive,
+            order,
+            entry_mode,
+            base_manifest,
+            trash_prefix,
+            mark_instead_of_delete,
+            emit_batch_manifest,
+            delete_verification,
+            dst_acl,
+            accelerate,
+            create_dst_bucket,
+            dst_bucket_versioning,
+            dst_bucket_encryption,
+            dst_bucket_kms_key_id,
+            skip_preflight,
+            upload_summary,
+            audit_log,
+            notify_slack_webhook,
+            resume_cursor,
+            save_cursor,
+            max_objects,
+            max_errors,
+            skip_list,
+            retry_skipped,
+            keys_from,
+            filter_metadata,
+            owner_id,
+            max_depth,
+            filter_cmd,
+            gpg_recipient,
+            zstd_dict,
+            source_checksums,
+            preserve_tags,
+            upload_state,
+            flush_interval,
+            progress_every,
+            run_id,
+            price_sheet,
+            yes,
+        }) => {
+            let disposal = match (trash_prefix, mark_instead_of_delete, emit_batch_manifest) {
+                (Some(trash), _, _) => Disposal::Trash(trash),
+                (None, Some(tag), _) => Disposal::Mark(tag),
+                (None, None, Some(url)) => Disposal::BatchManifest(url),
+                (None, None, None) => Disposal::Delete,
+            };
+            rewrite.extend(strip_prefix.map(KeyRewrite::strip));
+            // clap makes sure there is at least one
+            let src = extra_src.remove(0);
+            ArchiveJob {
+                name: None,
+                src,
+                extra_src,
+                archive_per_src,
+                jobs,
+                dst,
+                filter: ObjectFilter {
+                    metadata: parse_metadata(&filter_metadata)?,
+                    max_depth: max_depth.map(NonZeroUsize::get),
+                    ..filter.into_filter()?
+                },
+                buffer_size: part_size,
+                io_buffer,
+                upload_concurrency,
+                delete_concurrency,
+                codec,
+                level: compression.level(),
+                store_precompressed: compression == Compression::Auto,
+                name_template,
+                rewrites: rewrite,
+                group_by,
+                group_date,
+                target_archive_size,
+                max_entries_per_archive: max_entries_per_archive.map(NonZeroUsize::get),
+                order,
+                entry_mode,
+                base_manifest,
+                disposal,
+                delete_verification,
+                dst_acl,
+                accelerate,
+                create_dst_bucket: create_dst_bucket.then_some(NewBucket {
+                    versioning: dst_bucket_versioning,
+                    encryption: dst_bucket_encryption,
+                    kms_key_id: dst_bucket_kms_key_id,
+                }),
+                preflight: !skip_preflight,
+                upload_summary,
+                audit_log,
+                notify_slack_webhook,
+                resume_cursor,
+                save_cursor,
+                max_objects,
+                max_errors,
+                skip_list,
+                retry_skipped,
+                keys_from,
+                owner_ids: owner_id,
+                filter_cmd,
+                gpg_recipients: gpg_recipient,
+                zstd_dict,
+                source_checksums,
+                preserve_tags,
+                upload_state,
+                flush_interval: flush_interval.map(Duration::from_secs),
+                progress_every: progress_every.map(progress::interval).transpose()?,
+                run_id,
+                confirm: !yes && io::stdin().is_terminal(),
+                spool_dir,
+                max_memory,
+                prices: price_sheet
+                    .map(Prices::load)
+                    .transpose()?
+                    .unwrap_or_default(),
+            }
+            .run()
+            .await?;
+        }
+        Some(Commands::Ls {
+            src,
+            filter,
+            start_after,
+            limit,
+        }) => {
+            ls(src, filter.into_filter()?, start_after, limit).await?;
+        }
+        Some(Commands::Stat { src, format }) => {
+            stat(src, format).await?;
+        }
+        Some(Commands::ListArchives { dst, format }) => {
+            list_archives(dst, format).await?;
+        }
+        Some(Commands::VerifyArchive { archive, format }) => {
+            verify_archive(archive, format).await?;
+        }
+        Some(Commands::Cat {
+            src,
+            decompress,
+            zstd_dict,
+        }) => {
+            cat(src, decompress, zstd_dict).await?;
+        }
+        Some(Commands::SelfTest { prefix }) => {
+            self_test(prefix).await?;
+        }
+        Some(Commands::Sync {
+            src,
+            dst,
+            filter,
+            concurrency,
+            dry_run,
+            buffer,
+            delete,
+            max_delete,
+        }) => {
+            sync(
+                src,
+                dst,
+                filter.into_filter()?,
+                concurrency,
+                dry_run,
+                buffer,
+                MirrorOptions { delete, max_delete },
+            )
+            .await?;
+        }
+        Some(Commands::Mv {
+            src,
+            dst,
+            filter,
+            concurrency,
+            dry_run,
+        }) => {
+            mv(src, dst, filter.into_filter()?, concurrency, dry_run).await?;
+        }
+        Some(Commands::Checksum {
             src,
             dst,
-            cutoff,
+            filter,
+            concurrency,
+            use_s3_checksums,
+        }) => {
+            checksum(
+                src,
+                dst,
+                filter.into_filter()?,
+                concurrency,
+                use_s3_checksums,
+            )
+            .await?;
+        }
+        Some(Commands::Inventory {
+            src,
+            dst,
+            format,
+            concurrency,
+            buffer,
+        }) => {
+            inventory(src, dst, format, concurrency, buffer).await?;
+        }
+        Some(Commands::Thaw {
+            src,
+            filter,
+            days,
+            tier,
+            concurrency,
+            dry_run,
+            wait,
+            poll_interval,
+            archive_to,
             buffer,
             compression,
+            yes,
         }) => {
-            let level = match compression {
-                Compression::Fastest => Level::Fastest,
-                Compression::Best => Level::Best,
+            let filter = filter.into_filter()?;
+            let options = ThawOptions {
+                days,
+                tier,
+                concurrency,
+                dry_run,
+                wait: wait || archive_to.is_some(),
+                poll_interval: Duration::from_secs(poll_interval),
             };
+            thaw(src.clone(), filter.clone(), options).await?;
 
-            archive(src, dst, cutoff, buffer, level).await?;
+            if let Some(dst) = archive_to
+                && !dry_run
+            {
+                ArchiveJob {
+                    filter,
+                    buffer_size: buffer,
+                    level: compression.level(),
+                    confirm: !yes && io::stdin().is_terminal(),
+                    ..ArchiveJob::new(src, dst)
+                }
+                .run()
+                .await?;
+            }
+        }
+        Some(Commands::Transition {
+            src,
+            storage_class,
+            filter,
+            concurrency,
+            dry_run,
+            emit_batch_manifest,
+            batch_role_arn,
+        }) => {
+            let batch = BatchOptions {
+                manifest: emit_batch_manifest,
+                role_arn: batch_role_arn,
+            };
+            transition(
+                src,
+                storage_class,
+                filter.into_filter()?,
+                concurrency,
+                dry_run,
+                batch,
+            )
+            .await?;
+        }
+        Some(Commands::Untrash {
+            trash,
+            dst,
+            filter,
+            concurrency,
+            dry_run,
+        }) => {
+            untrash(trash, dst, filter.into_filter()?, concurrency, dry_run).await?;
+        }
+        Some(Commands::TrashGc {
+            trash,
+            retention_days,
+            dry_run,
+        }) => {
+            trash_gc(trash, retention_days, dry_run).await?;
+        }
+        Some(Commands::CleanDeleteMarkers { src, dry_run }) => {
+            clean_delete_markers(src, dry_run).await?;
+        }
+        Some(Commands::Presign {
+            src,
+            method,
+            expires,
+        }) => {
+            presign(src, method, Duration::from_secs(expires)).await?;
+        }
+        Some(Commands::Recompress {
+            src,
+            filter,
+            to,
+            compression,
+            concurrency,
+            buffer,
+            dry_run,
+        }) => {
+            let options = RecompressOptions {
+                codec: to,
+                level: compression.level(),
+                concurrency,
+                buffer_size: buffer,
+                dry_run,
+            };
+            recompress(src, filter.into_filter()?, options).await?;
+        }
+        Some(Commands::TrainZstdDict {
+            src,
+            filter,
+            dst,
+            max_samples,
+            sample_bytes,
+            dict_size,
+            concurrency,
+        }) => {
+            let options = TrainOptions {
+                max_samples,
+                sample_bytes,
+                dict_size,
+                concurrency,
+            };
+            train_zstd_dict(src, dst, filter.into_filter()?, options).await?;
+        }
+        Some(Commands::Estimate {
+            src,
+            filter,
+            sample_every,
+            sample_bytes,
+            codec,
+            compression,
+            concurrency,
+        }) => {
+            let options = EstimateOptions {
+                sample_every,
+                codec,
+                level: compression.level(),
+                sample_bytes,
+                concurrency,
+            };
+            estimate(src, filter.into_filter()?, options).await?;
+        }
+        Some(Commands::Advise {
+            src,
+            filter,
+            older_than_days,
+            depth,
+            format,
+            archive_dst,
+        }) => {
+            let options = AdviseOptions {
+                older_than_days,
+                depth,
+                format,
+                archive_dst,
+            };
+            advise(src, filter.into_filter()?, options).await?;
+        }
+        Some(Commands::DedupArchive {
+            src,
+            dst,
+            filter,
+            chunk_size,
+            codec,
+            compression,
+            concurrency,
+            keep_sources,
+            yes,
+        }) => {
+            let options = DedupOptions {
+                chunk_size,
+                codec,
+                level: compression.level(),
+                concurrency,
+                keep_sources,
+                confirm: !yes && io::stdin().is_terminal(),
+            };
+            dedup_archive(src, dst, filter.into_filter()?, options).await?;
+        }
+        Some(Commands::Restore {
+            archive,
+            catalog,
+            key,
+            keys_from,
+            dst,
+            rewrite_prefix,
+            concurrency,
+        }) => {
+            let keys = match keys_from {
+                Some(source) => read_keys(&source)?
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+                None => key.into_iter().collect(),
+            };
+            restore(archive, catalog, keys, dst, rewrite_prefix, concurrency).await?;
+        }
+        Some(Commands::Run { job, yes }) => {
+            let confirm = !yes && io::stdin().is_terminal();
+            Config::load(&args.config)?.run(&job, confirm).await?;
+        }
+        Some(Commands::ApplyRules { yes }) => {
+            let confirm = !yes && io::stdin().is_terminal();
+            Config::load(&args.config)?.apply_rules(confirm).await?;
         }
         None => {
             println!("No subcommand selected. Add a subcommand like 'archive'.");
@@ -0,0 +1,183 @@
+//! Listings of what went into an archive, stored next to it as `<archive>.manifest.json`.
+
+use crate::commands::KeyRewrite;
+use crate::compressor::{ArchiveIndex, Frame};
+use crate::error::{AppError, Result};
+use crate::storage::get_store_and_path;
+use chrono::{DateTime, Utc};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+/// Entity tags by key.
+pub type Versions = HashMap<String, HashSet<String>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Key of the archive.
+    pub archive: String,
+    /// Manifest of the archive this one only holds the changes to.
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Objects modified before this time were archived.
+    #[serde(default)]
+    pub cutoff: Option<DateTime<Utc>>,
+    pub objects: Vec<ManifestEntry>,
+    /// Independently compressed frames of the archive, empty for archives written before
+    /// frames were indexed.
+    #[serde(default)]
+    pub frames: Vec<Frame>,
+    /// URL of the zstd dictionary the archive is compressed with, if any.
+    #[serde(default)]
+    pub zstd_dict: Option<String>,
+    /// ID of the run that wrote the archive.
+    #[serde(default)]
+    pub run_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub key: String,
+    /// Path of the entry in the tarball.
+    pub path: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+    pub e_tag: Option<String>,
+    /// Hex encoded SHA-256 of the object as S3 recorded it, which restoring checks.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Offset of the tar header of the entry in the decompressed archive.
+    #[serde(default)]
+    pub offset: Option<u64>,
+}
+
+impl Manifest {
+    pub fn new(
+        archive: &Path,
+        parent: Option<&str>,
+        objects: &[ObjectMeta],
+        index: &ArchiveIndex,
+        rewrites: &[KeyRewrite],
+    ) -> Self {
+        let objects = objects
+            .iter()
+            .enumerate()
+            .map(|(i, meta)| ManifestEntry {
+                key: meta.location.to_string(),
+                path: KeyRewrite::apply(rewrites, meta.location.as_ref()).into_owned(),
+                size: meta.size,
+                last_modified: meta.last_modified,
+                e_tag: meta.e_tag.clone(),
+                sha256: index.checksums.get(i).cloned().flatten(),
+                offset: index.entries.get(i).copied(),
+            })
+            .collect();
+        Self {
+            archive: archive.to_string(),
+            parent: parent.map(str::to_string),
+            cutoff: None,
+            objects,
+            frames: index.frames.clone(),
+            zstd_dict: None,
+            run_id: None,
+        }
+    }
+
+    /// Where the manifest of `archive` is stored.
+    pub fn location(archive: &Path) -> Path {
+        Path::from(format!("{archive}.manifest.json"))
+    }
+
+    /// Stores the manifest next to its archive, returning its hex encoded SHA-256.
+    pub async fn put(&self, store: &dyn ObjectStore) -> Result<String> {
+        let location = Self::location(&Path::from(self.archive.as_str()));
+        let bytes = serde_json::to_vec_pretty(self)?;
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+        store.put(&location, bytes.into()).await?;
+        Ok(sha256)
+    }
+
+    /// The manifest stored next to `archive`, `None` when it has none.
+    pub async fn find(store: &dyn ObjectStore, archive: &Path) -> Result<Option<Self>> {
+        match store.get(&Self::location(archive)).await {
+            Ok(result) => Ok(Some(serde_json::from_slice(&result.bytes().await?)?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn load(url: &str) -> Result<Self> {
+        let (store, path) = get_store_and_path(url)?;
+        let bytes = store.get(&path).await?.bytes().await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// The objects in the manifest at `url` and the ones it builds on.
+    pub async fn versions(url: &str) -> Result<Versions> {
+        let mut versions = Versions::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(url.to_string());
+        while let Some(url) = next {
+            if !seen.insert(url.clone()) {
+                return Err(AppError::Archive(format!(
+                    "manifest {url} is its own ancestor"
+                )));
+            }
+            let manifest = Self::load(&url).await?;
+            for entry in manifest.objects {
+                if let Some(e_tag) = entry.e_tag {
+                    versions.entry(entry.key).or_default().insert(e_tag);
+                }
+            }
+            next = manifest.parent;
+        }
+        Ok(versions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::local::LocalFileSystem;
+
+    fn meta(key: &str, e_tag: &str) -> ObjectMeta {
+        ObjectMeta {
+            location: Path::from(key),
+            last_modified: Utc::now(),
+            size: 1,
+            e_tag: Some(e_tag.to_string()),
+            version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_versions_follow_parents() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("manifests-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let store = LocalFileSystem::new_with_prefix(&dir)?;
+        let url = |name: &str| format!("file://{}/{name}.manifest.json", dir.display());
+
+        let index = ArchiveIndex::default();
+        Manifest::new(&Path::from("full"), None, &[meta("a", "1")], &index, &[])
+            .put(&store)
+            .await?;
+        Manifest::new(
+            &Path::from("diff"),
+            Some(&url("full")),
+            &[meta("a", "2"), meta("b", "1")],
+            &index,
+            &[],
+        )
+        .put(&store)
+        .await?;
+
+        let versions = Manifest::versions(&url("diff")).await;
+        std::fs::remove_dir_all(&dir)?;
+        let versions = versions?;
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions["a"].len(), 2);
+        assert!(versions["b"].contains("1"));
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use crate::error::{AppError, Result};
+use crate::output::summary;
+use crate::s3::S3Client;
+use futures::{StreamExt, TryStreamExt};
+use object_store::path::Path;
+
+/// Number of tagging requests in flight at once.
+const MARK_CONCURRENCY: usize = 16;
+
+/// An object tag flagging archived source objects that are kept instead of deleted, so
+/// a bucket lifecycle rule can expire them later and later runs can skip them.
+pub struct ArchiveMark {
+    client: S3Client,
+    key: String,
+    value: String,
+}
+
+impl ArchiveMark {
+    /// Parses a `key=value` tag for objects under the S3 URL `src`.
+    pub fn new(src: &str, tag: &str) -> Result<Self> {
+        let (key, value) = tag
+            .split_once('=')
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or_else(|| AppError::Archive(format!("expected a key=value tag, got '{tag}'")))?;
+        let client = S3Client::from_url(src)?.ok_or_else(|| {
+            AppError::Unsupported(format!(
+                "tagging objects requires an s3:// source, got {src}"
+            ))
+        })?;
+
+        Ok(Self {
+            client,
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    pub async fn is_marked(&self, location: &Path) -> Result<bool> {
+        let tags = self.client.get_object_tagging(location.as_ref()).await?;
+        Ok(tags
+            .iter()
+            .any(|(key, value)| *key == self.key && *value == self.value))
+    }
+
+    /// Adds the tag to every object, keeping the tags it already has.
+    pub async fn mark_all(&self, locations: Vec<Path>) -> Result<()> {
+        let count = locations.len();
+
+        futures::stream::iter(locations)
+            .map(|location| async move {
+                let mut tags = self.client.get_object_tagging(location.as_ref()).await?;
+                tags.retain(|(key, _)| *key != self.key);
+                tags.push((self.key.clone(), self.value.clone()));
+                self.client
+                    .put_object_tagging(location.as_ref(), &tags)
+                    .await
+            })
+            .buffer_unordered(MARK_CONCURRENCY)
+            .try_collect::<()>()
+            .await?;
+
+        summary!("Tagged {count} objects with {}={}", self.key, self.value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_parses_tag() -> Result<()> {
+        let mark = ArchiveMark::new("s3://bucket/audit", "state=archived")?;
+        assert_eq!(mark.key, "state");
+        assert_eq!(mark.value, "archived");
+
+        assert!(ArchiveMark::new("s3://bucket/audit", "archived").is_err());
+        assert!(ArchiveMark::new("file:///tmp/audit", "state=archived").is_err());
+        Ok(())
+    }
+}
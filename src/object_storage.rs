@@ -1,25 +1,346 @@
 use crate::error::{AppError, Result};
-use futures::StreamExt;
-use object_store::{ObjectStore, path::Path};
+use crate::output::{info, summary};
+use crate::s3::{MAX_DELETE_BATCH, S3Client};
+use crate::storage::same_store;
+use futures::{StreamExt, TryStreamExt};
+use object_store::buffered::BufWriter;
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 
-pub async fn delete_keys(store: &dyn ObjectStore, keys: Vec<Path>) -> Result<()> {
-    if keys.is_empty() {
-        return Ok(());
-    }
+/// Delete batches in flight at once for commands without a `--delete-concurrency` flag.
+pub const DELETE_CONCURRENCY: usize = 8;
+
+/// Attempts per key before a retryable failure is given up on.
+const DELETE_ATTEMPTS: u32 = 3;
+
+/// Per-key `DeleteObjects` error codes that are worth another attempt.
+const RETRYABLE_DELETE_CODES: [&str; 4] = [
+    "InternalError",
+    "ServiceUnavailable",
+    "SlowDown",
+    "RequestTimeout",
+];
+
+/// A key that is still there because deleting it failed.
+#[derive(Debug, Serialize)]
+pub struct FailedDelete {
+    pub key: String,
+    pub error: String,
+}
+
+fn is_retryable(error: &str) -> bool {
+    RETRYABLE_DELETE_CODES
+        .iter()
+        .any(|code| error.contains(&format!("(code: {code})")))
+}
 
-    let locations = futures::stream::iter(keys.into_iter().map(Ok));
+/// Deletes a single batch, returning how many keys were deleted and which failed with what.
+async fn delete_batch(store: &dyn ObjectStore, batch: Vec<Path>) -> (usize, Vec<(Path, String)>) {
+    let locations = futures::stream::iter(batch.clone().into_iter().map(Ok));
     let mut results = store.delete_stream(locations.boxed());
 
+    // Results come in the order of `batch`; a failed request ends the stream with a single
+    // error, which then applies to all keys without a result.
+    let mut deleted = 0;
+    let mut failed = Vec::new();
+    let mut last_error = None;
+    for key in batch {
+        let error = match results.next().await {
+            Some(Ok(_)) => {
+                deleted += 1;
+                continue;
+            }
+            Some(Err(e)) => last_error.insert(e.to_string()).clone(),
+            None => last_error
+                .clone()
+                .unwrap_or_else(|| "no result for key".to_string()),
+        };
+        failed.push((key, error));
+    }
+    (deleted, failed)
+}
+
+/// Deletes `keys` in batches of [`MAX_DELETE_BATCH`], up to `concurrency` batches at a time.
+/// Keys failing with a retryable error are tried again, the ones that could not be deleted
+/// after all are returned.
+pub async fn delete_keys_reporting(
+    store: &dyn ObjectStore,
+    keys: Vec<Path>,
+    concurrency: usize,
+) -> Vec<FailedDelete> {
+    let mut pending = keys;
+    let mut given_up = Vec::new();
     let mut success_count = 0;
 
-    while let Some(res) = results.next().await {
-        res.map_err(AppError::from)?;
-        success_count += 1;
+    for attempt in 1..=DELETE_ATTEMPTS {
+        if attempt > 1 {
+            info!("Retrying deletion of {} objects", pending.len());
+            tokio::time::sleep(Duration::from_secs(u64::from(attempt - 1))).await;
+        }
+
+        let batches: Vec<(usize, Vec<(Path, String)>)> =
+            futures::stream::iter(pending.chunks(MAX_DELETE_BATCH).map(<[Path]>::to_vec))
+                .map(|batch| delete_batch(store, batch))
+                .buffer_unordered(concurrency.max(1))
+                .collect()
+                .await;
+
+        pending.clear();
+        for (deleted, failed) in batches {
+            success_count += deleted;
+            for (location, error) in failed {
+                if is_retryable(&error) && attempt < DELETE_ATTEMPTS {
+                    pending.push(location);
+                } else {
+                    given_up.push(FailedDelete {
+                        key: location.to_string(),
+                        error,
+                    });
+                }
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
     }
 
     if success_count > 0 {
-        println!("Successfully deleted {success_count} objects.");
+        summary!("Successfully deleted {success_count} objects.");
+    }
+    given_up.sort_by(|a, b| a.key.cmp(&b.key));
+    given_up
+}
+
+/// Like [`delete_keys_reporting`], failing when any key could not be deleted.
+pub async fn delete_keys(
+    store: &dyn ObjectStore,
+    keys: Vec<Path>,
+    concurrency: usize,
+) -> Result<()> {
+    let failed = delete_keys_reporting(store, keys, concurrency).await;
+    if let Some(first) = failed.first() {
+        return Err(AppError::IncompleteDeletion(format!(
+            "{} objects could not be deleted, first '{}': {}",
+            failed.len(),
+            first.key,
+            first.error
+        )));
+    }
+    Ok(())
+}
+
+/// Like [`delete_keys`], but a key paired with a version id permanently deletes that
+/// version instead of adding a delete marker, which is what frees space in versioned buckets.
+pub async fn delete_key_versions(
+    client: &S3Client,
+    keys: Vec<(Path, Option<String>)>,
+    concurrency: usize,
+) -> Result<()> {
+    let keys: Vec<(String, Option<String>)> = keys
+        .into_iter()
+        .map(|(location, version)| (location.to_string(), version))
+        .collect();
+
+    futures::stream::iter(keys.chunks(MAX_DELETE_BATCH))
+        .map(|batch| client.delete_objects(batch))
+        .buffer_unordered(concurrency.max(1))
+        .try_collect::<()>()
+        .await?;
+
+    if !keys.is_empty() {
+        summary!("Successfully deleted {} objects.", keys.len());
+    }
+
+    Ok(())
+}
+
+/// Maps `location` from under the `from` prefix to the same relative key under `to`.
+pub fn rebase_key(location: &Path, from: &Path, to: &Path) -> Option<Path> {
+    let relative = location.prefix_match(from)?;
+    Some(to.parts().chain(relative).collect())
+}
+
+/// Copies a single object, server-side when both locations live in the same store.
+pub async fn copy_object(
+    src_store: &dyn ObjectStore,
+    dst_store: Arc<dyn ObjectStore>,
+    from: &Path,
+    to: &Path,
+    same_store: bool,
+    buffer_size: usize,
+) -> Result<()> {
+    if same_store {
+        return Ok(src_store.copy(from, to).await?);
+    }
+
+    // Chunks are handed over as `Bytes`, so they are uploaded without being copied.
+    let mut chunks = src_store.get(from).await?.into_stream();
+    let mut writer = BufWriter::with_capacity(dst_store, to.clone(), buffer_size);
+
+    while let Some(chunk) = chunks.try_next().await? {
+        writer.put(chunk).await?;
     }
+    writer.shutdown().await?;
 
     Ok(())
 }
+
+/// How objects get to their new location without passing through this machine.
+pub enum ServerSideCopy {
+    /// `CopyObject`/`UploadPartCopy`, which also works across S3 buckets.
+    S3 {
+        client: S3Client,
+        /// Client of the source bucket, reading the metadata multipart copies need.
+        src: Box<S3Client>,
+    },
+    /// The store's own copy, only possible within a single bucket/container.
+    Store,
+}
+
+impl ServerSideCopy {
+    /// Returns `None` when objects cannot be copied from `src` to `dst` server-side.
+    pub fn between(src: &str, dst: &str) -> Result<Option<Self>> {
+        Ok(match (S3Client::from_url(src)?, S3Client::from_url(dst)?) {
+            (Some(src), Some(client)) => Some(Self::S3 {
+                client,
+                src: Box::new(src),
+            }),
+            _ if same_store(src, dst)? => Some(Self::Store),
+            _ => None,
+        })
+    }
+
+    pub async fn copy(&self, store: &dyn ObjectStore, meta: &ObjectMeta, to: &Path) -> Result<()> {
+        match self {
+            Self::S3 { client, src } => {
+                client
+                    .copy_object(src, meta.location.as_ref(), to.as_ref(), meta.size, None)
+                    .await
+            }
+            Self::Store => Ok(store.copy(&meta.location, to).await?),
+        }
+    }
+}
+
+/// Streams the object body through SHA-256, returning the hex encoded digest.
+pub async fn sha256_object(store: &dyn ObjectStore, location: &Path) -> Result<String> {
+    let mut stream = store.get(location).await?.into_stream();
+    let mut hasher = Sha256::new();
+
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk?);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_rebase_key() {
+        let rebased = rebase_key(
+            &Path::from("logs/2024/app.log"),
+            &Path::from("logs"),
+            &Path::from("backup/logs"),
+        );
+        assert_eq!(rebased, Some(Path::from("backup/logs/2024/app.log")));
+        assert_eq!(
+            rebase_key(
+                &Path::from("other/app.log"),
+                &Path::from("logs"),
+                &Path::from("x")
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sha256_object() -> Result<()> {
+        let store = InMemory::new();
+        store.put(&Path::from("a.txt"), "hello".into()).await?;
+
+        assert_eq!(
+            sha256_object(&store, &Path::from("a.txt")).await?,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_keys_in_parallel_batches() -> Result<()> {
+        let store = InMemory::new();
+        let keys: Vec<Path> = (0..MAX_DELETE_BATCH + 5)
+            .map(|i| Path::from(format!("logs/{i}.log")))
+            .collect();
+        for key in &keys {
+            store.put(key, "x".into()).await?;
+        }
+        store.put(&Path::from("keep.log"), "x".into()).await?;
+
+        delete_keys(&store, keys, 2).await?;
+
+        let left: Vec<ObjectMeta> = store.list(None).try_collect().await?;
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].location, Path::from("keep.log"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_keys_reports_failures() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("delete-report-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let store = object_store::local::LocalFileSystem::new_with_prefix(&dir)?;
+        store.put(&Path::from("a.log"), "x".into()).await?;
+
+        let failed = delete_keys_reporting(
+            &store,
+            vec![Path::from("a.log"), Path::from("missing.log")],
+            2,
+        )
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].key, "missing.log");
+        assert!(!is_retryable(&failed[0].error));
+        assert!(is_retryable(
+            "DeleteObjects request failed for key a.log: Please reduce your request rate. \
+             (code: SlowDown)"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_object_between_stores() -> Result<()> {
+        let src_store = Arc::new(InMemory::new());
+        let dst_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        src_store
+            .put(&Path::from("a.txt"), "content".into())
+            .await?;
+
+        copy_object(
+            src_store.as_ref(),
+            dst_store.clone(),
+            &Path::from("a.txt"),
+            &Path::from("copy/a.txt"),
+            false,
+            1024,
+        )
+        .await?;
+
+        let copied = dst_store
+            .get(&Path::from("copy/a.txt"))
+            .await?
+            .bytes()
+            .await?;
+        assert_eq!(copied.as_ref(), b"content");
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+//! How much the commands print, set once per process from `-q`/`-v`, and the log file all of
+//! it goes to as well.
+//!
+//! Progress goes through [`info!`], an action per object through [`verbose!`] and the parts
+//! of multipart uploads through [`debug!`]. Final summaries go through [`summary!`] and
+//! warnings through [`warning!`], both printed at every level like results and dry run
+//! listings. Everything but results and listings is also appended to the [`log_to_file`]
+//! file, whatever the verbosity.
+
+use crate::error::Result;
+use crate::run_id;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only results, final summaries and errors.
+    Quiet,
+    /// Progress of each step as well.
+    Normal,
+    /// An action per object as well.
+    Verbose,
+    /// The parts of multipart uploads as well.
+    Debug,
+}
+
+impl Verbosity {
+    /// Level for `-q` or the number of `-v` given.
+    #[must_use]
+    pub const fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Debug,
+        }
+    }
+
+    /// Makes this the level of the whole process.
+    pub fn set(self) {
+        VERBOSITY.store(self as u8, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn current() -> Self {
+        match VERBOSITY.load(Ordering::Relaxed) {
+            0 => Self::Quiet,
+            1 => Self::Normal,
+            2 => Self::Verbose,
+            _ => Self::Debug,
+        }
+    }
+
+    /// Whether output of this level is printed.
+    #[must_use]
+    pub fn enabled(self) -> bool {
+        Self::current() >= self
+    }
+}
+
+/// Period after which the log file is rotated.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotateEvery {
+    Hour,
+    Day,
+}
+
+impl RotateEvery {
+    /// Number of the UTC hour or day `time` falls into.
+    const fn period(self, time: DateTime<Utc>) -> i64 {
+        let seconds = match self {
+            Self::Hour => 3600,
+            Self::Day => 86_400,
+        };
+        time.timestamp().div_euclid(seconds)
+    }
+}
+
+/// When the log file is moved aside to `<path>.1` and a new one started. Older rotated files
+/// move up a number, and those beyond `keep` are deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate before the file would grow beyond this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate once a line falls into another UTC hour or day than the first one did.
+    pub every: Option<RotateEvery>,
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            every: None,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Time of the first line in the file, as far as known.
+    started: DateTime<Utc>,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let started = metadata.modified().map_or_else(|_| Utc::now(), Into::into);
+        Ok(Self {
+            path,
+            file,
+            size: metadata.len(),
+            started,
+            rotation,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Whether `line` has to go into a new file.
+    fn due(&self, now: DateTime<Utc>, line: &str) -> bool {
+        let Rotation {
+            max_size, every, ..
+        } = self.rotation;
+        self.size > 0
+            && (max_size.is_some_and(|max_size| self.size + line.len() as u64 > max_size)
+                || every.is_some_and(|every| every.period(now) != every.period(self.started)))
+    }
+
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        for n in (1..keep).rev() {
+            ignore_missing(std::fs::rename(self.rotated(n), self.rotated(n + 1)))?;
+        }
+        if keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        *self = Self {
+            started: now,
+            ..Self::open(self.path.clone(), self.rotation)?
+        };
+        Ok(())
+    }
+
+    fn write(&mut self, message: &str) -> io::Result<()> {
+        let now = Utc::now();
+        let timestamp = now.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let run = run_id::current().map(|run_id| format!(" [{run_id}]"));
+        let line = format!("{timestamp}{} {message}\n", run.unwrap_or_default());
+        if self.due(now, &line) {
+            self.rotate(now)?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Appends everything printed through this module to `path` from now on, each line with a
+/// timestamp and whatever the verbosity.
+///
+/// # Errors
+///
+/// Fails when the file cannot be opened for appending.
+pub fn log_to_file(path: impl Into<PathBuf>, rotation: Rotation) -> Result<()> {
+    let log_file = LogFile::open(path.into(), rotation)?;
+    *LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner) = Some(log_file);
+    Ok(())
+}
+
+/// Appends `message` to the log file, if there is one. The log file is given up on after the
+/// first failure, so a full disk does not fail the run.
+fn log(message: &str) {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(file) = log_file.as_mut()
+        && let Err(e) = file.write(message)
+    {
+        eprintln!(
+            "Cannot write log file {}, no longer logging: {e}",
+            file.path.display()
+        );
+        *log_file = None;
+    }
+}
+
+/// Prints `args` to stdout at `level`, and logs it in any case.
+pub fn print(level: Verbosity, args: fmt::Arguments<'_>) {
+    let message = args.to_string();
+    if level.enabled() {
+        println!("{message}");
+    }
+    log(&message);
+}
+
+/// Prints `args` to stderr and logs it.
+pub fn eprint(args: fmt::Arguments<'_>) {
+    let message = args.to_string();
+    eprintln!("{message}");
+    log(&message);
+}
+
+/// Prints a final summary at every verbosity.
+macro_rules! summary {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Verbosity::Quiet, format_args!($($arg)*))
+    };
+}
+
+/// Prints progress unless `-q` was given.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Verbosity::Normal, format_args!($($arg)*))
+    };
+}
+
+/// Prints an action on a single object with `-v`.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Verbosity::Verbose, format_args!($($arg)*))
+    };
+}
+
+/// Prints upload details with `-vv`.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Verbosity::Debug, format_args!($($arg)*))
+    };
+}
+
+/// Prints a warning to stderr.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::output::eprint(format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, info, summary, verbose, warning};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_flags() {
+        assert_eq!(Verbosity::from_flags(true, 2), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_flags(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(false, 1), Verbosity::Verbose);
+        assert_eq!(Verbosity::from_flags(false, 3), Verbosity::Debug);
+        assert!(Verbosity::Quiet < Verbosity::Debug);
+    }
+
+    #[test]
+    fn test_log_file_rotates_by_size() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("osm-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let rotation = Rotation {
+            max_size: Some(100),
+            keep: 2,
+            ..Rotation::default()
+        };
+        let mut log_file = LogFile::open(dir.join("run.log"), rotation)?;
+        for i in 0..10 {
+            log_file.write(&format!("line {i} of about fifty bytes"))?;
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name));
+        let (current, first, second) = (read("run.log")?, read("run.log.1")?, read("run.log.2")?);
+        let third = dir.join("run.log.3").exists();
+        std::fs::remove_dir_all(&dir)?;
+        assert!(
+            current.ends_with("line 9 of about fifty bytes\n"),
+            "{current}"
+        );
+        assert!(first.contains("line 8 "), "{first}");
+        assert!(second.contains("line 7 "), "{second}");
+        assert!(!third);
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+//! WebAssembly plugins with site-specific policy, selecting objects and transforming them on
+//! their way into an archive without forking the tool or starting a process per object like
+//! `--filter-cmd`.
+//!
+//! A plugin is a core WebAssembly module, in the binary or text format, importing nothing and
+//! exporting its `memory`, an `alloc(len: i32) -> i32` function returning where `len` bytes
+//! may be written, and one or both of the hooks:
+//!
+//! - `select(key: i32, key_len: i32, size: i64, last_modified: i64) -> i32` selects the
+//!   object with the key at `key`, its size and its modification time in seconds since the
+//!   epoch unless it returns 0.
+//! - `transform(data: i32, len: i32) -> i64` takes the next chunk of an object and returns
+//!   where its output for it is, as `offset << 32 | len`. A chunk of length 0 ends the
+//!   object, returning what is left. Each object gets an instance of its own.
+
+use crate::error::{AppError, Result};
+use crate::output::verbose;
+use crate::spool::SpoolFile;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio_util::io::ReaderStream;
+use wasmtime::{Engine, InstancePre, Linker, Memory, Module, Store, TypedFunc, WasmParams};
+use wasmtime::{WasmResults, WasmTy};
+
+type Body = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Plugin compiled once and instantiated for each object it selects or transforms.
+#[derive(Clone)]
+pub struct Plugin {
+    path: PathBuf,
+    engine: Engine,
+    instance: InstancePre<()>,
+    select: bool,
+    transform: bool,
+}
+
+/// Instance of a plugin running a hook, with the exports every hook needs.
+struct Running<'a> {
+    plugin: &'a Plugin,
+    store: Store<()>,
+    instance: wasmtime::Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin")
+            .field("path", &self.path)
+            .field("select", &self.select)
+            .field("transform", &self.transform)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Plugin {
+    /// Compiles the plugin at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the file cannot be read, is no valid module, imports anything or lacks
+    /// the exports described in [`crate::plugin`].
+    pub fn load(path: &Path) -> Result<Self> {
+        let engine = Engine::default();
+        let failed = |e: wasmtime::Error| AppError::Plugin(format!("{}: {e:#}", path.display()));
+        let module = Module::from_file(&engine, path).map_err(failed)?;
+        let exports = |name| module.get_export(name).is_some();
+        let (select, transform) = (exports("select"), exports("transform"));
+        if !exports("memory") || !exports("alloc") || !(select || transform) {
+            return Err(AppError::Plugin(format!(
+                "{} must export memory, alloc and select or transform",
+                path.display()
+            )));
+        }
+        let instance = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(failed)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            engine,
+            instance,
+            select,
+            transform,
+        })
+    }
+
+    /// Whether the plugin has the `transform` hook.
+    #[must_use]
+    pub const fn transforms(&self) -> bool {
+        self.transform
+    }
+
+    fn error(&self, e: &wasmtime::Error) -> AppError {
+        AppError::Plugin(format!("{}: {e:#}", self.path.display()))
+    }
+
+    fn instantiate(&self) -> Result<Running<'_>> {
+        let mut store = Store::new(&self.engine, ());
+        let instance = self
+            .instance
+            .instantiate(&mut store)
+            .map_err(|e| self.error(&e))?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| {
+            AppError::Plugin(format!("{} exports no memory", self.path.display()))
+        })?;
+        let alloc = instance
+            .get_typed_func(&mut store, "alloc")
+            .map_err(|e| self.error(&e))?;
+        Ok(Running {
+            plugin: self,
+            store,
+            instance,
+            memory,
+            alloc,
+        })
+    }
+
+    /// Whether the plugin selects the object at `meta`; all are without the `select` hook.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the plugin traps or its hook has the wrong signature.
+    pub fn select(&self, meta: &ObjectMeta) -> Result<bool> {
+        if !self.select {
+            return Ok(true);
+        }
+        let mut instance = self.instantiate()?;
+        let (key, key_len) = instance.write(meta.location.as_ref().as_bytes())?;
+        let size = i64::try_from(meta.size).unwrap_or(i64::MAX);
+        let selected: i32 = instance.call(
+            "select",
+            (key, key_len, size, meta.last_modified.timestamp()),
+        )?;
+        Ok(selected != 0)
+    }
+
+    /// Transforms `body`, the content of the object at `key`, with the `transform` hook.
+    /// Returns the size of the output and the output, buffered in memory or in `spool_dir`
+    /// as tar entries need their size upfront.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the plugin traps, returns output outside its memory or `body` fails.
+    pub async fn apply(
+        &self,
+        key: &str,
+        mut body: Body,
+        spool_dir: Option<&Path>,
+    ) -> Result<(u64, Body)> {
+        verbose!("Transforming {key} with {}", self.path.display());
+        let mut instance = self.instantiate()?;
+        let mut spool = match spool_dir {
+            Some(dir) => Some(SpoolFile::create(dir).await?),
+            None => None,
+        };
+        let mut output = Vec::new();
+        loop {
+            let chunk = body.try_next().await?;
+            if chunk.as_ref().is_some_and(Bytes::is_empty) {
+                continue;
+            }
+            let (data, len) = instance.write(chunk.as_deref().unwrap_or_default())?;
+            let location: i64 = instance.call("transform", (data, len))?;
+            let transformed = instance.read(location)?;
+            match &mut spool {
+                Some(spool) => spool.write_all(&transformed).await?,
+                None => output.extend_from_slice(&transformed),
+            }
+            if chunk.is_none() {
+                break;
+            }
+        }
+
+        Ok(match spool {
+            Some(spool) => (
+                spool.len(),
+                ReaderStream::new(spool.into_reader().await?).boxed(),
+            ),
+            None => (
+                output.len() as u64,
+                futures::stream::once(async move { Ok(output.into()) }).boxed(),
+            ),
+        })
+    }
+}
+
+impl Running<'_> {
+    /// Copies `data` into memory the plugin allocated, returning where it is. Nothing is
+    /// allocated for no data.
+    fn write(&mut self, data: &[u8]) -> Result<(i32, i32)> {
+        if data.is_empty() {
+            return Ok((0, 0));
+        }
+        let len = i32::try_from(data.len())
+            .map_err(|_| AppError::Plugin(format!("{} bytes do not fit", data.len())))?;
+        let offset = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|e| self.plugin.error(&e))?;
+        self.memory
+            .write(&mut self.store, offset.cast_unsigned() as usize, data)
+            .map_err(|e| self.plugin.error(&e.into()))?;
+        Ok((offset, len))
+    }
+
+    /// The bytes at `location`, as returned by `transform`.
+    fn read(&self, location: i64) -> Result<Vec<u8>> {
+        let location = location.cast_unsigned();
+        let offset = (location >> 32) as usize;
+        let len = (location & 0xffff_ffff) as usize;
+        self.memory
+            .data(&self.store)
+            .get(offset..offset + len)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                AppError::Plugin(format!(
+                    "{} returned {len} bytes at {offset}, outside its memory",
+                    self.plugin.path.display()
+                ))
+            })
+    }
+
+    /// Calls the hook `name`.
+    fn call<P: WasmParams, R: WasmResults + WasmTy>(&mut self, name: &str, params: P) -> Result<R> {
+        self.instance
+            .get_typed_func::<P, R>(&mut self.store, name)
+            .and_then(|hook| hook.call(&mut self.store, params))
+            .map_err(|e| self.plugin.error(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    /// Selects keys starting with `keep/` and upper-cases ASCII letters.
+    const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $at i32)
+            (local.set $at (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $at))
+          (func (export "select") (param $key i32) (param $len i32) (param i64 i64) (result i32)
+            (i32.and
+              (i32.ge_u (local.get $len) (i32.const 5))
+              (i32.eq (i32.load (local.get $key)) (i32.const 0x7065656b))))
+          (func (export "transform") (param $data i32) (param $len i32) (result i64)
+            (local $i i32)
+            (local $c i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (local.set $c (i32.load8_u (i32.add (local.get $data) (local.get $i))))
+                (if (i32.and (i32.ge_u (local.get $c) (i32.const 97))
+                             (i32.le_u (local.get $c) (i32.const 122)))
+                  (then (i32.store8 (i32.add (local.get $data) (local.get $i))
+                                    (i32.sub (local.get $c) (i32.const 32)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $data)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    fn plugin(name: &str, source: &str) -> Result<Plugin> {
+        let path = std::env::temp_dir().join(format!("osm-{name}-{}.wat", std::process::id()));
+        std::fs::write(&path, source)?;
+        let plugin = Plugin::load(&path);
+        std::fs::remove_file(&path)?;
+        plugin
+    }
+
+    fn meta(key: &str) -> ObjectMeta {
+        ObjectMeta {
+            location: key.into(),
+            last_modified: Utc::now(),
+            size: 1,
+            e_tag: None,
+            version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin_selects_and_transforms() -> Result<()> {
+        let plugin = plugin("plugin", PLUGIN)?;
+        assert!(plugin.select(&meta("keep/a.log"))?);
+        assert!(!plugin.select(&meta("drop/a.log"))?);
+        assert!(plugin.transforms());
+
+        let body: Body = futures::stream::iter(["hello ", "", "world"])
+            .map(|chunk| Ok(Bytes::from(chunk)))
+            .boxed();
+        let (size, body) = plugin.apply("keep/a.log", body, None).await?;
+        let output: Vec<Bytes> = body.try_collect().await?;
+        assert_eq!(size, 11);
+        assert_eq!(output.concat(), b"HELLO WORLD");
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_needs_a_hook() {
+        let module = r#"(module (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0)))"#;
+        assert!(plugin("hookless-plugin", module).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! Progress object of long archive runs, `run_<id>.progress.json` below the destination.
+//!
+//! The object is uploaded when the run starts, every interval while it goes on and once more
+//! when it ends, so monitors without access to the host can follow a run, and how far it got
+//! is known even when the host and its disk are gone.
+
+use crate::error::{AppError, Result};
+use crate::output::warning;
+use chrono::{DateTime, Utc};
+use object_store::{ObjectMeta, ObjectStore, ObjectStoreExt, path::Path};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Start of the names of progress objects.
+pub const PROGRESS_PREFIX: &str = "run_";
+
+/// Interval of `--progress-every <minutes>`.
+///
+/// # Errors
+///
+/// Fails on zero minutes and on intervals too long to represent.
+pub fn interval(minutes: u64) -> Result<Duration> {
+    minutes
+        .checked_mul(60)
+        .filter(|&seconds| seconds > 0)
+        .map(Duration::from_secs)
+        .ok_or_else(|| {
+            AppError::Config(format!(
+                "cannot upload the progress every {minutes} minutes"
+            ))
+        })
+}
+
+/// Content of the progress object.
+#[derive(Debug, Clone, Serialize)]
+pub struct Checkpoint {
+    pub run_id: String,
+    pub src: String,
+    pub dst: String,
+    pub started: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    /// Set by the last upload of the object, when the run ended.
+    pub finished: Option<DateTime<Utc>>,
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+    /// Objects appended to archives so far, including the one being written.
+    pub archived: u64,
+    /// Size of those objects.
+    pub bytes_in: u64,
+    /// Bytes of archives uploaded so far.
+    pub bytes_written: u64,
+    /// Key of the object appended last, i.e. how far the listing got.
+    pub last_key: Option<String>,
+    /// Archives completed.
+    pub archives: Vec<String>,
+}
+
+/// Progress of a run, updated as objects are archived and uploaded.
+#[derive(Debug)]
+pub struct Progress {
+    checkpoint: Mutex<Checkpoint>,
+}
+
+impl Progress {
+    #[must_use]
+    pub fn new(run_id: &str, src: &str, dst: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            checkpoint: Mutex::new(Checkpoint {
+                run_id: run_id.to_string(),
+                src: src.to_string(),
+                dst: dst.to_string(),
+                started: now,
+                updated: now,
+                finished: None,
+                error: None,
+                archived: 0,
+                bytes_in: 0,
+                bytes_written: 0,
+                last_key: None,
+                archives: Vec::new(),
+            }),
+        }
+    }
+
+    /// Location of the progress object of run `run_id` below `dst_path`.
+    #[must_use]
+    pub fn location(dst_path: &Path, run_id: &str) -> Path {
+        dst_path
+            .clone()
+            .join(format!("{PROGRESS_PREFIX}{run_id}.progress.json"))
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Checkpoint)) {
+        update(
+            &mut self
+                .checkpoint
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+
+    /// Records `meta` as appended to an archive.
+    pub fn archived(&self, meta: &ObjectMeta) {
+        self.update(|checkpoint| {
+            checkpoint.archived += 1;
+            checkpoint.bytes_in += meta.size;
+            checkpoint.last_key = Some(meta.location.to_string());
+        });
+    }
+
+    /// Records `bytes` more of an archive as uploaded.
+    pub fn written(&self, bytes: u64) {
+        self.update(|checkpoint| checkpoint.bytes_written += bytes);
+    }
+
+    /// Records the archive at `path` as completed.
+    pub fn completed(&self, path: &Path) {
+        self.update(|checkpoint| checkpoint.archives.push(path.to_string()));
+    }
+
+    /// The progress so far.
+    #[must_use]
+    pub fn checkpoint(&self) -> Checkpoint {
+        let mut checkpoint = self
+            .checkpoint
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        checkpoint.updated = Utc::now();
+        checkpoint
+    }
+
+    /// Uploads the progress so far to `location`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the object cannot be uploaded.
+    pub async fn put(&self, store: &dyn ObjectStore, location: &Path) -> Result<()> {
+        let checkpoint = serde_json::to_vec_pretty(&self.checkpoint())?;
+        store.put(location, checkpoint.into()).await?;
+        Ok(())
+    }
+
+    /// Uploads the progress to `location` right away, then every `interval`, until the
+    /// returned task is aborted. Failures are printed and retried at the next interval.
+    pub fn spawn(
+        self: &Arc<Self>,
+        store: Arc<dyn ObjectStore>,
+        location: Path,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let progress = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = progress.put(store.as_ref(), &location).await {
+                    warning!("Cannot upload the progress to {location}: {e}");
+                }
+            }
+        })
+    }
+
+    /// Uploads the progress to `location` a last time, with how the run ended.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the object cannot be uploaded.
+    pub async fn finish(
+        &self,
+        store: &dyn ObjectStore,
+        location: &Path,
+        error: Option<&AppError>,
+    ) -> Result<()> {
+        self.update(|checkpoint| {
+            checkpoint.finished = Some(Utc::now());
+            checkpoint.error = error.map(ToString::to_string);
+        });
+        self.put(store, location).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_interval() -> Result<()> {
+        assert_eq!(interval(10)?, Duration::from_mins(10));
+        assert!(interval(0).is_err());
+        assert!(interval(u64::MAX).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress_follows_the_run() -> Result<()> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let location = Progress::location(&Path::from("archive"), "run");
+        assert_eq!(location.as_ref(), "archive/run_run.progress.json");
+        let read = || async {
+            let body = store.get(&location).await?.bytes().await?;
+            Ok::<_, AppError>(serde_json::from_slice::<serde_json::Value>(&body)?)
+        };
+
+        let progress = Arc::new(Progress::new("run", "s3://logs/", "s3://archive/"));
+        let task = progress.spawn(
+            Arc::clone(&store),
+            location.clone(),
+            Duration::from_millis(50),
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(read().await?["archived"], 0);
+
+        let meta = ObjectMeta {
+            location: Path::from("logs/a.log"),
+            last_modified: Utc::now(),
+            size: 10,
+            e_tag: None,
+            version: None,
+        };
+        progress.archived(&meta);
+        progress.written(4);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        task.abort();
+        let checkpoint = read().await?;
+        assert_eq!(checkpoint["archived"], 1);
+        assert_eq!(checkpoint["bytes_in"], 10);
+        assert_eq!(checkpoint["bytes_written"], 4);
+        assert_eq!(checkpoint["last_key"], "logs/a.log");
+        assert!(checkpoint["finished"].is_null());
+
+        progress.completed(&Path::from("archive/a.tar.zst"));
+        progress.finish(store.as_ref(), &location, None).await?;
+        let checkpoint = read().await?;
+        assert_eq!(checkpoint["archives"][0], "archive/a.tar.zst");
+        assert!(checkpoint["finished"].is_string());
+        Ok(())
+    }
+}
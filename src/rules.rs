@@ -0,0 +1,282 @@
+//! Retention rules of the configuration file, lifecycle policies for S3 compatible stores
+//! that lack them.
+//!
+//! The objects under a prefix older than some days are archived, expired or moved to another
+//! storage class, all rules in one run.
+//!
+//! ```toml
+//! [[rules]]
+//! prefix = "s3://project/logs/"
+//! older_than_days = 30
+//! action = "archive"
+//! dst = "s3://archive/logs/"
+//! compression = "best"
+//!
+//! [[rules]]
+//! prefix = "s3://project/tmp/"
+//! older_than_days = 7
+//! action = "expire"
+//! ```
+
+use crate::codec::{Codec, Compression};
+use crate::commands::{ArchiveJob, BatchOptions, ask, transition};
+use crate::error::{AppError, Result};
+use crate::filter::ObjectFilter;
+use crate::object_storage::{DELETE_CONCURRENCY, delete_keys};
+use crate::output::{info, summary, warning};
+use crate::storage::get_store_and_path;
+use crate::usage::Prices;
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use object_store::ObjectMeta;
+use serde::Deserialize;
+
+/// Objects transitioned in parallel.
+const TRANSITION_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Archive the objects into `dst`, then delete them.
+    Archive,
+    /// Delete the objects.
+    Expire,
+    /// Copy the objects onto themselves in `storage_class`, S3 only.
+    Transition,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Shown in the output instead of the prefix.
+    pub name: Option<String>,
+    pub prefix: String,
+    pub older_than_days: u32,
+    pub action: RuleAction,
+    /// Name of the entry in `endpoints` used for `s3://` URLs.
+    pub endpoint: Option<String>,
+    /// Where `archive` uploads the archive.
+    pub dst: Option<String>,
+    pub codec: Option<Codec>,
+    pub compression: Option<Compression>,
+    /// Target of `transition`, e.g. `GLACIER_IR`.
+    pub storage_class: Option<String>,
+}
+
+impl Rule {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.prefix)
+    }
+
+    /// Rejects settings the action needs but lacks, or does not use.
+    ///
+    /// # Errors
+    ///
+    /// Fails naming the offending setting.
+    pub fn validate(&self) -> Result<()> {
+        let archive = self.action == RuleAction::Archive;
+        let transition = self.action == RuleAction::Transition;
+        let settings = [
+            ("dst", self.dst.is_some(), archive),
+            ("codec", self.codec.is_some(), archive),
+            ("compression", self.compression.is_some(), archive),
+            ("storage_class", self.storage_class.is_some(), transition),
+        ];
+        for (setting, set, used) in settings {
+            if set && !used {
+                return Err(AppError::Config(format!(
+                    "rule '{}' sets {setting}, which its action does not use",
+                    self.name()
+                )));
+            }
+        }
+        let required = match self.action {
+            RuleAction::Archive => Some(("dst", self.dst.is_some())),
+            RuleAction::Transition => Some(("storage_class", self.storage_class.is_some())),
+            RuleAction::Expire => None,
+        };
+        if let Some((setting, false)) = required {
+            return Err(AppError::Config(format!(
+                "rule '{}' needs {setting}",
+                self.name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Objects last modified more than `older_than_days` ago.
+    fn filter(&self) -> ObjectFilter {
+        ObjectFilter {
+            cutoff: Some(Utc::now() - Duration::days(i64::from(self.older_than_days))),
+            ..ObjectFilter::default()
+        }
+    }
+
+    /// Applies the rule once. With `confirm`, deletions are confirmed on the terminal first.
+    async fn apply(&self, prices: &Prices, confirm: bool) -> Result<()> {
+        match self.action {
+            RuleAction::Archive => {
+                let dst = self.dst.as_deref().unwrap_or_default();
+                let defaults = ArchiveJob::new(&self.prefix, dst);
+                ArchiveJob {
+                    filter: self.filter(),
+                    codec: self.codec.unwrap_or(defaults.codec),
+                    level: self.compression.map_or(defaults.level, Compression::level),
+                    store_precompressed: self.compression == Some(Compression::Auto),
+                    confirm,
+                    prices: prices.clone(),
+                    ..defaults
+                }
+                .run()
+                .await
+            }
+            RuleAction::Expire => expire(&self.prefix, &self.filter(), confirm).await,
+            RuleAction::Transition => {
+                transition(
+                    self.prefix.clone(),
+                    self.storage_class.clone().unwrap_or_default(),
+                    self.filter(),
+                    TRANSITION_CONCURRENCY,
+                    false,
+                    BatchOptions::default(),
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Deletes the objects under `prefix` selected by `filter`.
+async fn expire(prefix: &str, filter: &ObjectFilter, confirm: bool) -> Result<()> {
+    let (store, path) = get_store_and_path(prefix)?;
+    let expired: Vec<ObjectMeta> = store
+        .list(Some(&path))
+        .try_filter(|meta| futures::future::ready(filter.matches(meta)))
+        .try_collect()
+        .await?;
+    if expired.is_empty() {
+        summary!("Nothing to expire under {prefix}");
+        return Ok(());
+    }
+
+    let bytes: u64 = expired.iter().map(|meta| meta.size).sum();
+    let question = format!(
+        "Delete the {} expired objects ({bytes} bytes) from {prefix}?",
+        expired.len()
+    );
+    if confirm && !ask(&question)? {
+        summary!("Keeping the expired objects.");
+        return Ok(());
+    }
+    let keys = expired.into_iter().map(|meta| meta.location).collect();
+    delete_keys(store.as_ref(), keys, DELETE_CONCURRENCY).await
+}
+
+/// Applies all `rules` in order. A failing rule does not keep the later ones from running;
+/// the first failure is returned once all ran.
+///
+/// # Errors
+///
+/// See [`ArchiveJob::run`] and [`transition`]; expiring fails when listing or deleting does.
+pub async fn apply(rules: &[Rule], prices: &Prices, confirm: bool) -> Result<()> {
+    let mut first_error = None;
+    for rule in rules {
+        info!(
+            "Applying rule {}: {:?} objects older than {} days",
+            rule.name(),
+            rule.action,
+            rule.older_than_days
+        );
+        if let Err(e) = rule.apply(prices, confirm).await {
+            warning!("Rule {} failed: {e}", rule.name());
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::ObjectStoreExt;
+    use url::Url;
+
+    fn rule(action: RuleAction) -> Rule {
+        Rule {
+            name: None,
+            prefix: "s3://project/logs/".to_string(),
+            older_than_days: 30,
+            action,
+            endpoint: None,
+            dst: None,
+            codec: None,
+            compression: None,
+            storage_class: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(rule(RuleAction::Expire).validate().is_ok());
+        assert!(rule(RuleAction::Archive).validate().is_err());
+        assert!(
+            Rule {
+                dst: Some("s3://archive/logs/".to_string()),
+                ..rule(RuleAction::Archive)
+            }
+            .validate()
+            .is_ok()
+        );
+        assert!(
+            Rule {
+                dst: Some("s3://archive/logs/".to_string()),
+                ..rule(RuleAction::Expire)
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(rule(RuleAction::Transition).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_archives_and_expires() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("osm-rules-{}", std::process::id()));
+        let url = Url::from_directory_path(&root)
+            .map_err(|()| AppError::Unsupported(root.display().to_string()))?;
+        let (store, path) = get_store_and_path(url.as_str())?;
+        for (dir, file) in [("logs", "a.log"), ("tmp", "b.tmp")] {
+            let location = path.clone().join(dir).join(file);
+            store.put(&location, "data".into()).await?;
+        }
+
+        let rules = [
+            Rule {
+                prefix: format!("{url}logs/"),
+                older_than_days: 0,
+                dst: Some(format!("{url}archive/")),
+                codec: Some(Codec::Gzip),
+                ..rule(RuleAction::Archive)
+            },
+            Rule {
+                prefix: format!("{url}tmp/"),
+                older_than_days: 0,
+                ..rule(RuleAction::Expire)
+            },
+        ];
+        apply(&rules, &Prices::default(), false).await?;
+
+        let left: Vec<ObjectMeta> = store.list(Some(&path)).try_collect().await?;
+        std::fs::remove_dir_all(&root)?;
+        let mut keys: Vec<&str> = left
+            .iter()
+            .filter_map(|meta| meta.location.as_ref().strip_prefix(path.as_ref()))
+            .collect();
+        keys.sort_unstable();
+        assert_eq!(keys.len(), 3, "{keys:?}");
+        assert!(keys[0].starts_with("/archive/archive_") && keys[0].ends_with(".tar.gz"));
+        assert!(keys[1].ends_with(".tar.gz.manifest.json"));
+        assert_eq!(keys[2], "/archive/catalog.jsonl");
+        Ok(())
+    }
+}
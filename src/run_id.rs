@@ -0,0 +1,93 @@
+//! IDs telling the outputs of overlapping and retried runs apart.
+//!
+//! The archive names (via `{run_id}`), manifests, catalog entries, summaries, audit logs and
+//! progress objects of a run carry its ID, and so do the lines of the log file and the
+//! heartbeat while it runs.
+
+use crate::error::{AppError, Result};
+use chrono::Utc;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Mutex, PoisonError};
+
+/// ID of the run in progress, if any.
+static CURRENT: Mutex<Option<String>> = Mutex::new(None);
+
+/// A new ID: the start time and process id, which repeat across containers, and a random
+/// suffix, e.g. `20250101T020000-1-9f86d081`.
+#[must_use]
+pub fn generate() -> String {
+    let now = Utc::now();
+    let random = RandomState::new().hash_one(now.timestamp_nanos_opt());
+    format!(
+        "{}-{}-{:08x}",
+        now.format("%Y%m%dT%H%M%S"),
+        std::process::id(),
+        random & 0xffff_ffff
+    )
+}
+
+/// Checks that an ID given from outside, e.g. by a scheduler retrying a run, can go into
+/// object keys and file names as it is.
+///
+/// # Errors
+///
+/// Fails on empty IDs and on those with other characters than ASCII letters, digits, `-`,
+/// `_` and `.`.
+pub fn check(id: &str) -> Result<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if id.is_empty() || !id.chars().all(allowed) {
+        return Err(AppError::Config(format!(
+            "run ID '{id}' may only hold ASCII letters, digits, '-', '_' and '.'"
+        )));
+    }
+    Ok(())
+}
+
+/// Makes `id` the ID of the run in progress until the returned guard is dropped, so what
+/// runs after it, e.g. the next rule of `apply-rules`, is not logged under it.
+pub fn enter(id: &str) -> Current {
+    *CURRENT.lock().unwrap_or_else(PoisonError::into_inner) = Some(id.to_string());
+    Current(())
+}
+
+/// Guard of the ID of the run in progress, see [`enter`].
+#[must_use = "the run ID is cleared when the guard is dropped"]
+#[derive(Debug)]
+pub struct Current(());
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        *CURRENT.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// ID of the run in progress, `None` before one started.
+#[must_use]
+pub fn current() -> Option<String> {
+    CURRENT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_ids_differ_and_pass_the_check() -> Result<()> {
+        let (a, b) = (generate(), generate());
+        assert_ne!(a, b);
+        check(&a)?;
+        check("nightly-2025-01-01.1")?;
+        assert!(check("").is_err());
+        assert!(check("a/b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_id_is_cleared_when_the_run_ends() {
+        drop(enter("ended-run"));
+        assert_ne!(current().as_deref(), Some("ended-run"));
+    }
+}
@@ -0,0 +1,1903 @@
+use crate::error::{AppError, Result};
+use crate::output::warning;
+use crate::storage::collect_options;
+use crate::usage::{self, Counters};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use object_store::aws::{AmazonS3, AmazonS3Builder, AmazonS3ConfigKey, AwsAuthorizer};
+use object_store::client::{HttpClient, HttpConnector, HttpResponse, ReqwestConnector};
+use object_store::path::Path;
+use object_store::{ClientOptions, ObjectMeta};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Characters left unescaped in object keys, as required for AWS request signing.
+pub const KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// `CopyObject` refuses sources larger than this; bigger objects need a multipart copy.
+const MAX_SINGLE_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Part size used for multipart server-side copies.
+const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Most keys a single `DeleteObjects` request accepts.
+pub const MAX_DELETE_BATCH: usize = 1000;
+
+/// Storage classes whose objects cannot be read before being restored.
+const ARCHIVED_STORAGE_CLASSES: [&str; 2] = ["GLACIER", "DEEP_ARCHIVE"];
+
+/// Predefined S3 grants, applied through the `x-amz-acl` header.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum CannedAcl {
+    Private,
+    PublicRead,
+    PublicReadWrite,
+    AuthenticatedRead,
+    AwsExecRead,
+    BucketOwnerRead,
+    BucketOwnerFullControl,
+}
+
+impl CannedAcl {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Private => "private",
+            Self::PublicRead => "public-read",
+            Self::PublicReadWrite => "public-read-write",
+            Self::AuthenticatedRead => "authenticated-read",
+            Self::AwsExecRead => "aws-exec-read",
+            Self::BucketOwnerRead => "bucket-owner-read",
+            Self::BucketOwnerFullControl => "bucket-owner-full-control",
+        }
+    }
+}
+
+/// Default encryption of buckets created when missing.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BucketEncryption {
+    /// `AES256`, with keys managed by S3.
+    SseS3,
+    /// `aws:kms`, with the AWS managed key unless a key id is given.
+    SseKms,
+}
+
+/// Settings of a bucket created when missing, see [`S3Client::create_bucket`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NewBucket {
+    pub versioning: bool,
+    pub encryption: Option<BucketEncryption>,
+    /// KMS key of [`BucketEncryption::SseKms`].
+    pub kms_key_id: Option<String>,
+}
+
+/// S3 Batch Operations job copying the objects of a manifest onto themselves in another
+/// storage class, see [`S3Client::create_copy_job`].
+#[derive(Debug)]
+pub struct CopyJob<'a> {
+    /// IAM role the job runs as, in the account it is created in.
+    pub role_arn: &'a str,
+    pub manifest_arn: String,
+    pub manifest_e_tag: &'a str,
+    pub storage_class: &'a str,
+    pub description: String,
+}
+
+/// API objects in S3 buckets are listed with.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListApi {
+    /// `ListObjects`, paging with markers, for older S3 compatible gateways that get the
+    /// continuation tokens of `ListObjectsV2` wrong.
+    V1,
+    /// `ListObjectsV2`, paging with continuation tokens.
+    #[default]
+    V2,
+}
+
+impl ListApi {
+    /// Key of the S3 option selecting the API, next to `endpoint` and `region`.
+    pub const OPTION: &str = "list_api";
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+        }
+    }
+
+    /// The API selected in S3 `options`, `ListObjectsV2` unless set.
+    pub(crate) fn from_options(options: &[(String, String)]) -> Result<Self> {
+        let Some((_, value)) = options.iter().find(|(key, _)| key == Self::OPTION) else {
+            return Ok(Self::default());
+        };
+        <Self as clap::ValueEnum>::from_str(value, true).map_err(|_| {
+            AppError::Config(format!(
+                "unknown {} '{value}', expected v1 or v2",
+                Self::OPTION
+            ))
+        })
+    }
+}
+
+/// Signed S3 requests for the bucket APIs `object_store` does not cover
+/// (tagging, storage classes, versions, ...). Only available for `s3://` URLs.
+#[derive(Debug, Clone)]
+pub struct S3Client {
+    store: AmazonS3,
+    http: HttpClient,
+    bucket: String,
+    bucket_endpoint: String,
+    region: String,
+    list_api: ListApi,
+    /// Have `ListObjectsV2` return the owner of each object, see [`S3Client::fetching_owner`].
+    fetch_owner: bool,
+    /// Send requests unsigned, for public buckets.
+    skip_signature: bool,
+}
+
+impl S3Client {
+    /// Returns `None` when `url_str` does not point at S3.
+    pub fn from_url(url_str: &str) -> Result<Option<Self>> {
+        let url = Url::parse(url_str)?;
+        if !matches!(url.scheme(), "s3" | "s3a") {
+            return Ok(None);
+        }
+        let bucket = url
+            .host_str()
+            .ok_or_else(|| AppError::S3Api(format!("missing bucket name in '{url_str}'")))?
+            .to_string();
+
+        let builder = s3_builder(&url);
+        let list_api = ListApi::from_options(&collect_options(&url))?;
+
+        let region = builder
+            .get_config_value(&AmazonS3ConfigKey::Region)
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let virtual_hosted = builder
+            .get_config_value(&AmazonS3ConfigKey::VirtualHostedStyleRequest)
+            .is_some_and(|v| v == "true");
+        let skip_signature = builder
+            .get_config_value(&AmazonS3ConfigKey::SkipSignature)
+            .is_some_and(|v| v == "true");
+        let endpoint = builder
+            .get_config_value(&AmazonS3ConfigKey::S3Endpoint)
+            .or_else(|| builder.get_config_value(&AmazonS3ConfigKey::Endpoint));
+
+        let bucket_endpoint = match (endpoint, virtual_hosted) {
+            (Some(endpoint), true) => endpoint,
+            (Some(endpoint), false) => format!("{}/{bucket}", endpoint.trim_end_matches('/')),
+            (None, true) => format!("https://{bucket}.s3.{region}.amazonaws.com"),
+            (None, false) => format!("https://s3.{region}.amazonaws.com/{bucket}"),
+        };
+
+        let options = ClientOptions::new().with_allow_http(bucket_endpoint.starts_with("http://"));
+        let http = ReqwestConnector::default().connect(&options)?;
+        let store = builder.build()?;
+
+        Ok(Some(Self {
+            store,
+            http,
+            bucket,
+            bucket_endpoint,
+            region,
+            list_api,
+            fetch_owner: false,
+            skip_signature,
+        }))
+    }
+
+    /// Lists objects with their [`S3Object::owner`], which `ListObjects` always returns and
+    /// `ListObjectsV2` only when asked to.
+    #[must_use]
+    pub const fn fetching_owner(mut self) -> Self {
+        self.fetch_owner = true;
+        self
+    }
+
+    /// Builds the request URL for `key` (or the bucket itself) with the given query.
+    fn url(&self, key: Option<&str>, query: &[(&str, &str)]) -> Result<Url> {
+        let mut url = self.bucket_endpoint.trim_end_matches('/').to_string();
+        if let Some(key) = key {
+            url.push('/');
+            url.extend(utf8_percent_encode(key, KEY_ENCODE_SET));
+        }
+
+        let mut url = Url::parse(&url)?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url)
+    }
+
+    /// Signs and sends a request, returning the response whatever its status. Requests stay
+    /// unsigned with `skip_signature`.
+    async fn execute(
+        &self,
+        method: Method,
+        url: &Url,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<HttpResponse> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .body(body.into())?;
+        request.headers_mut().extend(headers);
+
+        if !self.skip_signature {
+            let credential = self.store.credentials().get_credential().await?;
+            AwsAuthorizer::new(&credential, "s3", &self.region).authorize(&mut request, None);
+        }
+
+        Ok(self.http.execute(request).await?)
+    }
+
+    pub async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<HttpResponse> {
+        let url = self.url(key, query)?;
+
+        let counters = Counters::global();
+        counters.request(match method {
+            Method::GET if key.is_none() => usage::Request::List,
+            Method::GET | Method::HEAD => usage::Request::Get,
+            Method::DELETE => usage::Request::Delete,
+            _ => usage::Request::Put,
+        });
+        counters.uploaded(body.len() as u64);
+        let response = self.execute(method.clone(), &url, headers, body).await?;
+        if !response.status().is_success() {
+            return Err(error_response(&method, &url, response).await);
+        }
+
+        Ok(response)
+    }
+
+    /// Raw `HeadObject` response headers, including checksums when S3 stored any.
+    pub async fn head_object(&self, key: &str) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-checksum-mode", HeaderValue::from_static("ENABLED"));
+
+        let response = self
+            .send(Method::HEAD, Some(key), &[], headers, Bytes::new())
+            .await?;
+        Ok(response.headers().clone())
+    }
+
+    /// Starts a `RestoreObject` job making an archived object readable for `days` days.
+    /// A restore that is already running is not an error.
+    pub async fn restore_object(&self, key: &str, days: u32, tier: &str) -> Result<()> {
+        let request = RestoreRequest {
+            days,
+            glacier_job_parameters: GlacierJobParameters {
+                tier: tier.to_string(),
+            },
+        };
+        let body = quick_xml::se::to_string(&request)
+            .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))?;
+
+        let method = Method::POST;
+        let url = self.url(Some(key), &[("restore", "")])?;
+        let response = self
+            .execute(method.clone(), &url, HeaderMap::new(), Bytes::from(body))
+            .await?;
+
+        // 409 means RestoreAlreadyInProgress.
+        if response.status().is_success() || response.status() == StatusCode::CONFLICT {
+            return Ok(());
+        }
+        Err(error_response(&method, &url, response).await)
+    }
+
+    /// Whether the bucket exists according to `HeadBucket`. Buckets the credentials may not
+    /// access are an error rather than missing.
+    pub async fn bucket_exists(&self) -> Result<bool> {
+        let method = Method::HEAD;
+        let url = self.url(None, &[])?;
+        let response = self
+            .execute(method.clone(), &url, HeaderMap::new(), Bytes::new())
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(error_response(&method, &url, response).await),
+        }
+    }
+
+    /// Creates the bucket in the region of the client, then turns on versioning and default
+    /// encryption as `settings` say. A bucket already owned by the caller is not an error.
+    pub async fn create_bucket(&self, settings: &NewBucket) -> Result<()> {
+        let body = create_bucket_body(&self.region)?;
+        match self
+            .send(Method::PUT, None, &[], HeaderMap::new(), body)
+            .await
+        {
+            Err(AppError::S3Api(message)) if message.contains("BucketAlreadyOwnedByYou") => {}
+            result => {
+                result?;
+            }
+        }
+
+        if settings.versioning {
+            let body = quick_xml::se::to_string(&VersioningConfiguration { status: "Enabled" })
+                .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))?;
+            self.send(
+                Method::PUT,
+                None,
+                &[("versioning", "")],
+                checksum_headers(body.as_bytes())?,
+                Bytes::from(body),
+            )
+            .await?;
+        }
+
+        if let Some(encryption) = settings.encryption {
+            let body = encryption_body(encryption, settings.kms_key_id.as_deref())?;
+            self.send(
+                Method::PUT,
+                None,
+                &[("encryption", "")],
+                checksum_headers(body.as_bytes())?,
+                Bytes::from(body),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_object_tagging(&self, key: &str) -> Result<Vec<(String, String)>> {
+        let response = self
+            .send(
+                Method::GET,
+                Some(key),
+                &[("tagging", "")],
+                HeaderMap::new(),
+                Bytes::new(),
+            )
+            .await?;
+        let body = response.into_body().bytes().await?;
+
+        let tagging: Tagging = parse_xml(&body)?;
+        Ok(tagging
+            .tag_set
+            .tags
+            .into_iter()
+            .map(|tag| (tag.key, tag.value))
+            .collect())
+    }
+
+    pub async fn put_object_acl(&self, key: &str, acl: CannedAcl) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-acl", HeaderValue::from_static(acl.as_str()));
+
+        self.send(
+            Method::PUT,
+            Some(key),
+            &[("acl", "")],
+            headers,
+            Bytes::new(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Replaces the tag set of `key`.
+    pub async fn put_object_tagging(&self, key: &str, tags: &[(String, String)]) -> Result<()> {
+        let tagging = Tagging {
+            tag_set: TagSet {
+                tags: tags
+                    .iter()
+                    .map(|(key, value)| Tag {
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                    .collect(),
+            },
+        };
+        let body = quick_xml::se::to_string(&tagging)
+            .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))?;
+
+        self.send(
+            Method::PUT,
+            Some(key),
+            &[("tagging", "")],
+            checksum_headers(body.as_bytes())?,
+            Bytes::from(body),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Full-object SHA-256 recorded by S3 at upload time, hex encoded. Composite checksums of
+    /// multipart uploads do not cover the whole body and are ignored.
+    pub async fn get_object_sha256(&self, key: &str) -> Result<Option<String>> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-amz-object-attributes",
+            HeaderValue::from_static("Checksum,ObjectParts"),
+        );
+        let response = self
+            .send(
+                Method::GET,
+                Some(key),
+                &[("attributes", "")],
+                headers,
+                Bytes::new(),
+            )
+            .await?;
+        let body = response.into_body().bytes().await?;
+        let attributes: GetObjectAttributesResponse = parse_xml(&body)?;
+
+        Ok(attributes.full_object_sha256())
+    }
+
+    /// One `ListObjectsV2` page under `prefix`, which is treated as a directory like
+    /// `object_store` does.
+    pub async fn list_objects_v2(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        continuation_token: Option<&str>,
+    ) -> Result<ListPage> {
+        let prefix = directory_prefix(prefix);
+        let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+        if let Some(delimiter) = delimiter {
+            query.push(("delimiter", delimiter));
+        }
+        if let Some(token) = continuation_token {
+            query.push(("continuation-token", token));
+        }
+        if self.fetch_owner {
+            query.push(("fetch-owner", "true"));
+        }
+
+        let response = self
+            .send(Method::GET, None, &query, HeaderMap::new(), Bytes::new())
+            .await?;
+        let body = response.into_body().bytes().await?;
+        let result: ListBucketResult = parse_xml(&body)?;
+        Ok(result.into())
+    }
+
+    /// One `ListObjects` page under `prefix`, starting after the key `marker`.
+    pub async fn list_objects_v1(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        marker: Option<&str>,
+    ) -> Result<ListPage> {
+        let prefix = directory_prefix(prefix);
+        let mut query = vec![("prefix", prefix.as_str())];
+        if let Some(delimiter) = delimiter {
+            query.push(("delimiter", delimiter));
+        }
+        if let Some(marker) = marker {
+            query.push(("marker", marker));
+        }
+
+        let response = self
+            .send(Method::GET, None, &query, HeaderMap::new(), Bytes::new())
+            .await?;
+        let body = response.into_body().bytes().await?;
+        let result: ListBucketResult = parse_xml(&body)?;
+        let next_marker = result.next_marker();
+        Ok(ListPage {
+            next_token: next_marker,
+            ..result.into()
+        })
+    }
+
+    /// One listing page with the API the bucket is configured for, see [`ListApi`]. `token`
+    /// is a continuation token for `ListObjectsV2` and a marker for `ListObjects`.
+    pub async fn list_page(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<ListPage> {
+        match self.list_api {
+            ListApi::V1 => self.list_objects_v1(prefix, delimiter, token).await,
+            ListApi::V2 => self.list_objects_v2(prefix, delimiter, token).await,
+        }
+    }
+
+    /// Every listing page under `prefix`, following continuation tokens or markers.
+    pub fn list_pages(
+        &self,
+        prefix: String,
+        delimiter: Option<String>,
+    ) -> BoxStream<'static, Result<ListPage>> {
+        self.list_pages_from(prefix, delimiter, None)
+    }
+
+    /// The listing pages under `prefix` following the one `token` was returned with.
+    pub(crate) fn list_pages_from(
+        &self,
+        prefix: String,
+        delimiter: Option<String>,
+        token: Option<String>,
+    ) -> BoxStream<'static, Result<ListPage>> {
+        let client = self.clone();
+        futures::stream::try_unfold(Some(token), move |token: Option<Option<String>>| {
+            let (client, prefix, delimiter) = (client.clone(), prefix.clone(), delimiter.clone());
+            async move {
+                let Some(token) = token else {
+                    return Ok(None);
+                };
+                let page = client
+                    .list_page(&prefix, delimiter.as_deref(), token.as_deref())
+                    .await?;
+                let next = page.next_token.clone().map(Some);
+                Ok(Some((page, next)))
+            }
+        })
+        .boxed()
+    }
+
+    /// Every object under `prefix`, recursively.
+    pub fn list_objects(&self, prefix: String) -> BoxStream<'static, Result<S3Object>> {
+        self.list_pages(prefix, None)
+            .map_ok(|page| futures::stream::iter(page.objects.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// One `ListObjectVersions` page under `prefix`, versions and delete markers merged in
+    /// key order.
+    pub async fn list_object_versions(
+        &self,
+        prefix: &str,
+        marker: Option<&VersionMarker>,
+    ) -> Result<VersionPage> {
+        let prefix = directory_prefix(prefix);
+        let mut query = vec![("versions", ""), ("prefix", prefix.as_str())];
+        if let Some(marker) = marker {
+            query.push(("key-marker", &marker.key));
+            query.push(("version-id-marker", &marker.version_id));
+        }
+
+        let response = self
+            .send(Method::GET, None, &query, HeaderMap::new(), Bytes::new())
+            .await?;
+        let body = response.into_body().bytes().await?;
+        let result: ListVersionsResult = parse_xml(&body)?;
+
+        let mut versions: Vec<ObjectVersion> = result
+            .versions
+            .into_iter()
+            .chain(result.delete_markers.into_iter().map(|mut marker| {
+                marker.delete_marker = true;
+                marker
+            }))
+            .collect();
+        // Stable, so versions of one key stay newest first.
+        versions.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let next = match (result.next_key_marker, result.next_version_id_marker) {
+            (Some(key), Some(version_id)) if result.is_truncated => {
+                Some(VersionMarker { key, version_id })
+            }
+            _ => None,
+        };
+        Ok(VersionPage { versions, next })
+    }
+
+    /// Every version and delete marker under `prefix`, in key order.
+    pub fn list_versions(&self, prefix: String) -> BoxStream<'static, Result<ObjectVersion>> {
+        let client = self.clone();
+        futures::stream::try_unfold(Some(None), move |marker: Option<Option<VersionMarker>>| {
+            let (client, prefix) = (client.clone(), prefix.clone());
+            async move {
+                let Some(marker) = marker else {
+                    return Ok(None);
+                };
+                let page = client
+                    .list_object_versions(&prefix, marker.as_ref())
+                    .await?;
+                Ok::<_, AppError>(Some((page.versions, page.next.map(Some))))
+            }
+        })
+        .map_ok(|versions| futures::stream::iter(versions.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+
+    /// Deletes up to [`MAX_DELETE_BATCH`] keys with a single `DeleteObjects` request. Keys with
+    /// a version id lose exactly that version (or delete marker) for good, keys without one
+    /// get a delete marker in versioned buckets.
+    pub async fn delete_objects(&self, keys: &[(String, Option<String>)]) -> Result<()> {
+        let request = Delete {
+            quiet: true,
+            objects: keys
+                .iter()
+                .map(|(key, version_id)| ObjectIdentifier {
+                    key: key.clone(),
+                    version_id: version_id.clone(),
+                })
+                .collect(),
+        };
+        let body = quick_xml::se::to_string(&request)
+            .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))?;
+
+        let response = self
+            .send(
+                Method::POST,
+                None,
+                &[("delete", "")],
+                checksum_headers(body.as_bytes())?,
+                Bytes::from(body),
+            )
+            .await?;
+        let body = response.into_body().bytes().await?;
+        let result: DeleteResult = parse_xml(&body)?;
+
+        match result.errors.first() {
+            None => Ok(()),
+            Some(error) => Err(AppError::S3Api(format!(
+                "failed to delete {} objects, first '{}': {} {}",
+                result.errors.len(),
+                error.key,
+                error.code,
+                error.message
+            ))),
+        }
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Server-side copies `src_key` in the bucket of `src` to `dst_key` in this client's
+    /// bucket, switching to a multipart copy for objects `CopyObject` cannot handle in one
+    /// request. The copy keeps the content headers, user metadata, tags and storage class of
+    /// the source, the latter unless `storage_class` is given.
+    pub async fn copy_object(
+        &self,
+        src: &Self,
+        src_key: &str,
+        dst_key: &str,
+        size: u64,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        let copy_source = format!(
+            "{}/{}",
+            src.bucket(),
+            utf8_percent_encode(src_key, KEY_ENCODE_SET)
+        );
+        let mut class_headers = HeaderMap::new();
+        if let Some(storage_class) = storage_class {
+            class_headers.insert("x-amz-storage-class", HeaderValue::from_str(storage_class)?);
+        }
+
+        if size <= MAX_SINGLE_COPY_SIZE {
+            let mut headers = class_headers;
+            headers.insert("x-amz-copy-source", HeaderValue::from_str(&copy_source)?);
+            // Copying an object onto itself is only allowed when something changes.
+            headers.insert("x-amz-metadata-directive", HeaderValue::from_static("COPY"));
+            let response = self
+                .send(Method::PUT, Some(dst_key), &[], headers, Bytes::new())
+                .await?;
+            // CopyObject may report failures in the body of a 200 response.
+            let body = response.into_body().bytes().await?;
+            return check_embedded_error(&body);
+        }
+
+        // Unlike `CopyObject`, a multipart upload only gets the metadata it is started with.
+        let (head, tags) =
+            futures::try_join!(src.head_object(src_key), src.get_object_tagging(src_key))?;
+        let headers = copied_headers(&head, &tags, storage_class)?;
+        let upload_id = self.create_multipart_upload(dst_key, headers).await?;
+        match self
+            .copy_parts(&copy_source, dst_key, &upload_id, size)
+            .await
+        {
+            Ok(parts) => {
+                self.complete_multipart_upload(dst_key, &upload_id, &parts)
+                    .await
+            }
+            Err(e) => {
+                if let Err(abort_err) = self.abort_multipart_upload(dst_key, &upload_id).await {
+                    warning!(
+                        "Failed to abort multipart upload {upload_id} for '{dst_key}': \
+                         {abort_err}"
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts a multipart upload to `key` with `headers`, returning its id.
+    pub async fn create_multipart_upload(&self, key: &str, headers: HeaderMap) -> Result<String> {
+        let response = self
+            .send(
+                Method::POST,
+                Some(key),
+                &[("uploads", "")],
+                headers,
+                Bytes::new(),
+            )
+            .await?;
+        let body = response.into_body().bytes().await?;
+        let upload: InitiateMultipartUploadResult = parse_xml(&body)?;
+        Ok(upload.upload_id)
+    }
+
+    /// Uploads `body` as part `part_number` of the upload `upload_id`, with its SHA-256, which
+    /// uploads created with that checksum algorithm require.
+    pub async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: usize,
+        body: Bytes,
+    ) -> Result<UploadedPart> {
+        let headers = checksum_headers(&body)?;
+        let checksum_sha256 = headers
+            .get("x-amz-checksum-sha256")
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string);
+        let size = body.len() as u64;
+        let response = self
+            .send(
+                Method::PUT,
+                Some(key),
+                &[
+                    ("partNumber", &part_number.to_string()),
+                    ("uploadId", upload_id),
+                ],
+                headers,
+                body,
+            )
+            .await?;
+        let e_tag = response
+            .headers()
+            .get("etag")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AppError::S3Api(format!("part {part_number} of {key} has no ETag")))?;
+        Ok(UploadedPart {
+            part_number,
+            e_tag: e_tag.to_string(),
+            checksum_sha256,
+            size,
+        })
+    }
+
+    /// The parts uploaded so far to the upload `upload_id`, in order.
+    pub async fn list_parts(&self, key: &str, upload_id: &str) -> Result<Vec<UploadedPart>> {
+        let mut parts = Vec::new();
+        let mut marker = String::new();
+        loop {
+            let mut query = vec![("uploadId", upload_id)];
+            if !marker.is_empty() {
+                query.push(("part-number-marker", &marker));
+            }
+            let response = self
+                .send(
+                    Method::GET,
+                    Some(key),
+                    &query,
+                    HeaderMap::new(),
+                    Bytes::new(),
+                )
+                .await?;
+            let body = response.into_body().bytes().await?;
+            let page: ListPartsResult = parse_xml(&body)?;
+            parts.extend(page.parts);
+            match page.next_part_number_marker {
+                Some(next) if page.is_truncated => marker = next,
+                _ => return Ok(parts),
+            }
+        }
+    }
+
+    /// Abandons the upload `upload_id`, dropping the parts uploaded so far.
+    pub async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Result<()> {
+        self.send(
+            Method::DELETE,
+            Some(key),
+            &[("uploadId", upload_id)],
+            HeaderMap::new(),
+            Bytes::new(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn copy_parts(
+        &self,
+        copy_source: &str,
+        dst_key: &str,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<Vec<UploadedPart>> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+
+        while start < size {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            let part_number = parts.len() + 1;
+
+            let mut headers = HeaderMap::new();
+            headers.insert("x-amz-copy-source", HeaderValue::from_str(copy_source)?);
+            headers.insert(
+                "x-amz-copy-source-range",
+                HeaderValue::from_str(&format!("bytes={start}-{end}"))?,
+            );
+
+            let response = self
+                .send(
+                    Method::PUT,
+                    Some(dst_key),
+                    &[
+                        ("partNumber", &part_number.to_string()),
+                        ("uploadId", upload_id),
+                    ],
+                    headers,
+                    Bytes::new(),
+                )
+                .await?;
+            let body = response.into_body().bytes().await?;
+            let part: CopyPartResult = parse_xml(&body)?;
+            parts.push(UploadedPart {
+                part_number,
+                e_tag: part.e_tag,
+                checksum_sha256: None,
+                size: end + 1 - start,
+            });
+
+            start = end + 1;
+        }
+
+        Ok(parts)
+    }
+
+    /// Completes the upload `upload_id` from `parts`, which must be in order.
+    pub async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<()> {
+        let request = CompleteMultipartUpload {
+            parts: parts
+                .iter()
+                .map(|part| CompletedPart {
+                    part_number: part.part_number,
+                    e_tag: part.e_tag.clone(),
+                    checksum_sha256: part.checksum_sha256.clone(),
+                })
+                .collect(),
+        };
+        let body = quick_xml::se::to_string(&request)
+            .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))?;
+
+        let response = self
+            .send(
+                Method::POST,
+                Some(key),
+                &[("uploadId", upload_id)],
+                HeaderMap::new(),
+                Bytes::from(body),
+            )
+            .await?;
+        let body = response.into_body().bytes().await?;
+        check_embedded_error(&body)
+    }
+
+    /// Creates `job` through the S3 Control API, in the account of its role and the region
+    /// of this client's bucket. Returns the id of the job, which starts without confirmation.
+    pub async fn create_copy_job(&self, job: &CopyJob<'_>) -> Result<String> {
+        let account_id = job
+            .role_arn
+            .split(':')
+            .nth(4)
+            .filter(|account_id| !account_id.is_empty())
+            .ok_or_else(|| AppError::Config(format!("{} is not an IAM role ARN", job.role_arn)))?;
+        let url = Url::parse(&format!(
+            "https://{account_id}.s3-control.{}.amazonaws.com/v20180820/jobs",
+            self.region
+        ))?;
+        let body = create_job_body(&self.bucket, job)?;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-account-id", HeaderValue::from_str(account_id)?);
+
+        Counters::global().request(usage::Request::Put);
+        let response = self
+            .execute(Method::POST, &url, headers, Bytes::from(body))
+            .await?;
+        if !response.status().is_success() {
+            return Err(error_response(&Method::POST, &url, response).await);
+        }
+        let body = response.into_body().bytes().await?;
+        let result: CreateJobResult = parse_xml(&body)?;
+        Ok(result.job_id)
+    }
+}
+
+async fn error_response(method: &Method, url: &Url, response: HttpResponse) -> AppError {
+    let status = response.status();
+    let body = response.into_body().bytes().await.unwrap_or_default();
+    AppError::S3Api(format!(
+        "{method} {url} returned {status}: {}",
+        String::from_utf8_lossy(&body)
+    ))
+}
+
+/// Where an archived object is in the restore cycle, according to `x-amz-restore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreStatus {
+    NotRequested,
+    InProgress,
+    Restored,
+}
+
+impl RestoreStatus {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        match headers.get("x-amz-restore").and_then(|v| v.to_str().ok()) {
+            None => Self::NotRequested,
+            Some(value) if value.contains("ongoing-request=\"true\"") => Self::InProgress,
+            Some(_) => Self::Restored,
+        }
+    }
+}
+
+/// `AmazonS3Builder` configured the same way `get_store_and_path` configures S3 stores.
+pub fn s3_builder(url: &Url) -> AmazonS3Builder {
+    collect_options(url).into_iter().fold(
+        AmazonS3Builder::new().with_url(url.as_str()),
+        |builder, (key, value)| match key.parse::<AmazonS3ConfigKey>() {
+            Ok(key) => builder.with_config(key, value),
+            Err(_) => builder,
+        },
+    )
+}
+
+/// Integrity header for request bodies of APIs that insist on one (tagging, batch deletes).
+/// A SHA-256 checksum is accepted wherever `Content-MD5` is required.
+fn checksum_headers(body: &[u8]) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "x-amz-checksum-sha256",
+        HeaderValue::from_str(&BASE64_STANDARD.encode(Sha256::digest(body)))?,
+    );
+    Ok(headers)
+}
+
+/// Headers starting a multipart copy of an object that `HeadObject` answered with `head` and
+/// is tagged with `tags`: what `CopyObject` would carry over, with `storage_class` instead of
+/// the source's if given.
+fn copied_headers(
+    head: &HeaderMap,
+    tags: &[(String, String)],
+    storage_class: Option<&str>,
+) -> Result<HeaderMap> {
+    const CONTENT_HEADERS: [&str; 6] = [
+        "content-type",
+        "content-encoding",
+        "content-disposition",
+        "content-language",
+        "cache-control",
+        "expires",
+    ];
+    let mut headers = HeaderMap::new();
+    for (name, value) in head {
+        let name_str = name.as_str();
+        if CONTENT_HEADERS.contains(&name_str)
+            || name_str.starts_with("x-amz-meta-")
+            || (name_str == "x-amz-storage-class" && storage_class.is_none())
+        {
+            headers.append(name, value.clone());
+        }
+    }
+    if let Some(storage_class) = storage_class {
+        headers.insert("x-amz-storage-class", HeaderValue::from_str(storage_class)?);
+    }
+    if !tags.is_empty() {
+        let tagging = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(tags)
+            .finish();
+        headers.insert("x-amz-tagging", HeaderValue::from_str(&tagging)?);
+    }
+    Ok(headers)
+}
+
+/// `CreateBucket` body placing the bucket in `region`. `us-east-1` is the default and must
+/// not be named.
+fn create_bucket_body(region: &str) -> Result<Bytes> {
+    if region == "us-east-1" {
+        return Ok(Bytes::new());
+    }
+    let configuration = CreateBucketConfiguration {
+        location_constraint: region.to_string(),
+    };
+    let body = quick_xml::se::to_string(&configuration)
+        .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))?;
+    Ok(Bytes::from(body))
+}
+
+/// `PutBucketEncryption` body with `encryption` as the default of new objects.
+fn encryption_body(encryption: BucketEncryption, kms_key_id: Option<&str>) -> Result<String> {
+    let (algorithm, kms_master_key_id) = match encryption {
+        BucketEncryption::SseS3 => ("AES256", None),
+        BucketEncryption::SseKms => ("aws:kms", kms_key_id.map(str::to_string)),
+    };
+    let configuration = ServerSideEncryptionConfiguration {
+        rule: EncryptionRule {
+            apply_server_side_encryption_by_default: EncryptionByDefault {
+                sse_algorithm: algorithm,
+                kms_master_key_id,
+            },
+        },
+    };
+    quick_xml::se::to_string(&configuration)
+        .map_err(|e| AppError::S3Api(format!("invalid request: {e}")))
+}
+
+/// `CreateJob` body for `job` on the objects in `bucket`.
+fn create_job_body(bucket: &str, job: &CopyJob<'_>) -> Result<String> {
+    let request = CreateJobRequest {
+        xmlns: "http://awss3control.amazonaws.com/doc/2018-08-20/",
+        confirmation_required: false,
+        operation: JobOperation {
+            copy: PutObjectCopy {
+                target_resource: format!("arn:aws:s3:::{bucket}"),
+                storage_class: job.storage_class,
+                metadata_directive: "COPY",
+            },
+        },
+        report: JobReport { enabled: false },
+        client_request_token: format!("{}-{}", job.manifest_e_tag, Utc::now().timestamp()),
+        manifest: JobManifest {
+            spec: ManifestSpec {
+                format: "S3BatchOperations_CSV_20180820",
+                fields: ManifestFields {
+                    member: ["Bucket", "Key"],
+                },
+            },
+            location: ManifestLocation {
+                object_arn: &job.manifest_arn,
+                e_tag: job.manifest_e_tag,
+            },
+        },
+        description: &job.description,
+        priority: 10,
+        role_arn: job.role_arn,
+    };
+    quick_xml::se::to_string(&request).map_err(|e| AppError::S3Api(format!("invalid request: {e}")))
+}
+
+fn directory_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    }
+}
+
+fn check_embedded_error(body: &[u8]) -> Result<()> {
+    let text = String::from_utf8_lossy(body);
+    if text.contains("<Error>") {
+        return Err(AppError::S3Api(text.into_owned()));
+    }
+    Ok(())
+}
+
+fn parse_xml<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T> {
+    let text = std::str::from_utf8(body).map_err(|e| AppError::S3Api(e.to_string()))?;
+    quick_xml::de::from_str(text).map_err(|e| AppError::S3Api(format!("invalid response: {e}")))
+}
+
+/// An entry of a `ListObjectsV2` response, carrying fields `ObjectMeta` has no room for.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct S3Object {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+    #[serde(rename = "ETag")]
+    pub e_tag: Option<String>,
+    pub storage_class: Option<String>,
+    /// Only listed with [`S3Client::fetching_owner`] or `ListObjects`.
+    pub owner: Option<Owner>,
+}
+
+/// Account owning an object.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Owner {
+    /// Canonical user ID of the account.
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub display_name: Option<String>,
+}
+
+impl S3Object {
+    /// Storage class as reported by S3, which omits it for some `STANDARD` objects.
+    pub fn storage_class(&self) -> &str {
+        self.storage_class.as_deref().unwrap_or("STANDARD")
+    }
+
+    /// Whether the object has to be restored before it can be read or copied.
+    pub fn is_archived(&self) -> bool {
+        ARCHIVED_STORAGE_CLASSES.contains(&self.storage_class())
+    }
+}
+
+impl From<&S3Object> for ObjectMeta {
+    fn from(object: &S3Object) -> Self {
+        Self {
+            location: Path::from(object.key.as_str()),
+            last_modified: object.last_modified,
+            size: object.size,
+            e_tag: object.e_tag.clone(),
+            version: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ListPage {
+    pub objects: Vec<S3Object>,
+    /// Common prefixes without their trailing delimiter.
+    pub common_prefixes: Vec<String>,
+    /// Continuation token, or marker with `ListObjects`, of the next page.
+    pub next_token: Option<String>,
+}
+
+impl From<ListBucketResult> for ListPage {
+    fn from(result: ListBucketResult) -> Self {
+        Self {
+            objects: result.contents,
+            common_prefixes: result
+                .common_prefixes
+                .into_iter()
+                .map(|p| p.prefix.trim_end_matches('/').to_string())
+                .collect(),
+            next_token: result.next_continuation_token,
+        }
+    }
+}
+
+/// A `Version` or `DeleteMarker` entry of a `ListObjectVersions` response.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ObjectVersion {
+    pub key: String,
+    pub version_id: String,
+    pub is_latest: bool,
+    pub last_modified: DateTime<Utc>,
+    #[serde(skip)]
+    pub delete_marker: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionMarker {
+    pub key: String,
+    pub version_id: String,
+}
+
+#[derive(Debug, Default)]
+pub struct VersionPage {
+    pub versions: Vec<ObjectVersion>,
+    pub next: Option<VersionMarker>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListVersionsResult {
+    #[serde(default)]
+    is_truncated: bool,
+    next_key_marker: Option<String>,
+    next_version_id_marker: Option<String>,
+    #[serde(rename = "Version", default)]
+    versions: Vec<ObjectVersion>,
+    #[serde(rename = "DeleteMarker", default)]
+    delete_markers: Vec<ObjectVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<S3Object>,
+    #[serde(default)]
+    common_prefixes: Vec<CommonPrefix>,
+    next_continuation_token: Option<String>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_marker: Option<String>,
+}
+
+impl ListBucketResult {
+    /// Marker of the `ListObjects` page after this one. `NextMarker` only comes with a
+    /// delimiter, without one the page continues after its last key.
+    fn next_marker(&self) -> Option<String> {
+        if !self.is_truncated {
+            return None;
+        }
+        self.next_marker.clone().or_else(|| {
+            let last_key = self.contents.last().map(|object| &object.key);
+            let last_prefix = self.common_prefixes.last().map(|p| &p.prefix);
+            last_key.max(last_prefix).cloned()
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CommonPrefix {
+    prefix: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+    upload_id: String,
+}
+
+/// A part of a multipart upload, as `ListParts` reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UploadedPart {
+    pub part_number: usize,
+    #[serde(rename = "ETag")]
+    pub e_tag: String,
+    /// Base64 encoded SHA-256 of the part, for uploads created with that checksum algorithm.
+    #[serde(rename = "ChecksumSHA256")]
+    pub checksum_sha256: Option<String>,
+    pub size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListPartsResult {
+    #[serde(default, rename = "Part")]
+    parts: Vec<UploadedPart>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_part_number_marker: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CopyPartResult {
+    #[serde(rename = "ETag")]
+    e_tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetObjectAttributesResponse {
+    checksum: Option<ObjectChecksum>,
+    object_parts: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ObjectChecksum {
+    #[serde(rename = "ChecksumSHA256")]
+    checksum_sha256: Option<String>,
+    checksum_type: Option<String>,
+}
+
+impl GetObjectAttributesResponse {
+    fn full_object_sha256(self) -> Option<String> {
+        let checksum = self.checksum?;
+        let full_object = match checksum.checksum_type.as_deref() {
+            Some(checksum_type) => checksum_type == "FULL_OBJECT",
+            None => self.object_parts.is_none(),
+        };
+        if !full_object {
+            return None;
+        }
+
+        let digest = BASE64_STANDARD.decode(checksum.checksum_sha256?).ok()?;
+        Some(hex::encode(digest))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CompleteMultipartUpload {
+    #[serde(rename = "Part")]
+    parts: Vec<CompletedPart>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletedPart {
+    #[serde(rename = "PartNumber")]
+    part_number: usize,
+    #[serde(rename = "ETag")]
+    e_tag: String,
+    #[serde(rename = "ChecksumSHA256", skip_serializing_if = "Option::is_none")]
+    checksum_sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateBucketConfiguration {
+    location_constraint: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct VersioningConfiguration {
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServerSideEncryptionConfiguration {
+    rule: EncryptionRule,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EncryptionRule {
+    apply_server_side_encryption_by_default: EncryptionByDefault,
+}
+
+#[derive(Debug, Serialize)]
+struct EncryptionByDefault {
+    #[serde(rename = "SSEAlgorithm")]
+    sse_algorithm: &'static str,
+    #[serde(rename = "KMSMasterKeyID", skip_serializing_if = "Option::is_none")]
+    kms_master_key_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct RestoreRequest {
+    days: u32,
+    glacier_job_parameters: GlacierJobParameters,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GlacierJobParameters {
+    tier: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Delete {
+    quiet: bool,
+    #[serde(rename = "Object")]
+    objects: Vec<ObjectIdentifier>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ObjectIdentifier {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteResult {
+    #[serde(rename = "Error", default)]
+    errors: Vec<DeleteError>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeleteError {
+    key: String,
+    code: String,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateJobRequest<'a> {
+    #[serde(rename = "@xmlns")]
+    xmlns: &'static str,
+    confirmation_required: bool,
+    operation: JobOperation<'a>,
+    report: JobReport,
+    client_request_token: String,
+    manifest: JobManifest<'a>,
+    description: &'a str,
+    priority: u32,
+    role_arn: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct JobOperation<'a> {
+    #[serde(rename = "S3PutObjectCopy")]
+    copy: PutObjectCopy<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PutObjectCopy<'a> {
+    target_resource: String,
+    storage_class: &'a str,
+    metadata_directive: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JobReport {
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JobManifest<'a> {
+    spec: ManifestSpec,
+    location: ManifestLocation<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ManifestSpec {
+    format: &'static str,
+    fields: ManifestFields,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestFields {
+    member: [&'static str; 2],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ManifestLocation<'a> {
+    object_arn: &'a str,
+    #[serde(rename = "ETag")]
+    e_tag: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateJobResult {
+    job_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Tagging {
+    tag_set: TagSet,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct TagSet {
+    #[serde(rename = "Tag", default)]
+    tags: Vec<Tag>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Tag {
+    key: String,
+    value: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_url_ignores_other_schemes() -> Result<()> {
+        assert!(S3Client::from_url("gs://bucket/path")?.is_none());
+        assert!(S3Client::from_url("file:///tmp/test")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_url_encodes_keys() -> Result<()> {
+        let client =
+            S3Client::from_url("s3://bucket/path")?.ok_or(AppError::S3Api(String::new()))?;
+        let url = client.url(Some("logs/a b+c.log"), &[("tagging", "")])?;
+        assert!(
+            url.as_str()
+                .ends_with("/bucket/logs/a%20b%2Bc.log?tagging=")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tagging() -> Result<()> {
+        let body =
+            b"<Tagging><TagSet><Tag><Key>team</Key><Value>growth</Value></Tag></TagSet></Tagging>";
+        let tagging: Tagging = parse_xml(body)?;
+        assert_eq!(tagging.tag_set.tags.len(), 1);
+        assert_eq!(tagging.tag_set.tags[0].key, "team");
+        assert_eq!(tagging.tag_set.tags[0].value, "growth");
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_tagging() -> Result<()> {
+        let tagging = Tagging {
+            tag_set: TagSet {
+                tags: vec![Tag {
+                    key: "state".to_string(),
+                    value: "archived".to_string(),
+                }],
+            },
+        };
+        let xml = quick_xml::se::to_string(&tagging).map_err(|e| AppError::S3Api(e.to_string()))?;
+        assert_eq!(
+            xml,
+            "<Tagging><TagSet><Tag><Key>state</Key><Value>archived</Value></Tag></TagSet></Tagging>"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_complete_multipart_upload() -> Result<()> {
+        let request = CompleteMultipartUpload {
+            parts: vec![
+                CompletedPart {
+                    part_number: 1,
+                    e_tag: "\"abc\"".to_string(),
+                    checksum_sha256: None,
+                },
+                CompletedPart {
+                    part_number: 2,
+                    e_tag: "\"def\"".to_string(),
+                    checksum_sha256: Some("c2hh".to_string()),
+                },
+            ],
+        };
+        let xml = quick_xml::se::to_string(&request).map_err(|e| AppError::S3Api(e.to_string()))?;
+        assert_eq!(
+            xml,
+            "<CompleteMultipartUpload><Part><PartNumber>1</PartNumber>\
+             <ETag>\"abc\"</ETag></Part><Part><PartNumber>2</PartNumber>\
+             <ETag>\"def\"</ETag><ChecksumSHA256>c2hh</ChecksumSHA256></Part>\
+             </CompleteMultipartUpload>"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_list_parts_result() -> Result<()> {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListPartsResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Bucket>archive</Bucket><Key>a.tar.zst</Key><UploadId>id</UploadId>
+  <PartNumberMarker>0</PartNumberMarker><NextPartNumberMarker>2</NextPartNumberMarker>
+  <MaxParts>2</MaxParts><IsTruncated>true</IsTruncated>
+  <Part><PartNumber>1</PartNumber><LastModified>2024-06-01T00:00:00.000Z</LastModified>
+    <ETag>"abc"</ETag><ChecksumSHA256>c2hh</ChecksumSHA256><Size>5242880</Size></Part>
+  <Part><PartNumber>2</PartNumber><LastModified>2024-06-01T00:00:00.000Z</LastModified>
+    <ETag>"def"</ETag><Size>10</Size></Part>
+</ListPartsResult>"#;
+        let result: ListPartsResult = parse_xml(xml.as_bytes())?;
+        assert!(result.is_truncated);
+        assert_eq!(result.next_part_number_marker.as_deref(), Some("2"));
+        assert_eq!(
+            result.parts,
+            [
+                UploadedPart {
+                    part_number: 1,
+                    e_tag: "\"abc\"".to_string(),
+                    checksum_sha256: Some("c2hh".to_string()),
+                    size: 5_242_880,
+                },
+                UploadedPart {
+                    part_number: 2,
+                    e_tag: "\"def\"".to_string(),
+                    checksum_sha256: None,
+                    size: 10,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_object_sha256() -> Result<()> {
+        let single: GetObjectAttributesResponse = parse_xml(
+            b"<GetObjectAttributesResponse><Checksum>\
+              <ChecksumSHA256>LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=</ChecksumSHA256>\
+              </Checksum></GetObjectAttributesResponse>",
+        )?;
+        assert_eq!(
+            single.full_object_sha256().as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+
+        let composite: GetObjectAttributesResponse = parse_xml(
+            b"<GetObjectAttributesResponse><Checksum>\
+              <ChecksumSHA256>LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=</ChecksumSHA256>\
+              <ChecksumType>COMPOSITE</ChecksumType></Checksum>\
+              <ObjectParts><TotalPartsCount>2</TotalPartsCount></ObjectParts>\
+              </GetObjectAttributesResponse>",
+        )?;
+        assert_eq!(composite.full_object_sha256(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_list_bucket_result() -> Result<()> {
+        let result: ListBucketResult = parse_xml(
+            b"<ListBucketResult><Contents><Key>logs/a.log</Key>\
+              <LastModified>2024-06-01T12:00:00.000Z</LastModified><ETag>&quot;abc&quot;</ETag>\
+              <Size>42</Size><StorageClass>GLACIER</StorageClass>\
+              <Owner><ID>75aa57f0</ID><DisplayName>growth</DisplayName></Owner></Contents>\
+              <CommonPrefixes><Prefix>logs/2024/</Prefix></CommonPrefixes>\
+              <IsTruncated>true</IsTruncated><NextContinuationToken>next</NextContinuationToken>\
+              </ListBucketResult>",
+        )?;
+        assert_eq!(result.contents.len(), 1);
+        assert_eq!(result.contents[0].key, "logs/a.log");
+        assert_eq!(result.contents[0].size, 42);
+        assert_eq!(result.contents[0].e_tag.as_deref(), Some("\"abc\""));
+        assert_eq!(result.contents[0].storage_class.as_deref(), Some("GLACIER"));
+        assert_eq!(
+            result.contents[0]
+                .owner
+                .as_ref()
+                .map(|owner| owner.id.as_str()),
+            Some("75aa57f0")
+        );
+        assert_eq!(result.common_prefixes[0].prefix, "logs/2024/");
+        assert_eq!(result.next_continuation_token.as_deref(), Some("next"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_next_marker() -> Result<()> {
+        let result: ListBucketResult = parse_xml(
+            b"<ListBucketResult><IsTruncated>true</IsTruncated>\
+              <Contents><Key>logs/a.log</Key><LastModified>2024-06-01T12:00:00.000Z</LastModified>\
+              <Size>1</Size></Contents><Contents><Key>logs/b.log</Key>\
+              <LastModified>2024-06-01T12:00:00.000Z</LastModified><Size>1</Size></Contents>\
+              </ListBucketResult>",
+        )?;
+        assert_eq!(result.next_marker().as_deref(), Some("logs/b.log"));
+
+        let result: ListBucketResult = parse_xml(
+            b"<ListBucketResult><IsTruncated>true</IsTruncated><NextMarker>logs/c/</NextMarker>\
+              <CommonPrefixes><Prefix>logs/c/</Prefix></CommonPrefixes></ListBucketResult>",
+        )?;
+        assert_eq!(result.next_marker().as_deref(), Some("logs/c/"));
+
+        let result: ListBucketResult =
+            parse_xml(b"<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>")?;
+        assert_eq!(result.next_marker(), None);
+        assert_eq!(
+            ListApi::from_options(&[("list_api".to_string(), "V1".to_string())])?,
+            ListApi::V1
+        );
+        assert!(ListApi::from_options(&[("list_api".to_string(), "v3".to_string())]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_restore_request() -> Result<()> {
+        let request = RestoreRequest {
+            days: 3,
+            glacier_job_parameters: GlacierJobParameters {
+                tier: "Bulk".to_string(),
+            },
+        };
+        let xml = quick_xml::se::to_string(&request).map_err(|e| AppError::S3Api(e.to_string()))?;
+        assert_eq!(
+            xml,
+            "<RestoreRequest><Days>3</Days><GlacierJobParameters><Tier>Bulk</Tier>\
+             </GlacierJobParameters></RestoreRequest>"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_bucket_bodies() -> Result<()> {
+        assert!(create_bucket_body("us-east-1")?.is_empty());
+        assert_eq!(
+            create_bucket_body("eu-central-1")?,
+            "<CreateBucketConfiguration><LocationConstraint>eu-central-1</LocationConstraint>\
+             </CreateBucketConfiguration>"
+        );
+        assert_eq!(
+            encryption_body(BucketEncryption::SseKms, Some("alias/archive"))?,
+            "<ServerSideEncryptionConfiguration><Rule><ApplyServerSideEncryptionByDefault>\
+             <SSEAlgorithm>aws:kms</SSEAlgorithm><KMSMasterKeyID>alias/archive</KMSMasterKeyID>\
+             </ApplyServerSideEncryptionByDefault></Rule></ServerSideEncryptionConfiguration>"
+        );
+        assert!(encryption_body(BucketEncryption::SseS3, None)?.contains(">AES256<"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_status_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            RestoreStatus::from_headers(&headers),
+            RestoreStatus::NotRequested
+        );
+
+        headers.insert(
+            "x-amz-restore",
+            HeaderValue::from_static("ongoing-request=\"true\""),
+        );
+        assert_eq!(
+            RestoreStatus::from_headers(&headers),
+            RestoreStatus::InProgress
+        );
+
+        headers.insert(
+            "x-amz-restore",
+            HeaderValue::from_static(
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"",
+            ),
+        );
+        assert_eq!(
+            RestoreStatus::from_headers(&headers),
+            RestoreStatus::Restored
+        );
+    }
+
+    #[test]
+    fn test_storage_class_defaults_to_standard() {
+        let mut object = S3Object {
+            key: "logs/a.log".to_string(),
+            size: 1,
+            last_modified: Utc::now(),
+            e_tag: None,
+            storage_class: None,
+            owner: None,
+        };
+        assert_eq!(object.storage_class(), "STANDARD");
+        assert!(!object.is_archived());
+
+        object.storage_class = Some("DEEP_ARCHIVE".to_string());
+        assert!(object.is_archived());
+        object.storage_class = Some("GLACIER_IR".to_string());
+        assert!(!object.is_archived());
+    }
+
+    #[test]
+    fn test_parse_list_versions_result() -> Result<()> {
+        let result: ListVersionsResult = parse_xml(
+            b"<ListVersionsResult><IsTruncated>true</IsTruncated>\
+              <NextKeyMarker>b.log</NextKeyMarker><NextVersionIdMarker>v3</NextVersionIdMarker>\
+              <DeleteMarker><Key>a.log</Key><VersionId>v1</VersionId><IsLatest>true</IsLatest>\
+              <LastModified>2024-06-01T12:00:00.000Z</LastModified></DeleteMarker>\
+              <Version><Key>b.log</Key><VersionId>v3</VersionId><IsLatest>true</IsLatest>\
+              <LastModified>2024-06-01T12:00:00.000Z</LastModified><Size>42</Size></Version>\
+              <DeleteMarker><Key>c.log</Key><VersionId>v4</VersionId><IsLatest>true</IsLatest>\
+              <LastModified>2024-06-01T12:00:00.000Z</LastModified></DeleteMarker>\
+              </ListVersionsResult>",
+        )?;
+        assert!(result.is_truncated);
+        assert_eq!(result.next_version_id_marker.as_deref(), Some("v3"));
+        assert_eq!(result.versions.len(), 1);
+        assert_eq!(result.versions[0].version_id, "v3");
+        assert_eq!(result.delete_markers.len(), 2);
+        assert_eq!(result.delete_markers[1].key, "c.log");
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_delete() -> Result<()> {
+        let request = Delete {
+            quiet: true,
+            objects: vec![
+                ObjectIdentifier {
+                    key: "a.log".to_string(),
+                    version_id: Some("v1".to_string()),
+                },
+                ObjectIdentifier {
+                    key: "b.log".to_string(),
+                    version_id: None,
+                },
+            ],
+        };
+        let xml = quick_xml::se::to_string(&request).map_err(|e| AppError::S3Api(e.to_string()))?;
+        assert_eq!(
+            xml,
+            "<Delete><Quiet>true</Quiet>\
+             <Object><Key>a.log</Key><VersionId>v1</VersionId></Object>\
+             <Object><Key>b.log</Key></Object></Delete>"
+        );
+
+        let result: DeleteResult = parse_xml(
+            b"<DeleteResult><Error><Key>a.log</Key><VersionId>v1</VersionId>\
+              <Code>AccessDenied</Code><Message>Access Denied</Message></Error></DeleteResult>",
+        )?;
+        assert_eq!(result.errors[0].code, "AccessDenied");
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_job_body() -> Result<()> {
+        let job = CopyJob {
+            role_arn: "arn:aws:iam::123456789012:role/batch",
+            manifest_arn: "arn:aws:s3:::state/manifest.csv".to_string(),
+            manifest_e_tag: "60e460c9d1046e73f7dde5043ac3ae85",
+            storage_class: "GLACIER_IR",
+            description: "transition".to_string(),
+        };
+        let xml = create_job_body("logs", &job)?;
+        assert!(xml.starts_with(
+            "<CreateJobRequest xmlns=\"http://awss3control.amazonaws.com/doc/2018-08-20/\">\
+             <ConfirmationRequired>false</ConfirmationRequired><Operation><S3PutObjectCopy>\
+             <TargetResource>arn:aws:s3:::logs</TargetResource>\
+             <StorageClass>GLACIER_IR</StorageClass><MetadataDirective>COPY</MetadataDirective>\
+             </S3PutObjectCopy></Operation><Report><Enabled>false</Enabled></Report>"
+        ));
+        assert!(xml.contains(
+            "<Spec><Format>S3BatchOperations_CSV_20180820</Format><Fields><member>Bucket</member>\
+             <member>Key</member></Fields></Spec><Location>\
+             <ObjectArn>arn:aws:s3:::state/manifest.csv</ObjectArn>\
+             <ETag>60e460c9d1046e73f7dde5043ac3ae85</ETag></Location>"
+        ));
+        assert!(xml.ends_with(
+            "<Priority>10</Priority><RoleArn>arn:aws:iam::123456789012:role/batch</RoleArn>\
+             </CreateJobRequest>"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_prefix() {
+        assert_eq!(directory_prefix(""), "");
+        assert_eq!(directory_prefix("logs"), "logs/");
+        assert_eq!(directory_prefix("logs/"), "logs/");
+    }
+
+    #[test]
+    fn test_copied_headers_keep_metadata_and_tags() -> Result<()> {
+        let mut head = HeaderMap::new();
+        for (name, value) in [
+            ("content-type", "application/json"),
+            ("cache-control", "no-cache"),
+            ("x-amz-meta-team", "growth"),
+            ("x-amz-storage-class", "STANDARD_IA"),
+            ("content-length", "6000000000"),
+            ("etag", "\"abc-12\""),
+        ] {
+            head.insert(name, HeaderValue::from_static(value));
+        }
+        let tags = [("state".to_string(), "live & well".to_string())];
+
+        let headers = copied_headers(&head, &tags, None)?;
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["cache-control"], "no-cache");
+        assert_eq!(headers["x-amz-meta-team"], "growth");
+        assert_eq!(headers["x-amz-storage-class"], "STANDARD_IA");
+        assert_eq!(headers["x-amz-tagging"], "state=live+%26+well");
+
+        // A transition replaces the storage class and keeps the rest.
+        let headers = copied_headers(&head, &tags, Some("GLACIER_IR"))?;
+        assert_eq!(headers["x-amz-storage-class"], "GLACIER_IR");
+        assert_eq!(headers["x-amz-meta-team"], "growth");
+        assert_eq!(headers["x-amz-tagging"], "state=live+%26+well");
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_embedded_error() {
+        assert!(check_embedded_error(b"<CopyObjectResult></CopyObjectResult>").is_ok());
+        assert!(check_embedded_error(b"<Error><Code>InternalError</Code></Error>").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Temporary files standing in for in-memory buffers on memory constrained machines.
+
+use bytes::Bytes;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+static SPOOL_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// A buffer backed by an anonymous file in a spool directory. The file is unlinked right
+/// after creation, so nothing is left behind even when the process is killed.
+pub struct SpoolFile {
+    file: File,
+    len: u64,
+}
+
+impl SpoolFile {
+    pub async fn create(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(format!(
+            ".object-storage-maintenance-{}-{}.spool",
+            std::process::id(),
+            SPOOL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(Self { file, len: 0 })
+    }
+
+    pub const fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data).await?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Reads back everything written so far and empties the file for reuse.
+    pub async fn take(&mut self) -> std::io::Result<Bytes> {
+        let mut data = Vec::with_capacity(usize::try_from(self.len).unwrap_or_default());
+        self.file.seek(SeekFrom::Start(0)).await?;
+        (&mut self.file)
+            .take(self.len)
+            .read_to_end(&mut data)
+            .await?;
+
+        self.file.set_len(0).await?;
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.len = 0;
+        Ok(data.into())
+    }
+
+    /// Rewinds the file and turns it into a reader over everything written.
+    pub async fn into_reader(mut self) -> std::io::Result<File> {
+        self.file.flush().await?;
+        self.file.seek(SeekFrom::Start(0)).await?;
+        Ok(self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spool_file_round_trip() -> crate::error::Result<()> {
+        let dir = std::env::temp_dir();
+        let mut spool = SpoolFile::create(&dir).await?;
+        spool.write_all(b"first").await?;
+        assert_eq!(spool.len(), 5);
+        assert_eq!(spool.take().await?, Bytes::from("first"));
+
+        spool.write_all(b"second").await?;
+        let mut rest = String::new();
+        spool.into_reader().await?.read_to_string(&mut rest).await?;
+        assert_eq!(rest, "second");
+        Ok(())
+    }
+}
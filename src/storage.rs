@@ -1,38 +1,299 @@
-use crate::error::Result;
+use crate::error::{AppError, Result};
+use crate::listing::{LegacyListing, OwnedListing};
+use crate::s3::{ListApi, S3Client, s3_builder};
+use crate::usage::{Counters, Metered};
+use object_store::azure::MicrosoftAzureBuilder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::local::LocalFileSystem;
+use object_store::signer::Signer;
 use object_store::{ObjectStore, parse_url_opts, path::Path};
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
 use url::Url;
 
+/// Resolves a URL such as `s3://bucket/prefix` to its store and the path inside it. S3
+/// stores additionally pick up the `S3_*` and `AWS_*` environment variables, Azure stores the
+/// `AZURE_*` ones.
+///
+/// S3 stores with the `list_api` option set to `v1` list with `ListObjects`.
+///
+/// # Errors
+///
+/// Fails when the URL is invalid or its scheme is not supported.
 pub fn get_store_and_path(url_str: &str) -> Result<(Arc<dyn ObjectStore>, Path)> {
+    store_and_path(url_str, false)
+}
+
+/// Like [`get_store_and_path`], with S3 requests going through the Transfer Acceleration
+/// endpoint of the bucket, which must have acceleration enabled.
+///
+/// # Errors
+///
+/// Fails for URLs other than `s3://` ones on AWS, and where [`get_store_and_path`] does.
+pub fn get_accelerated_store_and_path(url_str: &str) -> Result<(Arc<dyn ObjectStore>, Path)> {
+    store_and_path(url_str, true)
+}
+
+/// Like [`get_store_and_path`], listing only the objects owned by one of the accounts with
+/// the canonical user IDs `owners`.
+///
+/// # Errors
+///
+/// Fails for URLs other than `s3://` ones, and where [`get_store_and_path`] does.
+pub fn get_owned_store_and_path(
+    url_str: &str,
+    owners: &[String],
+) -> Result<(Arc<dyn ObjectStore>, Path)> {
+    let (store, path) = get_store_and_path(url_str)?;
+    let client = S3Client::from_url(url_str)?.ok_or_else(|| {
+        AppError::Unsupported(format!(
+            "selecting objects by owner is only available for s3:// URLs, got {url_str}"
+        ))
+    })?;
+    let owners = owners.iter().cloned().collect();
+    Ok((Arc::new(OwnedListing::new(store, client, owners)), path))
+}
+
+fn store_and_path(url_str: &str, accelerate: bool) -> Result<(Arc<dyn ObjectStore>, Path)> {
     let url = Url::parse(url_str)?;
-    let options = collect_options(&url);
+    if accelerate && url.scheme() != "s3" {
+        return Err(AppError::Unsupported(format!(
+            "transfer acceleration is only available for s3:// URLs, got {url_str}"
+        )));
+    }
+    if url.scheme() == "file" {
+        // Archiving a local directory must not leave its emptied subdirectories behind.
+        let store = LocalFileSystem::new().with_automatic_cleanup(true);
+        let path = Path::from_url_path(url.path()).map_err(object_store::Error::from)?;
+        return Ok((metered(Arc::new(store)), path));
+    }
+    let mut options = collect_options(&url);
+    if accelerate {
+        accelerated(&url, &mut options)?;
+    }
+    let list_api = if url.scheme() == "s3" {
+        check_s3_credentials(&options)?;
+        ListApi::from_options(&options)?
+    } else {
+        ListApi::default()
+    };
     let (store, path) = parse_url_opts(&url, options)?;
-    Ok((Arc::from(store), path))
+    let store = metered(Arc::from(store));
+    if list_api == ListApi::V1 {
+        // Listings go through the client, which counts its requests itself.
+        let client = S3Client::from_url(url_str)?
+            .ok_or_else(|| AppError::Unsupported(format!("{url_str} is not an S3 URL")))?;
+        return Ok((Arc::new(LegacyListing::new(store, client)), path));
+    }
+    Ok((store, path))
+}
+
+/// Points S3 `options` at the Transfer Acceleration endpoint of the bucket of `url`.
+fn accelerated(url: &Url, options: &mut Vec<(String, String)>) -> Result<()> {
+    if let Some((_, endpoint)) = options.iter().find(|(key, _)| key == "endpoint") {
+        return Err(AppError::Unsupported(format!(
+            "transfer acceleration is only available on AWS, not at {endpoint}"
+        )));
+    }
+    let bucket = url.host_str().unwrap_or_default();
+    options.extend([
+        (
+            "endpoint".to_string(),
+            format!("https://{bucket}.s3-accelerate.amazonaws.com"),
+        ),
+        (
+            "virtual_hosted_style_request".to_string(),
+            "true".to_string(),
+        ),
+    ]);
+    Ok(())
+}
+
+/// Counts the requests made through `store` into the process wide [`Usage`](crate::usage::Usage).
+fn metered(store: Arc<dyn ObjectStore>) -> Arc<dyn ObjectStore> {
+    Arc::new(Metered::new(store, Counters::global()))
+}
+
+/// Store able to presign URLs for `url_str`, for providers supporting it.
+pub(crate) fn get_signer_and_path(url_str: &str) -> Result<(Arc<dyn Signer>, Path)> {
+    let url = Url::parse(url_str)?;
+    let path = Path::from_url_path(url.path()).map_err(object_store::Error::from)?;
+
+    let signer: Arc<dyn Signer> = match url.scheme() {
+        "s3" | "s3a" => Arc::new(s3_builder(&url).build()?),
+        "gs" => Arc::new(GoogleCloudStorageBuilder::new().with_url(url_str).build()?),
+        "az" | "azure" | "abfs" | "abfss" => {
+            let builder = collect_options(&url).into_iter().fold(
+                MicrosoftAzureBuilder::new().with_url(url_str),
+                |builder, (key, value)| match key.parse() {
+                    Ok(key) => builder.with_config(key, value),
+                    Err(_) => builder,
+                },
+            );
+            Arc::new(builder.build()?)
+        }
+        scheme => {
+            return Err(AppError::Unsupported(format!(
+                "presigned URLs are not available for {scheme}:// URLs"
+            )));
+        }
+    };
+
+    Ok((signer, path))
+}
+
+/// Whether both URLs resolve to the same bucket/container, allowing server-side copies.
+pub(crate) fn same_store(a: &str, b: &str) -> Result<bool> {
+    let (a, b) = (Url::parse(a)?, Url::parse(b)?);
+    Ok(a.scheme() == b.scheme() && a.host_str() == b.host_str())
+}
+
+/// Options of the S3 endpoint selected in the configuration file.
+static S3_CONFIG: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+/// S3 options given on the command line.
+static S3_FLAGS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+/// Points every `s3://` store and client created from now on at a configured endpoint.
+pub(crate) fn use_s3_endpoint(options: Vec<(String, String)>) -> Result<()> {
+    S3_CONFIG
+        .set(options)
+        .map_err(|_| AppError::Config("an S3 endpoint is already in use".to_string()))
+}
+
+/// Sets S3 options such as `endpoint` or `region` that take precedence over both the
+/// environment and the configuration file, for all `s3://` stores created afterwards.
+///
+/// # Errors
+///
+/// Fails when called more than once.
+pub fn override_s3_options(options: Vec<(String, String)>) -> Result<()> {
+    S3_FLAGS
+        .set(options)
+        .map_err(|_| AppError::Config("S3 options are already overridden".to_string()))
+}
+
+/// Makes every TLS connection opened from now on use the FIPS validated aws-lc module, for
+/// regulated environments. Only builds with the `fips` feature have it.
+///
+/// # Errors
+///
+/// Fails without the `fips` feature, or when another crypto provider is already in use.
+pub fn use_fips_crypto() -> Result<()> {
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    if !provider.fips() {
+        return Err(AppError::Unsupported(
+            "FIPS mode needs a build with the `fips` feature".to_string(),
+        ));
+    }
+    provider
+        .install_default()
+        .map_err(|_| AppError::Config("a TLS crypto provider is already in use".to_string()))
 }
 
-fn collect_options(url: &Url) -> Vec<(String, String)> {
-    collect_options_impl(url, |k| std::env::var(k).ok())
+pub(crate) fn collect_options(url: &Url) -> Vec<(String, String)> {
+    let env = collect_options_impl(url, |k| std::env::var(k).ok());
+    if url.scheme() != "s3" {
+        return env;
+    }
+    layer_options(&[
+        S3_CONFIG.get().map_or(&[], Vec::as_slice),
+        &env,
+        S3_FLAGS.get().map_or(&[], Vec::as_slice),
+    ])
+}
+
+/// Merges option layers, later layers winning over earlier ones.
+fn layer_options(layers: &[&[(String, String)]]) -> Vec<(String, String)> {
+    let merged: BTreeMap<&str, &str> = layers
+        .iter()
+        .flat_map(|layer| layer.iter())
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    merged
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Rejects half configured static credentials instead of silently falling back to the
+/// instance or container credentials, naming what is missing.
+fn check_s3_credentials(options: &[(String, String)]) -> Result<()> {
+    let has = |key: &str| options.iter().any(|(k, _)| k == key);
+    if !has("access_key_id") && !has("secret_access_key") && !has("token") {
+        return Ok(());
+    }
+
+    let missing: Vec<&str> = [
+        ("access_key_id", "S3_ACCESS_KEY_ID or AWS_ACCESS_KEY_ID"),
+        (
+            "secret_access_key",
+            "S3_SECRET_ACCESS_KEY or AWS_SECRET_ACCESS_KEY",
+        ),
+    ]
+    .into_iter()
+    .filter(|(key, _)| !has(key))
+    .map(|(_, variables)| variables)
+    .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::Config(format!(
+            "incomplete S3 credentials, missing {}",
+            missing.join(" and ")
+        )))
+    }
 }
 
 fn collect_options_impl<F>(url: &Url, get_env: F) -> Vec<(String, String)>
 where
     F: Fn(&str) -> Option<String>,
 {
-    let mut options = Vec::new();
-    if url.scheme() == "s3" {
-        for (env_var, opt_key) in [
-            ("AWS_ENDPOINT_URL_S3", "endpoint"),
-            ("S3_REGION", "region"),
-            ("S3_ACCESS_KEY_ID", "access_key_id"),
-            ("S3_SECRET_ACCESS_KEY", "secret_access_key"),
-            ("S3_ALLOW_HTTP", "allow_http"),
-        ] {
-            if let Some(val) = get_env(env_var) {
-                options.push((opt_key.to_string(), val));
-            }
-        }
-    }
-    options
+    // The first variable set wins, so the tool specific names override the standard ones.
+    let variables: &[(&[&str], &str)] = match url.scheme() {
+        "s3" => &[
+            (
+                &["S3_ENDPOINT_URL", "AWS_ENDPOINT_URL_S3", "AWS_ENDPOINT_URL"],
+                "endpoint",
+            ),
+            (&["S3_REGION", "AWS_REGION", "AWS_DEFAULT_REGION"], "region"),
+            (&["S3_ACCESS_KEY_ID", "AWS_ACCESS_KEY_ID"], "access_key_id"),
+            (
+                &["S3_SECRET_ACCESS_KEY", "AWS_SECRET_ACCESS_KEY"],
+                "secret_access_key",
+            ),
+            (&["S3_SESSION_TOKEN", "AWS_SESSION_TOKEN"], "token"),
+            (&["S3_ALLOW_HTTP"], "allow_http"),
+            (&["S3_LIST_API"], ListApi::OPTION),
+            (
+                &["S3_NO_SIGN_REQUEST", "AWS_SKIP_SIGNATURE"],
+                "skip_signature",
+            ),
+        ],
+        "az" | "azure" | "abfs" | "abfss" => &[
+            (&["AZURE_STORAGE_ACCOUNT_NAME"], "account_name"),
+            (&["AZURE_STORAGE_ACCOUNT_KEY"], "account_key"),
+            (&["AZURE_STORAGE_SAS_TOKEN"], "sas_token"),
+            (&["AZURE_STORAGE_TOKEN"], "token"),
+            (&["AZURE_STORAGE_ENDPOINT"], "endpoint"),
+            (&["AZURE_STORAGE_USE_EMULATOR"], "use_emulator"),
+            (&["AZURE_CLIENT_ID"], "client_id"),
+            (&["AZURE_CLIENT_SECRET"], "client_secret"),
+            (&["AZURE_TENANT_ID"], "tenant_id"),
+            (&["AZURE_FEDERATED_TOKEN_FILE"], "federated_token_file"),
+            (&["AZURE_USE_AZURE_CLI"], "use_azure_cli"),
+        ],
+        _ => &[],
+    };
+
+    variables
+        .iter()
+        .filter_map(|(env_vars, opt_key)| {
+            let val = env_vars.iter().find_map(|env_var| get_env(env_var))?;
+            Some((opt_key.to_string(), val))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -54,6 +315,115 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_collect_options_s3_standard_names() -> Result<()> {
+        let url = Url::parse("s3://bucket/path")?;
+        let env = |k: &str| match k {
+            "S3_REGION" => Some("us-north-1".to_string()),
+            "AWS_REGION" => Some("eu-west-1".to_string()),
+            "AWS_ACCESS_KEY_ID" => Some("key".to_string()),
+            "AWS_SESSION_TOKEN" => Some("token".to_string()),
+            "AWS_SKIP_SIGNATURE" => Some("true".to_string()),
+            _ => None,
+        };
+        let options = collect_options_impl(&url, env);
+        assert_eq!(
+            options,
+            vec![
+                ("region".to_string(), "us-north-1".to_string()),
+                ("access_key_id".to_string(), "key".to_string()),
+                ("token".to_string(), "token".to_string()),
+                ("skip_signature".to_string(), "true".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_options() {
+        let option = |key: &str, value: &str| (key.to_string(), value.to_string());
+        let config = [option("endpoint", "http://config"), option("region", "a")];
+        let env = [option("region", "b")];
+        let flags = [option("endpoint", "http://flag")];
+        assert_eq!(
+            layer_options(&[&config, &env, &flags]),
+            vec![option("endpoint", "http://flag"), option("region", "b")]
+        );
+    }
+
+    #[test]
+    fn test_check_s3_credentials() {
+        let option = |key: &str| (key.to_string(), "x".to_string());
+        assert!(check_s3_credentials(&[option("region")]).is_ok());
+        assert!(
+            check_s3_credentials(&[option("access_key_id"), option("secret_access_key")]).is_ok()
+        );
+        let err = check_s3_credentials(&[option("access_key_id")])
+            .err()
+            .map(|e| e.to_string());
+        assert_eq!(
+            err.as_deref(),
+            Some(
+                "Configuration error: incomplete S3 credentials, missing \
+                 S3_SECRET_ACCESS_KEY or AWS_SECRET_ACCESS_KEY"
+            )
+        );
+        assert!(check_s3_credentials(&[option("token")]).is_err());
+    }
+
+    #[test]
+    fn test_collect_options_azure() -> Result<()> {
+        let url = Url::parse("az://container/path")?;
+        let env = |k: &str| match k {
+            "AZURE_STORAGE_ACCOUNT_NAME" => Some("archive".to_string()),
+            "S3_REGION" => Some("us-north-1".to_string()),
+            _ => None,
+        };
+        let options = collect_options_impl(&url, env);
+        assert_eq!(
+            options,
+            vec![("account_name".to_string(), "archive".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_store() -> Result<()> {
+        assert!(same_store("s3://bucket/a", "s3://bucket/b")?);
+        assert!(!same_store("s3://bucket/a", "s3://other/a")?);
+        assert!(!same_store("s3://bucket/a", "gs://bucket/a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_signer_and_path() -> Result<()> {
+        let (_signer, path) = get_signer_and_path("s3://bucket/archive/a.tar.xz")?;
+        assert_eq!(path.to_string(), "archive/a.tar.xz");
+        assert!(get_signer_and_path("file:///tmp/a.tar.xz").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_accelerated() -> Result<()> {
+        let url = Url::parse("s3://archive/logs")?;
+        let option = |key: &str, value: &str| (key.to_string(), value.to_string());
+        let mut options = vec![option("region", "eu-west-1")];
+        accelerated(&url, &mut options)?;
+        assert_eq!(
+            options,
+            vec![
+                option("region", "eu-west-1"),
+                option("endpoint", "https://archive.s3-accelerate.amazonaws.com"),
+                option("virtual_hosted_style_request", "true"),
+            ]
+        );
+
+        let mut options = vec![option("endpoint", "http://localhost:9000")];
+        assert!(accelerated(&url, &mut options).is_err());
+        assert!(get_accelerated_store_and_path("gs://archive/logs").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_get_store_and_path_s3() -> Result<()> {
         let res = get_store_and_path("s3://bucket/path/to/object");
@@ -72,6 +442,24 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_file_store_removes_empty_directories() -> Result<()> {
+        use object_store::ObjectStoreExt;
+
+        let root = std::env::temp_dir().join(format!("osm-storage-{}", std::process::id()));
+        let url = Url::from_directory_path(&root)
+            .map_err(|()| AppError::Unsupported(root.display().to_string()))?;
+        let (store, path) = get_store_and_path(url.as_str())?;
+
+        let location = path.join("nested").join("a.log");
+        store.put(&location, "data".into()).await?;
+        assert!(root.join("nested").is_dir());
+
+        store.delete(&location).await?;
+        assert!(!root.join("nested").exists());
+        Ok(())
+    }
+
     #[test]
     fn test_get_store_and_path_memory() {
         // memory provider is not usually enabled by default in parse_url unless we use memory://
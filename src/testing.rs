@@ -0,0 +1,217 @@
+//! In-memory fixtures for exercising the pipeline without `MinIO` or cloud credentials.
+//!
+//! [`FixtureStore`] is an [`InMemory`] store whose objects can be given arbitrary
+//! modification times, which is what cutoff based selection looks at.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use object_store::memory::InMemory;
+use object_store::path::Path;
+use object_store::{
+    CopyOptions, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    ObjectStoreExt, PutMultipartOptions, PutOptions, PutPayload, PutResult, Result,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// In-memory store reporting the modification times given to [`FixtureStore::insert`].
+#[derive(Debug, Default)]
+pub struct FixtureStore {
+    inner: InMemory,
+    mtimes: Arc<Mutex<HashMap<Path, DateTime<Utc>>>>,
+}
+
+impl FixtureStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store populated with `(key, content, last_modified)` fixtures.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an object cannot be stored.
+    pub async fn with_objects(objects: &[(&str, &str, DateTime<Utc>)]) -> Result<Arc<Self>> {
+        let store = Self::new();
+        for (key, content, last_modified) in objects {
+            store
+                .insert(&Path::from(*key), content.to_string(), *last_modified)
+                .await?;
+        }
+        Ok(Arc::new(store))
+    }
+
+    /// Stores `content` at `location`, pretending it was last modified at `last_modified`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the object cannot be stored.
+    pub async fn insert(
+        &self,
+        location: &Path,
+        content: impl Into<PutPayload>,
+        last_modified: DateTime<Utc>,
+    ) -> Result<()> {
+        self.inner.put(location, content.into()).await?;
+        self.mtimes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(location.clone(), last_modified);
+        Ok(())
+    }
+
+    /// Keys currently stored, sorted.
+    ///
+    /// # Errors
+    ///
+    /// Fails when listing fails.
+    pub async fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .inner
+            .list(None)
+            .map(|meta| meta.map(|meta| meta.location.to_string()))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Replaces the modification time of `meta` if a fixture time was recorded for it.
+fn with_mtime(mtimes: &Mutex<HashMap<Path, DateTime<Utc>>>, mut meta: ObjectMeta) -> ObjectMeta {
+    if let Some(last_modified) = mtimes
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&meta.location)
+    {
+        meta.last_modified = *last_modified;
+    }
+    meta
+}
+
+impl fmt::Display for FixtureStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FixtureStore")
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FixtureStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        // Objects written by the code under test are fresh.
+        self.mtimes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(location);
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        self.mtimes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(location);
+        self.inner.put_multipart_opts(location, opts).await
+    }
+
+    async fn get_opts(&self, location: &Path, mut options: GetOptions) -> Result<GetResult> {
+        // Date preconditions are checked against the fixture time, not the time of insertion.
+        let dates = GetOptions {
+            if_modified_since: options.if_modified_since.take(),
+            if_unmodified_since: options.if_unmodified_since.take(),
+            ..GetOptions::default()
+        };
+        let mut result = self.inner.get_opts(location, options).await?;
+        result.meta = with_mtime(&self.mtimes, result.meta);
+        dates.check_preconditions(&result.meta)?;
+        Ok(result)
+    }
+
+    fn delete_stream(
+        &self,
+        locations: BoxStream<'static, Result<Path>>,
+    ) -> BoxStream<'static, Result<Path>> {
+        let mtimes = Arc::clone(&self.mtimes);
+        self.inner
+            .delete_stream(locations)
+            .map(move |location| {
+                let location = location?;
+                mtimes
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&location);
+                Ok(location)
+            })
+            .boxed()
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+        let mtimes = Arc::clone(&self.mtimes);
+        self.inner
+            .list(prefix)
+            .map(move |meta| meta.map(|meta| with_mtime(&mtimes, meta)))
+            .boxed()
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        let mut result = self.inner.list_with_delimiter(prefix).await?;
+        result.objects = result
+            .objects
+            .into_iter()
+            .map(|meta| with_mtime(&self.mtimes, meta))
+            .collect();
+        Ok(result)
+    }
+
+    async fn copy_opts(&self, from: &Path, to: &Path, options: CopyOptions) -> Result<()> {
+        self.inner.copy_opts(from, to, options).await?;
+        let mut mtimes = self.mtimes.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(last_modified) = mtimes.get(from).copied() {
+            mtimes.insert(to.clone(), last_modified);
+        }
+        drop(mtimes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_fixture_mtimes() -> crate::error::Result<()> {
+        let old = Utc::now() - Duration::days(30);
+        let store = FixtureStore::with_objects(&[("logs/a.log", "a", old)]).await?;
+        store.put(&Path::from("logs/b.log"), "b".into()).await?;
+
+        let listed: Vec<ObjectMeta> = store.list(None).try_collect().await?;
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].last_modified, old);
+        assert!(listed[1].last_modified > old);
+        assert_eq!(
+            store.head(&Path::from("logs/a.log")).await?.last_modified,
+            old
+        );
+
+        store.delete(&Path::from("logs/a.log")).await?;
+        assert_eq!(store.keys().await?, vec!["logs/b.log"]);
+        Ok(())
+    }
+}
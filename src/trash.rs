@@ -0,0 +1,86 @@
+use crate::error::{AppError, Result};
+use crate::object_storage::{FailedDelete, ServerSideCopy, delete_keys_reporting};
+use crate::output::summary;
+use crate::storage::get_store_and_path;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{ObjectMeta, ObjectStore, path::Path};
+
+/// Number of server-side copies into the trash in flight at once.
+const TRASH_CONCURRENCY: usize = 16;
+
+/// A prefix receiving objects instead of deleting them outright. Objects keep their full
+/// original key below the trash prefix, so they can be put back later.
+pub struct Trash {
+    copier: ServerSideCopy,
+    path: Path,
+}
+
+impl Trash {
+    /// Fails upfront when objects of `src` cannot be copied into `trash_url` server-side.
+    pub fn new(src: &str, trash_url: &str) -> Result<Self> {
+        let (_, path) = get_store_and_path(trash_url)?;
+        let copier = ServerSideCopy::between(src, trash_url)?.ok_or_else(|| {
+            AppError::Unsupported(format!(
+                "cannot copy objects from {src} into trash {trash_url} server-side"
+            ))
+        })?;
+
+        Ok(Self { copier, path })
+    }
+
+    pub fn trash_key(&self, location: &Path) -> Path {
+        self.path.parts().chain(location.parts()).collect()
+    }
+
+    /// Copies `objects` into the trash, deleting the originals once all copies succeeded.
+    /// Returns the originals that could not be deleted.
+    pub async fn discard(
+        &self,
+        store: &dyn ObjectStore,
+        objects: Vec<ObjectMeta>,
+        delete_concurrency: usize,
+    ) -> Result<Vec<FailedDelete>> {
+        if objects.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let trashed: Vec<Path> = futures::stream::iter(objects)
+            .map(|meta| async move {
+                self.copier
+                    .copy(store, &meta, &self.trash_key(&meta.location))
+                    .await?;
+                Ok::<_, AppError>(meta.location)
+            })
+            .buffer_unordered(TRASH_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        summary!("Moved {} objects to trash {}", trashed.len(), self.path);
+        Ok(delete_keys_reporting(store, trashed, delete_concurrency).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use object_store::{ObjectStoreExt, PutPayload};
+
+    #[tokio::test]
+    async fn test_discard_keeps_original_key() -> Result<()> {
+        let trash = Trash {
+            copier: ServerSideCopy::Store,
+            path: Path::from("trash"),
+        };
+        let store = InMemory::new();
+        let location = Path::from("audit/2024/a.json");
+        store.put(&location, PutPayload::from("data")).await?;
+        let meta = store.head(&location).await?;
+
+        assert!(trash.discard(&store, vec![meta], 1).await?.is_empty());
+
+        assert!(store.head(&location).await.is_err());
+        store.head(&Path::from("trash/audit/2024/a.json")).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,546 @@
+//! Multipart uploads that outlive the process: the id of the upload and the parts uploaded
+//! so far are saved in a [`StateStore`] as soon as the upload is created and after every
+//! part, so a run writing the same archive again lists the parts already uploaded and only
+//! sends the rest instead of starting over, and uploads left behind can be found.
+//!
+//! The archive is compressed again from the start and each part compared by its SHA-256
+//! with the one uploaded before, which only matches while the run reproduces the archive
+//! byte for byte: the same objects, selected with the saved cutoff, the same compression
+//! settings and part size.
+//!
+//! [`PartedUpload`] is a multipart upload to any store that cuts its parts itself, so it can
+//! flush what it has buffered when the archive trickles in slowly.
+
+use crate::commands::MIN_PART_SIZE;
+use crate::error::{AppError, Result};
+use crate::output::{debug, info, warning};
+use crate::s3::{S3Client, UploadedPart};
+use crate::storage::get_store_and_path;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, HeaderValue};
+use object_store::path::Path;
+use object_store::{MultipartUpload, ObjectStore, ObjectStoreExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// State written by `--upload-state` while an upload is in progress.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadState {
+    /// Key of the archive being uploaded.
+    pub key: String,
+    pub upload_id: String,
+    /// Cutoff of the run that started the upload, which the runs resuming it select with.
+    pub cutoff: DateTime<Utc>,
+    pub part_size: usize,
+    pub started: DateTime<Utc>,
+    /// Parts uploaded so far, in order.
+    #[serde(default)]
+    pub parts: Vec<UploadedPart>,
+    /// ID of the run that started the upload, which the runs resuming it take over, so an
+    /// archive named after it keeps its key.
+    #[serde(default)]
+    pub run_id: Option<String>,
+}
+
+/// Where the [`UploadState`] of an upload is kept. Saving replaces the state as a whole, so
+/// a crash leaves either the old or the new one behind.
+#[async_trait]
+pub trait StateStore: fmt::Debug + fmt::Display + Send + Sync {
+    /// The saved state, `None` when there is none.
+    async fn load(&self) -> Result<Option<UploadState>>;
+
+    /// Saves `state`, replacing the one saved before.
+    async fn save(&self, state: &UploadState) -> Result<()>;
+
+    /// Removes the state once the upload is complete.
+    async fn remove(&self) -> Result<()>;
+}
+
+/// A [`StateStore`] keeping the state as a JSON object in an object store, e.g. S3 or a
+/// local file, both of which replace objects atomically.
+#[derive(Debug)]
+pub struct ObjectStateStore {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+}
+
+impl ObjectStateStore {
+    pub fn new(store: Arc<dyn ObjectStore>, path: Path) -> Self {
+        Self { store, path }
+    }
+
+    /// The state object at `url`.
+    ///
+    /// # Errors
+    ///
+    /// Fails for unsupported URLs.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let (store, path) = get_store_and_path(url)?;
+        Ok(Self::new(store, path))
+    }
+}
+
+impl fmt::Display for ObjectStateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {}", self.path, self.store)
+    }
+}
+
+#[async_trait]
+impl StateStore for ObjectStateStore {
+    async fn load(&self) -> Result<Option<UploadState>> {
+        match self.store.get(&self.path).await {
+            Ok(result) => Ok(Some(serde_json::from_slice(&result.bytes().await?)?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&self, state: &UploadState) -> Result<()> {
+        self.store
+            .put(&self.path, serde_json::to_vec_pretty(state)?.into())
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self) -> Result<()> {
+        self.store.delete(&self.path).await?;
+        Ok(())
+    }
+}
+
+/// Where a resumable upload keeps its state, and the cutoff saved with it.
+#[derive(Debug, Clone)]
+pub struct UploadCheckpoint {
+    /// Client for the bucket of the archive.
+    pub client: S3Client,
+    pub state: Arc<dyn StateStore>,
+    pub cutoff: DateTime<Utc>,
+    pub run_id: Option<String>,
+}
+
+/// Whether part `part_number` with `checksum` and `size` was uploaded before as `uploaded`.
+/// Fails when a different part was, as the archive was not reproduced.
+fn already_uploaded(
+    uploaded: Option<&UploadedPart>,
+    part_number: usize,
+    checksum: &str,
+    size: u64,
+) -> Result<bool> {
+    match uploaded {
+        None => Ok(false),
+        Some(part) if part.checksum_sha256.as_deref() == Some(checksum) && part.size == size => {
+            Ok(true)
+        }
+        Some(_) => Err(AppError::Archive(format!(
+            "part {part_number} differs from the one uploaded before, the objects or settings \
+             changed since; delete the upload state to start over"
+        ))),
+    }
+}
+
+/// A multipart upload in parts of exactly the part size, the last one excepted, resumed
+/// from its [`UploadState`] when there is one for the key, which is saved after every part.
+pub struct ResumableUpload {
+    client: S3Client,
+    state: UploadState,
+    state_store: Arc<dyn StateStore>,
+    max_concurrency: usize,
+    /// Parts uploaded by earlier runs and not yet compared, by number.
+    uploaded: HashMap<usize, UploadedPart>,
+    buffer: BytesMut,
+    next_part: usize,
+    /// Parts of this run, uploaded or found uploaded before.
+    parts: Vec<UploadedPart>,
+    in_flight: JoinSet<Result<UploadedPart>>,
+}
+
+impl ResumableUpload {
+    /// Resumes the upload to `key` saved in the state store of `checkpoint`, or starts one
+    /// and saves its state there.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the saved state is for another archive or part size, or when S3 refuses to
+    /// start or list the upload.
+    pub async fn start(
+        checkpoint: &UploadCheckpoint,
+        key: &str,
+        part_size: usize,
+        max_concurrency: usize,
+    ) -> Result<Self> {
+        let UploadCheckpoint {
+            client,
+            state: state_store,
+            cutoff,
+            run_id,
+        } = checkpoint;
+        let state = match state_store.load().await? {
+            Some(state) if state.key != key => {
+                return Err(AppError::Config(format!(
+                    "the upload state {state_store} is for {}, not {key}",
+                    state.key
+                )));
+            }
+            Some(state) if state.part_size != part_size => {
+                return Err(AppError::Config(format!(
+                    "the upload in {state_store} was started with parts of {} bytes, not \
+                     {part_size}",
+                    state.part_size
+                )));
+            }
+            Some(mut state) => {
+                // What S3 lists is authoritative, the state may lag a part behind.
+                state.parts = client.list_parts(key, &state.upload_id).await?;
+                info!(
+                    "Resuming upload {} of {key} with {} parts uploaded",
+                    state.upload_id,
+                    state.parts.len()
+                );
+                state
+            }
+            None => {
+                let mut headers = HeaderMap::new();
+                headers.insert(
+                    "x-amz-checksum-algorithm",
+                    HeaderValue::from_static("SHA256"),
+                );
+                UploadState {
+                    key: key.to_string(),
+                    upload_id: client.create_multipart_upload(key, headers).await?,
+                    cutoff: *cutoff,
+                    part_size,
+                    started: Utc::now(),
+                    parts: Vec::new(),
+                    run_id: run_id.clone(),
+                }
+            }
+        };
+        state_store.save(&state).await?;
+        Ok(Self {
+            client: client.clone(),
+            uploaded: state
+                .parts
+                .iter()
+                .map(|part| (part.part_number, part.clone()))
+                .collect(),
+            state,
+            state_store: Arc::clone(state_store),
+            max_concurrency: max_concurrency.max(1),
+            buffer: BytesMut::new(),
+            next_part: 1,
+            parts: Vec::new(),
+            in_flight: JoinSet::new(),
+        })
+    }
+
+    /// Appends `data`, uploading the parts it completes.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a part cannot be uploaded or differs from the one uploaded before.
+    pub async fn put(&mut self, data: Bytes) -> Result<()> {
+        self.buffer.extend_from_slice(&data);
+        let part_size = self.state.part_size.max(1);
+        while self.buffer.len() >= part_size {
+            let part = self.buffer.split_to(part_size).freeze();
+            self.send(part).await?;
+        }
+        Ok(())
+    }
+
+    /// Uploads `part` as the next part unless an earlier run did.
+    async fn send(&mut self, part: Bytes) -> Result<()> {
+        let part_number = self.next_part;
+        self.next_part += 1;
+        let checksum = BASE64_STANDARD.encode(Sha256::digest(&part));
+        let uploaded = self.uploaded.remove(&part_number);
+        if already_uploaded(uploaded.as_ref(), part_number, &checksum, part.len() as u64)? {
+            debug!(
+                "Part {part_number} of {} was uploaded before",
+                self.state.key
+            );
+            self.parts.extend(uploaded);
+            return Ok(());
+        }
+        while self.in_flight.len() >= self.max_concurrency {
+            self.join_next().await?;
+        }
+        let (client, key, upload_id) = (
+            self.client.clone(),
+            self.state.key.clone(),
+            self.state.upload_id.clone(),
+        );
+        self.in_flight.spawn(async move {
+            let part = client
+                .upload_part(&key, &upload_id, part_number, part)
+                .await?;
+            debug!("Uploaded part {part_number} of {key}");
+            Ok(part)
+        });
+        Ok(())
+    }
+
+    /// Waits for the next part in flight and saves it in the state.
+    async fn join_next(&mut self) -> Result<()> {
+        let Some(part) = self.in_flight.join_next().await else {
+            return Ok(());
+        };
+        let part = part.map_err(std::io::Error::other)??;
+        let parts = &mut self.state.parts;
+        parts.retain(|saved| saved.part_number != part.part_number);
+        parts.push(part.clone());
+        parts.sort_by_key(|part| part.part_number);
+        self.parts.push(part);
+        self.state_store.save(&self.state).await
+    }
+
+    /// Uploads the rest and completes the upload, removing its state.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a part cannot be uploaded or S3 refuses to complete the upload.
+    pub async fn finish(mut self) -> Result<()> {
+        // Even an empty archive has a part.
+        if !self.buffer.is_empty() || self.next_part == 1 {
+            let part = self.buffer.split().freeze();
+            self.send(part).await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.join_next().await?;
+        }
+        self.parts.sort_by_key(|part| part.part_number);
+        self.client
+            .complete_multipart_upload(&self.state.key, &self.state.upload_id, &self.parts)
+            .await?;
+        self.state_store.remove().await
+    }
+
+    /// Gives up for this run, waiting for the parts in flight so the next run finds them.
+    /// The upload and its state are kept.
+    pub async fn keep(mut self) {
+        while !self.in_flight.is_empty() {
+            if let Err(e) = self.join_next().await {
+                warning!("Failed to finish a part of {}: {e}", self.state.key);
+            }
+        }
+        info!(
+            "Kept upload {} of {} for the next run to resume, see {}",
+            self.state.upload_id, self.state.key, self.state_store
+        );
+    }
+}
+
+/// Multipart upload to any [`ObjectStore`] with parts cut here rather than by the
+/// [`BufWriter`](object_store::buffered::BufWriter), so whatever is buffered can go out as a
+/// part of its own, see [`PartedUpload::flush`].
+pub struct PartedUpload {
+    upload: Box<dyn MultipartUpload>,
+    location: Path,
+    part_size: usize,
+    max_concurrency: usize,
+    buffer: BytesMut,
+    parts: usize,
+    /// When the last part was sent or the last flush was due.
+    last_part: Instant,
+    in_flight: JoinSet<Result<()>>,
+}
+
+impl PartedUpload {
+    /// Starts a multipart upload to `location` in `store`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the store refuses to start the upload.
+    pub async fn start(
+        store: &dyn ObjectStore,
+        location: Path,
+        part_size: usize,
+        max_concurrency: usize,
+    ) -> Result<Self> {
+        let upload = store.put_multipart(&location).await?;
+        Ok(Self {
+            upload,
+            location,
+            part_size: part_size.max(1),
+            max_concurrency: max_concurrency.max(1),
+            buffer: BytesMut::new(),
+            parts: 0,
+            last_part: Instant::now(),
+            in_flight: JoinSet::new(),
+        })
+    }
+
+    /// Adds `data` to the part being filled, uploading it once it is complete.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an earlier part failed to upload.
+    pub async fn put(&mut self, data: Bytes) -> Result<()> {
+        self.buffer.extend_from_slice(&data);
+        while self.buffer.len() >= self.part_size {
+            let part = self.buffer.split_to(self.part_size).freeze();
+            self.send(part).await?;
+        }
+        Ok(())
+    }
+
+    /// When a flush is due if no part is sent within `interval`.
+    #[must_use]
+    pub fn flush_deadline(&self, interval: Duration) -> Instant {
+        self.last_part + interval
+    }
+
+    /// Uploads the part being filled as it is, unless it is still below the
+    /// [`MIN_PART_SIZE`] S3 accepts for all but the last part. The next flush is due an
+    /// interval later either way.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an earlier part failed to upload.
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.buffer.len() >= MIN_PART_SIZE {
+            let part = self.buffer.split().freeze();
+            debug!(
+                "Flushing {} bytes of {} as a part",
+                part.len(),
+                self.location
+            );
+            self.send(part).await?;
+        }
+        self.last_part = Instant::now();
+        Ok(())
+    }
+
+    async fn send(&mut self, part: Bytes) -> Result<()> {
+        while self.in_flight.len() >= self.max_concurrency {
+            self.join_next().await?;
+        }
+        self.parts += 1;
+        self.last_part = Instant::now();
+        let upload = self.upload.put_part(part.into());
+        self.in_flight.spawn(async move { Ok(upload.await?) });
+        Ok(())
+    }
+
+    async fn join_next(&mut self) -> Result<()> {
+        match self.in_flight.join_next().await {
+            Some(part) => part.map_err(std::io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+
+    /// Uploads the rest and completes the upload.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a part cannot be uploaded or the store refuses to complete the upload.
+    pub async fn finish(mut self) -> Result<()> {
+        // Even an empty archive has a part.
+        if !self.buffer.is_empty() || self.parts == 0 {
+            let part = self.buffer.split().freeze();
+            self.send(part).await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.join_next().await?;
+        }
+        self.upload.complete().await?;
+        Ok(())
+    }
+
+    /// Stops the parts in flight and aborts the upload.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the store refuses to abort the upload.
+    pub async fn abort(mut self) -> Result<()> {
+        self.in_flight.shutdown().await;
+        self.upload.abort().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_already_uploaded_compares_checksums() -> Result<()> {
+        let part = UploadedPart {
+            part_number: 3,
+            e_tag: "\"abc\"".to_string(),
+            checksum_sha256: Some("c2hh".to_string()),
+            size: 10,
+        };
+        assert!(!already_uploaded(None, 3, "c2hh", 10)?);
+        assert!(already_uploaded(Some(&part), 3, "c2hh", 10)?);
+        assert!(already_uploaded(Some(&part), 3, "b3RoZXI=", 10).is_err());
+        assert!(already_uploaded(Some(&part), 3, "c2hh", 9).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parted_upload_flushes_what_is_buffered() -> Result<()> {
+        let store = object_store::memory::InMemory::new();
+        let location = Path::from("archive.tar.zst");
+        let mut upload =
+            PartedUpload::start(&store, location.clone(), 3 * MIN_PART_SIZE, 2).await?;
+        let before = upload.flush_deadline(Duration::from_secs(30));
+
+        // Too small a part for S3 stays buffered.
+        upload.put(Bytes::from(vec![1; MIN_PART_SIZE - 1])).await?;
+        upload.flush().await?;
+        assert_eq!(upload.parts, 0);
+        assert!(upload.flush_deadline(Duration::from_secs(30)) >= before);
+
+        upload.put(Bytes::from(vec![2; 1])).await?;
+        upload.flush().await?;
+        assert_eq!(upload.parts, 1);
+        assert!(upload.buffer.is_empty());
+
+        upload.put(Bytes::from(vec![3; 10])).await?;
+        upload.finish().await?;
+        let archive = store.get(&location).await?.bytes().await?;
+        assert_eq!(archive.len(), MIN_PART_SIZE + 10);
+        assert_eq!(archive[(MIN_PART_SIZE - 1)..=MIN_PART_SIZE], [2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_object_state_store_round_trip() -> Result<()> {
+        let store = ObjectStateStore::new(
+            Arc::new(object_store::memory::InMemory::new()),
+            Path::from("state/upload.json"),
+        );
+        assert_eq!(store.load().await?, None);
+        let state = UploadState {
+            key: "archive.tar.zst".to_string(),
+            upload_id: "id".to_string(),
+            cutoff: Utc::now(),
+            part_size: 5 * 1024 * 1024,
+            started: Utc::now(),
+            parts: vec![UploadedPart {
+                part_number: 1,
+                e_tag: "\"abc\"".to_string(),
+                checksum_sha256: Some("c2hh".to_string()),
+                size: 5 * 1024 * 1024,
+            }],
+            run_id: Some("run".to_string()),
+        };
+        store.save(&state).await?;
+        assert_eq!(store.load().await?, Some(state));
+        store.remove().await?;
+        assert_eq!(store.load().await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+//! Requests and bytes a run sends to object storage, and what they cost.
+//!
+//! Stores returned by [`crate::get_store_and_path`] are [`Metered`], and the raw S3 requests
+//! count themselves, so [`Usage::now`] taken before and after a run tells what it did.
+
+use crate::error::{AppError, Result as AppResult};
+use crate::output::{debug, summary};
+use async_trait::async_trait;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use object_store::path::Path;
+use object_store::{
+    CopyOptions, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOptions, PutOptions, PutPayload, PutResult, Result, UploadPart,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Keys returned per `LIST` request by S3, which listings are metered by.
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Request classes as object storage prices them. `HEAD` is priced like `GET`, `COPY` and
+/// `POST` like `PUT`.
+#[derive(Debug, Clone, Copy)]
+pub enum Request {
+    List,
+    Get,
+    Put,
+    Delete,
+}
+
+/// Running totals, see [`Counters::global`].
+#[derive(Debug, Default)]
+pub struct Counters {
+    requests: [AtomicU64; 4],
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+}
+
+impl Counters {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            requests: [const { AtomicU64::new(0) }; 4],
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+        }
+    }
+
+    /// Totals of the whole process.
+    #[must_use]
+    pub fn global() -> &'static Self {
+        static GLOBAL: Counters = Counters::new();
+        &GLOBAL
+    }
+
+    pub fn request(&self, request: Request) {
+        self.requests[request as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Usage {
+        let requests = |request: Request| self.requests[request as usize].load(Ordering::Relaxed);
+        Usage {
+            list: requests(Request::List),
+            get: requests(Request::Get),
+            put: requests(Request::Put),
+            delete: requests(Request::Delete),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Requests by class and bytes transferred.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub list: u64,
+    pub get: u64,
+    pub put: u64,
+    pub delete: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl Usage {
+    /// Totals of the process so far.
+    #[must_use]
+    pub fn now() -> Self {
+        Counters::global().snapshot()
+    }
+
+    /// What happened between `earlier` and this snapshot.
+    #[must_use]
+    pub const fn since(self, earlier: Self) -> Self {
+        Self {
+            list: self.list.saturating_sub(earlier.list),
+            get: self.get.saturating_sub(earlier.get),
+            put: self.put.saturating_sub(earlier.put),
+            delete: self.delete.saturating_sub(earlier.delete),
+            downloaded: self.downloaded.saturating_sub(earlier.downloaded),
+            uploaded: self.uploaded.saturating_sub(earlier.uploaded),
+        }
+    }
+
+    /// Prints the requests and bytes, and the cost they come to at `prices`.
+    pub fn report(&self, prices: &Prices) {
+        summary!(
+            "Requests: {} LIST, {} GET, {} PUT, {} DELETE; {} bytes downloaded, {} bytes uploaded",
+            self.list,
+            self.get,
+            self.put,
+            self.delete,
+            self.downloaded,
+            self.uploaded
+        );
+        let costs = prices.costs(self);
+        let total: f64 = costs.iter().map(|(_, cost)| cost).sum();
+        let breakdown: Vec<String> = costs
+            .iter()
+            .map(|(item, cost)| format!("{cost:.4} {item}"))
+            .collect();
+        summary!("Estimated cost: {total:.4} ({})", breakdown.join(", "));
+    }
+}
+
+/// Price sheet in any currency, per 1000 requests and per GiB transferred. The defaults are
+/// those of S3 Standard in `us-east-1`, with transfers inside the region being free.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Prices {
+    pub list: f64,
+    pub get: f64,
+    pub put: f64,
+    pub delete: f64,
+    pub download_per_gib: f64,
+    pub upload_per_gib: f64,
+}
+
+impl Default for Prices {
+    fn default() -> Self {
+        Self {
+            list: 0.005,
+            get: 0.0004,
+            put: 0.005,
+            delete: 0.0,
+            download_per_gib: 0.0,
+            upload_per_gib: 0.0,
+        }
+    }
+}
+
+impl Prices {
+    /// Reads a price sheet from a TOML file; items it leaves out keep their default.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the file cannot be read or is not a valid price sheet.
+    pub fn load(path: impl AsRef<std::path::Path>) -> AppResult<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AppError::Config(format!("cannot read {}: {e}", path.display())))?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Cost of `usage` per item of the sheet.
+    #[allow(clippy::cast_precision_loss)] // Counts and sizes are far below 2^52.
+    fn costs(&self, usage: &Usage) -> [(&'static str, f64); 6] {
+        const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+        let requests = |count: u64, price: f64| count as f64 / 1000.0 * price;
+        [
+            ("LIST", requests(usage.list, self.list)),
+            ("GET", requests(usage.get, self.get)),
+            ("PUT", requests(usage.put, self.put)),
+            ("DELETE", requests(usage.delete, self.delete)),
+            (
+                "download",
+                usage.downloaded as f64 / GIB * self.download_per_gib,
+            ),
+            ("upload", usage.uploaded as f64 / GIB * self.upload_per_gib),
+        ]
+    }
+}
+
+/// Counts the requests made through `inner` and the bytes they move.
+///
+/// Listings count a request per [`LIST_PAGE_SIZE`] keys, deletions one per key, multipart
+/// uploads one per part plus the requests starting and completing them.
+#[derive(Debug)]
+pub struct Metered {
+    inner: Arc<dyn ObjectStore>,
+    counters: &'static Counters,
+}
+
+impl Metered {
+    pub fn new(inner: Arc<dyn ObjectStore>, counters: &'static Counters) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl fmt::Display for Metered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Metered({})", self.inner)
+    }
+}
+
+/// Counts a `LIST` request for every page of keys going by.
+fn metered_listing<T: Send + 'static>(
+    listing: BoxStream<'static, T>,
+    counters: &'static Counters,
+) -> BoxStream<'static, T> {
+    counters.request(Request::List);
+    listing
+        .enumerate()
+        .map(move |(i, item)| {
+            if i > 0 && i % LIST_PAGE_SIZE == 0 {
+                counters.request(Request::List);
+            }
+            item
+        })
+        .boxed()
+}
+
+#[async_trait]
+impl ObjectStore for Metered {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        self.counters.request(Request::Put);
+        self.counters.uploaded(payload.content_length() as u64);
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        self.counters.request(Request::Put);
+        let upload = self.inner.put_multipart_opts(location, opts).await?;
+        Ok(Box::new(MeteredUpload {
+            inner: upload,
+            counters: self.counters,
+            location: location.clone(),
+            parts: 0,
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        self.counters.request(Request::Get);
+        let head = options.head;
+        let result = self.inner.get_opts(location, options).await?;
+        if !head {
+            self.counters
+                .downloaded(result.range.end.saturating_sub(result.range.start));
+        }
+        Ok(result)
+    }
+
+    fn delete_stream(
+        &self,
+        locations: BoxStream<'static, Result<Path>>,
+    ) -> BoxStream<'static, Result<Path>> {
+        let counters = self.counters;
+        self.inner.delete_stream(
+            locations
+                .inspect(move |_| counters.request(Request::Delete))
+                .boxed(),
+        )
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+        metered_listing(self.inner.list(prefix), self.counters)
+    }
+
+    fn list_with_offset(
+        &self,
+        prefix: Option<&Path>,
+        offset: &Path,
+    ) -> BoxStream<'static, Result<ObjectMeta>> {
+        metered_listing(self.inner.list_with_offset(prefix, offset), self.counters)
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        let result = self.inner.list_with_delimiter(prefix).await?;
+        let keys = result.objects.len() + result.common_prefixes.len();
+        for _ in 0..=keys / LIST_PAGE_SIZE {
+            self.counters.request(Request::List);
+        }
+        Ok(result)
+    }
+
+    async fn copy_opts(&self, from: &Path, to: &Path, options: CopyOptions) -> Result<()> {
+        self.counters.request(Request::Put);
+        self.inner.copy_opts(from, to, options).await
+    }
+}
+
+#[derive(Debug)]
+struct MeteredUpload {
+    inner: Box<dyn MultipartUpload>,
+    counters: &'static Counters,
+    location: Path,
+    /// Parts started so far.
+    parts: usize,
+}
+
+#[async_trait]
+impl MultipartUpload for MeteredUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        self.parts += 1;
+        debug!(
+            "Uploading part {} of {} ({} bytes)",
+            self.parts,
+            self.location,
+            data.content_length()
+        );
+        self.counters.request(Request::Put);
+        self.counters.uploaded(data.content_length() as u64);
+        self.inner.put_part(data)
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        debug!("Completing {} in {} parts", self.location, self.parts);
+        self.counters.request(Request::Put);
+        self.inner.complete().await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        debug!("Aborting {} after {} parts", self.location, self.parts);
+        self.counters.request(Request::Delete);
+        self.inner.abort().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use object_store::ObjectStoreExt;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_metered_counts_requests_and_bytes() -> crate::error::Result<()> {
+        static COUNTERS: Counters = Counters::new();
+        let store = Metered::new(Arc::new(InMemory::new()), &COUNTERS);
+        for i in 0..3 {
+            store
+                .put(&Path::from(format!("logs/{i}.log")), "line".into())
+                .await?;
+        }
+        let listed: Vec<ObjectMeta> = store.list(None).try_collect().await?;
+        store.get(&listed[0].location).await?.bytes().await?;
+        store.head(&listed[1].location).await?;
+        store.delete(&listed[2].location).await?;
+
+        let usage = COUNTERS.snapshot();
+        assert_eq!(
+            usage,
+            Usage {
+                list: 1,
+                get: 2,
+                put: 3,
+                delete: 1,
+                downloaded: 4,
+                uploaded: 12,
+            }
+        );
+
+        let costs = Prices::default().costs(&usage);
+        assert!((costs[2].1 - 0.000_015).abs() < 1e-12);
+        Ok(())
+    }
+}